@@ -0,0 +1,312 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use emulator::io::{InterruptLines, PortBus, PortDevice};
+use emulator::Emulator;
+
+// A20ゲートを操作するI/Oポート
+pub const KBC_DATA_PORT: u16 = 0x60;
+pub const KBC_COMMAND_PORT: u16 = 0x64;
+pub const SYSTEM_CONTROL_PORT_A: u16 = 0x92;
+
+// キーボードコントローラのコマンド
+const KBC_READ_OUTPUT_PORT: u8 = 0xD0;
+const KBC_WRITE_OUTPUT_PORT: u8 = 0xD1;
+const KBC_DISABLE_A20: u8 = 0xDD;
+const KBC_ENABLE_A20: u8 = 0xDF;
+
+// ステータスレジスタ: 出力バッファに読み出せるデータがある
+const KBC_STATUS_OUTPUT_FULL: u8 = 1;
+// 出力ポートとポート0x92でA20ゲートを表すビット(出力ポートのビット0はリセット線で常に1)
+const A20_BIT: u8 = 1 << 1;
+const KBC_OUTPUT_PORT_RESET: u8 = 1;
+
+// A20ゲートを有効にしている要因(Fast A20とキーボードコントローラ)
+// 既定のデバイスを置き換えるデバイスは、同じビットか空いているビットを使う
+pub const FAST_A20_SOURCE: u8 = 1;
+pub const KBC_A20_SOURCE: u8 = 1 << 1;
+
+// A20ゲートの状態。デバイスはread8/write8に渡されるInterruptLinesから操作する
+// どれか1つの要因で有効になっていれば、アドレスのビット20がそのまま使われる
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct A20Line {
+    sources: u8,
+}
+
+impl A20Line {
+    pub fn is_enabled(&self) -> bool {
+        self.sources != 0
+    }
+
+    pub fn set_source(&mut self, source: u8, enabled: bool) {
+        if enabled {
+            self.sources |= source;
+        } else {
+            self.sources &= !source;
+        }
+    }
+
+    pub fn has_source(&self, source: u8) -> bool {
+        self.sources & source != 0
+    }
+}
+
+// ポート0x92(System Control Port A)のFast A20
+pub struct SystemControlPortA;
+
+impl PortDevice for SystemControlPortA {
+    fn read8(&mut self, _: u16, lines: &mut InterruptLines) -> u8 {
+        if lines.a20_line().has_source(FAST_A20_SOURCE) {
+            A20_BIT
+        } else {
+            0
+        }
+    }
+
+    fn write8(&mut self, _: u16, value: u8, lines: &mut InterruptLines) {
+        // ビット0(高速リセット)は扱わない
+        lines.a20_line_mut().set_source(FAST_A20_SOURCE, value & A20_BIT != 0);
+    }
+}
+
+// キーボードコントローラ(8042)のうちA20ゲートに関係する部分
+struct KeyboardControllerState {
+    // データポートへの書き込みを待っているコマンド
+    command: Option<u8>,
+    output_buffer: Option<u8>,
+}
+
+// データポート(0x60)とコマンド/ステータスポート(0x64)は離れているので、状態を共有する2つのデバイスとして登録する
+// 出力ポートのA20のビットはInterruptLinesのA20Lineが持つ
+pub struct KeyboardController {
+    state: Rc<RefCell<KeyboardControllerState>>,
+    command_port: bool,
+}
+
+impl KeyboardController {
+    // データポートとコマンドポートに登録するデバイスを作る
+    pub fn new() -> (KeyboardController, KeyboardController) {
+        let state = Rc::new(RefCell::new(KeyboardControllerState {
+            command: None,
+            output_buffer: None,
+        }));
+        let data = KeyboardController {
+            state: state.clone(),
+            command_port: false,
+        };
+        let command = KeyboardController { state, command_port: true };
+        (data, command)
+    }
+}
+
+fn output_port(lines: &InterruptLines) -> u8 {
+    if lines.a20_line().has_source(KBC_A20_SOURCE) {
+        KBC_OUTPUT_PORT_RESET | A20_BIT
+    } else {
+        KBC_OUTPUT_PORT_RESET
+    }
+}
+
+impl PortDevice for KeyboardController {
+    fn read8(&mut self, _: u16, _: &mut InterruptLines) -> u8 {
+        let mut state = self.state.borrow_mut();
+        if self.command_port {
+            if state.output_buffer.is_some() {
+                KBC_STATUS_OUTPUT_FULL
+            } else {
+                0
+            }
+        } else {
+            state.output_buffer.take().unwrap_or(0)
+        }
+    }
+
+    fn write8(&mut self, _: u16, value: u8, lines: &mut InterruptLines) {
+        let mut state = self.state.borrow_mut();
+        if self.command_port {
+            state.command = None;
+            match value {
+                KBC_READ_OUTPUT_PORT => state.output_buffer = Some(output_port(lines)),
+                KBC_WRITE_OUTPUT_PORT => state.command = Some(value),
+                KBC_DISABLE_A20 => lines.a20_line_mut().set_source(KBC_A20_SOURCE, false),
+                KBC_ENABLE_A20 => lines.a20_line_mut().set_source(KBC_A20_SOURCE, true),
+                _ => {}
+            }
+        } else if state.command.take() == Some(KBC_WRITE_OUTPUT_PORT) {
+            lines.a20_line_mut().set_source(KBC_A20_SOURCE, value & A20_BIT != 0);
+        }
+    }
+}
+
+// ポート0x92とキーボードコントローラを登録する
+// 独自のキーボードコントローラを使うときは、PortBus::unregisterで0x60と0x64のデバイスを外してから登録する
+pub fn register_a20_devices(ports: &mut PortBus) {
+    let (data, command) = KeyboardController::new();
+    let _ = ports.register(SYSTEM_CONTROL_PORT_A, 1, Box::new(SystemControlPortA));
+    let _ = ports.register(KBC_DATA_PORT, 1, Box::new(data));
+    let _ = ports.register(KBC_COMMAND_PORT, 1, Box::new(command));
+}
+
+pub trait A20Gate {
+    fn is_a20_enabled(&self) -> bool;
+    fn mask_a20(&self, address: usize) -> usize;
+}
+
+impl Emulator {
+    // ホストからA20ゲートを設定する(保護モードの起動時は有効、リアルモードのリセット後は無効)
+    pub fn set_a20_enabled(&mut self, enabled: bool) {
+        let line = self.interrupt_lines.a20_line_mut();
+        line.set_source(KBC_A20_SOURCE, enabled);
+        if !enabled {
+            line.set_source(FAST_A20_SOURCE, false);
+        }
+    }
+
+    pub fn a20_line(&self) -> &A20Line {
+        self.interrupt_lines.a20_line()
+    }
+}
+
+impl A20Gate for Emulator {
+    fn is_a20_enabled(&self) -> bool {
+        self.a20_line().is_enabled()
+    }
+
+    // A20ゲートが無効なときはアドレスのビット20を0にして、1MBを超えたアクセスを先頭に折り返す
+    fn mask_a20(&self, address: usize) -> usize {
+        if self.is_a20_enabled() {
+            address
+        } else {
+            address & !(1 << 20)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{A20Gate, KBC_A20_SOURCE, KBC_COMMAND_PORT, KBC_DATA_PORT};
+    use emulator::io::{InterruptLines, PortDevice, PortMapError};
+    use emulator::testing::*;
+    use emulator::MEMORY_SIZE;
+
+    // コマンド0xDF/0xDDだけを受け付ける、ホストが用意したキーボードコントローラ
+    struct HostKeyboardController;
+
+    impl PortDevice for HostKeyboardController {
+        fn read8(&mut self, _: u16, _: &mut InterruptLines) -> u8 {
+            0
+        }
+
+        fn write8(&mut self, _: u16, value: u8, lines: &mut InterruptLines) {
+            match value {
+                0xDF => lines.a20_line_mut().set_source(KBC_A20_SOURCE, true),
+                0xDD => lines.a20_line_mut().set_source(KBC_A20_SOURCE, false),
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn protected_mode_start_has_a20_enabled() {
+        let emu = emulator(&[]);
+        assert!(emu.is_a20_enabled());
+        assert_eq!(emu.mask_a20(0x10_0000), 0x10_0000);
+    }
+
+    #[test]
+    fn real_mode_reset_disables_a20_until_keyboard_controller_enables_it() {
+        let mut emu = emulator(&[
+            0xB0, 0xD1, // mov al, 0xD1(出力ポートへの書き込み)
+            0xE6, 0x64, // out 0x64, al
+            0xB0, 0xDF, // mov al, 0xDF(A20を有効にした出力ポートの値)
+            0xE6, 0x60, // out 0x60, al
+        ]);
+        emu.reset_to_real_mode();
+        assert!(!emu.is_a20_enabled());
+        assert_eq!(emu.mask_a20(0x10_FFEF), 0xFFEF);
+        run(&mut emu, 4);
+        assert!(emu.is_a20_enabled());
+    }
+
+    #[test]
+    fn real_mode_addresses_wrap_at_1mb_until_fast_a20_is_enabled() {
+        let mut emu = emulator(&[
+            0xB8, 0xFF, 0xFF, // mov ax, 0xFFFF
+            0x8E, 0xD8, // mov ds, ax
+            0x8B, 0x0E, 0x10, 0x00, // mov cx, [0x10]
+            0xB0, 0x02, // mov al, 2
+            0xE6, 0x92, // out 0x92, al
+            0x8B, 0x16, 0x10, 0x00, // mov dx, [0x10]
+            0xE4, 0x92, // in al, 0x92
+        ]);
+        emu.bus.add_ram(MEMORY_SIZE, 0x10000).unwrap();
+        emu.reset_to_real_mode();
+        write16(&mut emu, 0, 0x1111);
+        write16(&mut emu, 0x10_0000, 0x2222);
+        run(&mut emu, 3);
+        // FFFF:0010は0x00000に折り返す
+        assert_eq!(emu.registers[1], 0x1111);
+        run(&mut emu, 4);
+        assert_eq!(emu.registers[2], 0x2222);
+        assert_eq!(emu.registers[0] & 0xFF, 0x02);
+    }
+
+    #[test]
+    fn keyboard_controller_commands_switch_a20_and_report_the_output_port() {
+        let mut emu = emulator(&[
+            0xB0, 0xDF, // mov al, 0xDF(A20を有効にする)
+            0xE6, 0x64, // out 0x64, al
+            0xB0, 0xD0, // mov al, 0xD0(出力ポートの読み出し)
+            0xE6, 0x64, // out 0x64, al
+            0xE4, 0x64, // in al, 0x64
+            0x88, 0xC3, // mov bl, al
+            0xE4, 0x60, // in al, 0x60
+            0x88, 0xC1, // mov cl, al
+            0xB0, 0xDD, // mov al, 0xDD(A20を無効にする)
+            0xE6, 0x64, // out 0x64, al
+            0xE4, 0x64, // in al, 0x64
+        ]);
+        emu.reset_to_real_mode();
+        run(&mut emu, 2);
+        assert!(emu.is_a20_enabled());
+        run(&mut emu, 6);
+        // 出力バッファにデータがあり、出力ポートはリセット線とA20のビットが立っている
+        assert_eq!(emu.registers[3] & 0xFF, 0x01);
+        assert_eq!(emu.registers[1] & 0xFF, 0x03);
+        run(&mut emu, 3);
+        assert!(!emu.is_a20_enabled());
+        assert_eq!(emu.registers[0] & 0xFF, 0x00);
+    }
+
+    #[test]
+    fn default_keyboard_controller_can_be_replaced() {
+        let mut emu = emulator(&[
+            0xB0, 0xDF, // mov al, 0xDF(A20を有効にする)
+            0xE6, 0x64, // out 0x64, al
+            0xB0, 0x02, // mov al, 2
+            0xE6, 0x92, // out 0x92, al
+            0xB0, 0xDD, // mov al, 0xDD(A20を無効にする)
+            0xE6, 0x64, // out 0x64, al
+        ]);
+        emu.reset_to_real_mode();
+        // 既定のデバイスを外さなければ同じポートには登録できない
+        let result = emu.ports_mut().register(KBC_COMMAND_PORT, 1, Box::new(HostKeyboardController));
+        assert_eq!(
+            result,
+            Err(PortMapError::Overlapping {
+                base: KBC_COMMAND_PORT,
+                count: 1
+            })
+        );
+        assert!(emu.ports_mut().unregister(KBC_DATA_PORT).is_some());
+        assert!(emu.ports_mut().unregister(KBC_COMMAND_PORT).is_some());
+        emu.ports_mut().register(KBC_COMMAND_PORT, 1, Box::new(HostKeyboardController)).unwrap();
+
+        run(&mut emu, 2);
+        assert!(emu.is_a20_enabled());
+        // ポート0x92のFast A20は既定のデバイスのまま使える
+        run(&mut emu, 4);
+        assert!(emu.is_a20_enabled());
+        assert!(!emu.a20_line().has_source(KBC_A20_SOURCE));
+    }
+}
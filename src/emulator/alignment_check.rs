@@ -0,0 +1,129 @@
+use emulator::control_register::CR0_AM;
+use emulator::emulator_function::EmulatorFunction;
+use emulator::interrupt::alignment_check;
+use emulator::segment::Segment;
+use emulator::{Emulator, SegmentRegister};
+
+pub trait AlignmentCheck {
+    fn is_alignment_check_enabled(&self) -> bool;
+    fn check_alignment(&mut self, address: usize, size: usize);
+    fn check_stack_alignment(&mut self, address: usize, size: usize);
+}
+
+impl Emulator {
+    // アクセスが境界に揃っていなければ#ACを命令の終わりに通知する
+    fn record_alignment_fault(&mut self, address: usize, size: usize) {
+        if address & (size - 1) != 0 && self.memory_fault.is_none() {
+            self.memory_fault = Some(alignment_check());
+        }
+    }
+}
+
+impl AlignmentCheck for Emulator {
+    // CR0.AMとEFLAGS.ACが立っていれば、リング3のデータアクセスを確認する
+    fn is_alignment_check_enabled(&self) -> bool {
+        self.control_registers[0] & CR0_AM != 0 && self.eflags & Self::ALIGNMENT_CHECK_FLAG != 0 && self.cpl() == 3
+    }
+
+    fn check_alignment(&mut self, address: usize, size: usize) {
+        if self.is_alignment_check_enabled() {
+            self.record_alignment_fault(address, size);
+        }
+    }
+
+    // 特権レベルの変化でリング0のスタックに切り替えた後のアクセスは確認しない
+    fn check_stack_alignment(&mut self, address: usize, size: usize) {
+        let ss = self.segment_registers[SegmentRegister::SS as usize];
+        if self.is_alignment_check_enabled() && (self.is_virtual_8086() || ss & 3 == 3) {
+            self.record_alignment_fault(address, size);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use emulator::control_register::CR0_AM;
+    use emulator::emulator_function::EmulatorFunction;
+    use emulator::testing::*;
+    use emulator::Emulator;
+
+    // リング3でcodeを実行し、CR0.AMを立てて#AC(ベクタ17)のハンドラ(0x7e00)を用意する
+    fn alignment_check_emulator(code: &[u8], alignment_check: bool) -> Emulator {
+        let mut emu = ring3_emulator(code, 17);
+        emu.control_registers[0] |= CR0_AM;
+        if alignment_check {
+            emu.eflags |= Emulator::ALIGNMENT_CHECK_FLAG;
+        }
+        emu
+    }
+
+    // エラーコード0と戻り先eipを積んで#ACのハンドラへ入ったことを確かめる
+    fn assert_alignment_check(emu: &Emulator, eip: u32) {
+        assert_eq!(emu.eip, 0x7e00);
+        assert_eq!(read32(emu, RING0_STACK - 24), 0);
+        assert_eq!(read32(emu, RING0_STACK - 20), eip);
+    }
+
+    #[test]
+    fn misaligned_load_in_ring3_raises_alignment_check() {
+        let mut emu = alignment_check_emulator(
+            &[
+                0x8B, 0x05, 0x00, 0x40, 0x00, 0x00, // mov eax, [0x4000]
+                0x8B, 0x0D, 0x02, 0x40, 0x00, 0x00, // mov ecx, [0x4002]
+            ],
+            true,
+        );
+        write32(&mut emu, 0x4000, 0x1234_5678);
+        run(&mut emu, 2);
+        assert_alignment_check(&emu, 0x7c06);
+        assert_eq!(emu.registers[0], 0x1234_5678);
+        assert_eq!(emu.registers[1], 0);
+    }
+
+    #[test]
+    fn misaligned_store_is_not_partially_written() {
+        let mut emu = alignment_check_emulator(
+            &[
+                0xB8, 0x44, 0x33, 0x22, 0x11, // mov eax, 0x11223344
+                0x89, 0x05, 0x01, 0x40, 0x00, 0x00, // mov [0x4001], eax
+            ],
+            true,
+        );
+        run(&mut emu, 2);
+        assert_alignment_check(&emu, 0x7c05);
+        assert_eq!(read32(&emu, 0x4000), 0);
+        assert_eq!(read32(&emu, 0x4004), 0);
+    }
+
+    #[test]
+    fn misaligned_push_in_ring3_raises_alignment_check() {
+        let mut emu = alignment_check_emulator(
+            &[
+                0x6A, 0x01, // push 1
+            ],
+            true,
+        );
+        emu.set_register32(4, STACK - 2);
+        run(&mut emu, 1);
+        assert_alignment_check(&emu, 0x7c00);
+        // スタックポインタは命令の実行前に戻っている
+        assert_eq!(read32(&emu, RING0_STACK - 8), STACK - 2);
+    }
+
+    #[test]
+    fn alignment_is_checked_only_in_ring3_with_eflags_ac() {
+        let code = [
+            0x8B, 0x05, 0x01, 0x40, 0x00, 0x00, // mov eax, [0x4001]
+        ];
+        let mut emu = alignment_check_emulator(&code, false);
+        run(&mut emu, 1);
+        assert_eq!(emu.eip, 0x7c06);
+
+        // リング0ではACが立っていても確認しない
+        let mut emu = emulator(&code);
+        emu.control_registers[0] |= CR0_AM;
+        emu.eflags |= Emulator::ALIGNMENT_CHECK_FLAG;
+        run(&mut emu, 1);
+        assert_eq!(emu.eip, 0x7c06);
+    }
+}
@@ -0,0 +1,558 @@
+use emulator::cpuid::{Cpuid, Feature};
+use emulator::emulator_function::EmulatorFunction;
+use emulator::interrupt::{invalid_opcode, Interrupt};
+use emulator::modrm::{Function as ModRMFunction, ModRM};
+use emulator::undefined_flags::{result_flags, UndefinedFlags};
+use emulator::{Emulator, Register};
+
+pub trait BitManipulation {
+    fn popcnt(&mut self);
+    fn bsf(&mut self);
+    fn bsr(&mut self);
+    fn movbe(&mut self, store: bool);
+    fn bswap(&mut self);
+    fn adcx_adox(&mut self);
+    fn crc32(&mut self, byte_source: bool);
+    fn andn(&mut self, vvvv: usize);
+    fn bmi1_group(&mut self, vvvv: usize);
+    fn bzhi(&mut self, vvvv: usize);
+    fn bextr(&mut self, vvvv: usize);
+    fn pdep(&mut self, vvvv: usize);
+    fn pext(&mut self, vvvv: usize);
+    fn mulx(&mut self, vvvv: usize);
+    fn shlx(&mut self, vvvv: usize);
+    fn sarx(&mut self, vvvv: usize);
+    fn shrx(&mut self, vvvv: usize);
+    fn rorx(&mut self, vvvv: usize);
+}
+
+impl Emulator {
+    // オペランドサイズに合わせてr/mを読み、値とビット数を返す
+    fn get_rm_operand(&mut self, modrm: &ModRM) -> (u32, u32) {
+        if self.is_operand_size16() {
+            (self.get_rm16(modrm) as u32, 16)
+        } else {
+            (self.get_rm32(modrm), 32)
+        }
+    }
+
+    fn set_r_operand(&mut self, modrm: &ModRM, value: u32) {
+        if self.is_operand_size16() {
+            self.set_r16(modrm, value as u16);
+        } else {
+            self.set_r32(modrm, value);
+        }
+    }
+
+    // ANDNなどの論理演算: ZFとSFを結果から設定し、CFとOFをクリアする
+    // AFとPFは未定義
+    fn update_eflags_logic(&mut self, result: u32) {
+        self.set_zero(result == 0);
+        self.set_sign(result >> 31 != 0);
+        self.set_carry(false);
+        self.set_overflow(false);
+        let flags = self.eflags;
+        self.set_undefined_flags(Self::ADJUST_FLAG | Self::PARITY_FLAG, result_flags(result, 32), flags);
+    }
+
+    // BSF/BSRはCF, OF, SF, AF, PFが未定義(Intelはソースから求め、AMDは変更しない)
+    fn set_scan_undefined_flags(&mut self, value: u32, bits: u32) {
+        let mask = Self::CARRY_FLAG | Self::OVERFLOW_FLAG | Self::SIGN_FLAG | Self::ADJUST_FLAG | Self::PARITY_FLAG;
+        let flags = self.eflags;
+        self.set_undefined_flags(mask, result_flags(value, bits), flags);
+    }
+
+    // TZCNT/LZCNTはOF, SF, AF, PFが未定義(Intelは結果から求め、AMDは変更しない)
+    fn set_count_undefined_flags(&mut self, count: u32, bits: u32) {
+        let mask = Self::OVERFLOW_FLAG | Self::SIGN_FLAG | Self::ADJUST_FLAG | Self::PARITY_FLAG;
+        let flags = self.eflags;
+        self.set_undefined_flags(mask, result_flags(count, bits), flags);
+    }
+
+    // VEXのvvvvで指定されたレジスタ(32ビットモードでは上位ビットを使わない)
+    fn get_vex_register(&self, vvvv: usize) -> u32 {
+        self.get_register32(vvvv & 7)
+    }
+
+    // VEXで符号化された命令のModRMとr/mの値を読む
+    fn parse_vex_operand(&mut self) -> (ModRM, u32) {
+        let modrm = self.parse_modrm();
+        let value = self.get_rm32(&modrm);
+        (modrm, value)
+    }
+
+    fn shift_by_register<F: Fn(u32, u32) -> u32>(&mut self, vvvv: usize, shift: F) {
+        let (modrm, value) = self.parse_vex_operand();
+        let count = self.get_vex_register(vvvv) & 31;
+        self.set_r32(&modrm, shift(value, count));
+    }
+}
+
+// マスクで選んだビットを下位へ詰める
+fn parallel_extract(source: u32, mask: u32) -> u32 {
+    let mut result = 0;
+    let mut k = 0;
+    for i in 0..32 {
+        if mask & (1 << i) != 0 {
+            result |= ((source >> i) & 1) << k;
+            k += 1;
+        }
+    }
+    result
+}
+
+// CRC32命令が使うCRC-32C(Castagnoli)の多項式をビット反転したもの
+const CRC32C_POLYNOMIAL: u32 = 0x82F6_3B78;
+
+// 最下位ビットから順に1バイト分を処理する(初期値と最後の反転はソフトウェアが行う)
+fn crc32c_update(crc: u32, byte: u8) -> u32 {
+    let mut crc = crc ^ byte as u32;
+    for _ in 0..8 {
+        crc = if crc & 1 != 0 { (crc >> 1) ^ CRC32C_POLYNOMIAL } else { crc >> 1 };
+    }
+    crc
+}
+
+// 下位のビットをマスクで選んだ位置へ配る
+fn parallel_deposit(source: u32, mask: u32) -> u32 {
+    let mut result = 0;
+    let mut k = 0;
+    for i in 0..32 {
+        if mask & (1 << i) != 0 {
+            result |= ((source >> k) & 1) << i;
+            k += 1;
+        }
+    }
+    result
+}
+
+impl BitManipulation for Emulator {
+    fn popcnt(&mut self) {
+        // F3プレフィックスのないF3 0F B8は未定義
+        if self.repeat_prefix != Some(0xF3) || !self.has_feature(Feature::Popcnt) {
+            self.raise_exception(invalid_opcode());
+            return;
+        }
+        self.eip += 2;
+        let modrm = self.parse_modrm();
+        let (value, _) = self.get_rm_operand(&modrm);
+        self.set_r_operand(&modrm, value.count_ones());
+        self.eflags &= !(Self::OVERFLOW_FLAG | Self::SIGN_FLAG | Self::ADJUST_FLAG | Self::CARRY_FLAG | Self::PARITY_FLAG);
+        self.set_zero(value == 0);
+    }
+
+    fn bsf(&mut self) {
+        self.eip += 2;
+        let modrm = self.parse_modrm();
+        let (value, bits) = self.get_rm_operand(&modrm);
+        // BMI1がなければF3プレフィックスは無視されてBSFになる
+        if self.repeat_prefix == Some(0xF3) && self.has_feature(Feature::Bmi1) {
+            let count = if value == 0 { bits } else { value.trailing_zeros() };
+            self.set_r_operand(&modrm, count);
+            self.set_carry(value == 0);
+            self.set_zero(count == 0);
+            self.set_count_undefined_flags(count, bits);
+            return;
+        }
+        // 0のときは書き込み先を変更しない
+        self.set_zero(value == 0);
+        self.set_scan_undefined_flags(value, bits);
+        if value != 0 {
+            self.set_r_operand(&modrm, value.trailing_zeros());
+        }
+    }
+
+    fn bsr(&mut self) {
+        self.eip += 2;
+        let modrm = self.parse_modrm();
+        let (value, bits) = self.get_rm_operand(&modrm);
+        if self.repeat_prefix == Some(0xF3) && self.has_feature(Feature::Lzcnt) {
+            let count = value.leading_zeros() - (32 - bits);
+            self.set_r_operand(&modrm, count);
+            self.set_carry(value == 0);
+            self.set_zero(count == 0);
+            self.set_count_undefined_flags(count, bits);
+            return;
+        }
+        self.set_zero(value == 0);
+        self.set_scan_undefined_flags(value, bits);
+        if value != 0 {
+            self.set_r_operand(&modrm, 31 - value.leading_zeros());
+        }
+    }
+
+    fn movbe(&mut self, store: bool) {
+        if !self.has_feature(Feature::Movbe) {
+            self.raise_exception(invalid_opcode());
+            return;
+        }
+        self.eip += 3;
+        let modrm = self.parse_modrm();
+        // メモリとの間でしか使えない
+        if modrm.mode == 3 {
+            self.raise_exception(invalid_opcode());
+            return;
+        }
+        match (store, self.is_operand_size16()) {
+            (false, true) => {
+                let value = self.get_rm16(&modrm);
+                self.set_r16(&modrm, value.swap_bytes());
+            }
+            (false, false) => {
+                let value = self.get_rm32(&modrm);
+                self.set_r32(&modrm, value.swap_bytes());
+            }
+            (true, true) => {
+                let value = self.get_r16(&modrm);
+                self.set_rm16(&modrm, value.swap_bytes());
+            }
+            (true, false) => {
+                let value = self.get_r32(&modrm);
+                self.set_rm32(&modrm, value.swap_bytes());
+            }
+        }
+    }
+
+    // 0F C8+r: BSWAP r32 (16ビットオペランドでは未定義で、実機と同じく下位16ビットを0にする)
+    fn bswap(&mut self) {
+        let reg = (self.get_code8(1) - 0xC8) as usize;
+        if self.is_operand_size16() {
+            self.set_register16(reg, 0);
+        } else {
+            let value = self.get_register32(reg);
+            self.set_register32(reg, value.swap_bytes());
+        }
+        self.eip += 2;
+    }
+
+    fn adcx_adox(&mut self) {
+        // 66プレフィックスならCFを使うADCX, F3プレフィックスならOFを使うADOX
+        let flag = match (self.operand_size_override, self.repeat_prefix) {
+            (true, None) => Self::CARRY_FLAG,
+            (false, Some(0xF3)) => Self::OVERFLOW_FLAG,
+            _ => 0,
+        };
+        if flag == 0 || !self.has_feature(Feature::Adx) {
+            self.raise_exception(invalid_opcode());
+            return;
+        }
+        self.eip += 3;
+        let modrm = self.parse_modrm();
+        let source = self.get_rm32(&modrm);
+        let destination = self.get_r32(&modrm);
+        let carry = (self.eflags & flag != 0) as u64;
+        let result = destination as u64 + source as u64 + carry;
+        self.set_r32(&modrm, result as u32);
+        self.set_flag(flag, result >> 32 != 0);
+    }
+
+    // F2 0F 38 F0: CRC32 r32, r/m8, F2 0F 38 F1: CRC32 r32, r/m16(66プレフィックス付き)またはr/m32
+    fn crc32(&mut self, byte_source: bool) {
+        if !self.has_feature(Feature::Sse42) {
+            self.raise_exception(invalid_opcode());
+            return;
+        }
+        self.eip += 3;
+        let modrm = self.parse_modrm();
+        let (value, bits) = if byte_source {
+            (self.get_rm8(&modrm) as u32, 8)
+        } else {
+            self.get_rm_operand(&modrm)
+        };
+        // 書き込み先はオペランドサイズによらず32ビットで、フラグは変化しない
+        let mut crc = self.get_r32(&modrm);
+        for i in 0..bits / 8 {
+            crc = crc32c_update(crc, (value >> (i * 8)) as u8);
+        }
+        self.set_r32(&modrm, crc);
+    }
+
+    fn andn(&mut self, vvvv: usize) {
+        let (modrm, value) = self.parse_vex_operand();
+        let result = !self.get_vex_register(vvvv) & value;
+        self.set_r32(&modrm, result);
+        self.update_eflags_logic(result);
+    }
+
+    // VEX.0F38 F3 /1 BLSR, /2 BLSMSK, /3 BLSI (書き込み先はvvvv)
+    fn bmi1_group(&mut self, vvvv: usize) {
+        let (modrm, value) = self.parse_vex_operand();
+        let lowest = value.wrapping_sub(1);
+        let (result, carry) = match modrm.get_opecode() {
+            1 => (value & lowest, value == 0),
+            2 => (value ^ lowest, value == 0),
+            3 => (value & value.wrapping_neg(), value != 0),
+            _ => {
+                self.raise_exception(invalid_opcode());
+                return;
+            }
+        };
+        self.set_register32(vvvv & 7, result);
+        self.update_eflags_logic(result);
+        self.set_carry(carry);
+        // BLSMSKは常にZFをクリアする
+        if modrm.get_opecode() == 2 {
+            self.set_zero(false);
+        }
+    }
+
+    fn bzhi(&mut self, vvvv: usize) {
+        let (modrm, value) = self.parse_vex_operand();
+        let index = self.get_vex_register(vvvv) & 0xFF;
+        let result = if index < 32 { value & ((1u32 << index) - 1) } else { value };
+        self.set_r32(&modrm, result);
+        self.update_eflags_logic(result);
+        self.set_carry(index > 31);
+    }
+
+    fn bextr(&mut self, vvvv: usize) {
+        let (modrm, value) = self.parse_vex_operand();
+        let control = self.get_vex_register(vvvv);
+        let start = control & 0xFF;
+        let length = (control >> 8) & 0xFF;
+        let shifted = if start < 32 { value >> start } else { 0 };
+        let result = if length < 32 { shifted & ((1u32 << length) - 1) } else { shifted };
+        self.set_r32(&modrm, result);
+        // BEXTRはSFも未定義
+        let flags = self.eflags;
+        self.update_eflags_logic(result);
+        self.set_undefined_flags(Self::SIGN_FLAG, result_flags(result, 32), flags);
+    }
+
+    fn pdep(&mut self, vvvv: usize) {
+        let (modrm, mask) = self.parse_vex_operand();
+        let source = self.get_vex_register(vvvv);
+        self.set_r32(&modrm, parallel_deposit(source, mask));
+    }
+
+    fn pext(&mut self, vvvv: usize) {
+        let (modrm, mask) = self.parse_vex_operand();
+        let source = self.get_vex_register(vvvv);
+        self.set_r32(&modrm, parallel_extract(source, mask));
+    }
+
+    fn mulx(&mut self, vvvv: usize) {
+        let (modrm, value) = self.parse_vex_operand();
+        let result = self.get_register32(Register::EDX as usize) as u64 * value as u64;
+        // 同じレジスタが指定されたときは上位が残る
+        self.set_register32(vvvv & 7, result as u32);
+        self.set_r32(&modrm, (result >> 32) as u32);
+    }
+
+    fn shlx(&mut self, vvvv: usize) {
+        self.shift_by_register(vvvv, |value, count| value << count);
+    }
+
+    fn sarx(&mut self, vvvv: usize) {
+        self.shift_by_register(vvvv, |value, count| ((value as i32) >> count) as u32);
+    }
+
+    fn shrx(&mut self, vvvv: usize) {
+        self.shift_by_register(vvvv, |value, count| value >> count);
+    }
+
+    fn rorx(&mut self, vvvv: usize) {
+        // vvvvは使わないので1111でなければならない
+        if vvvv != 0 {
+            self.raise_exception(invalid_opcode());
+            return;
+        }
+        let (modrm, value) = self.parse_vex_operand();
+        let count = self.get_code8(0) as u32 & 31;
+        self.eip += 1;
+        self.set_r32(&modrm, value.rotate_right(count));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use emulator::cpu_model::CpuModel;
+    use emulator::emulator_function::EmulatorFunction;
+    use emulator::testing::*;
+    use emulator::Emulator;
+
+    // modelのCPUで、ECX, EDX, EBXに値を入れてcodeを1命令実行する
+    fn execute(model: CpuModel, code: &[u8], ecx: u32, edx: u32, ebx: u32) -> Emulator {
+        let mut emu = protected_mode_emulator(code, 6);
+        emu.set_cpu_model(model);
+        emu.set_register32(1, ecx);
+        emu.set_register32(2, edx);
+        emu.set_register32(3, ebx);
+        run(&mut emu, 1);
+        emu
+    }
+
+    fn broadwell(code: &[u8], ecx: u32, edx: u32, ebx: u32) -> Emulator {
+        execute(CpuModel::Broadwell, code, ecx, edx, ebx)
+    }
+
+    const POPCNT: [u8; 4] = [0xF3, 0x0F, 0xB8, 0xC1]; // popcnt eax, ecx
+    const TZCNT: [u8; 4] = [0xF3, 0x0F, 0xBC, 0xC1]; // tzcnt eax, ecx
+    const LZCNT: [u8; 4] = [0xF3, 0x0F, 0xBD, 0xC1]; // lzcnt eax, ecx
+
+    #[test]
+    fn popcnt_counts_set_bits() {
+        let emu = broadwell(&POPCNT, 0xF0F0_0001, 0, 0);
+        assert_eq!(emu.registers[0], 9);
+        assert!(!emu.is_zero());
+        let emu = broadwell(&POPCNT, 0, 0, 0);
+        assert_eq!(emu.registers[0], 0);
+        assert!(emu.is_zero());
+        assert!(!emu.is_carry());
+    }
+
+    #[test]
+    fn tzcnt_and_lzcnt_count_zero_bits() {
+        let emu = broadwell(&TZCNT, 0x1000, 0, 0);
+        assert_eq!((emu.registers[0], emu.is_carry()), (12, false));
+        let emu = broadwell(&LZCNT, 0x1000, 0, 0);
+        assert_eq!((emu.registers[0], emu.is_carry()), (19, false));
+        // ソースが0ならオペランドのビット数を返してCFを立てる
+        let emu = broadwell(&TZCNT, 0, 0, 0);
+        assert_eq!((emu.registers[0], emu.is_carry()), (32, true));
+        let emu = broadwell(&[0x66, 0xF3, 0x0F, 0xBD, 0xC1], 0, 0, 0);
+        assert_eq!((emu.registers[0], emu.is_carry()), (16, true));
+        // 結果が0ならZFを立てる
+        let emu = broadwell(&LZCNT, 0x8000_0000, 0, 0);
+        assert_eq!(emu.registers[0], 0);
+        assert!(emu.is_zero());
+    }
+
+    #[test]
+    fn older_models_decode_f3_prefixed_counts_as_bit_scans() {
+        // LZCNT/TZCNTのないCPUではF3プレフィックスを無視してBSR/BSFになる
+        let emu = execute(CpuModel::PentiumII, &LZCNT, 0x1000, 0, 0);
+        assert_eq!(emu.registers[0], 12);
+        let emu = execute(CpuModel::PentiumII, &TZCNT, 0x1000, 0, 0);
+        assert_eq!(emu.registers[0], 12);
+        // POPCNTは#UD
+        let emu = execute(CpuModel::PentiumII, &POPCNT, 0x1000, 0, 0);
+        assert_eq!(emu.eip, 0x7e00);
+    }
+
+    #[test]
+    fn movbe_swaps_bytes_between_memory_and_register() {
+        let mut emu = emulator(&[
+            0x0F, 0x38, 0xF0, 0x05, 0x00, 0x40, 0x00, 0x00, // movbe eax, [0x4000]
+            0x0F, 0x38, 0xF1, 0x05, 0x04, 0x40, 0x00, 0x00, // movbe [0x4004], eax
+            0x66, 0x0F, 0x38, 0xF0, 0x0D, 0x00, 0x40, 0x00, 0x00, // movbe cx, [0x4000]
+        ]);
+        emu.set_cpu_model(CpuModel::Broadwell);
+        write32(&mut emu, 0x4000, 0x1122_3344);
+        run(&mut emu, 3);
+        assert_eq!(emu.registers[0], 0x4433_2211);
+        assert_eq!(read32(&emu, 0x4004), 0x1122_3344);
+        assert_eq!(emu.registers[1], 0x4433);
+    }
+
+    #[test]
+    fn adcx_and_adox_carry_through_separate_flags() {
+        let mut emu = emulator(&[
+            0x66, 0x0F, 0x38, 0xF6, 0xC1, // adcx eax, ecx
+            0xF3, 0x0F, 0x38, 0xF6, 0xD3, // adox edx, ebx
+        ]);
+        emu.set_cpu_model(CpuModel::Broadwell);
+        emu.registers[0] = 0xFFFF_FFFF;
+        emu.registers[2] = 0x7FFF_FFFF;
+        emu.registers[3] = 1;
+        emu.set_carry(true);
+        run(&mut emu, 1);
+        assert_eq!(emu.registers[0], 0);
+        assert!(emu.is_carry());
+        assert!(!emu.is_overflow());
+        run(&mut emu, 1);
+        // ADOXはOFだけを桁上がりに使い、CFは変えない
+        assert_eq!(emu.registers[2], 0x8000_0000);
+        assert!(!emu.is_overflow());
+        assert!(emu.is_carry());
+    }
+
+    #[test]
+    fn bmi1_logic_instructions() {
+        // andn eax, ecx, edx
+        let emu = broadwell(&[0xC4, 0xE2, 0x70, 0xF2, 0xC2], 0xFF00_FF00, 0x1234_5678, 0);
+        assert_eq!(emu.registers[0], 0x0034_0078);
+        // bextr eax, edx, ecx(開始位置8、長さ12)
+        let emu = broadwell(&[0xC4, 0xE2, 0x70, 0xF7, 0xC2], 0x0C08, 0x1234_5678, 0);
+        assert_eq!(emu.registers[0], 0x456);
+        // blsi eax, edx
+        let emu = broadwell(&[0xC4, 0xE2, 0x78, 0xF3, 0xDA], 0, 0x0001_2300, 0);
+        assert_eq!((emu.registers[0], emu.is_carry()), (0x100, true));
+        // blsr eax, edx
+        let emu = broadwell(&[0xC4, 0xE2, 0x78, 0xF3, 0xCA], 0, 0x0001_2300, 0);
+        assert_eq!((emu.registers[0], emu.is_carry()), (0x0001_2200, false));
+        // blsmsk eax, edx
+        let emu = broadwell(&[0xC4, 0xE2, 0x78, 0xF3, 0xD2], 0, 0x0001_2300, 0);
+        assert_eq!(emu.registers[0], 0x1FF);
+        assert!(!emu.is_zero());
+    }
+
+    #[test]
+    fn bmi2_bit_field_instructions() {
+        // bzhi eax, edx, ecx
+        let emu = broadwell(&[0xC4, 0xE2, 0x70, 0xF5, 0xC2], 12, 0x1234_5678, 0);
+        assert_eq!((emu.registers[0], emu.is_carry()), (0x678, false));
+        let emu = broadwell(&[0xC4, 0xE2, 0x70, 0xF5, 0xC2], 40, 0x1234_5678, 0);
+        assert_eq!((emu.registers[0], emu.is_carry()), (0x1234_5678, true));
+        // pdep eax, ecx, edx
+        let emu = broadwell(&[0xC4, 0xE2, 0x73, 0xF5, 0xC2], 0b1011, 0xF0F0, 0);
+        assert_eq!(emu.registers[0], 0xB0);
+        let emu = broadwell(&[0xC4, 0xE2, 0x73, 0xF5, 0xC2], 0b1011_0110, 0xF0F0, 0);
+        assert_eq!(emu.registers[0], 0xB060);
+        // pext eax, ecx, edx
+        let emu = broadwell(&[0xC4, 0xE2, 0x72, 0xF5, 0xC2], 0x1234_5678, 0xF0F0, 0);
+        assert_eq!(emu.registers[0], 0x57);
+    }
+
+    #[test]
+    fn bmi2_multiply_shift_and_rotate() {
+        // mulx eax, ecx, ebx(EDX * EBXの上位がEAX、下位がECX)
+        let emu = broadwell(&[0xC4, 0xE2, 0x73, 0xF6, 0xC3], 0, 0x8000_0001, 4);
+        assert_eq!((emu.registers[0], emu.registers[1]), (2, 4));
+        // shlx/sarx/shrx eax, edx, ecx(シフト数は下位5ビット)
+        let emu = broadwell(&[0xC4, 0xE2, 0x71, 0xF7, 0xC2], 36, 0x8000_0001, 0);
+        assert_eq!(emu.registers[0], 0x10);
+        let emu = broadwell(&[0xC4, 0xE2, 0x72, 0xF7, 0xC2], 4, 0x8000_0000, 0);
+        assert_eq!(emu.registers[0], 0xF800_0000);
+        let emu = broadwell(&[0xC4, 0xE2, 0x73, 0xF7, 0xC2], 4, 0x8000_0000, 0);
+        assert_eq!(emu.registers[0], 0x0800_0000);
+        // rorx eax, edx, 8
+        let emu = broadwell(&[0xC4, 0xE3, 0x7B, 0xF0, 0xC2, 0x08], 0, 0x1234_5678, 0);
+        assert_eq!(emu.registers[0], 0x7812_3456);
+        assert_eq!(emu.eip, 0x7c06);
+    }
+
+    #[test]
+    fn vex_instructions_need_the_cpuid_feature() {
+        let emu = execute(CpuModel::PentiumII, &[0xC4, 0xE2, 0x70, 0xF2, 0xC2], 0, 0, 0);
+        assert_eq!(emu.eip, 0x7e00);
+        // VEX.L=1も#UD
+        let emu = broadwell(&[0xC4, 0xE2, 0x74, 0xF2, 0xC2], 0, 0, 0);
+        assert_eq!(emu.eip, 0x7e00);
+    }
+
+    #[test]
+    fn crc32_computes_crc32c_known_answer() {
+        // "123456789"のCRC-32Cは0xE3069283(初期値と最後の反転はソフトウェアが行う)
+        let mut emu = emulator(&[
+            0xF2, 0x0F, 0x38, 0xF1, 0x0D, 0x00, 0x40, 0x00, 0x00, // crc32 ecx, dword [0x4000]
+            0x66, 0xF2, 0x0F, 0x38, 0xF1, 0x0D, 0x04, 0x40, 0x00, 0x00, // crc32 ecx, word [0x4004]
+            0x66, 0xF2, 0x0F, 0x38, 0xF1, 0x0D, 0x06, 0x40, 0x00, 0x00, // crc32 ecx, word [0x4006]
+            0xF2, 0x0F, 0x38, 0xF0, 0x0D, 0x08, 0x40, 0x00, 0x00, // crc32 ecx, byte [0x4008]
+        ]);
+        emu.set_cpu_model(CpuModel::Broadwell);
+        emu.bus.load(0x4000, b"123456789");
+        emu.registers[1] = 0xFFFF_FFFF;
+        emu.set_carry(true);
+        run(&mut emu, 4);
+        assert_eq!(!emu.get_register32(1), 0xE306_9283);
+        // フラグは変化しない
+        assert!(emu.is_carry());
+    }
+
+    #[test]
+    fn crc32_needs_sse42() {
+        let emu = execute(CpuModel::PentiumII, &[0xF2, 0x0F, 0x38, 0xF0, 0xC1], 0, 0, 0);
+        assert_eq!(emu.eip, 0x7e00);
+    }
+}
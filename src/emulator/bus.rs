@@ -0,0 +1,312 @@
+use std::fmt;
+
+use emulator::sparse_memory::{SparseMemory, PAGE_SIZE};
+
+// 物理アドレス空間の大きさ(32ビットの4GB)
+pub const PHYSICAL_ADDRESS_SPACE: u64 = 1 << 32;
+
+// メモリマップドI/Oのデバイス(offsetは領域の先頭からのオフセット)
+pub trait MmioDevice {
+    fn read8(&mut self, offset: usize) -> u8;
+    fn write8(&mut self, offset: usize, value: u8);
+}
+
+// どの領域にも割り当てられていないアドレスをアクセスした
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BusFault {
+    pub address: usize,
+}
+
+// 領域を登録できなかった
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MapError {
+    // 大きさが0か、4GBの物理アドレス空間の終わりを超えている
+    InvalidRange { base: usize, size: usize },
+    // 登録済みの領域と重なっている
+    Overlapping { base: usize, size: usize },
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MapError::InvalidRange { base, size } => write!(f, "物理アドレス空間に収まらない領域です: {:08x} (大きさ {:x})", base, size),
+            MapError::Overlapping { base, size } => write!(f, "登録済みの領域と重なっています: {:08x} (大きさ {:x})", base, size),
+        }
+    }
+}
+
+enum RegionKind {
+    // 書き込んだページだけをホストのメモリに確保する
+    Ram(SparseMemory),
+    // 書き込みは無視する
+    Rom(Vec<u8>),
+    Mmio(Box<dyn MmioDevice>),
+}
+
+struct Region {
+    base: usize,
+    size: usize,
+    kind: RegionKind,
+}
+
+impl Region {
+    fn contains(&self, address: usize) -> bool {
+        address >= self.base && address - self.base < self.size
+    }
+}
+
+// CPUからの物理アドレスでのアクセスを、登録した領域に振り分ける
+#[derive(Default)]
+pub struct Bus {
+    // ベースアドレスの順に並べ、互いに重ならない
+    regions: Vec<Region>,
+    // 割り当てのないアドレスを読んだときの値(Noneならフォールトにする)
+    open_bus: Option<u8>,
+}
+
+impl Bus {
+    pub fn new() -> Bus {
+        Bus::default()
+    }
+
+    pub fn add_ram(&mut self, base: usize, size: usize) -> Result<(), MapError> {
+        self.add_region(base, size, RegionKind::Ram(SparseMemory::new(size)))
+    }
+
+    pub fn add_rom(&mut self, base: usize, data: Vec<u8>) -> Result<(), MapError> {
+        let size = data.len();
+        self.add_region(base, size, RegionKind::Rom(data))
+    }
+
+    pub fn add_mmio(&mut self, base: usize, size: usize, device: Box<dyn MmioDevice>) -> Result<(), MapError> {
+        self.add_region(base, size, RegionKind::Mmio(device))
+    }
+
+    pub fn open_bus(&self) -> Option<u8> {
+        self.open_bus
+    }
+
+    pub fn set_open_bus(&mut self, value: Option<u8>) {
+        self.open_bus = value;
+    }
+
+    fn add_region(&mut self, base: usize, size: usize, kind: RegionKind) -> Result<(), MapError> {
+        let end = match base.checked_add(size) {
+            Some(end) if size > 0 && end as u64 <= PHYSICAL_ADDRESS_SPACE => end,
+            _ => return Err(MapError::InvalidRange { base, size }),
+        };
+        if self.regions.iter().any(|region| base < region.base + region.size && region.base < end) {
+            return Err(MapError::Overlapping { base, size });
+        }
+        let index = self.regions.iter().position(|region| region.base > base).unwrap_or(self.regions.len());
+        self.regions.insert(index, Region { base, size, kind });
+        Ok(())
+    }
+
+    fn find(&self, address: usize) -> Option<&Region> {
+        self.regions.iter().find(|region| region.contains(address))
+    }
+
+    fn find_mut(&mut self, address: usize) -> Option<&mut Region> {
+        self.regions.iter_mut().find(|region| region.contains(address))
+    }
+
+    // MMIOではデバイスの読み出しで状態が変わることがある
+    pub fn read8(&mut self, address: usize) -> Result<u8, BusFault> {
+        let open_bus = self.open_bus;
+        match self.find_mut(address) {
+            Some(region) => {
+                let offset = address - region.base;
+                Ok(match region.kind {
+                    RegionKind::Ram(ref memory) => memory.read8(offset),
+                    RegionKind::Rom(ref data) => data[offset],
+                    RegionKind::Mmio(ref mut device) => device.read8(offset),
+                })
+            }
+            None => open_bus.ok_or(BusFault { address }),
+        }
+    }
+
+    // 割り当てのないアドレスへの書き込みは、オープンバスなら捨てる
+    pub fn write8(&mut self, address: usize, value: u8) -> Result<(), BusFault> {
+        let open_bus = self.open_bus;
+        match self.find_mut(address) {
+            Some(region) => {
+                let offset = address - region.base;
+                match region.kind {
+                    RegionKind::Ram(ref mut memory) => memory.write8(offset, value),
+                    RegionKind::Rom(_) => {}
+                    RegionKind::Mmio(ref mut device) => device.write8(offset, value),
+                }
+                Ok(())
+            }
+            None if open_bus.is_some() => Ok(()),
+            None => Err(BusFault { address }),
+        }
+    }
+
+    // デバイスを動かさずにRAMとROMの内容を読む(命令の読み出しや書き込み前の値の保存に使う)
+    pub fn peek8(&self, address: usize) -> Option<u8> {
+        self.find(address).and_then(|region| match region.kind {
+            RegionKind::Ram(ref memory) => Some(memory.read8(address - region.base)),
+            RegionKind::Rom(ref data) => Some(data[address - region.base]),
+            RegionKind::Mmio(_) => None,
+        })
+    }
+
+    // 命令として読むバイト(MMIOと割り当てのないアドレスはオープンバスの値になる)
+    pub fn fetch8(&self, address: usize) -> Option<u8> {
+        self.peek8(address).or(self.open_bus)
+    }
+
+    // ROMも含めてRAMとROMの内容を直接書き換える(プログラムの読み込みや命令の取り消しに使う)
+    pub fn poke8(&mut self, address: usize, value: u8) {
+        if let Some(region) = self.find_mut(address) {
+            let offset = address - region.base;
+            match region.kind {
+                RegionKind::Ram(ref mut memory) => memory.write8(offset, value),
+                RegionKind::Rom(ref mut data) => data[offset] = value,
+                RegionKind::Mmio(_) => {}
+            }
+        }
+    }
+
+    // RAMのうち実際にホストのメモリを確保したバイト数
+    pub fn allocated_ram(&self) -> usize {
+        self.regions
+            .iter()
+            .map(|region| match region.kind {
+                RegionKind::Ram(ref memory) => memory.allocated_pages() * PAGE_SIZE,
+                _ => 0,
+            })
+            .sum()
+    }
+
+    pub fn load(&mut self, address: usize, data: &[u8]) {
+        for (i, &value) in data.iter().enumerate() {
+            self.poke8(address + i, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use emulator::testing::*;
+
+    // アクセスを記録し、読み出しにはオフセットを返すデバイス
+    struct Recorder {
+        accesses: Rc<RefCell<Vec<(usize, Option<u8>)>>>,
+    }
+
+    impl MmioDevice for Recorder {
+        fn read8(&mut self, offset: usize) -> u8 {
+            self.accesses.borrow_mut().push((offset, None));
+            offset as u8
+        }
+
+        fn write8(&mut self, offset: usize, value: u8) {
+            self.accesses.borrow_mut().push((offset, Some(value)));
+        }
+    }
+
+    fn recorder() -> (Box<Recorder>, Rc<RefCell<Vec<(usize, Option<u8>)>>>) {
+        let accesses = Rc::new(RefCell::new(Vec::new()));
+        (Box::new(Recorder { accesses: accesses.clone() }), accesses)
+    }
+
+    #[test]
+    fn regions_must_fit_and_not_overlap() {
+        let mut bus = Bus::new();
+        bus.add_ram(0x1000, 0x1000).unwrap();
+        assert_eq!(bus.add_ram(0x1800, 0x1000), Err(MapError::Overlapping { base: 0x1800, size: 0x1000 }));
+        assert_eq!(bus.add_rom(0x0800, vec![0; 0x1000]), Err(MapError::Overlapping { base: 0x0800, size: 0x1000 }));
+        assert_eq!(bus.add_ram(0x3000, 0), Err(MapError::InvalidRange { base: 0x3000, size: 0 }));
+        assert_eq!(
+            bus.add_ram(0xFFFF_F000, 0x2000),
+            Err(MapError::InvalidRange {
+                base: 0xFFFF_F000,
+                size: 0x2000
+            })
+        );
+        // 隣り合う領域は登録できる
+        bus.add_ram(0x2000, 0x1000).unwrap();
+        bus.add_rom(0xFFFF_F000, vec![0; 0x1000]).unwrap();
+    }
+
+    #[test]
+    fn ram_reads_back_writes() {
+        let mut bus = Bus::new();
+        bus.add_ram(0x1000, 0x1000).unwrap();
+        bus.write8(0x1234, 0x56).unwrap();
+        assert_eq!(bus.read8(0x1234), Ok(0x56));
+        assert_eq!(bus.read8(0x1235), Ok(0));
+        assert_eq!(bus.peek8(0x1234), Some(0x56));
+    }
+
+    #[test]
+    fn rom_ignores_writes_but_can_be_poked() {
+        let mut bus = Bus::new();
+        bus.add_rom(0xF0000, vec![0xEA, 0x5B]).unwrap();
+        bus.write8(0xF0000, 0x90).unwrap();
+        assert_eq!(bus.read8(0xF0000), Ok(0xEA));
+        bus.poke8(0xF0001, 0xE0);
+        assert_eq!(bus.read8(0xF0001), Ok(0xE0));
+    }
+
+    #[test]
+    fn mmio_accesses_reach_the_device() {
+        let mut bus = Bus::new();
+        let (device, accesses) = recorder();
+        bus.add_mmio(0xA0000, 0x100, device).unwrap();
+        bus.write8(0xA0010, 0x42).unwrap();
+        assert_eq!(bus.read8(0xA0020), Ok(0x20));
+        assert_eq!(*accesses.borrow(), vec![(0x10, Some(0x42)), (0x20, None)]);
+        // peekとfetchはデバイスを動かさない
+        assert_eq!(bus.peek8(0xA0020), None);
+        assert_eq!(bus.fetch8(0xA0020), None);
+        assert_eq!(accesses.borrow().len(), 2);
+    }
+
+    #[test]
+    fn unmapped_addresses_fault_unless_open_bus() {
+        let mut bus = Bus::new();
+        assert_eq!(bus.read8(0x5000), Err(BusFault { address: 0x5000 }));
+        assert_eq!(bus.write8(0x5000, 1), Err(BusFault { address: 0x5000 }));
+        assert_eq!(bus.fetch8(0x5000), None);
+        bus.set_open_bus(Some(0xFF));
+        assert_eq!(bus.open_bus(), Some(0xFF));
+        assert_eq!(bus.read8(0x5000), Ok(0xFF));
+        assert_eq!(bus.write8(0x5000, 1), Ok(()));
+        assert_eq!(bus.fetch8(0x5000), Some(0xFF));
+    }
+
+    #[test]
+    fn ram_is_allocated_on_write() {
+        let mut bus = Bus::new();
+        bus.add_ram(0, 0x10_0000).unwrap();
+        assert_eq!(bus.allocated_ram(), 0);
+        bus.load(0x7c00, &[1, 2, 3]);
+        assert_eq!(bus.allocated_ram(), PAGE_SIZE);
+    }
+
+    #[test]
+    fn guest_accesses_go_through_the_bus() {
+        let mut emu = emulator(&[
+            0xA2, 0x00, 0x00, 0x10, 0x00, // mov [0x100000], al
+            0xA2, 0x15, 0x00, 0x10, 0x00, // mov [0x100015], al
+            0x8A, 0x0D, 0x17, 0x00, 0x10, 0x00, // mov cl, [0x100017]
+        ]);
+        let (device, accesses) = recorder();
+        emu.bus_mut().add_rom(0x10_0000, vec![0x11; 0x10]).unwrap();
+        emu.bus_mut().add_mmio(0x10_0010, 0x10, device).unwrap();
+        emu.registers[0] = 0x33;
+        run(&mut emu, 3);
+        assert_eq!(emu.bus().peek8(0x10_0000), Some(0x11));
+        assert_eq!(emu.registers[1] & 0xFF, 0x07);
+        assert_eq!(*accesses.borrow(), vec![(0x05, Some(0x33)), (0x07, None)]);
+    }
+}
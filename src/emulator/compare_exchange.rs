@@ -0,0 +1,127 @@
+use emulator::emulator_function::EmulatorFunction;
+use emulator::interrupt::{invalid_opcode, Interrupt};
+use emulator::modrm::{Function as ModRMFunction, ModRM};
+use emulator::{Emulator, Register};
+
+pub trait CompareExchange {
+    fn cmpxchg(&mut self);
+    fn cmpxchg8b(&mut self, modrm: &ModRM);
+}
+
+impl Emulator {
+    // CMPと同じようにv1 - v2の結果でフラグを設定する
+    fn update_eflags_compare(&mut self, v1: u32, v2: u32, size: u32) {
+        let bits = size * 8;
+        let result = v1.wrapping_sub(v2) & (0xFFFF_FFFF >> (32 - bits));
+        self.set_carry(v1 < v2);
+        self.set_zero(result == 0);
+        self.set_sign((result >> (bits - 1)) & 1 != 0);
+        self.set_overflow((((v1 ^ v2) & (v1 ^ result)) >> (bits - 1)) & 1 != 0);
+        self.set_parity_adjust(v1, v2, result);
+    }
+}
+
+impl CompareExchange for Emulator {
+    // 0F B0: CMPXCHG r/m8, r8, 0F B1: CMPXCHG r/m16/32, r16/32
+    fn cmpxchg(&mut self) {
+        let size = if self.get_code8(1) == 0xB0 { 1 } else { self.operand_size() };
+        self.eip += 2;
+        let modrm = self.parse_modrm();
+        let destination = self.get_rm_sized(&modrm, size);
+        let accumulator = self.get_accumulator(size);
+        self.update_eflags_compare(accumulator, destination, size);
+        if accumulator == destination {
+            let source = self.get_r_sized(&modrm, size);
+            self.set_rm_sized(&modrm, size, source);
+        } else {
+            // 一致しなかった場合もデスティネーションには元の値が書き戻される
+            self.set_rm_sized(&modrm, size, destination);
+            self.set_accumulator(size, destination);
+        }
+    }
+
+    // 0F C7 /1: EDX:EAXとm64を比較し、一致すればECX:EBXを書き込む(ZF以外のフラグは変化しない)
+    fn cmpxchg8b(&mut self, modrm: &ModRM) {
+        if modrm.mode == 3 {
+            self.raise_exception(invalid_opcode());
+            return;
+        }
+        let address = self.calc_linear_address(modrm, 8) as usize;
+        let low = self.get_memory32(address);
+        let high = self.get_memory32(address + 4);
+        let eax = self.get_register32(Register::EAX as usize);
+        let edx = self.get_register32(Register::EDX as usize);
+        let equal = low == eax && high == edx;
+        let (new_low, new_high) = if equal {
+            (self.get_register32(Register::EBX as usize), self.get_register32(Register::ECX as usize))
+        } else {
+            self.set_register32(Register::EAX as usize, low);
+            self.set_register32(Register::EDX as usize, high);
+            (low, high)
+        };
+        self.set_memory32(address, new_low);
+        self.set_memory32(address + 4, new_high);
+        self.set_zero(equal);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use emulator::emulator_function::EmulatorFunction;
+    use emulator::testing::*;
+
+    #[test]
+    fn cmpxchg_stores_source_when_accumulator_matches() {
+        let mut emu = emulator(&[
+            0x0F, 0xB1, 0x0D, 0x00, 0x40, 0x00, 0x00, // cmpxchg [0x4000], ecx
+            0x0F, 0xB1, 0x0D, 0x00, 0x40, 0x00, 0x00, // cmpxchg [0x4000], ecx
+        ]);
+        write32(&mut emu, 0x4000, 0x1111);
+        emu.registers[0] = 0x1111;
+        emu.registers[1] = 0x2222;
+        run(&mut emu, 1);
+        assert_eq!(read32(&emu, 0x4000), 0x2222);
+        assert!(emu.is_zero());
+        // 一致しなければアキュムレータにメモリの値を読み込む
+        run(&mut emu, 1);
+        assert_eq!(read32(&emu, 0x4000), 0x2222);
+        assert_eq!(emu.registers[0], 0x2222);
+        assert!(!emu.is_zero());
+        assert!(emu.is_carry());
+    }
+
+    #[test]
+    fn cmpxchg8b_compares_edx_eax_with_memory() {
+        let code = [0x0F, 0xC7, 0x0D, 0x00, 0x40, 0x00, 0x00]; // cmpxchg8b [0x4000]
+        let mut emu = emulator(&code);
+        write32(&mut emu, 0x4000, 0x1111_1111);
+        write32(&mut emu, 0x4004, 0x2222_2222);
+        emu.registers[0] = 0x1111_1111;
+        emu.registers[2] = 0x2222_2222;
+        emu.registers[3] = 0x3333_3333;
+        emu.registers[1] = 0x4444_4444;
+        emu.set_carry(true);
+        run(&mut emu, 1);
+        assert_eq!((read32(&emu, 0x4000), read32(&emu, 0x4004)), (0x3333_3333, 0x4444_4444));
+        assert!(emu.is_zero());
+        // ZF以外のフラグは変化しない
+        assert!(emu.is_carry());
+        let mut emu = emulator(&code);
+        write32(&mut emu, 0x4000, 0x5555_5555);
+        write32(&mut emu, 0x4004, 0x6666_6666);
+        run(&mut emu, 1);
+        assert_eq!((emu.registers[0], emu.registers[2]), (0x5555_5555, 0x6666_6666));
+        assert_eq!((read32(&emu, 0x4000), read32(&emu, 0x4004)), (0x5555_5555, 0x6666_6666));
+        assert!(!emu.is_zero());
+    }
+
+    #[test]
+    fn cmpxchg8b_with_register_operand_raises_invalid_opcode() {
+        let mut emu = emulator(&[0x0F, 0xC7, 0xC8]); // cmpxchg8b eax
+        install_flat_segments(&mut emu);
+        install_idt(&mut emu, 32);
+        set_interrupt_gate(&mut emu, 6, 0x7e00);
+        run(&mut emu, 1);
+        assert_eq!(emu.eip, 0x7e00);
+    }
+}
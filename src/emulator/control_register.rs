@@ -0,0 +1,268 @@
+use emulator::cpu_model::CpuModel;
+use emulator::cpuid::{Cpuid, Feature};
+use emulator::emulator_function::EmulatorFunction;
+use emulator::interrupt::{general_protection, invalid_opcode, Exception, Interrupt};
+use emulator::modrm::{Function as ModRMFunction, ModRM};
+use emulator::msr::{EFER_LMA, EFER_LME};
+use emulator::segment::Segment;
+use emulator::stop::{EmuError, Stop};
+use emulator::{Emulator, SegmentRegister};
+
+// CR0のビット
+pub const CR0_PE: u64 = 1;
+pub const CR0_EM: u64 = 1 << 2;
+pub const CR0_TS: u64 = 1 << 3;
+pub const CR0_ET: u64 = 1 << 4;
+pub const CR0_WP: u64 = 1 << 16;
+pub const CR0_AM: u64 = 1 << 18;
+pub const CR0_PG: u64 = 1 << 31;
+// CR4のビット
+pub const CR4_VME: u64 = 1;
+pub const CR4_PAE: u64 = 1 << 5;
+pub const CR4_OSFXSR: u64 = 1 << 9;
+pub const CR4_OSXMMEXCPT: u64 = 1 << 10;
+
+// 起動時は保護モードに移行済みの状態から始める
+pub const CR0_INITIAL: u64 = CR0_PE | CR0_ET;
+
+pub trait ControlRegister {
+    fn write_control_register(&mut self, index: usize, value: u64) -> Result<(), Exception>;
+    fn mov_r32_cr(&mut self);
+    fn mov_cr_r32(&mut self);
+    fn smsw(&mut self, modrm: &ModRM);
+    fn lmsw(&mut self, modrm: &ModRM);
+    fn clts(&mut self);
+    fn invlpg(&mut self);
+}
+
+impl Emulator {
+    // CR4で設定できるビット(SSEに対応していればOSFXSRとOSXMMEXCPT、ロングモードに対応していればPAEも設定できる)
+    fn supported_cr4_bits(&self) -> u64 {
+        let mut bits = CR4_VME;
+        if self.has_feature(Feature::Sse) {
+            bits |= CR4_OSFXSR | CR4_OSXMMEXCPT;
+        }
+        if self.cpu_model.supports_long_mode() {
+            bits |= CR4_PAE;
+        }
+        bits
+    }
+
+    // EFER.LMEを立ててCR0.PGを有効にするとEFER.LMAが立ち、PGを無効にするとロングモードを抜ける
+    fn set_paging(&mut self, cr0: u64) -> Result<(), Exception> {
+        let lma = self.msr.efer & EFER_LMA != 0;
+        if cr0 & CR0_PG == 0 {
+            // 64ビットモードのままではページングを無効にできない(互換モードから抜ける)
+            if lma && self.segment_caches[SegmentRegister::CS as usize].is_64bit() {
+                return Err(general_protection(0));
+            }
+            self.msr.efer &= !EFER_LMA;
+            return Ok(());
+        }
+        if cr0 & CR0_PE == 0 {
+            return Err(general_protection(0));
+        }
+        if lma {
+            return Ok(());
+        }
+        if self.control_registers[4] & CR4_PAE == 0 {
+            return Err(general_protection(0));
+        }
+        self.msr.efer |= EFER_LMA;
+        Ok(())
+    }
+}
+
+impl ControlRegister for Emulator {
+    fn write_control_register(&mut self, index: usize, value: u64) -> Result<(), Exception> {
+        match index {
+            // CR0の上位32ビットは予約されている
+            0 if value >> 32 != 0 => return Err(general_protection(0)),
+            0 => {
+                // ページングはロングモードの4レベルページングにしか対応していない
+                if value & CR0_PG != 0 && self.msr.efer & EFER_LME == 0 {
+                    self.set_emulation_error(EmuError::UnsupportedFeature("CR0.PG"));
+                    return Ok(());
+                }
+                // PEを切り替えてもセグメントのディスクリプタキャッシュはそのまま残る
+                self.set_paging(value)?;
+                self.control_registers[0] = value | CR0_ET;
+            }
+            2 | 3 => self.control_registers[index] = value,
+            4 if self.cpu_model.supports_cr4() => {
+                if value & !self.supported_cr4_bits() != 0 {
+                    return Err(general_protection(0));
+                }
+                // ロングモードではPAEを無効にできない
+                if self.msr.efer & EFER_LMA != 0 && value & CR4_PAE == 0 {
+                    return Err(general_protection(0));
+                }
+                self.control_registers[4] = value;
+            }
+            _ => return Err(invalid_opcode()),
+        }
+        Ok(())
+    }
+
+    fn mov_r32_cr(&mut self) {
+        if self.cpl() != 0 {
+            self.raise_exception(general_protection(0));
+            return;
+        }
+        self.eip += 2;
+        let modrm = self.parse_modrm();
+        let index = self.modrm_reg_index(&modrm);
+        if index == 1 || index > 4 || (index == 4 && !self.cpu_model.supports_cr4()) {
+            self.raise_exception(invalid_opcode());
+            return;
+        }
+        // 64ビットモードでは64ビットのレジスタに転送する
+        let value = self.control_registers[index];
+        let rm = self.modrm_rm_index(&modrm);
+        if self.is_64bit_mode() {
+            self.set_register64(rm, value);
+        } else {
+            self.set_register32(rm, value as u32);
+        }
+    }
+
+    fn mov_cr_r32(&mut self) {
+        if self.cpl() != 0 {
+            self.raise_exception(general_protection(0));
+            return;
+        }
+        self.eip += 2;
+        let modrm = self.parse_modrm();
+        let rm = self.modrm_rm_index(&modrm);
+        let value = if self.is_64bit_mode() {
+            self.get_register64(rm)
+        } else {
+            self.get_register32(rm) as u64
+        };
+        if let Err(e) = self.write_control_register(self.modrm_reg_index(&modrm), value) {
+            self.raise_exception(e);
+        }
+    }
+
+    fn smsw(&mut self, modrm: &ModRM) {
+        // マシンステータスワード(CR0の下位16ビット)はどの特権レベルからでも読める
+        let cr0 = self.control_registers[0] as u32;
+        if modrm.mode == 3 && !self.is_operand_size16() {
+            self.set_rm32(modrm, cr0);
+        } else {
+            self.set_rm16(modrm, cr0 as u16);
+        }
+    }
+
+    fn lmsw(&mut self, modrm: &ModRM) {
+        if self.cpl() != 0 {
+            self.raise_exception(general_protection(0));
+            return;
+        }
+        // PE, MP, EM, TSだけを変更できる(PEはクリアできない)
+        let value = self.get_rm16(modrm) as u64 & 0xF;
+        let cr0 = self.control_registers[0];
+        self.control_registers[0] = (cr0 & !0xE) | value;
+    }
+
+    fn clts(&mut self) {
+        if self.cpl() != 0 {
+            self.raise_exception(general_protection(0));
+            return;
+        }
+        self.control_registers[0] &= !CR0_TS;
+        self.eip += 2;
+    }
+
+    // TLBを持たずにページテーブルを毎回たどるので、特権レベルを確かめるだけでよい
+    fn invlpg(&mut self) {
+        if self.cpu_model < CpuModel::I80486 {
+            self.raise_exception(invalid_opcode());
+            return;
+        }
+        if self.cpl() != 0 {
+            self.raise_exception(general_protection(0));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use emulator::cpu_model::CpuModel;
+    use emulator::msr::{Msr, IA32_EFER};
+    use emulator::testing::*;
+
+    #[test]
+    fn smsw_and_lmsw_access_the_machine_status_word() {
+        let mut emu = emulator(&[
+            0x0F, 0x01, 0xE0, // smsw eax
+            0x66, 0xB9, 0x0A, 0x00, // mov cx, 0x0A
+            0x0F, 0x01, 0xF1, // lmsw cx
+        ]);
+        emu.control_registers[0] |= CR0_WP;
+        run(&mut emu, 1);
+        // レジスタへは上位ビットも含めて格納する
+        assert_eq!(emu.get_register32(0) as u64, CR0_PE | CR0_ET | CR0_WP);
+        run(&mut emu, 2);
+        // LMSWでPEはクリアできない
+        assert_eq!(emu.control_registers[0], CR0_PE | 0x2 | CR0_TS | CR0_ET | CR0_WP);
+    }
+
+    #[test]
+    fn clts_clears_task_switched() {
+        let mut emu = emulator(&[
+            0x0F, 0x06, // clts
+        ]);
+        emu.control_registers[0] |= CR0_TS;
+        run(&mut emu, 1);
+        assert_eq!(emu.control_registers[0] & CR0_TS, 0);
+    }
+
+    #[test]
+    fn clts_lmsw_and_invlpg_outside_ring0_raise_general_protection() {
+        for code in [[0x0F, 0x06, 0xF8], [0x0F, 0x01, 0xF0], [0x0F, 0x01, 0x38]].iter() {
+            let mut emu = ring3_emulator(code, 13);
+            emu.control_registers[0] |= CR0_TS;
+            run(&mut emu, 1);
+            assert_eq!(emu.eip, 0x7e00);
+            assert_eq!(read32(&emu, RING0_STACK - 20), 0x7c00);
+            assert_ne!(emu.control_registers[0] & CR0_TS, 0);
+        }
+    }
+
+    #[test]
+    fn invlpg_in_ring0_only_advances_eip() {
+        let mut emu = emulator(&[
+            0x0F, 0x01, 0x38, // invlpg [eax]
+        ]);
+        run(&mut emu, 1);
+        assert_eq!(emu.eip, CODE as u64 + 3);
+    }
+
+    #[test]
+    fn paging_with_long_mode_enable_activates_long_mode() {
+        let mut emu = long_mode_emulator(&[]);
+        assert_ne!(emu.read_msr(IA32_EFER).unwrap() & EFER_LMA, 0);
+        // ロングモードの間はPAEを無効にできず、LMEも切り替えられない
+        assert_eq!(emu.write_control_register(4, 0).unwrap_err().vector, 13);
+        assert_eq!(emu.write_msr(IA32_EFER, 0).unwrap_err().vector, 13);
+        // ページングを無効にするとロングモードを抜ける
+        emu.write_control_register(0, CR0_PE).unwrap();
+        assert_eq!(emu.read_msr(IA32_EFER).unwrap(), EFER_LME);
+        // PAEを無効にしたままではページングを有効にできない
+        emu.write_control_register(4, 0).unwrap();
+        assert_eq!(emu.write_control_register(0, CR0_PE | CR0_PG).unwrap_err().vector, 13);
+        assert_eq!(emu.control_registers[0] & CR0_PG, 0);
+    }
+
+    #[test]
+    fn paging_without_long_mode_enable_is_unsupported() {
+        let mut emu = emulator(&[]);
+        emu.set_cpu_model(CpuModel::X86_64);
+        emu.write_control_register(4, CR4_PAE).unwrap();
+        assert!(emu.write_control_register(0, CR0_PE | CR0_PG).is_ok());
+        assert_eq!(emu.emulation_error, Some(EmuError::UnsupportedFeature("CR0.PG")));
+        assert_eq!(emu.control_registers[0] & CR0_PG, 0);
+    }
+}
@@ -0,0 +1,254 @@
+use emulator::Emulator;
+
+// エミュレートするCPUのモデル(世代の古い順に並べる)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CpuModel {
+    // 16ビットのリアルモードだけを持つCPU
+    I8086,
+    // 16ビットの保護モードを持つCPU(80186の命令も使える)
+    I80286,
+    // 32ビットのCPU
+    I80386,
+    // BSWAP/CMPXCHG/XADDが追加されたCPU
+    I80486,
+    // CPUID/RDTSC/MSR/CMPXCHG8Bが追加されたCPU
+    Pentium,
+    // 32ビットのCPU(SYSENTER/MTRRに対応したファミリ6)
+    #[default]
+    PentiumII,
+    // ロングモード(64ビットモード)と4レベルページングに対応したファミリ6 (Core 2)
+    X86_64,
+    // POPCNT/LZCNT/MOVBE/BMI1/BMI2/ADXまで対応した第5世代Core
+    Broadwell,
+}
+
+// 1バイト目のオペコードが使えるようになった世代(80186で追加された命令は80286から使えるものとして扱う)
+fn minimum_model(code: u8) -> CpuModel {
+    match code {
+        0x0F | 0x60..=0x63 | 0x68..=0x6F | 0xC0 | 0xC1 | 0xC8 | 0xC9 => CpuModel::I80286,
+        // FS/GSとオペランドサイズ・アドレスサイズのプレフィックス
+        0x64..=0x67 | 0xF1 => CpuModel::I80386,
+        _ => CpuModel::I8086,
+    }
+}
+
+// 0Fに続くオペコードが使えるようになった世代(SSEなどはCPUIDの機能ビットでも確認する)
+fn minimum_model_0f(code: u8) -> CpuModel {
+    match code {
+        0x00..=0x03 | 0x06 => CpuModel::I80286,
+        0x08 | 0x09 | 0xB0 | 0xB1 | 0xC0 | 0xC1 | 0xC8..=0xCF => CpuModel::I80486,
+        0x30..=0x32 | 0xA2 | 0xC7 => CpuModel::Pentium,
+        0x05 | 0x07 | 0x10..=0x17 | 0x28..=0x2F | 0x34 | 0x35 | 0x38 | 0x3A | 0x40..=0x7F | 0xB8 | 0xD0..=0xFF => CpuModel::PentiumII,
+        _ => CpuModel::I80386,
+    }
+}
+
+impl CpuModel {
+    // コマンドラインで指定するモデル名
+    pub fn from_name(name: &str) -> Option<CpuModel> {
+        match name {
+            "8086" => Some(CpuModel::I8086),
+            "286" => Some(CpuModel::I80286),
+            "386" => Some(CpuModel::I80386),
+            "486" => Some(CpuModel::I80486),
+            "pentium" => Some(CpuModel::Pentium),
+            "pentium2" => Some(CpuModel::PentiumII),
+            "x86_64" => Some(CpuModel::X86_64),
+            "broadwell" => Some(CpuModel::Broadwell),
+            _ => None,
+        }
+    }
+
+    // ビット操作命令などの整数演算拡張に対応しているか
+    pub fn supports_integer_extensions(self) -> bool {
+        self == CpuModel::Broadwell
+    }
+
+    // 32ビットのレジスタとセグメントを扱えるか
+    pub fn is_32bit(self) -> bool {
+        self >= CpuModel::I80386
+    }
+
+    pub fn supports_cpuid(self) -> bool {
+        self >= CpuModel::Pentium
+    }
+
+    pub fn supports_cr4(self) -> bool {
+        self >= CpuModel::Pentium
+    }
+
+    // EFER.LMEとCR4.PAEを持ち、ロングモードに移行できるか
+    pub fn supports_long_mode(self) -> bool {
+        self >= CpuModel::X86_64
+    }
+
+    // シフト・ローテートの回数を下位5ビットでマスクするか(8086はマスクしない)
+    pub fn masks_shift_count(self) -> bool {
+        self != CpuModel::I8086
+    }
+
+    // PUSH SPがデクリメントする前のSPを積むか(8086はデクリメントした後の値を積む)
+    pub fn pushes_original_stack_pointer(self) -> bool {
+        self != CpuModel::I8086
+    }
+
+    pub fn supports_opcode(self, code: u8) -> bool {
+        self >= minimum_model(code)
+    }
+
+    pub fn supports_opcode_0f(self, code: u8) -> bool {
+        self >= minimum_model_0f(code)
+    }
+}
+
+impl Emulator {
+    pub fn cpu_model(&self) -> CpuModel {
+        self.cpu_model
+    }
+
+    // CPUIDやEFERで見えるCPUの機能と、デコーダが受け付ける命令を切り替える
+    // 8086と80286は32ビットのセグメントを扱えないので、リセット直後のリアルモードから始める
+    pub fn set_cpu_model(&mut self, model: CpuModel) {
+        self.cpu_model = model;
+        if !model.is_32bit() {
+            self.reset_to_real_mode();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use emulator::testing::*;
+    use emulator::SegmentRegister;
+
+    // modelのCPUにして、codeをリセット直後のリアルモードで実行する(#UDは0x7e00へ飛ぶ)
+    fn real_mode_cpu(model: CpuModel, code: &[u8]) -> Emulator {
+        let mut emu = real_mode_emulator(code, 6);
+        emu.set_cpu_model(model);
+        emu
+    }
+
+    // modelのCPUで、codeをフラットな保護モードで実行する(#UDは0x7e00へ飛ぶ)
+    fn protected_mode_cpu(model: CpuModel, code: &[u8]) -> Emulator {
+        let mut emu = protected_mode_emulator(code, 6);
+        emu.set_cpu_model(model);
+        emu
+    }
+
+    #[test]
+    fn models_are_ordered_by_generation_and_named() {
+        let names = ["8086", "286", "386", "486", "pentium", "pentium2", "x86_64", "broadwell"];
+        let models: Vec<CpuModel> = names.iter().map(|name| CpuModel::from_name(name).unwrap()).collect();
+        assert!(models.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(models[0], CpuModel::I8086);
+        assert_eq!(CpuModel::from_name("z80"), None);
+        assert_eq!(CpuModel::default(), CpuModel::PentiumII);
+    }
+
+    #[test]
+    fn sixteen_bit_models_start_in_real_mode() {
+        let mut emu = emulator(&[]);
+        emu.set_cpu_model(CpuModel::I80286);
+        assert_eq!(emu.control_registers[0] & 1, 0);
+        assert_eq!(emu.idtr.limit, 0x3FF);
+    }
+
+    #[test]
+    fn i8086_pushes_decremented_stack_pointer() {
+        let code = [
+            0xBC, 0x00, 0x10, // mov sp, 0x1000
+            0x54, // push sp
+        ];
+        let mut emu = real_mode_cpu(CpuModel::I8086, &code);
+        run(&mut emu, 2);
+        assert_eq!(read16(&emu, 0x0FFE), 0x0FFE);
+        let mut emu = real_mode_cpu(CpuModel::I80286, &code);
+        run(&mut emu, 2);
+        assert_eq!(read16(&emu, 0x0FFE), 0x1000);
+    }
+
+    #[test]
+    fn i8086_does_not_mask_shift_count() {
+        let code = [
+            0xB0, 0x01, // mov al, 1
+            0xB1, 0x21, // mov cl, 0x21
+            0xD2, 0xE0, // shl al, cl
+        ];
+        let mut emu = real_mode_cpu(CpuModel::I8086, &code);
+        run(&mut emu, 3);
+        assert_eq!(emu.registers[0] & 0xFF, 0);
+        // 80286以降は0x21 & 0x1F = 1回だけシフトする
+        let mut emu = real_mode_cpu(CpuModel::I80286, &code);
+        run(&mut emu, 3);
+        assert_eq!(emu.registers[0] & 0xFF, 2);
+    }
+
+    #[test]
+    fn i8086_decodes_0f_as_pop_cs() {
+        let mut emu = real_mode_cpu(
+            CpuModel::I8086,
+            &[
+                0xB8, 0x00, 0x07, // mov ax, 0x0700
+                0x50, // push ax
+                0x0F, // pop cs
+            ],
+        );
+        run(&mut emu, 3);
+        assert_eq!(emu.segment_registers[SegmentRegister::CS as usize], 0x0700);
+        assert_eq!(emu.segment_cache(SegmentRegister::CS).base, 0x7000);
+        assert_eq!(emu.eip, 0x7c05);
+    }
+
+    #[test]
+    fn later_opcodes_raise_invalid_opcode_on_older_models() {
+        // PUSH imm16は80186から
+        let mut emu = real_mode_cpu(CpuModel::I8086, &[0x68, 0x34, 0x12]);
+        run(&mut emu, 1);
+        assert_eq!(emu.eip, 0x7e00);
+        // オペランドサイズのプレフィックスは80386から
+        let mut emu = real_mode_cpu(CpuModel::I80286, &[0x66, 0xB8, 0x78, 0x56, 0x34, 0x12]);
+        run(&mut emu, 1);
+        assert_eq!(emu.eip, 0x7e00);
+        let mut emu = real_mode_cpu(CpuModel::I80386, &[0x66, 0xB8, 0x78, 0x56, 0x34, 0x12]);
+        run(&mut emu, 1);
+        assert_eq!(emu.registers[0], 0x1234_5678);
+    }
+
+    #[test]
+    fn bswap_needs_i80486() {
+        let code = [0x0F, 0xC8]; // bswap eax
+        let mut emu = protected_mode_cpu(CpuModel::I80386, &code);
+        emu.registers[0] = 0x1122_3344;
+        run(&mut emu, 1);
+        assert_eq!((emu.eip, emu.registers[0]), (0x7e00, 0x1122_3344));
+        let mut emu = protected_mode_cpu(CpuModel::I80486, &code);
+        emu.registers[0] = 0x1122_3344;
+        run(&mut emu, 1);
+        assert_eq!(emu.registers[0], 0x4433_2211);
+    }
+
+    #[test]
+    fn cpuid_rdtsc_and_cmpxchg8b_need_pentium() {
+        let codes: [&[u8]; 3] = [
+            &[0x0F, 0xA2],                               // cpuid
+            &[0x0F, 0x31],                               // rdtsc
+            &[0x0F, 0xC7, 0x0D, 0x00, 0x40, 0x00, 0x00], // cmpxchg8b [0x4000]
+        ];
+        for code in codes.iter() {
+            let mut emu = protected_mode_cpu(CpuModel::I80486, code);
+            run(&mut emu, 1);
+            assert_eq!(emu.eip, 0x7e00);
+            let mut emu = protected_mode_cpu(CpuModel::Pentium, code);
+            run(&mut emu, 1);
+            assert_eq!(emu.eip, 0x7c00 + code.len() as u64);
+        }
+    }
+
+    #[test]
+    fn sysenter_needs_pentium_ii() {
+        let mut emu = protected_mode_cpu(CpuModel::Pentium, &[0x0F, 0x34]);
+        run(&mut emu, 1);
+        assert_eq!(emu.eip, 0x7e00);
+    }
+}
@@ -0,0 +1,161 @@
+use emulator::cpu_model::CpuModel;
+use emulator::emulator_function::EmulatorFunction;
+use emulator::{Emulator, Register};
+
+// CPUID.01H:EDXの機能ビット
+const CPUID_VME: u32 = 1 << 1;
+const CPUID_TSC: u32 = 1 << 4;
+const CPUID_MSR: u32 = 1 << 5;
+const CPUID_PAE: u32 = 1 << 6;
+const CPUID_CX8: u32 = 1 << 8;
+const CPUID_SEP: u32 = 1 << 11;
+const CPUID_MTRR: u32 = 1 << 12;
+const CPUID_SSE: u32 = 1 << 25;
+const CPUID_SSE2: u32 = 1 << 26;
+// CPUID.01H:ECXの機能ビット
+const CPUID_PCLMULQDQ: u32 = 1 << 1;
+const CPUID_SSE42: u32 = 1 << 20;
+const CPUID_MOVBE: u32 = 1 << 22;
+const CPUID_POPCNT: u32 = 1 << 23;
+const CPUID_AES: u32 = 1 << 25;
+// CPUID.07H:EBXの機能ビット
+const CPUID_BMI1: u32 = 1 << 3;
+const CPUID_BMI2: u32 = 1 << 8;
+const CPUID_ADX: u32 = 1 << 19;
+// CPUID.80000001H:ECXの機能ビット
+const CPUID_LZCNT: u32 = 1 << 5;
+// CPUID.80000001H:EDXの機能ビット
+const CPUID_SYSCALL: u32 = 1 << 11;
+const CPUID_LM: u32 = 1 << 29;
+
+// 命令を実行できるかどうかを決めるCPUIDの機能
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Feature {
+    Popcnt,
+    Movbe,
+    Lzcnt,
+    Bmi1,
+    Bmi2,
+    Adx,
+    Sse,
+    Sse2,
+    Sse42,
+    Aes,
+    Pclmulqdq,
+}
+
+impl Feature {
+    // 機能ビットが格納されているリーフ, レジスタ(EAX, EBX, ECX, EDXの順), ビット
+    fn location(self) -> (u32, usize, u32) {
+        match self {
+            Feature::Popcnt => (0x0000_0001, 2, CPUID_POPCNT),
+            Feature::Movbe => (0x0000_0001, 2, CPUID_MOVBE),
+            Feature::Lzcnt => (0x8000_0001, 2, CPUID_LZCNT),
+            Feature::Bmi1 => (0x0000_0007, 1, CPUID_BMI1),
+            Feature::Bmi2 => (0x0000_0007, 1, CPUID_BMI2),
+            Feature::Adx => (0x0000_0007, 1, CPUID_ADX),
+            Feature::Sse => (0x0000_0001, 3, CPUID_SSE),
+            Feature::Sse2 => (0x0000_0001, 3, CPUID_SSE2),
+            Feature::Sse42 => (0x0000_0001, 2, CPUID_SSE42),
+            Feature::Aes => (0x0000_0001, 2, CPUID_AES),
+            Feature::Pclmulqdq => (0x0000_0001, 2, CPUID_PCLMULQDQ),
+        }
+    }
+}
+
+pub trait Cpuid {
+    fn cpuid_leaf(&self, leaf: u32, subleaf: u32) -> [u32; 4];
+    fn has_feature(&self, feature: Feature) -> bool;
+    fn cpuid(&mut self);
+}
+
+// 12文字のベンダー名をEBX, EDX, ECXの順に詰める
+fn vendor_registers(vendor: &[u8; 12]) -> [u32; 3] {
+    let mut registers = [0; 3];
+    for (i, register) in registers.iter_mut().enumerate() {
+        for j in 0..4 {
+            *register |= (vendor[i * 4 + j] as u32) << (j * 8);
+        }
+    }
+    registers
+}
+
+impl Cpuid for Emulator {
+    // EAX, EBX, ECX, EDXの順に返す
+    fn cpuid_leaf(&self, leaf: u32, subleaf: u32) -> [u32; 4] {
+        let extensions = self.cpu_model.supports_integer_extensions();
+        // CPUIDを持たない世代では、どの機能もないものとして扱う
+        if !self.cpu_model.supports_cpuid() {
+            return [0; 4];
+        }
+        match leaf {
+            0x0000_0000 => {
+                let vendor = vendor_registers(b"GenuineIntel");
+                let max_leaf = if extensions { 0x0000_0007 } else { 0x0000_0001 };
+                [max_leaf, vendor[0], vendor[2], vendor[1]]
+            }
+            // ファミリ6, モデル0x3D (Broadwell)
+            0x0000_0001 if extensions => [
+                0x0003_06D4,
+                0,
+                CPUID_PCLMULQDQ | CPUID_SSE42 | CPUID_MOVBE | CPUID_POPCNT | CPUID_AES,
+                CPUID_VME | CPUID_TSC | CPUID_MSR | CPUID_PAE | CPUID_CX8 | CPUID_SEP | CPUID_MTRR | CPUID_SSE | CPUID_SSE2,
+            ],
+            // ファミリ6, モデル0x0F (Core 2)
+            0x0000_0001 if self.cpu_model == CpuModel::X86_64 => [
+                0x0000_06F6,
+                0,
+                0,
+                CPUID_VME | CPUID_TSC | CPUID_MSR | CPUID_PAE | CPUID_CX8 | CPUID_SEP | CPUID_MTRR | CPUID_SSE | CPUID_SSE2,
+            ],
+            // ファミリ6, モデル3, ステッピング3 (SEPが有効になる最初のモデル)
+            // ファミリ5, モデル2 (P54C)
+            0x0000_0001 if self.cpu_model == CpuModel::Pentium => [0x0000_0525, 0, 0, CPUID_VME | CPUID_TSC | CPUID_MSR | CPUID_CX8],
+            0x0000_0001 => [0x0000_0633, 0, 0, CPUID_VME | CPUID_TSC | CPUID_MSR | CPUID_CX8 | CPUID_SEP | CPUID_MTRR],
+            0x0000_0007 if extensions && subleaf == 0 => [0, CPUID_BMI1 | CPUID_BMI2 | CPUID_ADX, 0, 0],
+            // Pentiumは拡張リーフを持たない
+            0x8000_0000..=0x8000_0001 if self.cpu_model == CpuModel::Pentium => [0, 0, 0, 0],
+            0x8000_0000 => [0x8000_0001, 0, 0, 0],
+            0x8000_0001 if extensions => [0, 0, CPUID_LZCNT, CPUID_SYSCALL | CPUID_LM],
+            0x8000_0001 if self.cpu_model.supports_long_mode() => [0, 0, 0, CPUID_SYSCALL | CPUID_LM],
+            0x8000_0001 => [0, 0, 0, CPUID_SYSCALL],
+            _ => [0, 0, 0, 0],
+        }
+    }
+
+    fn has_feature(&self, feature: Feature) -> bool {
+        let (leaf, register, bit) = feature.location();
+        self.cpuid_leaf(leaf, 0)[register] & bit != 0
+    }
+
+    fn cpuid(&mut self) {
+        let leaf = self.get_register32(Register::EAX as usize);
+        let subleaf = self.get_register32(Register::ECX as usize);
+        let [eax, ebx, ecx, edx] = self.cpuid_leaf(leaf, subleaf);
+        self.set_register32(Register::EAX as usize, eax);
+        self.set_register32(Register::EBX as usize, ebx);
+        self.set_register32(Register::ECX as usize, ecx);
+        self.set_register32(Register::EDX as usize, edx);
+        self.eip += 2;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use emulator::cpu_model::CpuModel;
+    use emulator::testing::*;
+
+    #[test]
+    fn long_mode_is_advertised_only_on_long_mode_models() {
+        let mut emu = emulator(&[]);
+        for &(model, long_mode) in [(CpuModel::PentiumII, false), (CpuModel::X86_64, true), (CpuModel::Broadwell, true)].iter() {
+            emu.set_cpu_model(model);
+            let [_, _, _, edx] = emu.cpuid_leaf(0x8000_0001, 0);
+            assert_eq!(edx & CPUID_LM != 0, long_mode);
+            assert_ne!(edx & CPUID_SYSCALL, 0);
+            let [_, _, _, edx] = emu.cpuid_leaf(0x0000_0001, 0);
+            assert_eq!(edx & CPUID_PAE != 0, long_mode);
+        }
+    }
+}
@@ -0,0 +1,272 @@
+use emulator::cpuid::Feature;
+use emulator::sse::Sse;
+use emulator::Emulator;
+
+// AESのSボックス
+const S_BOX: [u8; 256] = [
+    0x63, 0x7C, 0x77, 0x7B, 0xF2, 0x6B, 0x6F, 0xC5, 0x30, 0x01, 0x67, 0x2B, 0xFE, 0xD7, 0xAB, 0x76, 0xCA, 0x82, 0xC9, 0x7D, 0xFA, 0x59, 0x47, 0xF0,
+    0xAD, 0xD4, 0xA2, 0xAF, 0x9C, 0xA4, 0x72, 0xC0, 0xB7, 0xFD, 0x93, 0x26, 0x36, 0x3F, 0xF7, 0xCC, 0x34, 0xA5, 0xE5, 0xF1, 0x71, 0xD8, 0x31, 0x15,
+    0x04, 0xC7, 0x23, 0xC3, 0x18, 0x96, 0x05, 0x9A, 0x07, 0x12, 0x80, 0xE2, 0xEB, 0x27, 0xB2, 0x75, 0x09, 0x83, 0x2C, 0x1A, 0x1B, 0x6E, 0x5A, 0xA0,
+    0x52, 0x3B, 0xD6, 0xB3, 0x29, 0xE3, 0x2F, 0x84, 0x53, 0xD1, 0x00, 0xED, 0x20, 0xFC, 0xB1, 0x5B, 0x6A, 0xCB, 0xBE, 0x39, 0x4A, 0x4C, 0x58, 0xCF,
+    0xD0, 0xEF, 0xAA, 0xFB, 0x43, 0x4D, 0x33, 0x85, 0x45, 0xF9, 0x02, 0x7F, 0x50, 0x3C, 0x9F, 0xA8, 0x51, 0xA3, 0x40, 0x8F, 0x92, 0x9D, 0x38, 0xF5,
+    0xBC, 0xB6, 0xDA, 0x21, 0x10, 0xFF, 0xF3, 0xD2, 0xCD, 0x0C, 0x13, 0xEC, 0x5F, 0x97, 0x44, 0x17, 0xC4, 0xA7, 0x7E, 0x3D, 0x64, 0x5D, 0x19, 0x73,
+    0x60, 0x81, 0x4F, 0xDC, 0x22, 0x2A, 0x90, 0x88, 0x46, 0xEE, 0xB8, 0x14, 0xDE, 0x5E, 0x0B, 0xDB, 0xE0, 0x32, 0x3A, 0x0A, 0x49, 0x06, 0x24, 0x5C,
+    0xC2, 0xD3, 0xAC, 0x62, 0x91, 0x95, 0xE4, 0x79, 0xE7, 0xC8, 0x37, 0x6D, 0x8D, 0xD5, 0x4E, 0xA9, 0x6C, 0x56, 0xF4, 0xEA, 0x65, 0x7A, 0xAE, 0x08,
+    0xBA, 0x78, 0x25, 0x2E, 0x1C, 0xA6, 0xB4, 0xC6, 0xE8, 0xDD, 0x74, 0x1F, 0x4B, 0xBD, 0x8B, 0x8A, 0x70, 0x3E, 0xB5, 0x66, 0x48, 0x03, 0xF6, 0x0E,
+    0x61, 0x35, 0x57, 0xB9, 0x86, 0xC1, 0x1D, 0x9E, 0xE1, 0xF8, 0x98, 0x11, 0x69, 0xD9, 0x8E, 0x94, 0x9B, 0x1E, 0x87, 0xE9, 0xCE, 0x55, 0x28, 0xDF,
+    0x8C, 0xA1, 0x89, 0x0D, 0xBF, 0xE6, 0x42, 0x68, 0x41, 0x99, 0x2D, 0x0F, 0xB0, 0x54, 0xBB, 0x16,
+];
+
+// MixColumnsとInvMixColumnsで使う行列の1行目(以降の行は1つずつ右へ回したもの)
+const MIX_COLUMNS: [u8; 4] = [2, 3, 1, 1];
+const INVERSE_MIX_COLUMNS: [u8; 4] = [14, 11, 13, 9];
+
+pub trait Crypto {
+    fn aesenc(&mut self);
+    fn aesenclast(&mut self);
+    fn aesdec(&mut self);
+    fn aesdeclast(&mut self);
+    fn aesimc(&mut self);
+    fn aeskeygenassist(&mut self);
+    fn pclmulqdq(&mut self);
+}
+
+fn inverse_s_box() -> [u8; 256] {
+    let mut table = [0; 256];
+    for (i, &value) in S_BOX.iter().enumerate() {
+        table[value as usize] = i as u8;
+    }
+    table
+}
+
+fn sub_bytes(state: [u8; 16], table: &[u8; 256]) -> [u8; 16] {
+    let mut result = [0; 16];
+    for (i, &value) in state.iter().enumerate() {
+        result[i] = table[value as usize];
+    }
+    result
+}
+
+// 状態はバイトiが(行i % 4, 列i / 4)に並ぶ。行rを左へr列回す(逆変換は右へ回す)
+fn shift_rows(state: [u8; 16], inverse: bool) -> [u8; 16] {
+    let mut result = [0; 16];
+    for (i, value) in result.iter_mut().enumerate() {
+        let (row, column) = (i % 4, i / 4);
+        let source = if inverse { (column + 4 - row) % 4 } else { (column + row) % 4 };
+        *value = state[row + source * 4];
+    }
+    result
+}
+
+// GF(2^8)での乗算(既約多項式はx^8 + x^4 + x^3 + x + 1)
+fn gf_multiply(mut a: u8, mut b: u8) -> u8 {
+    let mut result = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            result ^= a;
+        }
+        a = (a << 1) ^ if a & 0x80 != 0 { 0x1B } else { 0 };
+        b >>= 1;
+    }
+    result
+}
+
+fn mix_columns(state: [u8; 16], matrix: &[u8; 4]) -> [u8; 16] {
+    let mut result = [0; 16];
+    for (i, value) in result.iter_mut().enumerate() {
+        let (row, column) = (i % 4, i / 4);
+        for j in 0..4 {
+            *value ^= gf_multiply(matrix[(j + 4 - row) % 4], state[column * 4 + j]);
+        }
+    }
+    result
+}
+
+fn aes_encrypt_round(state: u128, round_key: u128, _: u8) -> u128 {
+    let state = sub_bytes(shift_rows(state.to_le_bytes(), false), &S_BOX);
+    u128::from_le_bytes(mix_columns(state, &MIX_COLUMNS)) ^ round_key
+}
+
+fn aes_encrypt_last_round(state: u128, round_key: u128, _: u8) -> u128 {
+    u128::from_le_bytes(sub_bytes(shift_rows(state.to_le_bytes(), false), &S_BOX)) ^ round_key
+}
+
+fn aes_decrypt_round(state: u128, round_key: u128, _: u8) -> u128 {
+    let state = sub_bytes(shift_rows(state.to_le_bytes(), true), &inverse_s_box());
+    u128::from_le_bytes(mix_columns(state, &INVERSE_MIX_COLUMNS)) ^ round_key
+}
+
+fn aes_decrypt_last_round(state: u128, round_key: u128, _: u8) -> u128 {
+    u128::from_le_bytes(sub_bytes(shift_rows(state.to_le_bytes(), true), &inverse_s_box())) ^ round_key
+}
+
+// 暗号化用のラウンド鍵を復号(Equivalent Inverse Cipher)用に変換する
+fn aes_inverse_mix_columns(_: u128, source: u128, _: u8) -> u128 {
+    u128::from_le_bytes(mix_columns(source.to_le_bytes(), &INVERSE_MIX_COLUMNS))
+}
+
+fn sub_word(word: u32) -> u32 {
+    let mut result = 0;
+    for i in 0..4 {
+        result |= (S_BOX[((word >> (i * 8)) & 0xFF) as usize] as u32) << (i * 8);
+    }
+    result
+}
+
+// 鍵拡張の補助: 2番目と4番目の32ビットにSubWordとRotWordを適用し、RCONをXORする
+fn aes_key_generation_assist(_: u128, source: u128, rcon: u8) -> u128 {
+    let x1 = sub_word((source >> 32) as u32);
+    let x3 = sub_word((source >> 96) as u32);
+    let rcon = rcon as u32;
+    (x1 as u128) | (((x1.rotate_right(8) ^ rcon) as u128) << 32) | ((x3 as u128) << 64) | (((x3.rotate_right(8) ^ rcon) as u128) << 96)
+}
+
+// 即値のビット0とビット4で、それぞれの64ビットの上位と下位のどちらを使うかを選ぶ
+fn carry_less_multiply(destination: u128, source: u128, immediate: u8) -> u128 {
+    let a = if immediate & 0x01 != 0 { destination >> 64 } else { destination } as u64;
+    let b = if immediate & 0x10 != 0 { source >> 64 } else { source } as u64;
+    let mut result = 0;
+    for i in 0..64 {
+        if (b >> i) & 1 != 0 {
+            result ^= (a as u128) << i;
+        }
+    }
+    result
+}
+
+impl Crypto for Emulator {
+    fn aesenc(&mut self) {
+        self.exec_xmm_instruction(Feature::Aes, 3, false, aes_encrypt_round);
+    }
+
+    fn aesenclast(&mut self) {
+        self.exec_xmm_instruction(Feature::Aes, 3, false, aes_encrypt_last_round);
+    }
+
+    fn aesdec(&mut self) {
+        self.exec_xmm_instruction(Feature::Aes, 3, false, aes_decrypt_round);
+    }
+
+    fn aesdeclast(&mut self) {
+        self.exec_xmm_instruction(Feature::Aes, 3, false, aes_decrypt_last_round);
+    }
+
+    fn aesimc(&mut self) {
+        self.exec_xmm_instruction(Feature::Aes, 3, false, aes_inverse_mix_columns);
+    }
+
+    fn aeskeygenassist(&mut self) {
+        self.exec_xmm_instruction(Feature::Aes, 3, true, aes_key_generation_assist);
+    }
+
+    fn pclmulqdq(&mut self) {
+        self.exec_xmm_instruction(Feature::Pclmulqdq, 3, true, carry_less_multiply);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use emulator::control_register::CR4_OSFXSR;
+    use emulator::cpu_model::CpuModel;
+    use emulator::testing::*;
+
+    // Intelのホワイトペーパー(AES-NIとPCLMULQDQ)の例で使われているオペランド
+    const XMM1: u128 = 0x7b5b_5465_7374_5665_6374_6f72_5d53_475d;
+    const XMM2: u128 = 0x4869_2853_6861_7929_5b47_7565_726f_6e5d;
+
+    // XMM0とXMM1に値を入れて、SSEを有効にしたBroadwellで1命令実行し、XMM0を返す
+    fn execute(code: &[u8], xmm0: u128, xmm1: u128) -> u128 {
+        let mut emu = emulator(code);
+        emu.set_cpu_model(CpuModel::Broadwell);
+        emu.control_registers[4] |= CR4_OSFXSR;
+        emu.xmm_registers[0] = xmm0;
+        emu.xmm_registers[1] = xmm1;
+        run(&mut emu, 1);
+        assert_eq!(emu.eip, 0x7c00 + code.len() as u64);
+        emu.xmm_register(0)
+    }
+
+    #[test]
+    fn aes_round_instructions_match_intel_examples() {
+        let aesenc = [0x66, 0x0F, 0x38, 0xDC, 0xC1];
+        let aesenclast = [0x66, 0x0F, 0x38, 0xDD, 0xC1];
+        let aesdec = [0x66, 0x0F, 0x38, 0xDE, 0xC1];
+        let aesdeclast = [0x66, 0x0F, 0x38, 0xDF, 0xC1];
+        assert_eq!(execute(&aesenc, XMM1, XMM2), 0xa831_1c2f_9fdb_a3c5_8b10_4b58_ded7_e595);
+        assert_eq!(execute(&aesenclast, XMM1, XMM2), 0xc7fb_881e_938c_5964_177e_c425_53fd_c611);
+        assert_eq!(execute(&aesdec, XMM1, XMM2), 0x138a_c342_faea_2787_b58e_b95e_b730_392a);
+        assert_eq!(execute(&aesdeclast, XMM1, XMM2), 0xc5a3_91ef_6b31_7f95_d410_637b_72a5_93d0);
+    }
+
+    #[test]
+    fn aeskeygenassist_matches_intel_example() {
+        // aeskeygenassist xmm0, xmm1, 1
+        let code = [0x66, 0x0F, 0x3A, 0xDF, 0xC1, 0x01];
+        let key = 0x3c4f_cf09_8815_f7ab_a6d2_ae28_1615_7e2b;
+        assert_eq!(execute(&code, 0, key), 0x01eb_848b_eb84_8a01_3424_b5e5_24b5_e434);
+    }
+
+    #[test]
+    fn pclmulqdq_selects_quadwords_by_immediate() {
+        // pclmulqdq xmm0, xmm1, imm (即値のビット0がXMM0、ビット4がXMM1の上位/下位を選ぶ)
+        let expected = [
+            (0x00, 0x1d4d_84c8_5c34_40c0_9296_33d5_d36f_0451),
+            (0x01, 0x1a2b_f6db_3a30_862f_babf_262d_f4b7_d5c9),
+            (0x10, 0x1bd1_7c8d_556a_b5a1_7fa5_40ac_2a28_1315),
+            (0x11, 0x1d1e_1f2c_592e_7c45_d66e_e03e_410f_d4ed),
+        ];
+        for &(immediate, product) in expected.iter() {
+            assert_eq!(execute(&[0x66, 0x0F, 0x3A, 0x44, 0xC1, immediate], XMM1, XMM2), product);
+        }
+        // (x + 1)^2 = x^2 + 1
+        assert_eq!(carry_less_multiply(0b11, 0b11, 0), 0b101);
+    }
+
+    // AESKEYGENASSISTを使ったAES-128の鍵拡張(Intelのホワイトペーパーと同じ手順)
+    fn expand_key(key: u128) -> Vec<u128> {
+        let mut keys = vec![key];
+        for &rcon in [0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x1B, 0x36].iter() {
+            let key = *keys.last().unwrap();
+            let word = (aes_key_generation_assist(0, key, rcon) >> 96) as u32 as u128;
+            let broadcast = word * 0x0000_0001_0000_0001_0000_0001_0000_0001;
+            keys.push(key ^ (key << 32) ^ (key << 64) ^ (key << 96) ^ broadcast);
+        }
+        keys
+    }
+
+    #[test]
+    fn aes_128_matches_fips_197() {
+        // FIPS-197 付録C.1
+        let key = u128::from_le_bytes([0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]);
+        let plaintext = u128::from_le_bytes([
+            0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff,
+        ]);
+        let ciphertext = u128::from_le_bytes([
+            0x69, 0xc4, 0xe0, 0xd8, 0x6a, 0x7b, 0x04, 0x30, 0xd8, 0xcd, 0xb7, 0x80, 0x70, 0xb4, 0xc5, 0x5a,
+        ]);
+        let keys = expand_key(key);
+        assert_eq!(keys[10], 0xc530_2b4d_8ba7_07f3_174a_94e3_7f1d_1113);
+
+        let mut state = plaintext ^ keys[0];
+        for &round_key in keys[1..10].iter() {
+            state = aes_encrypt_round(state, round_key, 0);
+        }
+        assert_eq!(aes_encrypt_last_round(state, keys[10], 0), ciphertext);
+
+        // 復号にはAESIMCで変換したラウンド鍵を使う(Equivalent Inverse Cipher)
+        let mut state = ciphertext ^ keys[10];
+        for &round_key in keys[1..10].iter().rev() {
+            state = aes_decrypt_round(state, aes_inverse_mix_columns(0, round_key, 0), 0);
+        }
+        assert_eq!(aes_decrypt_last_round(state, keys[0], 0), plaintext);
+    }
+
+    #[test]
+    fn aes_instructions_need_the_aes_feature() {
+        let mut emu = protected_mode_emulator(&[0x66, 0x0F, 0x38, 0xDC, 0xC1], 6);
+        emu.control_registers[4] |= CR4_OSFXSR;
+        run(&mut emu, 1);
+        assert_eq!(emu.eip, 0x7e00);
+    }
+}
@@ -0,0 +1,251 @@
+use emulator::emulator_function::EmulatorFunction;
+use emulator::interrupt::{general_protection, Interrupt, DEBUG_EXCEPTION};
+use emulator::modrm::Function as ModRMFunction;
+use emulator::segment::Segment;
+use emulator::Emulator;
+
+// DR6のビット
+pub const DR6_BREAKPOINT_MASK: u32 = 0x0F;
+pub const DR6_BD: u32 = 1 << 13;
+pub const DR6_BS: u32 = 1 << 14;
+// DR7のビット
+pub const DR7_GD: u32 = 1 << 13;
+
+// 電源投入時のDR6/DR7の値(予約ビットが1になっている)
+pub const DR6_INITIAL: u32 = 0xFFFF_0FF0;
+pub const DR7_INITIAL: u32 = 0x0000_0400;
+
+// DR7のR/Wフィールド
+const RW_EXECUTE: u32 = 0b00;
+const RW_WRITE: u32 = 0b01;
+const RW_READ_WRITE: u32 = 0b11;
+
+pub trait DebugRegister {
+    fn check_instruction_breakpoint(&mut self) -> bool;
+    fn check_data_breakpoint(&mut self, address: usize, is_write: bool);
+    fn deliver_debug_exception(&mut self);
+    fn int1(&mut self);
+    fn mov_r32_dr(&mut self);
+    fn mov_dr_r32(&mut self);
+}
+
+// DR4/DR5はDR6/DR7の別名として扱う
+fn debug_register_index(index: u8) -> usize {
+    match index {
+        4 => 6,
+        5 => 7,
+        _ => index as usize,
+    }
+}
+
+// DR0〜DR3のうち、指定したR/W条件で有効になっているブレークポイントの番号を返す
+fn enabled_breakpoints(dr7: u32, rw: u32) -> Vec<usize> {
+    (0..4)
+        .filter(|i| (dr7 >> (i * 2)) & 0b11 != 0)
+        .filter(|i| (dr7 >> (16 + i * 4)) & 0b11 == rw)
+        .collect()
+}
+
+// ブレークポイントの範囲にアクセスしたアドレスが含まれるか
+fn breakpoint_matches(registers: &[u32; 8], index: usize, address: usize) -> bool {
+    let len = match (registers[7] >> (18 + index * 4)) & 0b11 {
+        0b00 => 1,
+        0b01 => 2,
+        0b10 => 8,
+        _ => 4,
+    };
+    // LENに合わせて下位ビットをマスクする
+    let start = (registers[index] & !(len - 1)) as usize;
+    start <= address && address < start + len as usize
+}
+
+impl DebugRegister for Emulator {
+    fn check_instruction_breakpoint(&mut self) -> bool {
+        // DR0〜DR3にはリニアアドレスを設定するので、CSのベースを足して比べる
+        let address = self.code_address(0) as usize;
+        let hits = enabled_breakpoints(self.debug_registers[7], RW_EXECUTE)
+            .into_iter()
+            .filter(|&i| breakpoint_matches(&self.debug_registers, i, address))
+            .fold(0, |bits, i| bits | (1 << i));
+        self.pending_debug |= hits;
+        hits != 0
+    }
+
+    fn check_data_breakpoint(&mut self, address: usize, is_write: bool) {
+        let dr7 = self.debug_registers[7];
+        let mut breakpoints = enabled_breakpoints(dr7, RW_READ_WRITE);
+        if is_write {
+            breakpoints.extend(enabled_breakpoints(dr7, RW_WRITE));
+        }
+        for i in breakpoints {
+            if breakpoint_matches(&self.debug_registers, i, address) {
+                self.pending_debug |= 1 << i;
+            }
+        }
+    }
+
+    fn deliver_debug_exception(&mut self) {
+        let dr6 = self.debug_registers[6] & !DR6_BREAKPOINT_MASK;
+        self.debug_registers[6] = dr6 | self.pending_debug;
+        self.pending_debug = 0;
+        self.raise_interrupt(DEBUG_EXCEPTION);
+    }
+
+    fn int1(&mut self) {
+        // ICEBPはDR6を変更せずに#DBを発生させる
+        self.eip += 1;
+        self.raise_interrupt(DEBUG_EXCEPTION);
+    }
+
+    fn mov_r32_dr(&mut self) {
+        if self.cpl() != 0 {
+            self.raise_exception(general_protection(0));
+            return;
+        }
+        if self.debug_registers[7] & DR7_GD != 0 {
+            self.debug_registers[7] &= !DR7_GD;
+            self.pending_debug |= DR6_BD;
+            self.deliver_debug_exception();
+            return;
+        }
+        self.eip += 2;
+        let modrm = self.parse_modrm();
+        let value = self.debug_registers[debug_register_index(modrm.get_reg_index())];
+        self.set_register32(modrm.rm as usize, value);
+    }
+
+    fn mov_dr_r32(&mut self) {
+        if self.cpl() != 0 {
+            self.raise_exception(general_protection(0));
+            return;
+        }
+        if self.debug_registers[7] & DR7_GD != 0 {
+            self.debug_registers[7] &= !DR7_GD;
+            self.pending_debug |= DR6_BD;
+            self.deliver_debug_exception();
+            return;
+        }
+        self.eip += 2;
+        let modrm = self.parse_modrm();
+        let value = self.get_register32(modrm.rm as usize);
+        let index = debug_register_index(modrm.get_reg_index());
+        self.debug_registers[index] = match index {
+            6 => value | DR6_INITIAL,
+            7 => value | DR7_INITIAL,
+            _ => value,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DR6_BD, DR6_BS, DR6_INITIAL, DR7_GD, DR7_INITIAL};
+    use emulator::emulator_function::EmulatorFunction;
+    use emulator::testing::*;
+    use emulator::{Emulator, SegmentRegister};
+    // #DBのハンドラに入っていて、戻り先がeipであること
+    fn assert_debug_exception(emu: &Emulator, eip: u32) {
+        assert_eq!(emu.eip, 0x7e00);
+        assert_eq!(read32(emu, emu.get_register32(4)), eip);
+    }
+
+    #[test]
+    fn trap_flag_single_steps_one_instruction() {
+        let mut emu = protected_mode_emulator(&[0xF8, 0xF8], 1);
+        emu.eflags |= Emulator::TRAP_FLAG;
+        run(&mut emu, 1);
+        assert_debug_exception(&emu, 0x7c01);
+        assert_ne!(emu.debug_registers[6] & DR6_BS, 0);
+        // 割り込みゲートを通るとTFは落ちる
+        assert_eq!(emu.eflags & Emulator::TRAP_FLAG, 0);
+    }
+
+    #[test]
+    fn instruction_breakpoint_faults_before_execution() {
+        let mut emu = protected_mode_emulator(&[0xF8, 0xF9], 1);
+        emu.debug_registers[0] = 0x7c01;
+        emu.debug_registers[7] = DR7_INITIAL | 1;
+        run(&mut emu, 2);
+        assert_debug_exception(&emu, 0x7c01);
+        assert_eq!(emu.debug_registers[6] & 0xF, 1);
+        // STCは実行されていない
+        assert_eq!(emu.eflags & Emulator::CARRY_FLAG, 0);
+    }
+
+    #[test]
+    fn instruction_breakpoint_compares_the_linear_address() {
+        let mut emu = emulator(&[0xF8, 0xF9]);
+        emu.segment_registers[SegmentRegister::CS as usize] = 0x07c0;
+        emu.eip = 0;
+        emu.reset_to_real_mode();
+        // 割り込みベクタテーブルの#DBのハンドラは0000:7e00
+        write16(&mut emu, 4, 0x7e00);
+        write16(&mut emu, 6, 0);
+        // 07c0:0001はリニアアドレス0x7c01
+        emu.debug_registers[0] = 0x7c01;
+        emu.debug_registers[7] = DR7_INITIAL | 1;
+        run(&mut emu, 2);
+        assert_eq!(emu.eip, 0x7e00);
+        assert_eq!(emu.debug_registers[6] & 0xF, 1);
+        assert_eq!(emu.eflags & Emulator::CARRY_FLAG, 0);
+        let ss = emu.segment_cache(SegmentRegister::SS).base;
+        let sp = emu.get_register32(4) & 0xFFFF;
+        assert_eq!(read16(&emu, ss + sp), 0x0001);
+        assert_eq!(read16(&emu, ss + sp + 2), 0x07c0);
+    }
+
+    #[test]
+    fn data_write_breakpoint_traps_after_the_write() {
+        let mut emu = protected_mode_emulator(
+            &[
+                0xC7, 0x05, 0x02, 0x60, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov dword [0x6002], 1
+            ],
+            1,
+        );
+        // DR1: 0x6000からの4バイトへの書き込み
+        emu.debug_registers[1] = 0x6000;
+        emu.debug_registers[7] = DR7_INITIAL | (0b1101 << 20) | (1 << 2);
+        run(&mut emu, 1);
+        assert_debug_exception(&emu, 0x7c0a);
+        assert_eq!(read32(&emu, 0x6002), 1);
+        assert_eq!(emu.debug_registers[6] & 0xF, 2);
+    }
+
+    #[test]
+    fn general_detect_faults_on_debug_register_access() {
+        let mut emu = protected_mode_emulator(
+            &[
+                0x0F, 0x21, 0xF8, // mov eax, dr7
+            ],
+            1,
+        );
+        emu.debug_registers[7] = DR7_INITIAL | DR7_GD;
+        run(&mut emu, 1);
+        assert_debug_exception(&emu, 0x7c00);
+        assert_ne!(emu.debug_registers[6] & DR6_BD, 0);
+        assert_eq!(emu.debug_registers[7] & DR7_GD, 0);
+    }
+
+    #[test]
+    fn dr4_and_dr5_alias_dr6_and_dr7() {
+        let mut emu = protected_mode_emulator(
+            &[
+                0xB8, 0x01, 0x00, 0x00, 0x00, // mov eax, 1
+                0x0F, 0x23, 0xE8, // mov dr5, eax
+                0x0F, 0x21, 0xE1, // mov ecx, dr4
+            ],
+            1,
+        );
+        run(&mut emu, 3);
+        assert_eq!(emu.debug_registers[7], DR7_INITIAL | 1);
+        assert_eq!(emu.get_register32(1), DR6_INITIAL);
+    }
+
+    #[test]
+    fn icebp_raises_debug_exception_without_touching_dr6() {
+        let mut emu = protected_mode_emulator(&[0xF1], 1);
+        run(&mut emu, 1);
+        assert_debug_exception(&emu, 0x7c01);
+        assert_eq!(emu.debug_registers[6], DR6_INITIAL);
+    }
+}
@@ -0,0 +1,401 @@
+use emulator::cpu_model::CpuModel;
+use emulator::emulator_function::EmulatorFunction;
+use emulator::interrupt::{general_protection, invalid_opcode, Exception};
+use emulator::long_mode::{is_invalid_in_64bit_mode, REX_W};
+use emulator::vex::Vex;
+use emulator::{Emulator, SegmentRegister};
+
+// プレフィックスを含めた命令の最大の長さ(これを超えると#GP(0))
+pub const MAXIMUM_INSTRUCTION_LENGTH: u32 = 15;
+
+// オペコードマップ
+#[derive(Clone, Copy, PartialEq)]
+enum OpcodeMap {
+    OneByte,
+    TwoByte,
+    ThreeByte38,
+    ThreeByte3A,
+}
+
+// オペコードとModRMの後に続く即値の種類
+#[derive(Clone, Copy, PartialEq)]
+enum Immediate {
+    None,
+    Byte,
+    Word,
+    // オペランドサイズに合わせて2バイトか4バイト
+    Full,
+    // アドレスサイズに合わせて2バイトか4バイト(moffs)
+    Offset,
+    // ENTERのimm16とimm8
+    Enter,
+    // オフセットとセレクタ(ptr16:16/ptr16:32)
+    FarPointer,
+    // REX.WのMOV r64, imm64の8バイト
+    Quad,
+}
+
+fn one_byte_has_modrm(code: u8) -> bool {
+    match code {
+        0x00..=0x3F => code & 7 < 4,
+        0x62 | 0x63 | 0x69 | 0x6B | 0x80..=0x8F | 0xC0 | 0xC1 | 0xC4..=0xC7 | 0xD0..=0xD3 | 0xD8..=0xDF | 0xF6 | 0xF7 | 0xFE | 0xFF => true,
+        _ => false,
+    }
+}
+
+fn one_byte_immediate(code: u8) -> Immediate {
+    match code {
+        0x00..=0x3F if code & 7 == 4 => Immediate::Byte,
+        0x00..=0x3F if code & 7 == 5 => Immediate::Full,
+        0x6A | 0x6B | 0x70..=0x7F | 0x80 | 0x82 | 0x83 | 0xA8 | 0xB0..=0xB7 | 0xC0 | 0xC1 | 0xC6 | 0xCD | 0xD4 | 0xD5 | 0xE0..=0xE7 | 0xEB => {
+            Immediate::Byte
+        }
+        0x68 | 0x69 | 0x81 | 0xA9 | 0xB8..=0xBF | 0xC7 | 0xE8 | 0xE9 => Immediate::Full,
+        0xA0..=0xA3 => Immediate::Offset,
+        0xC2 | 0xCA => Immediate::Word,
+        0xC8 => Immediate::Enter,
+        0x9A | 0xEA => Immediate::FarPointer,
+        _ => Immediate::None,
+    }
+}
+
+// UD0/UD1/UD2と、どの世代でも定義されていない0F xx
+fn is_undefined_two_byte(code: u8, model: CpuModel) -> bool {
+    match code {
+        0x04 | 0x0A | 0x0B | 0x0C | 0x0F | 0x25 | 0x27 | 0x36 | 0x39 | 0x3B..=0x3F | 0x7A | 0x7B | 0xA6 | 0xA7 | 0xB9 | 0xFF => true,
+        // テストレジスタの転送はPentiumでなくなった
+        0x24 | 0x26 => model >= CpuModel::Pentium,
+        _ => false,
+    }
+}
+
+fn two_byte_has_modrm(code: u8) -> bool {
+    !matches!(code, 0x05..=0x09 | 0x0E | 0x30..=0x37 | 0x77 | 0x80..=0x8F | 0xA0..=0xA2 | 0xA8..=0xAA | 0xC8..=0xCF)
+}
+
+fn two_byte_immediate(code: u8) -> Immediate {
+    match code {
+        0x70..=0x73 | 0xA4 | 0xAC | 0xBA | 0xC2 | 0xC4..=0xC6 => Immediate::Byte,
+        0x80..=0x8F => Immediate::Full,
+        _ => Immediate::None,
+    }
+}
+
+// ModRMのregやmodの組み合わせが定義されていない
+fn is_invalid_modrm(map: OpcodeMap, code: u8, modrm: u8) -> bool {
+    let mode = modrm >> 6;
+    let reg = (modrm >> 3) & 7;
+    match (map, code) {
+        // LEA/BOUND/LES/LDSのレジスタ形式(32ビットモードのC4/C5 mod=11はVEXとして別に扱う)
+        (OpcodeMap::OneByte, 0x62) | (OpcodeMap::OneByte, 0x8D) | (OpcodeMap::OneByte, 0xC4) | (OpcodeMap::OneByte, 0xC5) => mode == 3,
+        (OpcodeMap::OneByte, 0x8C) => reg > 5,
+        // MOV CS, r/m16はできない
+        (OpcodeMap::OneByte, 0x8E) => reg > 5 || reg == SegmentRegister::CS as u8,
+        (OpcodeMap::OneByte, 0x8F) | (OpcodeMap::OneByte, 0xC6) | (OpcodeMap::OneByte, 0xC7) => reg != 0,
+        (OpcodeMap::OneByte, 0xFE) => reg > 1,
+        // 遠隔CALL/JMPのオペランドはメモリでなければならない
+        (OpcodeMap::OneByte, 0xFF) => reg == 7 || (mode == 3 && (reg == 3 || reg == 5)),
+        (OpcodeMap::TwoByte, 0x00) => reg > 5,
+        (OpcodeMap::TwoByte, 0xB2) | (OpcodeMap::TwoByte, 0xB4) | (OpcodeMap::TwoByte, 0xB5) => mode == 3,
+        (OpcodeMap::TwoByte, 0xC7) => reg == 1 && mode == 3,
+        _ => false,
+    }
+}
+
+// LOCKプレフィックスを付けられる(メモリを読み書きする)命令
+fn is_lockable(map: OpcodeMap, code: u8, reg: u8) -> bool {
+    match (map, code) {
+        // ADD/OR/ADC/SBB/AND/SUB/XOR r/m, r(CMPは除く)
+        (OpcodeMap::OneByte, 0x00..=0x3F) => code & 7 < 2 && code & 0x38 != 0x38,
+        (OpcodeMap::OneByte, 0x80..=0x83) => reg != 7,
+        (OpcodeMap::OneByte, 0x86) | (OpcodeMap::OneByte, 0x87) => true,
+        // NOT/NEG
+        (OpcodeMap::OneByte, 0xF6) | (OpcodeMap::OneByte, 0xF7) => reg == 2 || reg == 3,
+        // INC/DEC
+        (OpcodeMap::OneByte, 0xFE) | (OpcodeMap::OneByte, 0xFF) => reg < 2,
+        // BTS/BTR/BTC, CMPXCHG, XADD
+        (OpcodeMap::TwoByte, 0xAB) | (OpcodeMap::TwoByte, 0xB3) | (OpcodeMap::TwoByte, 0xBB) => true,
+        (OpcodeMap::TwoByte, 0xB0) | (OpcodeMap::TwoByte, 0xB1) | (OpcodeMap::TwoByte, 0xC0) | (OpcodeMap::TwoByte, 0xC1) => true,
+        (OpcodeMap::TwoByte, 0xBA) => reg >= 5,
+        // CMPXCHG8B
+        (OpcodeMap::TwoByte, 0xC7) => reg == 1,
+        _ => false,
+    }
+}
+
+pub trait Decoder {
+    fn decode_instruction(&mut self) -> Result<u32, Exception>;
+}
+
+impl Emulator {
+    // プレフィックスを読み取ってEIPをオペコードまで進める(同じ種類のプレフィックスは最後のものが有効)
+    fn decode_prefixes(&mut self) -> Result<u32, Exception> {
+        self.operand_size_override = false;
+        self.address_size_override = false;
+        self.segment_override = None;
+        self.repeat_prefix = None;
+        self.lock_prefix = false;
+        self.rex = 0;
+        loop {
+            let code = self.get_code8(0);
+            let is_rex = (0x40..=0x4F).contains(&code) && self.is_64bit_mode();
+            match code {
+                _ if is_rex => self.rex = code,
+                // 80386より前の世代にはFS/GSとサイズのプレフィックスがない
+                0x64..=0x67 if !self.cpu_model.is_32bit() => break,
+                0x26 => self.segment_override = Some(SegmentRegister::ES as usize),
+                0x2E => self.segment_override = Some(SegmentRegister::CS as usize),
+                0x36 => self.segment_override = Some(SegmentRegister::SS as usize),
+                0x3E => self.segment_override = Some(SegmentRegister::DS as usize),
+                0x64 => self.segment_override = Some(SegmentRegister::FS as usize),
+                0x65 => self.segment_override = Some(SegmentRegister::GS as usize),
+                0x66 => self.operand_size_override = true,
+                0x67 => self.address_size_override = true,
+                0xF0 => self.lock_prefix = true,
+                prefix @ 0xF2..=0xF3 => self.repeat_prefix = Some(prefix),
+                _ => break,
+            }
+            // REXプレフィックスはオペコードの直前にあるときだけ有効で、後に別のプレフィックスが続けば無視される
+            if !is_rex {
+                self.rex = 0;
+            }
+            self.eip += 1;
+            // プレフィックスだけで最大の長さに達したら、オペコードを足すと必ず超える
+            if self.eip.wrapping_sub(self.instruction_eip) >= MAXIMUM_INSTRUCTION_LENGTH as u64 {
+                return Err(general_protection(0));
+            }
+        }
+        Ok(self.eip.wrapping_sub(self.instruction_eip) as u32)
+    }
+
+    // offsetにあるModRMと、それに続くSIBとディスプレースメントのバイト数
+    fn modrm_length(&mut self, offset: i32) -> u32 {
+        let modrm = self.get_code8(offset);
+        let mode = modrm >> 6;
+        let rm = modrm & 7;
+        if mode == 3 {
+            return 1;
+        }
+        if self.is_address_size16() {
+            return match mode {
+                0 if rm == 6 => 3,
+                0 => 1,
+                1 => 2,
+                _ => 3,
+            };
+        }
+        let (sib_length, base) = if rm == 4 { (1, self.get_code8(offset + 1) & 7) } else { (0, rm) };
+        let disp_length = match mode {
+            0 if base == 5 => 4,
+            0 => 0,
+            1 => 1,
+            _ => 4,
+        };
+        1 + sib_length + disp_length
+    }
+
+    fn immediate_length(&self, immediate: Immediate) -> u32 {
+        let operand_size = if self.is_operand_size16() { 2 } else { 4 };
+        match immediate {
+            Immediate::None => 0,
+            Immediate::Byte => 1,
+            Immediate::Word => 2,
+            Immediate::Full => operand_size,
+            Immediate::Offset => {
+                if self.is_address_size16() {
+                    2
+                } else if self.is_address_size64() {
+                    8
+                } else {
+                    4
+                }
+            }
+            Immediate::Enter => 3,
+            Immediate::FarPointer => operand_size + 2,
+            Immediate::Quad => 8,
+        }
+    }
+
+    // VEXプレフィックスから始まる命令の長さ(プレフィックスを除く)
+    fn vex_instruction_length(&mut self) -> Result<u32, Exception> {
+        // VEXにLOCKは付けられない(66/F2/F3はVEXの命令を実行するときに確かめる)
+        if self.lock_prefix {
+            return Err(invalid_opcode());
+        }
+        let (map, offset) = if self.get_code8(0) == 0xC4 {
+            (self.get_code8(1) & 0x1F, 3)
+        } else {
+            (1, 2)
+        };
+        let immediate = match map {
+            1 => two_byte_immediate(self.get_code8(offset)),
+            2 => Immediate::None,
+            3 => Immediate::Byte,
+            _ => return Err(invalid_opcode()),
+        };
+        Ok(offset as u32 + 1 + self.modrm_length(offset + 1) + self.immediate_length(immediate))
+    }
+}
+
+impl Decoder for Emulator {
+    // プレフィックスを読み取り、命令全体の長さを求めて符号化が正しいか確かめる
+    // 長さが15バイトを超えれば#GP(0)、定義されていない符号化なら#UDで、成功すれば命令の長さを返す
+    fn decode_instruction(&mut self) -> Result<u32, Exception> {
+        let prefix_length = self.decode_prefixes()?;
+        let first = self.get_code8(0);
+        if (first == 0xC4 || first == 0xC5) && self.is_vex_prefix() {
+            let length = prefix_length + self.vex_instruction_length()?;
+            if length > MAXIMUM_INSTRUCTION_LENGTH {
+                return Err(general_protection(0));
+            }
+            return Ok(length);
+        }
+
+        // 8086では0FがPOP CSになる
+        let (map, code, mut length) = match first {
+            0x0F if self.cpu_model != CpuModel::I8086 => match self.get_code8(1) {
+                0x38 => (OpcodeMap::ThreeByte38, self.get_code8(2), 3),
+                0x3A => (OpcodeMap::ThreeByte3A, self.get_code8(2), 3),
+                second => (OpcodeMap::TwoByte, second, 2),
+            },
+            _ => (OpcodeMap::OneByte, first, 1),
+        };
+        if map == OpcodeMap::OneByte && self.is_64bit_mode() && is_invalid_in_64bit_mode(code) {
+            return Err(invalid_opcode());
+        }
+        let (has_modrm, mut immediate, mut invalid) = match map {
+            OpcodeMap::OneByte => (one_byte_has_modrm(code), one_byte_immediate(code), false),
+            OpcodeMap::TwoByte => (two_byte_has_modrm(code), two_byte_immediate(code), is_undefined_two_byte(code, self.cpu_model)),
+            OpcodeMap::ThreeByte38 => (true, Immediate::None, false),
+            OpcodeMap::ThreeByte3A => (true, Immediate::Byte, false),
+        };
+
+        if map == OpcodeMap::OneByte && (0xB8..=0xBF).contains(&code) && self.rex & REX_W != 0 {
+            immediate = Immediate::Quad;
+        }
+        if has_modrm {
+            let modrm = self.get_code8(length);
+            let reg = (modrm >> 3) & 7;
+            // TEST r/m, immだけがF6/F7のグループで即値を持つ
+            if map == OpcodeMap::OneByte && (code == 0xF6 || code == 0xF7) && reg < 2 {
+                immediate = if code == 0xF6 { Immediate::Byte } else { Immediate::Full };
+            }
+            invalid |= is_invalid_modrm(map, code, modrm);
+            invalid |= self.lock_prefix && (modrm >> 6 == 3 || !is_lockable(map, code, reg));
+            length += self.modrm_length(length) as i32;
+        } else {
+            invalid |= self.lock_prefix;
+        }
+
+        let length = prefix_length + length as u32 + self.immediate_length(immediate);
+        if length > MAXIMUM_INSTRUCTION_LENGTH {
+            return Err(general_protection(0));
+        }
+        if invalid {
+            return Err(invalid_opcode());
+        }
+        Ok(length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use emulator::interrupt::{GENERAL_PROTECTION, INVALID_OPCODE};
+    use emulator::testing::*;
+
+    // codeの先頭の命令をmodelのCPUでデコードし、長さか例外のベクタを返す
+    fn decode_on(model: CpuModel, code: &[u8]) -> Result<u32, u8> {
+        let mut emu = emulator(code);
+        emu.set_cpu_model(model);
+        emu.decode_instruction().map_err(|e| e.vector)
+    }
+
+    fn decode(code: &[u8]) -> Result<u32, u8> {
+        decode_on(CpuModel::default(), code)
+    }
+
+    #[test]
+    fn lengths_include_modrm_sib_displacement_and_immediate() {
+        assert_eq!(decode(&[0xB8, 0x78, 0x56, 0x34, 0x12]), Ok(5)); // mov eax, imm32
+        assert_eq!(decode(&[0x66, 0xB8, 0x34, 0x12]), Ok(4)); // mov ax, imm16
+        assert_eq!(decode(&[0x8B, 0x84, 0x24, 0x00, 0x01, 0x00, 0x00]), Ok(7)); // mov eax, [esp+0x100]
+        assert_eq!(decode(&[0x8B, 0x04, 0x25, 0x00, 0x40, 0x00, 0x00]), Ok(7)); // mov eax, [0x4000]
+        assert_eq!(decode(&[0x67, 0x8B, 0x06, 0x34, 0x12]), Ok(5)); // mov eax, [0x1234]
+        assert_eq!(decode(&[0x67, 0x8B, 0x40, 0x10]), Ok(4)); // mov eax, [bx+si+0x10]
+        assert_eq!(decode(&[0xA1, 0x00, 0x40, 0x00, 0x00]), Ok(5)); // mov eax, [moffs32]
+        assert_eq!(decode(&[0x67, 0xA1, 0x00, 0x40]), Ok(4)); // mov eax, [moffs16]
+        assert_eq!(decode(&[0xC8, 0x10, 0x00, 0x01]), Ok(4)); // enter 0x10, 1
+        assert_eq!(decode(&[0xEA, 0x00, 0x7c, 0x00, 0x00, 0x08, 0x00]), Ok(7)); // jmp 0x08:0x7c00
+        assert_eq!(decode(&[0xF7, 0xC1, 0x01, 0x00, 0x00, 0x00]), Ok(6)); // test ecx, imm32
+        assert_eq!(decode(&[0xF7, 0xD1]), Ok(2)); // not ecx
+        assert_eq!(decode(&[0x0F, 0x80, 0x00, 0x00, 0x00, 0x00]), Ok(6)); // jo rel32
+        assert_eq!(decode(&[0x66, 0x0F, 0x3A, 0x0F, 0xC1, 0x08]), Ok(6)); // palignr xmm0, xmm1, 8
+    }
+
+    #[test]
+    fn repeated_prefixes_count_toward_fifteen_bytes() {
+        let mut code = vec![0x3E; 14];
+        code.push(0xC3); // ret
+        assert_eq!(decode(&code), Ok(15));
+        // 同じ種類のプレフィックスは最後のものが有効になる
+        let mut emu = emulator(&[0x2E, 0x26, 0x66, 0x66, 0x3E, 0xC3]);
+        assert_eq!(emu.decode_instruction().ok(), Some(6));
+        assert_eq!(emu.segment_override, Some(SegmentRegister::DS as usize));
+        assert!(emu.operand_size_override);
+        // オペコードの後ろまで数えて15バイトを超えれば#GP
+        let mut code = vec![0x3E; 14];
+        code.extend_from_slice(&[0xB8, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(decode(&code), Err(GENERAL_PROTECTION));
+        assert_eq!(decode(&[0x66; 16]), Err(GENERAL_PROTECTION));
+    }
+
+    #[test]
+    fn invalid_modrm_forms_raise_invalid_opcode() {
+        assert_eq!(decode(&[0x8D, 0xC1]), Err(INVALID_OPCODE)); // lea eax, ecx
+        assert_eq!(decode(&[0x8E, 0xC8]), Err(INVALID_OPCODE)); // mov cs, ax
+        assert_eq!(decode(&[0x8C, 0xF0]), Err(INVALID_OPCODE)); // mov ax, sreg6
+        assert_eq!(decode(&[0xFF, 0xD8]), Err(INVALID_OPCODE)); // call far eax
+        assert_eq!(decode(&[0xFF, 0xF8]), Err(INVALID_OPCODE)); // ff /7
+        assert_eq!(decode(&[0x0F, 0xB2, 0xC1]), Err(INVALID_OPCODE)); // lss eax, ecx
+        assert_eq!(decode(&[0x0F, 0x0B]), Err(INVALID_OPCODE)); // ud2
+        assert_eq!(decode(&[0x8D, 0x41, 0x04]), Ok(3)); // lea eax, [ecx+4]
+    }
+
+    #[test]
+    fn lock_needs_a_lockable_memory_destination() {
+        assert_eq!(decode(&[0xF0, 0x01, 0x08]), Ok(3)); // lock add [eax], ecx
+        assert_eq!(decode(&[0xF0, 0x0F, 0xB1, 0x08]), Ok(4)); // lock cmpxchg [eax], ecx
+        assert_eq!(decode(&[0xF0, 0x01, 0xC8]), Err(INVALID_OPCODE)); // lock add eax, ecx
+        assert_eq!(decode(&[0xF0, 0x39, 0x08]), Err(INVALID_OPCODE)); // lock cmp [eax], ecx
+        assert_eq!(decode(&[0xF0, 0x8B, 0x08]), Err(INVALID_OPCODE)); // lock mov ecx, [eax]
+        assert_eq!(decode(&[0xF0, 0xC3]), Err(INVALID_OPCODE)); // lock ret
+    }
+
+    #[test]
+    fn test_register_moves_are_undefined_from_pentium() {
+        let code = [0x0F, 0x24, 0xF0]; // mov eax, tr6
+        assert_eq!(decode_on(CpuModel::I80486, &code), Ok(3));
+        assert_eq!(decode_on(CpuModel::Pentium, &code), Err(INVALID_OPCODE));
+    }
+
+    #[test]
+    fn decode_errors_are_raised_into_the_guest() {
+        let mut emu = emulator(&[0xF0, 0x01, 0xC8]); // lock add eax, ecx
+        install_flat_segments(&mut emu);
+        install_idt(&mut emu, 32);
+        set_interrupt_gate(&mut emu, 6, 0x7e00);
+        run(&mut emu, 1);
+        assert_eq!(emu.eip, 0x7e00);
+        assert_eq!(read32(&emu, STACK - 12), 0x7c00);
+
+        let mut code = vec![0x3E; 15];
+        code.push(0xC3);
+        let mut emu = protected_mode_emulator(&code, 13);
+        run(&mut emu, 1);
+        assert_eq!(emu.eip, 0x7e00);
+        // エラーコード0と、プレフィックスの先頭を指すEIP
+        assert_eq!(read32(&emu, STACK - 16), 0);
+        assert_eq!(read32(&emu, STACK - 12), 0x7c00);
+    }
+}
@@ -0,0 +1,203 @@
+use emulator::emulator_function::EmulatorFunction;
+use emulator::interrupt::{invalid_opcode, Interrupt};
+use emulator::modrm::{Function as ModRMFunction, ModRM};
+use emulator::segment::{Segment, SegmentDescriptor, CALL_GATE_32, LDT, TASK_GATE, TSS_AVAILABLE_32, TSS_BUSY_32};
+use emulator::Emulator;
+
+// 16ビットTSSと16ビットコールゲートのタイプ
+const TSS_AVAILABLE_16: u32 = 0x1;
+const TSS_BUSY_16: u32 = 0x3;
+const CALL_GATE_16: u32 = 0x4;
+
+// LARでアクセス権を読み出せるシステムディスクリプタ
+const LAR_SYSTEM_TYPES: [u32; 8] = [
+    TSS_AVAILABLE_16,
+    LDT,
+    TSS_BUSY_16,
+    CALL_GATE_16,
+    TASK_GATE,
+    TSS_AVAILABLE_32,
+    TSS_BUSY_32,
+    CALL_GATE_32,
+];
+// LSLでリミットを読み出せるシステムディスクリプタ
+const LSL_SYSTEM_TYPES: [u32; 5] = [TSS_AVAILABLE_16, LDT, TSS_BUSY_16, TSS_AVAILABLE_32, TSS_BUSY_32];
+
+pub trait DescriptorQuery {
+    fn lar(&mut self);
+    fn lsl(&mut self);
+    fn verr(&mut self, modrm: &ModRM);
+    fn verw(&mut self, modrm: &ModRM);
+    fn arpl(&mut self);
+}
+
+impl Emulator {
+    // 現在の特権レベルとRPLから参照できるディスクリプタを読む(参照できなければNone)
+    fn read_visible_descriptor(&mut self, selector: u16) -> Option<SegmentDescriptor> {
+        if selector & 0xFFFC == 0 {
+            return None;
+        }
+        let descriptor = self.read_descriptor(selector).ok()?;
+        let rpl = (selector & 3) as u8;
+        // コンフォーミングコードセグメントは特権レベルを確認しない
+        if !descriptor.is_conforming() && (descriptor.dpl() < self.cpl() || descriptor.dpl() < rpl) {
+            return None;
+        }
+        Some(descriptor)
+    }
+
+    // LAR/LSLの共通部分: 読み出した値があればZFを立ててレジスタへ格納する
+    fn load_descriptor_field<F: Fn(&SegmentDescriptor) -> Option<u32>>(&mut self, field: F) {
+        // リアルモードとV86モードでは使えない
+        if self.uses_real_mode_segments() {
+            self.raise_exception(invalid_opcode());
+            return;
+        }
+        self.eip += 2;
+        let modrm = self.parse_modrm();
+        let selector = self.get_rm16(&modrm);
+        let value = self.read_visible_descriptor(selector).and_then(|descriptor| field(&descriptor));
+        self.set_zero(value.is_some());
+        if let Some(value) = value {
+            if self.is_operand_size16() {
+                self.set_r16(&modrm, value as u16);
+            } else {
+                self.set_r32(&modrm, value);
+            }
+        }
+    }
+}
+
+impl DescriptorQuery for Emulator {
+    fn lar(&mut self) {
+        self.load_descriptor_field(|descriptor| {
+            if descriptor.is_system() && !LAR_SYSTEM_TYPES.contains(&descriptor.descriptor_type()) {
+                None
+            } else {
+                Some(descriptor.high & 0x00F0_FF00)
+            }
+        });
+    }
+
+    fn lsl(&mut self) {
+        self.load_descriptor_field(|descriptor| {
+            if descriptor.is_system() && !LSL_SYSTEM_TYPES.contains(&descriptor.descriptor_type()) {
+                None
+            } else {
+                Some(descriptor.limit())
+            }
+        });
+    }
+
+    fn verr(&mut self, modrm: &ModRM) {
+        let selector = self.get_rm16(modrm);
+        let readable = self.read_visible_descriptor(selector).is_some_and(|descriptor| descriptor.is_readable());
+        self.set_zero(readable);
+    }
+
+    fn verw(&mut self, modrm: &ModRM) {
+        let selector = self.get_rm16(modrm);
+        let writable = self
+            .read_visible_descriptor(selector)
+            .is_some_and(|descriptor| descriptor.is_writable_data());
+        self.set_zero(writable);
+    }
+
+    fn arpl(&mut self) {
+        if self.uses_real_mode_segments() {
+            self.raise_exception(invalid_opcode());
+            return;
+        }
+        self.eip += 1;
+        let modrm = self.parse_modrm();
+        let destination = self.get_rm16(&modrm);
+        let source = self.get_r16(&modrm);
+        // 書き込み先のRPLが小さければ引き上げる
+        if destination & 3 < source & 3 {
+            self.set_rm16(&modrm, (destination & !3) | (source & 3));
+            self.set_zero(true);
+        } else {
+            self.set_zero(false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use emulator::emulator_function::EmulatorFunction;
+    use emulator::testing::*;
+    // selectorを指定した命令(0F 02/03 C8やVERR/VERW)を実行して、ZFとECXを返す
+    fn query(code: &[u8], selector: u16, ring3: bool) -> (bool, u32) {
+        let mut program = vec![0x66, 0xB8, selector as u8, (selector >> 8) as u8]; // mov ax, selector
+        program.extend_from_slice(code);
+        let mut emu = query_emulator(&program);
+        if ring3 {
+            enter_ring3(&mut emu);
+        }
+        emu.registers[1] = 0xFFFF_FFFF;
+        emu.set_zero(true);
+        run(&mut emu, 2);
+        (emu.is_zero(), emu.get_register32(1))
+    }
+
+    const LAR: [u8; 3] = [0x0F, 0x02, 0xC8]; // lar ecx, ax
+    const LSL: [u8; 3] = [0x0F, 0x03, 0xC8]; // lsl ecx, ax
+    const VERR: [u8; 3] = [0x0F, 0x00, 0xE0]; // verr ax
+    const VERW: [u8; 3] = [0x0F, 0x00, 0xE8]; // verw ax
+
+    #[test]
+    fn lar_and_lsl_read_segment_descriptors() {
+        assert_eq!(query(&LAR, 0x10, false), (true, 0x00C0_9200));
+        assert_eq!(query(&LSL, 0x10, false), (true, 0xFFFF_FFFF));
+        assert_eq!(query(&LAR, 0x38, false), (true, 0x0040_9000));
+        assert_eq!(query(&LSL, 0x38, false), (true, 0xFFF));
+    }
+
+    #[test]
+    fn lar_and_lsl_accept_only_some_system_descriptors() {
+        assert_eq!(query(&LAR, 0x28, false), (true, 0x0000_8900));
+        assert_eq!(query(&LSL, 0x28, false), (true, 0x67));
+        // 割り込みゲートはどちらでも読めない
+        assert_eq!(query(&LAR, 0x40, false), (false, 0xFFFF_FFFF));
+        assert_eq!(query(&LSL, 0x40, false), (false, 0xFFFF_FFFF));
+    }
+
+    #[test]
+    fn descriptor_queries_fail_without_privilege() {
+        // ヌルセレクタとGDTの外のセレクタ
+        assert_eq!(query(&LAR, 0x00, false), (false, 0xFFFF_FFFF));
+        assert_eq!(query(&LAR, 0x80, false), (false, 0xFFFF_FFFF));
+        // リング3からDPL0のディスクリプタは見えない(RPLを上げても同じ)
+        assert_eq!(query(&LAR, 0x10, true), (false, 0xFFFF_FFFF));
+        assert!(query(&LAR, 0x23, true).0);
+        assert_eq!(query(&LAR, 0x13, false), (false, 0xFFFF_FFFF));
+    }
+
+    #[test]
+    fn verr_and_verw_check_readable_and_writable_segments() {
+        assert!(query(&VERR, 0x08, false).0);
+        assert!(query(&VERR, 0x10, false).0);
+        assert!(!query(&VERR, 0x30, false).0);
+        assert!(!query(&VERR, 0x28, false).0);
+        assert!(query(&VERW, 0x10, false).0);
+        assert!(!query(&VERW, 0x38, false).0);
+        assert!(!query(&VERW, 0x08, false).0);
+        assert!(!query(&VERW, 0x10, true).0);
+    }
+
+    #[test]
+    fn arpl_raises_the_destination_rpl() {
+        let mut emu = query_emulator(&[
+            0x63, 0xC8, // arpl ax, cx
+            0x63, 0xC8, // arpl ax, cx
+        ]);
+        emu.registers[0] = 0x10;
+        emu.registers[1] = 0x1B;
+        run(&mut emu, 1);
+        assert_eq!(emu.registers[0], 0x13);
+        assert!(emu.is_zero());
+        run(&mut emu, 1);
+        assert_eq!(emu.registers[0], 0x13);
+        assert!(!emu.is_zero());
+    }
+}
@@ -1,29 +1,71 @@
 pub trait EmulatorFunction {
     const CARRY_FLAG: u32;
+    const FIXED_FLAGS: u32;
+    const PARITY_FLAG: u32;
+    const ADJUST_FLAG: u32;
     const ZERO_FLAG: u32;
     const SIGN_FLAG: u32;
+    const TRAP_FLAG: u32;
+    const INTERRUPT_FLAG: u32;
+    const DIRECTION_FLAG: u32;
     const OVERFLOW_FLAG: u32;
-    fn get_code8(&self, index: i32) -> u8;
-    fn get_sign_code8(&self, index: i32) -> i8;
-    fn get_code32(&self, index: i32) -> u32;
-    fn get_sign_code32(&self, index: i32) -> i32;
+    const IOPL_MASK: u32;
+    const NESTED_TASK_FLAG: u32;
+    const RESUME_FLAG: u32;
+    const VIRTUAL_8086_FLAG: u32;
+    const ALIGNMENT_CHECK_FLAG: u32;
+    const VIRTUAL_INTERRUPT_FLAG: u32;
+    const VIRTUAL_INTERRUPT_PENDING: u32;
+    const DEFINED_FLAGS: u32;
+    fn get_code8(&mut self, index: i32) -> u8;
+    fn get_sign_code8(&mut self, index: i32) -> i8;
+    fn get_code16(&mut self, index: i32) -> u16;
+    fn get_code32(&mut self, index: i32) -> u32;
+    fn get_sign_code32(&mut self, index: i32) -> i32;
     fn get_memory8(&mut self, address: usize) -> u32;
+    fn get_memory16(&mut self, address: usize) -> u32;
     fn get_memory32(&mut self, address: usize) -> u32;
+    fn get_memory64(&mut self, address: usize) -> u64;
     fn set_memory8(&mut self, address: usize, value: u32);
+    fn set_memory16(&mut self, address: usize, value: u32);
     fn set_memory32(&mut self, address: usize, value: u32);
+    fn set_memory64(&mut self, address: usize, value: u64);
     fn get_register8(&self, usize) -> u8;
+    fn get_register16(&self, index: usize) -> u16;
     fn get_register32(&self, usize) -> u32;
+    fn get_register64(&self, index: usize) -> u64;
     fn set_register8(&mut self, index: usize, value: u8);
+    fn set_register16(&mut self, index: usize, value: u16);
     fn set_register32(&mut self, index: usize, value: u32);
+    fn set_register64(&mut self, index: usize, value: u64);
+    fn push16(&mut self, value: u16);
     fn push32(&mut self, u32);
+    fn push64(&mut self, value: u64);
+    fn pop16(&mut self) -> u16;
     fn pop32(&mut self) -> u32;
+    fn pop64(&mut self) -> u64;
+    fn is_virtual_8086(&self) -> bool;
+    fn is_real_mode(&self) -> bool;
+    fn uses_real_mode_segments(&self) -> bool;
+    fn is_64bit_mode(&self) -> bool;
+    fn is_operand_size16(&self) -> bool;
+    fn is_operand_size64(&self) -> bool;
+    fn is_address_size16(&self) -> bool;
+    fn is_address_size64(&self) -> bool;
+    fn segment_base(&self, index: usize) -> u32;
+    fn linear_address(&mut self, index: usize, offset: u64, size: u32) -> u64;
+    fn set_flag(&mut self, flag: u32, value: bool);
+    fn set_eflags(&mut self, value: u32);
     fn is_carry(&self) -> bool;
     fn is_zero(&self) -> bool;
     fn is_sign(&self) -> bool;
     fn is_overflow(&self) -> bool;
+    fn get_iopl(&self) -> u8;
     fn set_carry(&mut self, bool);
     fn set_sign(&mut self, bool);
     fn set_zero(&mut self, bool);
     fn set_overflow(&mut self, bool);
     fn update_eflags_sub(&mut self, v1: u32, v2: u32, result: u64);
+    fn update_eflags_sub16(&mut self, v1: u16, v2: u16, result: u32);
+    fn update_eflags_sub64(&mut self, v1: u64, v2: u64, result: u128);
 }
@@ -0,0 +1,238 @@
+use emulator::emulator_function::EmulatorFunction;
+use emulator::interrupt::{general_protection, invalid_opcode, Interrupt};
+use emulator::msr::EFER_SCE;
+use emulator::segment::{Segment, SegmentCache, FLAT_CODE_SEGMENT, FLAT_DATA_SEGMENT};
+use emulator::{Emulator, Register, SegmentRegister};
+
+pub trait FastSystemCall {
+    fn sysenter(&mut self);
+    fn sysexit(&mut self);
+    fn syscall(&mut self);
+    fn sysret(&mut self);
+}
+
+impl Emulator {
+    // GDTを参照せずにフラットなCS/SSのセレクタを読み込む
+    fn load_flat_segments(&mut self, cs: u16, ss: u16) {
+        self.segment_registers[SegmentRegister::CS as usize] = cs;
+        self.segment_registers[SegmentRegister::SS as usize] = ss;
+        // キャッシュにはCPLに合わせたDPLのフラットなセグメントを設定する
+        let dpl = ((cs & 3) as u32) << 13;
+        self.segment_caches[SegmentRegister::CS as usize] = SegmentCache {
+            attributes: FLAT_CODE_SEGMENT.attributes | dpl,
+            ..FLAT_CODE_SEGMENT
+        };
+        self.segment_caches[SegmentRegister::SS as usize] = SegmentCache {
+            attributes: FLAT_DATA_SEGMENT.attributes | dpl,
+            ..FLAT_DATA_SEGMENT
+        };
+    }
+}
+
+impl FastSystemCall for Emulator {
+    fn sysenter(&mut self) {
+        // SYSENTER_CSが未設定のときとリアルモードでは#GP(0)になる
+        let cs = self.msr.sysenter_cs as u16 & 0xFFFC;
+        if cs == 0 || self.is_real_mode() {
+            self.raise_exception(general_protection(0));
+            return;
+        }
+        // V86モードから呼んだときもリング0の保護モードへ入る
+        self.eflags &= !(Self::VIRTUAL_8086_FLAG | Self::INTERRUPT_FLAG | Self::RESUME_FLAG);
+        self.load_flat_segments(cs, cs.wrapping_add(8));
+        self.set_register32(Register::ESP as usize, self.msr.sysenter_esp as u32);
+        self.eip = self.msr.sysenter_eip & 0xFFFF_FFFF;
+    }
+
+    fn sysexit(&mut self) {
+        let cs = self.msr.sysenter_cs as u16 & 0xFFFC;
+        if cs == 0 || self.is_real_mode() || self.cpl() != 0 {
+            self.raise_exception(general_protection(0));
+            return;
+        }
+        self.load_flat_segments(cs.wrapping_add(16) | 3, cs.wrapping_add(24) | 3);
+        self.registers[Register::ESP as usize] = self.registers[Register::ECX as usize];
+        self.eip = self.registers[Register::EDX as usize];
+    }
+
+    fn syscall(&mut self) {
+        if self.msr.efer & EFER_SCE == 0 {
+            self.raise_exception(invalid_opcode());
+            return;
+        }
+        let cs = (self.msr.star >> 32) as u16 & 0xFFFC;
+        self.registers[Register::ECX as usize] = self.eip.wrapping_add(2);
+        self.eflags &= !(Self::VIRTUAL_8086_FLAG | Self::INTERRUPT_FLAG | Self::RESUME_FLAG);
+        self.load_flat_segments(cs, cs.wrapping_add(8));
+        self.eip = self.msr.star & 0xFFFF_FFFF;
+    }
+
+    fn sysret(&mut self) {
+        if self.msr.efer & EFER_SCE == 0 {
+            self.raise_exception(invalid_opcode());
+            return;
+        }
+        if self.cpl() != 0 {
+            self.raise_exception(general_protection(0));
+            return;
+        }
+        let cs = (self.msr.star >> 48) as u16;
+        self.eflags |= Self::INTERRUPT_FLAG;
+        self.load_flat_segments(cs | 3, cs.wrapping_add(8) | 3);
+        self.eip = self.registers[Register::ECX as usize];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use emulator::emulator_function::EmulatorFunction;
+    use emulator::msr::EFER_SCE;
+    use emulator::segment::Segment;
+    use emulator::testing::*;
+    use emulator::{Emulator, SegmentRegister};
+
+    fn selectors(emu: &Emulator) -> (u16, u16) {
+        (emu.segment_registers[SegmentRegister::CS as usize], emu.segment_registers[SegmentRegister::SS as usize])
+    }
+
+    #[test]
+    fn sysenter_enters_ring0_from_msrs() {
+        let mut emu = emulator(&[
+            0x0F, 0x34, // sysenter
+        ]);
+        install_flat_segments(&mut emu);
+        enter_ring3(&mut emu);
+        emu.eflags |= Emulator::INTERRUPT_FLAG;
+        emu.msr.sysenter_cs = 0x08;
+        emu.msr.sysenter_esp = 0x5000;
+        emu.msr.sysenter_eip = 0x7d00;
+        run(&mut emu, 1);
+        assert_eq!(emu.eip, 0x7d00);
+        assert_eq!(emu.registers[4], 0x5000);
+        assert_eq!(selectors(&emu), (0x08, 0x10));
+        assert_eq!(emu.cpl(), 0);
+        assert_eq!(emu.eflags & Emulator::INTERRUPT_FLAG, 0);
+    }
+
+    #[test]
+    fn sysexit_returns_to_ring3_at_edx_with_ecx_stack() {
+        let mut emu = emulator(&[
+            0x0F, 0x35, // sysexit
+        ]);
+        install_flat_segments(&mut emu);
+        emu.msr.sysenter_cs = 0x08;
+        emu.registers[1] = 0x6000;
+        emu.registers[2] = 0x7d00;
+        run(&mut emu, 1);
+        assert_eq!(emu.eip, 0x7d00);
+        assert_eq!(emu.registers[4], 0x6000);
+        assert_eq!(selectors(&emu), (0x1B, 0x23));
+        assert_eq!(emu.cpl(), 3);
+    }
+
+    #[test]
+    fn selectors_derived_from_msrs_wrap_at_16_bits() {
+        let mut emu = emulator(&[
+            0x0F, 0x34, // sysenter
+            0x0F, 0x35, // sysexit
+        ]);
+        install_flat_segments(&mut emu);
+        emu.msr.sysenter_cs = 0xFFF8;
+        emu.registers[2] = 0x7c02;
+        run(&mut emu, 1);
+        assert_eq!(selectors(&emu), (0xFFF8, 0x0000));
+        emu.eip = 0x7c02;
+        run(&mut emu, 1);
+        assert_eq!(selectors(&emu), (0x000B, 0x0013));
+    }
+
+    #[test]
+    fn sysenter_without_sysenter_cs_raises_general_protection() {
+        let mut emu = protected_mode_emulator(
+            &[
+                0x0F, 0x34, // sysenter
+            ],
+            13,
+        );
+        run(&mut emu, 1);
+        assert_eq!(emu.eip, 0x7e00);
+        assert_eq!(read32(&emu, emu.get_register32(4) + 4), 0x7c00);
+    }
+
+    #[test]
+    fn syscall_and_sysret_use_star_selectors() {
+        let mut emu = emulator(&[
+            0x0F, 0x05, // syscall
+        ]);
+        emu.bus.load(0x7d00, &[0x0F, 0x07]); // sysret
+        install_flat_segments(&mut emu);
+        enter_ring3(&mut emu);
+        emu.msr.efer = EFER_SCE;
+        emu.msr.star = (0x18 << 48) | (0x08 << 32) | 0x7d00;
+        run(&mut emu, 1);
+        assert_eq!(emu.eip, 0x7d00);
+        assert_eq!(emu.registers[1], 0x7c02);
+        assert_eq!(selectors(&emu), (0x08, 0x10));
+
+        run(&mut emu, 1);
+        assert_eq!(emu.eip, 0x7c02);
+        assert_eq!(selectors(&emu), (0x1B, 0x23));
+        assert_ne!(emu.eflags & Emulator::INTERRUPT_FLAG, 0);
+    }
+
+    #[test]
+    fn syscall_without_efer_sce_raises_invalid_opcode() {
+        let mut emu = protected_mode_emulator(
+            &[
+                0x0F, 0x05, // syscall
+            ],
+            6,
+        );
+        run(&mut emu, 1);
+        assert_eq!(emu.eip, 0x7e00);
+        assert_eq!(read32(&emu, emu.get_register32(4)), 0x7c00);
+    }
+
+    #[test]
+    fn sysenter_and_syscall_leave_virtual_8086_mode() {
+        let mut emu = virtual_8086_emulator(
+            &[
+                0x0F, 0x34, // sysenter
+            ],
+            0,
+        );
+        emu.msr.sysenter_cs = 0x08;
+        emu.msr.sysenter_eip = 0x7d00;
+        run(&mut emu, 1);
+        assert!(!emu.is_virtual_8086());
+        assert_eq!(emu.eip, 0x7d00);
+        assert_eq!(selectors(&emu), (0x08, 0x10));
+        assert_eq!(emu.cpl(), 0);
+
+        let mut emu = virtual_8086_emulator(
+            &[
+                0x0F, 0x05, // syscall
+            ],
+            0,
+        );
+        emu.msr.efer = EFER_SCE;
+        emu.msr.star = (0x08 << 32) | 0x7d00;
+        run(&mut emu, 1);
+        assert!(!emu.is_virtual_8086());
+        assert_eq!(emu.eip, 0x7d00);
+        assert_eq!(emu.cpl(), 0);
+    }
+
+    #[test]
+    fn sysenter_and_sysexit_raise_general_protection_in_real_mode() {
+        for &opcode in [0x34, 0x35].iter() {
+            let mut emu = real_mode_emulator(&[0x0F, opcode], 13);
+            emu.msr.sysenter_cs = 0x08;
+            run(&mut emu, 1);
+            assert_eq!(emu.eip, 0x7e00);
+            assert_eq!(emu.control_registers[0], 0);
+            // 戻り先のIPは命令の先頭
+            assert_eq!(read16(&emu, STACK - 6), 0x7c00);
+        }
+    }
+}
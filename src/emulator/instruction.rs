@@ -1,14 +1,26 @@
 use emulator::modrm::ModRM;
+use emulator::stop::{EmuError, StopReason};
 
 pub trait Instruction {
-    fn run_instructions(&mut self, bool);
-    fn exec_instruction(&mut self, bool);
+    fn run_instructions(&mut self, quiet: bool, budget: Option<u64>) -> Result<StopReason, EmuError>;
+    fn exec_instruction(&mut self, quiet: bool) -> Result<(), EmuError>;
+    fn code_0f(&mut self);
+    fn code_0f_00(&mut self);
+    fn code_0f_01(&mut self);
+    fn code_0f_38(&mut self);
+    fn code_0f_3a(&mut self);
+    fn code_0f_c7(&mut self);
 
     fn mov_r32_imm32(&mut self);
     fn move_rm32_imm32(&mut self);
     fn mov_rm32_r32(&mut self);
     fn mov_r8_rm8(&mut self);
     fn mov_r32_rm32(&mut self);
+    fn lea(&mut self);
+    fn mov_al_moffs8(&mut self);
+    fn mov_eax_moffs32(&mut self);
+    fn mov_moffs8_al(&mut self);
+    fn mov_moffs32_eax(&mut self);
     fn mov_r8_imm8(&mut self);
     fn add_rm32_r32(&mut self);
     fn cmp_r32_rm32(&mut self);
@@ -26,13 +38,39 @@ pub trait Instruction {
     fn pop_r32(&mut self);
     fn call_rel32(&mut self);
     fn ret(&mut self);
+    fn pushfd(&mut self);
+    fn popfd(&mut self);
     fn leave(&mut self);
     fn push_imm8(&mut self);
     fn push_imm32(&mut self);
     fn short_jump(&mut self);
     fn near_jump(&mut self);
     fn in_al_dx(&mut self);
+    fn in_eax_dx(&mut self);
+    fn in_al_imm8(&mut self);
+    fn in_eax_imm8(&mut self);
     fn out_dx_al(&mut self);
+    fn out_dx_eax(&mut self);
+    fn out_imm8_al(&mut self);
+    fn out_imm8_eax(&mut self);
+    fn ins_m8_dx(&mut self);
+    fn ins_m32_dx(&mut self);
+    fn outs_dx_m8(&mut self);
+    fn outs_dx_m32(&mut self);
+    fn cli(&mut self);
+    fn sti(&mut self);
+    fn clc(&mut self);
+    fn stc(&mut self);
+    fn hlt(&mut self);
+    fn cmc(&mut self);
+    fn cld(&mut self);
+    fn std(&mut self);
+    fn lahf(&mut self);
+    fn sahf(&mut self);
+    fn xlat(&mut self);
+    fn salc(&mut self);
+    fn cbw(&mut self);
+    fn cwd(&mut self);
     fn jo(&mut self);
     fn jno(&mut self);
     fn jc(&mut self);
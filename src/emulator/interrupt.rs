@@ -0,0 +1,485 @@
+use emulator::emulator_function::EmulatorFunction;
+use emulator::long_mode::LongMode;
+use emulator::modrm::ModRM;
+use emulator::msr::EFER_LMA;
+use emulator::segment::{selector_error, Segment, SegmentDescriptor, INTERRUPT_GATE_32, TASK_GATE, TRAP_GATE_32};
+use emulator::stop::StopReason;
+use emulator::task::{Task, TaskSwitchReason};
+use emulator::virtual8086::Virtual8086;
+use emulator::{Emulator, Register, SegmentRegister};
+
+// 例外・割り込みのベクタ番号
+pub const DIVIDE_ERROR: u8 = 0;
+pub const DEBUG_EXCEPTION: u8 = 1;
+pub const BREAKPOINT: u8 = 3;
+pub const INVALID_OPCODE: u8 = 6;
+pub const DEVICE_NOT_AVAILABLE: u8 = 7;
+pub const DOUBLE_FAULT: u8 = 8;
+pub const INVALID_TSS: u8 = 10;
+pub const SEGMENT_NOT_PRESENT: u8 = 11;
+pub const STACK_FAULT: u8 = 12;
+pub const GENERAL_PROTECTION: u8 = 13;
+pub const PAGE_FAULT: u8 = 14;
+pub const ALIGNMENT_CHECK: u8 = 17;
+
+// IDTR/GDTRのようなディスクリプタテーブルレジスタ(ロングモードではベースが64ビットになる)
+#[derive(Clone, Copy, Default)]
+pub struct DescriptorTableRegister {
+    pub base: u64,
+    pub limit: u16,
+}
+
+// 命令の実行中に検出した例外
+#[derive(Clone, Copy, Debug)]
+pub struct Exception {
+    pub vector: u8,
+    pub error_code: Option<u32>,
+}
+
+pub fn invalid_opcode() -> Exception {
+    Exception {
+        vector: INVALID_OPCODE,
+        error_code: None,
+    }
+}
+
+pub fn device_not_available() -> Exception {
+    Exception {
+        vector: DEVICE_NOT_AVAILABLE,
+        error_code: None,
+    }
+}
+
+pub fn general_protection(error_code: u32) -> Exception {
+    Exception {
+        vector: GENERAL_PROTECTION,
+        error_code: Some(error_code),
+    }
+}
+
+pub fn invalid_tss(error_code: u32) -> Exception {
+    Exception {
+        vector: INVALID_TSS,
+        error_code: Some(error_code),
+    }
+}
+
+pub fn segment_not_present(error_code: u32) -> Exception {
+    Exception {
+        vector: SEGMENT_NOT_PRESENT,
+        error_code: Some(error_code),
+    }
+}
+
+pub fn alignment_check() -> Exception {
+    Exception {
+        vector: ALIGNMENT_CHECK,
+        error_code: Some(0),
+    }
+}
+
+pub fn stack_fault(error_code: u32) -> Exception {
+    Exception {
+        vector: STACK_FAULT,
+        error_code: Some(error_code),
+    }
+}
+
+pub fn page_fault(error_code: u32) -> Exception {
+    Exception {
+        vector: PAGE_FAULT,
+        error_code: Some(error_code),
+    }
+}
+
+// 2つ続けて発生するとダブルフォールトになる例外
+fn is_contributory(vector: u8) -> bool {
+    matches!(vector, DIVIDE_ERROR | INVALID_TSS | SEGMENT_NOT_PRESENT | STACK_FAULT | GENERAL_PROTECTION)
+}
+
+// firstを配送している途中でsecondが起きたらダブルフォールトになるか
+// #PFの配送中は#PFでもダブルフォールトになる
+fn is_double_fault(first: u8, second: u8) -> bool {
+    match first {
+        PAGE_FAULT => second == PAGE_FAULT || is_contributory(second),
+        _ => is_contributory(first) && is_contributory(second),
+    }
+}
+
+impl Emulator {
+    // リアルモードではIDTRの指す割り込みベクタテーブル(4バイトのCS:IP)からハンドラを探す
+    fn real_mode_interrupt(&mut self, vector: u8) -> Result<(), Exception> {
+        let offset = vector as u32 * 4;
+        if offset + 3 > self.idtr.limit as u32 {
+            return Err(general_protection(0));
+        }
+        let address = self.descriptor_table_address(self.idtr.base, offset);
+        let ip = self.get_memory16(address);
+        let cs = self.get_memory16(address + 2) as u16;
+
+        // エラーコードは積まない
+        let flags = self.eflags as u16;
+        let old_cs = self.segment_registers[SegmentRegister::CS as usize];
+        let old_ip = self.eip as u16;
+        self.push16(flags);
+        self.push16(old_cs);
+        self.push16(old_ip);
+
+        self.eflags &= !(Self::INTERRUPT_FLAG | Self::TRAP_FLAG | Self::RESUME_FLAG);
+        self.set_segment_register(SegmentRegister::CS as usize, cs);
+        self.eip = ip as u64;
+        self.interrupt_delivered = true;
+        Ok(())
+    }
+
+    fn real_mode_interrupt_return(&mut self) {
+        let (eip, cs, flags, mask) = if self.is_operand_size16() {
+            let eip = self.pop16() as u32;
+            let cs = self.pop16();
+            (eip, cs, self.pop16() as u32, 0xffff)
+        } else {
+            // VM, VIF, VIPはリアルモードのIRETDでは変わらない
+            let eip = self.pop32();
+            let cs = self.pop32() as u16;
+            let mask = !(Self::VIRTUAL_8086_FLAG | Self::VIRTUAL_INTERRUPT_FLAG | Self::VIRTUAL_INTERRUPT_PENDING);
+            (eip, cs, self.pop32(), mask)
+        };
+        self.set_eflags((self.eflags & !mask) | (flags & mask));
+        self.set_segment_register(SegmentRegister::CS as usize, cs);
+        self.eip = eip as u64;
+    }
+}
+
+pub trait Interrupt {
+    fn deliver_interrupt(&mut self, vector: u8, error_code: Option<u32>, software: bool) -> Result<(), Exception>;
+    fn deliver_exception(&mut self, exception: Exception);
+    fn raise_exception(&mut self, exception: Exception);
+    fn raise_interrupt(&mut self, vector: u8);
+    fn raise_software_interrupt(&mut self, vector: u8);
+    fn int3(&mut self);
+    fn int_imm8(&mut self);
+    fn iret(&mut self);
+    fn interrupt_return(&mut self) -> Result<(), Exception>;
+    fn lidt(&mut self, modrm: &ModRM);
+}
+
+impl Interrupt for Emulator {
+    fn deliver_interrupt(&mut self, vector: u8, error_code: Option<u32>, software: bool) -> Result<(), Exception> {
+        if self.is_real_mode() {
+            return self.real_mode_interrupt(vector);
+        }
+        if self.msr.efer & EFER_LMA != 0 {
+            return self.deliver_long_mode_interrupt(vector, error_code, software);
+        }
+        // 外部要因による割り込みはエラーコードのEXTビットを立てる
+        let ext = if software { 0 } else { 1 };
+        let idt_error = vector as u32 * 8 + 2 + ext;
+        let offset = vector as u32 * 8;
+        if offset + 7 > self.idtr.limit as u32 {
+            return Err(general_protection(idt_error));
+        }
+
+        // ゲートディスクリプタを読み込む
+        let address = self.descriptor_table_address(self.idtr.base, offset);
+        let gate = self.supervisor_access(|emu| SegmentDescriptor {
+            low: emu.get_memory32(address),
+            high: emu.get_memory32(address + 4),
+        });
+        let gate_type = gate.descriptor_type();
+        if !gate.is_system() || (gate_type != INTERRUPT_GATE_32 && gate_type != TRAP_GATE_32 && gate_type != TASK_GATE) {
+            return Err(general_protection(idt_error));
+        }
+        let cpl = self.cpl();
+        if software && cpl > gate.dpl() {
+            return Err(general_protection(idt_error));
+        }
+        if !gate.is_present() {
+            return Err(segment_not_present(idt_error));
+        }
+
+        if gate_type == TASK_GATE {
+            self.switch_task(gate.gate_selector(), TaskSwitchReason::Call, ext)?;
+            if let Some(code) = error_code {
+                self.push32(code);
+            }
+            self.interrupt_delivered = true;
+            return Ok(());
+        }
+
+        // 割り込みハンドラのコードセグメントを確認する
+        let selector = gate.gate_selector();
+        if selector & 0xFFFC == 0 {
+            return Err(general_protection(ext));
+        }
+        let code = self
+            .read_descriptor(selector)
+            .map_err(|_| general_protection(selector_error(selector) + ext))?;
+        if !code.is_code() || code.dpl() > cpl {
+            return Err(general_protection(selector_error(selector) + ext));
+        }
+        if !code.is_present() {
+            return Err(segment_not_present(selector_error(selector) + ext));
+        }
+
+        let eflags = self.eflags;
+        let cs = self.segment_registers[SegmentRegister::CS as usize];
+        let eip = self.eip as u32;
+        let new_cpl = if self.is_virtual_8086() {
+            // V86モードからはリング0の非コンフォーミングなコードセグメントにしか入れない
+            if code.is_conforming() || code.dpl() != 0 {
+                return Err(general_protection(selector_error(selector) + ext));
+            }
+            let (ss, esp) = self.tss_stack(0)?;
+            self.check_stack_segment(ss, 0).map_err(|_| invalid_tss(selector_error(ss) + ext))?;
+            self.leave_virtual_8086(ss, esp);
+            0
+        } else if !code.is_conforming() && code.dpl() < cpl {
+            // 特権レベルが上がる場合はTSSからスタックを切り替える
+            let (ss, esp) = self.tss_stack(code.dpl())?;
+            self.check_stack_segment(ss, code.dpl())
+                .map_err(|_| invalid_tss(selector_error(ss) + ext))?;
+            let old_ss = self.segment_registers[SegmentRegister::SS as usize];
+            let old_esp = self.get_register32(Register::ESP as usize);
+            self.set_segment_register(SegmentRegister::SS as usize, ss);
+            self.set_register32(Register::ESP as usize, esp);
+            self.push32(old_ss as u32);
+            self.push32(old_esp);
+            code.dpl()
+        } else {
+            cpl
+        };
+
+        // EFLAGS, CS, EIP(, エラーコード)の順にスタックへ積んでハンドラへ飛ぶ
+        self.push32(eflags);
+        self.push32(cs as u32);
+        self.push32(eip);
+        if let Some(code) = error_code {
+            self.push32(code);
+        }
+
+        self.eflags &= !(Self::TRAP_FLAG | Self::NESTED_TASK_FLAG | Self::RESUME_FLAG);
+        if gate_type == INTERRUPT_GATE_32 {
+            self.eflags &= !Self::INTERRUPT_FLAG;
+        }
+        self.set_segment_register(SegmentRegister::CS as usize, (selector & 0xFFFC) | new_cpl as u16);
+        self.eip = gate.gate_offset() as u64;
+        self.interrupt_delivered = true;
+        Ok(())
+    }
+
+    fn deliver_exception(&mut self, exception: Exception) {
+        let second = match self.deliver_interrupt(exception.vector, exception.error_code, false) {
+            Ok(()) => return,
+            Err(second) => second,
+        };
+        if exception.vector == DOUBLE_FAULT {
+            self.stop_request = Some(StopReason::Shutdown);
+            return;
+        }
+        if is_double_fault(exception.vector, second.vector) {
+            self.deliver_exception(Exception {
+                vector: DOUBLE_FAULT,
+                error_code: Some(0),
+            });
+        } else {
+            self.deliver_exception(second);
+        }
+    }
+
+    fn raise_exception(&mut self, exception: Exception) {
+        // フォールトは例外を起こした命令から再実行する
+        self.eip = self.instruction_eip;
+        self.pending_debug = 0;
+        self.deliver_exception(exception);
+    }
+
+    fn raise_interrupt(&mut self, vector: u8) {
+        self.deliver_exception(Exception { vector, error_code: None });
+    }
+
+    fn raise_software_interrupt(&mut self, vector: u8) {
+        if let Err(e) = self.deliver_interrupt(vector, None, true) {
+            self.raise_exception(e);
+        }
+    }
+
+    fn int3(&mut self) {
+        self.eip += 1;
+        self.raise_software_interrupt(BREAKPOINT);
+    }
+
+    fn int_imm8(&mut self) {
+        let vector = self.get_code8(1);
+        self.eip += 2;
+        // V86モードのINT nはIOPLとVMEの設定に従ってモニタへトラップする
+        if self.is_virtual_8086() {
+            self.virtual_8086_interrupt(vector);
+        } else {
+            self.raise_software_interrupt(vector);
+        }
+    }
+
+    fn iret(&mut self) {
+        if let Err(e) = self.interrupt_return() {
+            self.raise_exception(e);
+        }
+    }
+
+    fn interrupt_return(&mut self) -> Result<(), Exception> {
+        if self.is_real_mode() {
+            self.real_mode_interrupt_return();
+            return Ok(());
+        }
+        if self.is_virtual_8086() {
+            return self.virtual_8086_iret();
+        }
+        if self.is_64bit_mode() {
+            return self.long_mode_interrupt_return();
+        }
+        // NTが立っていれば呼び出し元のタスクへ戻る
+        if self.eflags & Self::NESTED_TASK_FLAG != 0 {
+            let tss = self.task_register.base as usize;
+            let back_link = self.get_memory16(tss) as u16;
+            return self.switch_task(back_link, TaskSwitchReason::Iret, 0);
+        }
+
+        // オペランドサイズに合わせて16ビットか32ビットのEIP, CS, EFLAGS(, ESP, SS)を読む
+        let size = self.operand_size();
+        let sp = self.get_stack_pointer();
+        let eip = self.read_stack(sp, size);
+        let cs = self.read_stack(sp.wrapping_add(size), size) as u16;
+        let eflags = self.read_stack(sp.wrapping_add(size * 2), size);
+        let cpl = self.cpl();
+        // リング0からVMの立ったEFLAGSへ戻るとV86モードに入る
+        if cpl == 0 && eflags & Self::VIRTUAL_8086_FLAG != 0 {
+            self.enter_virtual_8086(sp, eip, cs, eflags);
+            return Ok(());
+        }
+        self.check_return_code_segment(cs)?;
+
+        let rpl = (cs & 3) as u8;
+        if rpl > cpl {
+            // 外側の特権レベルへ戻る場合はスタックも戻す
+            let new_sp = self.read_stack(sp.wrapping_add(size * 3), size);
+            let new_ss = self.read_stack(sp.wrapping_add(size * 4), size) as u16;
+            self.check_stack_segment(new_ss, rpl)?;
+            self.set_segment_register(SegmentRegister::SS as usize, new_ss);
+            self.set_stack_pointer(new_sp);
+        } else {
+            self.set_stack_pointer(sp.wrapping_add(size * 3));
+        }
+
+        // IOPLはリング0でのみ、IFはCPL<=IOPLのときのみ変更できる
+        // VM, VIF, VIPもリング0以外からは変えられず、16ビットのIRETは下位16ビットだけを戻す
+        let iopl = self.get_iopl();
+        let mut mask = if size == 2 { 0xffff } else { !0 };
+        if cpl != 0 {
+            mask &= !(Self::IOPL_MASK | Self::VIRTUAL_8086_FLAG | Self::VIRTUAL_INTERRUPT_FLAG | Self::VIRTUAL_INTERRUPT_PENDING);
+        }
+        if cpl > iopl {
+            mask &= !Self::INTERRUPT_FLAG;
+        }
+        self.set_eflags((self.eflags & !mask) | (eflags & mask));
+        self.set_segment_register(SegmentRegister::CS as usize, cs);
+        self.eip = eip as u64;
+        if rpl > cpl {
+            self.invalidate_data_segments();
+        }
+        Ok(())
+    }
+
+    fn lidt(&mut self, modrm: &ModRM) {
+        if self.cpl() != 0 {
+            self.raise_exception(general_protection(0));
+            return;
+        }
+        self.idtr = self.read_descriptor_table_register(modrm);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use emulator::emulator_function::EmulatorFunction;
+    use emulator::testing::*;
+    use emulator::{Emulator, SegmentRegister};
+
+    #[test]
+    fn iret_to_outer_ring_nulls_inaccessible_data_segments() {
+        let mut emu = emulator(&[
+            0x66, 0xB8, 0x10, 0x00, // mov ax, 0x10
+            0x8E, 0xD8, // mov ds, ax
+            0x8E, 0xC0, // mov es, ax
+            0x66, 0xB8, 0x23, 0x00, // mov ax, 0x23
+            0x8E, 0xE0, // mov fs, ax
+            0x6A, 0x23, // push 0x23(SS)
+            0x68, 0x00, 0x80, 0x00, 0x00, // push 0x8000(ESP)
+            0x9C, // pushfd
+            0x6A, 0x1B, // push 0x1B(CS)
+            0x68, 0x00, 0x7D, 0x00, 0x00, // push 0x7d00(EIP)
+            0xCF, // iretd
+        ]);
+        install_flat_segments(&mut emu);
+        run(&mut emu, 11);
+        assert_eq!(emu.eip, 0x7d00);
+        assert_eq!(emu.segment_registers[SegmentRegister::CS as usize], 0x1B);
+        assert_eq!(emu.segment_registers[SegmentRegister::SS as usize], 0x23);
+        assert_eq!(emu.registers[4], 0x8000);
+        assert_eq!(emu.segment_registers[SegmentRegister::DS as usize], 0);
+        assert_eq!(emu.segment_registers[SegmentRegister::ES as usize], 0);
+        assert_eq!(emu.segment_registers[SegmentRegister::FS as usize], 0x23);
+    }
+
+    #[test]
+    fn iret_outside_ring0_cannot_set_virtual_8086_flags() {
+        let mut emu = emulator(&[
+            0x68, 0x02, 0x02, 0x1A, 0x00, // push 0x001A0202(VM, VIF, VIP, IF)
+            0x6A, 0x1B, // push 0x1B(CS)
+            0x68, 0x00, 0x7D, 0x00, 0x00, // push 0x7d00(EIP)
+            0xCF, // iretd
+        ]);
+        install_flat_segments(&mut emu);
+        enter_ring3(&mut emu);
+        emu.eflags |= 3 << 12;
+        run(&mut emu, 4);
+        // リング3からはV86モードに入らず、同じ特権レベルのまま戻る
+        assert_eq!(emu.eip, 0x7d00);
+        assert_eq!(emu.segment_registers[SegmentRegister::CS as usize], 0x1B);
+        let virtual_flags = Emulator::VIRTUAL_8086_FLAG | Emulator::VIRTUAL_INTERRUPT_FLAG | Emulator::VIRTUAL_INTERRUPT_PENDING;
+        assert_eq!(emu.eflags & virtual_flags, 0);
+        assert_ne!(emu.eflags & Emulator::INTERRUPT_FLAG, 0);
+        assert_eq!(emu.get_register32(4), STACK);
+    }
+
+    #[test]
+    fn iret16_reads_the_frame_through_the_stack_segment() {
+        let mut emu = emulator(&[
+            0x66, 0xCF, // iret
+        ]);
+        install_flat_segments(&mut emu);
+        emu.segment_caches[SegmentRegister::SS as usize].base = 0x10000;
+        emu.eflags |= Emulator::ALIGNMENT_CHECK_FLAG;
+        write16(&mut emu, 0x10000 + STACK, 0x7d00);
+        write16(&mut emu, 0x10000 + STACK + 2, 0x08);
+        write16(&mut emu, 0x10000 + STACK + 4, 0x0046);
+        run(&mut emu, 1);
+        assert_eq!(emu.eip, 0x7d00);
+        assert_eq!(emu.get_register32(4), STACK + 6);
+        // 16ビットのIRETはEFLAGSの上位16ビットを変えない
+        assert_eq!(emu.eflags, Emulator::ALIGNMENT_CHECK_FLAG | 0x0046);
+    }
+
+    #[test]
+    fn idt_address_wraps_at_4gb() {
+        let mut emu = emulator(&[
+            0xCD, 0x01, // int 1
+        ]);
+        install_flat_segments(&mut emu);
+        // IDTのベースが4GBの手前にあり、ベクタ1のゲートは0番地に折り返す
+        emu.idtr.base = 0xFFFF_FFF8;
+        emu.idtr.limit = 0xFF;
+        set_gate(&mut emu, 0, 0, 0x08, 0x7e00, 0x8E);
+        run(&mut emu, 1);
+        assert_eq!(emu.eip, 0x7e00);
+        assert_eq!(read32(&emu, STACK - 12), 0x7c02);
+    }
+}
@@ -1,26 +1,454 @@
 use std::io;
-pub trait Io {
-    fn io_in8(address: u16) -> u8 {
-        match address {
-            0x03f8 => {
-                let mut input = String::new();
-                let ret: char;
-                match io::stdin().read_line(&mut input) {
-                    Ok(_) => ret = input.remove(0),
-                    Err(e) => panic!("Cant't read line : {}", e),
-                };
-                ret as u8
+
+use emulator::a20::A20Line;
+
+// 割り込みラインの本数(IRQ0〜IRQ15)
+pub const INTERRUPT_LINES: u8 = 16;
+
+// デバイスからCPUへの割り込みライン(レベルトリガで、デバイスが下げるまで要求し続ける)
+// CPUが受け付けたラインは処理中になり、EOIかデバイスがラインを下げるまでは再び通知しない
+// CPUのA20M#にあたるA20ゲートの状態もここに持ち、ポート0x92やキーボードコントローラのデバイスから操作する
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct InterruptLines {
+    raised: u16,
+    in_service: u16,
+    a20: A20Line,
+}
+
+impl InterruptLines {
+    pub fn a20_line(&self) -> &A20Line {
+        &self.a20
+    }
+
+    pub fn a20_line_mut(&mut self) -> &mut A20Line {
+        &mut self.a20
+    }
+
+    pub fn raise(&mut self, irq: u8) {
+        self.raised |= 1 << (irq % INTERRUPT_LINES);
+    }
+
+    // ラインを下げれば、そのラインの処理も終わったものとする
+    pub fn lower(&mut self, irq: u8) {
+        self.raised &= !(1 << (irq % INTERRUPT_LINES));
+        self.in_service &= !(1 << (irq % INTERRUPT_LINES));
+    }
+
+    pub fn is_raised(&self, irq: u8) -> bool {
+        self.raised & (1 << (irq % INTERRUPT_LINES)) != 0
+    }
+
+    // CPUが割り込みを受け付けたときに呼ぶ
+    pub fn acknowledge(&mut self, irq: u8) {
+        self.in_service |= 1 << (irq % INTERRUPT_LINES);
+    }
+
+    // ハンドラが処理を終えたことを通知する(ラインが上がったままなら再び通知する)
+    pub fn end_of_interrupt(&mut self, irq: u8) {
+        self.in_service &= !(1 << (irq % INTERRUPT_LINES));
+    }
+
+    pub fn is_in_service(&self, irq: u8) -> bool {
+        self.in_service & (1 << (irq % INTERRUPT_LINES)) != 0
+    }
+
+    // 番号の小さいラインを優先し、処理中のラインより優先度の高いラインだけを通知する
+    pub fn highest_priority(&self) -> Option<u8> {
+        let pending = self.raised & !self.in_service;
+        if pending == 0 {
+            return None;
+        }
+        let irq = pending.trailing_zeros();
+        if irq < self.in_service.trailing_zeros() {
+            Some(irq as u8)
+        } else {
+            None
+        }
+    }
+}
+
+// I/Oポートに接続するデバイス(offsetは登録したポート範囲の先頭からのオフセット)
+// 16/32ビットのアクセスは、実装しなければ連続したポートへの8ビットアクセスに分割する
+pub trait PortDevice {
+    fn read8(&mut self, offset: u16, lines: &mut InterruptLines) -> u8;
+    fn write8(&mut self, offset: u16, value: u8, lines: &mut InterruptLines);
+
+    fn read16(&mut self, offset: u16, lines: &mut InterruptLines) -> u16 {
+        (self.read8(offset, lines) as u16) | ((self.read8(offset.wrapping_add(1), lines) as u16) << 8)
+    }
+
+    fn read32(&mut self, offset: u16, lines: &mut InterruptLines) -> u32 {
+        (self.read16(offset, lines) as u32) | ((self.read16(offset.wrapping_add(2), lines) as u32) << 16)
+    }
+
+    fn write16(&mut self, offset: u16, value: u16, lines: &mut InterruptLines) {
+        self.write8(offset, value as u8, lines);
+        self.write8(offset.wrapping_add(1), (value >> 8) as u8, lines);
+    }
+
+    fn write32(&mut self, offset: u16, value: u32, lines: &mut InterruptLines) {
+        self.write16(offset, value as u16, lines);
+        self.write16(offset.wrapping_add(2), (value >> 16) as u16, lines);
+    }
+}
+
+// ポート範囲を登録できなかった
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PortMapError {
+    // 大きさが0か、ポート0xFFFFを超えている
+    InvalidRange { base: u16, count: u32 },
+    // 登録済みのデバイスのポートと重なっている
+    Overlapping { base: u16, count: u32 },
+}
+
+struct PortRange {
+    base: u16,
+    count: u32,
+    device: Box<dyn PortDevice>,
+}
+
+impl PortRange {
+    fn contains(&self, port: u16, size: u32) -> bool {
+        port >= self.base && (port - self.base) as u32 + size <= self.count
+    }
+}
+
+// IN/OUT命令のアクセスを、ポート範囲に登録したデバイスに振り分ける
+#[derive(Default)]
+pub struct PortBus {
+    ranges: Vec<PortRange>,
+    // デバイスのないポートを読んだときの値
+    unmapped_value: u8,
+}
+
+impl PortBus {
+    pub fn new() -> PortBus {
+        PortBus::default()
+    }
+
+    pub fn register(&mut self, base: u16, count: u32, device: Box<dyn PortDevice>) -> Result<(), PortMapError> {
+        let end = base as u32 + count;
+        if count == 0 || end > 0x10000 {
+            return Err(PortMapError::InvalidRange { base, count });
+        }
+        if self
+            .ranges
+            .iter()
+            .any(|range| (base as u32) < range.base as u32 + range.count && (range.base as u32) < end)
+        {
+            return Err(PortMapError::Overlapping { base, count });
+        }
+        self.ranges.push(PortRange { base, count, device });
+        Ok(())
+    }
+
+    // baseから始まる範囲に登録したデバイスを外して返す
+    pub fn unregister(&mut self, base: u16) -> Option<Box<dyn PortDevice>> {
+        let index = self.ranges.iter().position(|range| range.base == base)?;
+        Some(self.ranges.remove(index).device)
+    }
+
+    pub fn set_unmapped_value(&mut self, value: u8) {
+        self.unmapped_value = value;
+    }
+
+    // アクセスするポートがすべて1つのデバイスの範囲に入っていなければ、8ビットずつ振り分ける
+    pub fn read(&mut self, port: u16, size: u32, lines: &mut InterruptLines) -> u32 {
+        if let Some(range) = self.ranges.iter_mut().find(|range| range.contains(port, size)) {
+            let offset = port - range.base;
+            return match size {
+                1 => range.device.read8(offset, lines) as u32,
+                2 => range.device.read16(offset, lines) as u32,
+                _ => range.device.read32(offset, lines),
+            };
+        }
+        if size == 1 {
+            return self.unmapped_value as u32;
+        }
+        (0..size).fold(0, |value, i| value | (self.read(port.wrapping_add(i as u16), 1, lines) << (i * 8)))
+    }
+
+    pub fn write(&mut self, port: u16, size: u32, value: u32, lines: &mut InterruptLines) {
+        if let Some(range) = self.ranges.iter_mut().find(|range| range.contains(port, size)) {
+            let offset = port - range.base;
+            match size {
+                1 => range.device.write8(offset, value as u8, lines),
+                2 => range.device.write16(offset, value as u16, lines),
+                _ => range.device.write32(offset, value, lines),
             }
-            _ => 0,
+            return;
+        }
+        if size == 1 {
+            return;
+        }
+        for i in 0..size {
+            self.write(port.wrapping_add(i as u16), 1, value >> (i * 8), lines);
         }
     }
+}
 
-    fn io_out8(address: u16, value: u8) {
-        match address {
-            0x03f8 => {
-                print!("{}", value as char);
+// 標準入出力につないだシリアルポート(COM1の0x3F8に置く送受信レジスタだけ)
+#[derive(Default)]
+pub struct SerialConsole;
+
+impl PortDevice for SerialConsole {
+    fn read8(&mut self, _: u16, _: &mut InterruptLines) -> u8 {
+        // 入力が読めないか終わっていれば0を返す
+        let mut input = String::new();
+        match io::stdin().read_line(&mut input) {
+            Ok(_) => input.bytes().next().unwrap_or(0),
+            Err(_) => 0,
+        }
+    }
+
+    fn write8(&mut self, _: u16, value: u8, _: &mut InterruptLines) {
+        print!("{}", value as char);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InterruptLines, PortBus, PortDevice, PortMapError};
+    use emulator::emulator_function::EmulatorFunction;
+    use emulator::stop::StopReason;
+    use emulator::testing::*;
+    use emulator::Register;
+
+    #[test]
+    fn acknowledged_line_is_not_delivered_again_until_eoi_or_lower() {
+        let mut lines = InterruptLines::default();
+        lines.raise(3);
+        assert_eq!(lines.highest_priority(), Some(3));
+        lines.acknowledge(3);
+        assert_eq!(lines.highest_priority(), None);
+        lines.end_of_interrupt(3);
+        assert_eq!(lines.highest_priority(), Some(3));
+        lines.acknowledge(3);
+        lines.lower(3);
+        assert!(!lines.is_in_service(3));
+        assert_eq!(lines.highest_priority(), None);
+    }
+
+    #[test]
+    fn only_higher_priority_lines_interrupt_a_line_in_service() {
+        let mut lines = InterruptLines::default();
+        lines.raise(3);
+        lines.acknowledge(3);
+        lines.raise(5);
+        assert_eq!(lines.highest_priority(), None);
+        lines.raise(1);
+        assert_eq!(lines.highest_priority(), Some(1));
+    }
+
+    #[test]
+    fn hlt_waits_for_an_interrupt() {
+        let mut emu = halted_emulator();
+        assert_eq!(run(&mut emu, 10), StopReason::Halted);
+        assert_eq!(emu.eip, 0x7c02);
+        // 割り込みが来なければ、再開してもすぐに停止する
+        assert_eq!(run(&mut emu, 10), StopReason::Halted);
+        assert!(emu.is_halted());
+
+        emu.interrupt_lines_mut().raise(1);
+        assert_eq!(run(&mut emu, 1), StopReason::BudgetExhausted);
+        assert!(!emu.is_halted());
+        assert_eq!(emu.eip, 0x7e00);
+        assert_eq!(read32(&emu, emu.get_register32(4)), 0x7c02);
+        assert!(emu.interrupt_lines().is_in_service(1));
+    }
+
+    #[test]
+    fn raised_line_is_delivered_once_per_acknowledge() {
+        let mut emu = halted_emulator();
+        run(&mut emu, 2);
+        emu.interrupt_lines_mut().raise(1);
+        // トラップゲートなのでIFは立ったままだが、処理中のラインは再び通知しない
+        run(&mut emu, 3);
+        assert_eq!(emu.eip, 0x7e02);
+        emu.interrupt_lines_mut().end_of_interrupt(1);
+        run(&mut emu, 1);
+        assert_eq!(emu.eip, 0x7e00);
+    }
+
+    #[test]
+    fn a20_gate_devices_are_registered_by_default() {
+        let mut emu = emulator(&[
+            0xB0, 0x02, // mov al, 2
+            0xE6, 0x92, // out 0x92, al
+            0xE4, 0x92, // in al, 0x92
+        ]);
+        emu.reset_to_real_mode();
+        run(&mut emu, 3);
+        assert!(emu.a20_line().is_enabled());
+        assert_eq!(emu.registers[0] & 0xFF, 2);
+        assert!(emu.ports_mut().unregister(0x60).is_some());
+        assert!(emu.ports_mut().unregister(0x64).is_some());
+    }
+
+    #[test]
+    fn in_with_immediate_port_reads_each_width() {
+        let (mut emu, _) = port_emulator(&[
+            0xE4, 0x41, // in al, 0x41
+            0xE5, 0x40, // in eax, 0x40
+            0xB8, 0xFF, 0xFF, 0xFF, 0xFF, // mov eax, 0xFFFFFFFF
+            0x66, 0xE5, 0x42, // in ax, 0x42
+        ]);
+        run(&mut emu, 1);
+        assert_eq!(emu.registers[Register::EAX as usize] & 0xFF, 0x11);
+        run(&mut emu, 1);
+        assert_eq!(emu.registers[Register::EAX as usize], 0x1312_1110);
+        run(&mut emu, 2);
+        assert_eq!(emu.registers[Register::EAX as usize], 0xFFFF_1312);
+    }
+
+    #[test]
+    fn out_with_immediate_and_dx_ports_writes_each_width() {
+        let (mut emu, writes) = port_emulator(&[
+            0xB8, 0x44, 0x33, 0x22, 0x11, // mov eax, 0x11223344
+            0xE6, 0x40, // out 0x40, al
+            0x66, 0xE7, 0x42, // out 0x42, ax
+            0x66, 0xBA, 0x44, 0x00, // mov dx, 0x44
+            0xEF, // out dx, eax
+        ]);
+        run(&mut emu, 5);
+        assert_eq!(*writes.borrow(), vec![(0, 0x44), (2, 0x44), (3, 0x33), (4, 0x44), (5, 0x33), (6, 0x22), (7, 0x11)]);
+    }
+
+    #[test]
+    fn in_with_dx_port_reads_each_width() {
+        let (mut emu, _) = port_emulator(&[
+            0x66, 0xBA, 0x44, 0x00, // mov dx, 0x44
+            0xEC, // in al, dx
+            0x66, 0xED, // in ax, dx
+            0xED, // in eax, dx
+        ]);
+        run(&mut emu, 2);
+        assert_eq!(emu.registers[Register::EAX as usize], 0x14);
+        run(&mut emu, 1);
+        assert_eq!(emu.registers[Register::EAX as usize], 0x1514);
+        run(&mut emu, 1);
+        assert_eq!(emu.registers[Register::EAX as usize], 0x1716_1514);
+    }
+
+    #[test]
+    fn rep_ins_and_outs_move_strings_through_a_port() {
+        let (mut emu, writes) = port_emulator(&[
+            0x66, 0xBA, 0x41, 0x00, // mov dx, 0x41
+            0xB9, 0x03, 0x00, 0x00, 0x00, // mov ecx, 3
+            0xF3, 0x6C, // rep insb
+            0xB9, 0x02, 0x00, 0x00, 0x00, // mov ecx, 2
+            0xF3, 0x6F, // rep outsd
+        ]);
+        emu.registers[Register::EDI as usize] = 0x4000;
+        emu.registers[Register::ESI as usize] = 0x5000;
+        write32(&mut emu, 0x5000, 0x0403_0201);
+        write32(&mut emu, 0x5004, 0x0807_0605);
+        run(&mut emu, 3);
+        assert_eq!(read32(&emu, 0x4000) & 0xFF_FFFF, 0x11_1111);
+        assert_eq!(emu.registers[Register::EDI as usize], 0x4003);
+        assert_eq!(emu.registers[Register::ECX as usize], 0);
+        // 0x41から4バイトはデバイスの範囲(0x40〜0x47)に収まる
+        run(&mut emu, 2);
+        assert_eq!(emu.registers[Register::ESI as usize], 0x5008);
+        assert_eq!(emu.registers[Register::ECX as usize], 0);
+        let bytes: Vec<u8> = writes.borrow().iter().map(|&(_, value)| value).collect();
+        assert_eq!(bytes, vec![1, 2, 3, 4, 5, 6, 7, 8]);
+        assert!(writes.borrow().iter().all(|&(offset, _)| (1..5).contains(&offset)));
+    }
+
+    // 16ビットのアクセスを自分で扱い、書き込まれた値でIRQ4を上げ下げするデバイス
+    struct WideRegister {
+        value: u16,
+    }
+
+    impl PortDevice for WideRegister {
+        fn read8(&mut self, _: u16, _: &mut InterruptLines) -> u8 {
+            0xEE
+        }
+
+        fn write8(&mut self, _: u16, _: u8, _: &mut InterruptLines) {}
+
+        fn read16(&mut self, _: u16, _: &mut InterruptLines) -> u16 {
+            self.value
+        }
+
+        fn write16(&mut self, _: u16, value: u16, lines: &mut InterruptLines) {
+            self.value = value;
+            if value != 0 {
+                lines.raise(4);
+            } else {
+                lines.lower(4);
             }
-            _ => {}
         }
     }
+
+    #[test]
+    fn register_rejects_invalid_and_overlapping_ranges() {
+        let mut ports = PortBus::new();
+        ports.register(0x40, 8, recorder().0).unwrap();
+        assert_eq!(ports.register(0x44, 8, recorder().0), Err(PortMapError::Overlapping { base: 0x44, count: 8 }));
+        assert_eq!(ports.register(0x3C, 5, recorder().0), Err(PortMapError::Overlapping { base: 0x3C, count: 5 }));
+        assert_eq!(ports.register(0x50, 0, recorder().0), Err(PortMapError::InvalidRange { base: 0x50, count: 0 }));
+        assert_eq!(ports.register(0xFFFF, 2, recorder().0), Err(PortMapError::InvalidRange { base: 0xFFFF, count: 2 }));
+        ports.register(0x48, 8, recorder().0).unwrap();
+        ports.register(0xFFFF, 1, recorder().0).unwrap();
+    }
+
+    #[test]
+    fn unmapped_ports_read_the_configured_value() {
+        let mut ports = PortBus::new();
+        let mut lines = InterruptLines::default();
+        assert_eq!(ports.read(0x80, 1, &mut lines), 0);
+        ports.set_unmapped_value(0xFF);
+        assert_eq!(ports.read(0x80, 4, &mut lines), 0xFFFF_FFFF);
+        // 書き込みは捨てられる
+        ports.write(0x80, 4, 0x1234_5678, &mut lines);
+    }
+
+    #[test]
+    fn unregister_removes_the_device() {
+        let mut ports = PortBus::new();
+        let mut lines = InterruptLines::default();
+        let (device, writes) = recorder();
+        ports.register(0x40, 8, device).unwrap();
+        assert!(ports.unregister(0x41).is_none());
+        assert!(ports.unregister(0x40).is_some());
+        ports.write(0x40, 1, 0x55, &mut lines);
+        assert!(writes.borrow().is_empty());
+        assert_eq!(ports.read(0x40, 1, &mut lines), 0);
+        // 外した範囲には別のデバイスを登録できる
+        ports.register(0x40, 8, recorder().0).unwrap();
+    }
+
+    #[test]
+    fn accesses_spanning_devices_are_split_into_bytes() {
+        let mut ports = PortBus::new();
+        let mut lines = InterruptLines::default();
+        let (device, writes) = recorder();
+        ports.register(0x40, 2, device).unwrap();
+        ports.set_unmapped_value(0xFF);
+        // 0x40と0x41だけがデバイスにつながっている
+        assert_eq!(ports.read(0x40, 4, &mut lines), 0xFFFF_1110);
+        ports.write(0x3F, 4, 0x4433_2211, &mut lines);
+        assert_eq!(*writes.borrow(), vec![(0, 0x22), (1, 0x33)]);
+    }
+
+    #[test]
+    fn devices_handle_wide_accesses_and_raise_lines() {
+        let mut emu = emulator(&[
+            0x66, 0xB8, 0x34, 0x12, // mov ax, 0x1234
+            0x66, 0xE7, 0x60, // out 0x60, ax
+            0x66, 0xE5, 0x60, // in ax, 0x60
+            0xE4, 0x61, // in al, 0x61
+        ]);
+        emu.ports_mut().unregister(0x60);
+        emu.ports_mut().register(0x60, 2, Box::new(WideRegister { value: 0 })).unwrap();
+        run(&mut emu, 2);
+        assert!(emu.interrupt_lines().is_raised(4));
+        emu.registers[Register::EAX as usize] = 0;
+        run(&mut emu, 1);
+        assert_eq!(emu.registers[Register::EAX as usize], 0x1234);
+        run(&mut emu, 1);
+        assert_eq!(emu.registers[Register::EAX as usize], 0x12EE);
+    }
 }
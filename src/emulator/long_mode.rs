@@ -0,0 +1,472 @@
+use emulator::emulator_function::EmulatorFunction;
+use emulator::interrupt::{general_protection, invalid_tss, segment_not_present, Exception};
+use emulator::modrm::Function as ModRMFunction;
+use emulator::segment::{selector_error, Segment, SegmentDescriptor, INTERRUPT_GATE_32, TRAP_GATE_32};
+use emulator::{Emulator, Register, SegmentRegister};
+
+// REXプレフィックス(0x40〜0x4F)の下位4ビット
+pub const REX_W: u8 = 1 << 3;
+pub const REX_R: u8 = 1 << 2;
+pub const REX_X: u8 = 1 << 1;
+pub const REX_B: u8 = 1;
+
+// 64ビットTSSのRSP0とIST1のオフセット
+const TSS_RSP0: usize = 0x04;
+const TSS_IST1: usize = 0x24;
+
+// ビット47より上がビット47の符号拡張になっている(正規形の)アドレス
+pub fn is_canonical(address: u64) -> bool {
+    ((address as i64) << 16 >> 16) as u64 == address
+}
+
+// 64ビットモードでは削除されて#UDになるオペコード
+// (PUSH/POP ES/CS/SS/DS, DAA/DAS/AAA/AAS, PUSHA/POPA, BOUND, 82, 遠隔CALL/JMPの即値, INTO, AAM/AAD/SALC)
+pub fn is_invalid_in_64bit_mode(code: u8) -> bool {
+    matches!(
+        code,
+        0x06 | 0x07 | 0x0E | 0x16 | 0x17 | 0x1E | 0x1F | 0x27 | 0x2F | 0x37 | 0x3F | 0x60..=0x62 | 0x82 | 0x9A | 0xCE | 0xD4..=0xD6 | 0xEA
+    )
+}
+
+pub trait LongMode {
+    fn is_implemented_in_64bit_mode(&mut self) -> bool;
+    fn movsxd(&mut self);
+    fn deliver_long_mode_interrupt(&mut self, vector: u8, error_code: Option<u32>, software: bool) -> Result<(), Exception>;
+    fn long_mode_interrupt_return(&mut self) -> Result<(), Exception>;
+}
+
+impl Emulator {
+    // 64ビットTSSのoffsetにあるスタックポインタ(RSP0〜RSP2かIST1〜IST7)を読む
+    fn tss_stack64(&mut self, offset: usize, ext: u32) -> Result<u64, Exception> {
+        if offset as u32 + 7 > self.task_register.limit {
+            return Err(invalid_tss(selector_error(self.task_register.selector) + ext));
+        }
+        let address = self.task_register.base as usize + offset;
+        Ok(self.supervisor_access(|emu| emu.get_memory64(address)))
+    }
+
+    // POPせずにスタックのRSP+index*8から8バイトを読む
+    fn read_stack64(&mut self, rsp: u64, index: u64) -> u64 {
+        let address = self.linear_address(SegmentRegister::SS as usize, rsp.wrapping_add(index * 8), 8) as usize;
+        self.get_memory64(address)
+    }
+}
+
+impl LongMode for Emulator {
+    // 64ビットのオペランドとアドレスに対応した命令だけを64ビットモードで実行する
+    // それ以外は32ビットのまま実行すると結果が変わるので、実装していない命令として扱う
+    fn is_implemented_in_64bit_mode(&mut self) -> bool {
+        let code = self.get_code8(0);
+        match code {
+            // 16ビットのスタック操作はSPではなくRSPを使うので対応していない
+            0x50..=0x5F | 0x68 | 0x6A | 0xC3 | 0xE8 => !self.operand_size_override,
+            // IRETQだけに対応している
+            0xCF => self.is_operand_size64(),
+            0xCC | 0xCD | 0xF1 => true,
+            0x01 | 0x3B..=0x3D | 0x63 | 0x70..=0x7F | 0x83 | 0x88..=0x8B | 0x8D | 0x98 | 0x99 | 0xB0..=0xBF | 0xC7 => true,
+            0xE4..=0xE7 | 0xE9 | 0xEB..=0xEF | 0xF4 | 0xF5 | 0xF8..=0xFD => true,
+            0xFF => (self.get_code8(1) >> 3) & 7 == 0,
+            0x0F => match self.get_code8(1) {
+                0x01 => matches!((self.get_code8(2) >> 3) & 7, 2 | 3 | 4 | 6 | 7),
+                0x06 | 0x20 | 0x22 | 0x30..=0x32 | 0xA2 => true,
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    // 64ビットモードの63はARPLではなくMOVSXD r64, r/m32
+    fn movsxd(&mut self) {
+        self.eip += 1;
+        let modrm = self.parse_modrm();
+        let value = self.get_rm32(&modrm);
+        if self.is_operand_size64() {
+            self.set_r64(&modrm, value as i32 as i64 as u64);
+        } else if self.is_operand_size16() {
+            self.set_r16(&modrm, value as u16);
+        } else {
+            self.set_r32(&modrm, value);
+        }
+    }
+
+    // ロングモードのIDTは16バイトのゲートが並び、ハンドラは64ビットモードで実行する
+    fn deliver_long_mode_interrupt(&mut self, vector: u8, error_code: Option<u32>, software: bool) -> Result<(), Exception> {
+        let ext = if software { 0 } else { 1 };
+        let idt_error = vector as u32 * 8 + 2 + ext;
+        let offset = vector as u32 * 16;
+        if offset + 15 > self.idtr.limit as u32 {
+            return Err(general_protection(idt_error));
+        }
+        let address = self.descriptor_table_address(self.idtr.base, offset);
+        let (gate, offset_high) = self.supervisor_access(|emu| {
+            let gate = SegmentDescriptor {
+                low: emu.get_memory32(address),
+                high: emu.get_memory32(address + 4),
+            };
+            (gate, emu.get_memory32(address + 8))
+        });
+        // タスクゲートはない
+        let gate_type = gate.descriptor_type();
+        if !gate.is_system() || (gate_type != INTERRUPT_GATE_32 && gate_type != TRAP_GATE_32) {
+            return Err(general_protection(idt_error));
+        }
+        let cpl = self.cpl();
+        if software && cpl > gate.dpl() {
+            return Err(general_protection(idt_error));
+        }
+        if !gate.is_present() {
+            return Err(segment_not_present(idt_error));
+        }
+
+        let selector = gate.gate_selector();
+        if selector & 0xFFFC == 0 {
+            return Err(general_protection(ext));
+        }
+        let code = self
+            .read_descriptor(selector)
+            .map_err(|_| general_protection(selector_error(selector) + ext))?;
+        if !code.is_64bit_code() || code.dpl() > cpl {
+            return Err(general_protection(selector_error(selector) + ext));
+        }
+        if !code.is_present() {
+            return Err(segment_not_present(selector_error(selector) + ext));
+        }
+
+        // ISTが指定されていればそのスタックへ、特権レベルが上がるときはTSSのRSPnへ切り替える
+        let new_cpl = if code.is_conforming() { cpl } else { code.dpl() };
+        let old_ss = self.segment_registers[SegmentRegister::SS as usize];
+        let old_rsp = self.get_register64(Register::ESP as usize);
+        let ist = (gate.high & 7) as usize;
+        let rsp = if ist != 0 {
+            self.tss_stack64(TSS_IST1 + (ist - 1) * 8, ext)?
+        } else if new_cpl < cpl {
+            self.tss_stack64(TSS_RSP0 + new_cpl as usize * 8, ext)?
+        } else {
+            old_rsp
+        };
+
+        // SS, RSP, RFLAGS, CS, RIP(, エラーコード)を、16バイト境界に揃えたスタックへ8バイトずつ積む
+        let rflags = self.eflags;
+        let cs = self.segment_registers[SegmentRegister::CS as usize];
+        let rip = self.eip;
+        self.set_segment_register(SegmentRegister::CS as usize, (selector & 0xFFFC) | new_cpl as u16);
+        if new_cpl < cpl {
+            // SSにはRPLを新しいCPLにしたヌルセレクタを読み込む
+            self.set_segment_register(SegmentRegister::SS as usize, new_cpl as u16);
+        }
+        self.set_register64(Register::ESP as usize, rsp & !0xF);
+        self.push64(old_ss as u64);
+        self.push64(old_rsp);
+        self.push64(rflags as u64);
+        self.push64(cs as u64);
+        self.push64(rip);
+        if let Some(code) = error_code {
+            self.push64(code as u64);
+        }
+
+        self.eflags &= !(Self::TRAP_FLAG | Self::NESTED_TASK_FLAG | Self::RESUME_FLAG);
+        if gate_type == INTERRUPT_GATE_32 {
+            self.eflags &= !Self::INTERRUPT_FLAG;
+        }
+        self.eip = (offset_high as u64) << 32 | gate.gate_offset() as u64;
+        self.interrupt_delivered = true;
+        Ok(())
+    }
+
+    // IRETQは特権レベルが変わらなくてもRIP, CS, RFLAGS, RSP, SSを読む
+    fn long_mode_interrupt_return(&mut self) -> Result<(), Exception> {
+        // ロングモードではタスクを切り替えられない
+        if self.eflags & Self::NESTED_TASK_FLAG != 0 {
+            return Err(general_protection(0));
+        }
+        let rsp = self.get_register64(Register::ESP as usize);
+        let rip = self.read_stack64(rsp, 0);
+        let cs = self.read_stack64(rsp, 1) as u16;
+        let rflags = self.read_stack64(rsp, 2) as u32;
+        let new_rsp = self.read_stack64(rsp, 3);
+        let ss = self.read_stack64(rsp, 4) as u16;
+        self.check_return_code_segment(cs)?;
+        // 64ビットモードのリング0〜2へ戻るときは、SSにヌルセレクタを読み込める
+        let cpl = self.cpl();
+        let rpl = (cs & 3) as u8;
+        let code = self.read_descriptor(cs)?;
+        if ss & 0xFFFC != 0 || !code.is_64bit_code() || rpl == 3 {
+            self.check_stack_segment(ss, rpl)?;
+        }
+
+        // VMは立てられず、IOPL, VIF, VIPはリング0でのみ、IFはCPL<=IOPLのときのみ変更できる
+        let mut mask = !Self::VIRTUAL_8086_FLAG;
+        if cpl != 0 {
+            mask &= !(Self::IOPL_MASK | Self::VIRTUAL_INTERRUPT_FLAG | Self::VIRTUAL_INTERRUPT_PENDING);
+        }
+        if cpl > self.get_iopl() {
+            mask &= !Self::INTERRUPT_FLAG;
+        }
+        self.set_eflags((self.eflags & !mask) | (rflags & mask));
+        self.set_segment_register(SegmentRegister::CS as usize, cs);
+        self.set_segment_register(SegmentRegister::SS as usize, ss);
+        self.set_register64(Register::ESP as usize, new_rsp);
+        self.eip = rip;
+        if rpl > cpl {
+            self.invalidate_data_segments();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use emulator::decoder::Decoder;
+    use emulator::instruction::Instruction;
+    use emulator::interrupt::{GENERAL_PROTECTION, INVALID_OPCODE, STACK_FAULT};
+    use emulator::stop::EmuError;
+    use emulator::testing::*;
+    use emulator::{Emulator, Register, SegmentRegister};
+
+    #[test]
+    fn canonical_addresses_sign_extend_bit_47() {
+        assert!(is_canonical(0x0000_7FFF_FFFF_FFFF));
+        assert!(is_canonical(0xFFFF_8000_0000_0000));
+        assert!(!is_canonical(0x0000_8000_0000_0000));
+        assert!(!is_canonical(0xFFFF_7FFF_FFFF_FFFF));
+    }
+
+    #[test]
+    fn rex_prefixes_select_64bit_operands_and_upper_registers() {
+        let mut emu = long_mode_64bit_emulator(&[
+            0x48, 0xB8, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11, // mov rax, 0x1122334455667788
+            0x49, 0x89, 0xC0, // mov r8, rax
+            0x4D, 0x01, 0xC0, // add r8, r8
+            0x49, 0x83, 0xE8, 0x01, // sub r8, 1
+            0x41, 0xB7, 0xAB, // mov r15b, 0xAB
+            0xB8, 0xFF, 0xFF, 0xFF, 0xFF, // mov eax, 0xFFFFFFFF
+        ]);
+        run(&mut emu, 5);
+        assert_eq!(emu.get_register64(Register::R8 as usize), 0x2244_6688_AACC_EF0F);
+        assert_eq!(emu.get_register64(Register::R15 as usize), 0xAB);
+        // 32ビットの書き込みは上位32ビットを0にする
+        run(&mut emu, 1);
+        assert_eq!(emu.get_register64(Register::EAX as usize), 0xFFFF_FFFF);
+    }
+
+    #[test]
+    fn rex_selects_low_byte_registers_instead_of_high_bytes() {
+        let mut emu = long_mode_64bit_emulator(&[
+            0x40, 0xB6, 0x12, // mov sil, 0x12
+            0xB4, 0x34, // mov ah, 0x34
+        ]);
+        run(&mut emu, 2);
+        assert_eq!(emu.get_register64(Register::ESI as usize), 0x12);
+        assert_eq!(emu.get_register64(Register::EAX as usize), 0x3400);
+    }
+
+    #[test]
+    fn rip_relative_addresses_follow_the_instruction() {
+        let mut emu = long_mode_64bit_emulator(&[
+            0x48, 0x8B, 0x05, 0x07, 0x00, 0x00, 0x00, // mov rax, [rip+7]
+            0x48, 0x8D, 0x0D, 0xF9, 0xFF, 0xFF, 0xFF, // lea rcx, [rip-7]
+            0xEF, 0xCD, 0xAB, 0x89, 0x67, 0x45, 0x23, 0x01,
+        ]);
+        run(&mut emu, 2);
+        assert_eq!(emu.get_register64(Register::EAX as usize), 0x0123_4567_89AB_CDEF);
+        assert_eq!(emu.get_register64(Register::ECX as usize), 0x7c07);
+    }
+
+    #[test]
+    fn stack_operations_move_eight_bytes() {
+        let mut emu = long_mode_64bit_emulator(&[
+            0x41, 0x50, // push r8
+            0x6A, 0xFF, // push -1
+            0xE8, 0x01, 0x00, 0x00, 0x00, // call +1
+            0xF4, // hlt
+            0x5B, // pop rbx
+            0x41, 0x5F, // pop r15
+            0x58, // pop rax
+            0x53, // push rbx
+            0xC3, // ret
+        ]);
+        emu.set_register64(Register::R8 as usize, 0x8000_0000_0000_0001);
+        run(&mut emu, 3);
+        assert_eq!(emu.get_register64(Register::ESP as usize), STACK as u64 - 24);
+        assert_eq!(read64(&emu, STACK - 16), u64::MAX);
+        run(&mut emu, 5);
+        assert_eq!(emu.get_register64(Register::EBX as usize), 0x7c09);
+        assert_eq!(emu.get_register64(Register::R15 as usize), u64::MAX);
+        assert_eq!(emu.get_register64(Register::EAX as usize), 0x8000_0000_0000_0001);
+        assert_eq!((emu.eip, emu.get_register64(Register::ESP as usize)), (0x7c09, STACK as u64));
+    }
+
+    #[test]
+    fn sign_extensions_to_64_bits() {
+        let mut emu = long_mode_64bit_emulator(&[
+            0x48, 0x63, 0xD8, // movsxd rbx, eax
+            0x48, 0x98, // cdqe
+            0x48, 0x99, // cqo
+            0x48, 0xC7, 0xC1, 0xFE, 0xFF, 0xFF, 0xFF, // mov rcx, -2
+        ]);
+        emu.set_register64(Register::EAX as usize, 0x8000_0000);
+        run(&mut emu, 4);
+        assert_eq!(emu.get_register64(Register::EBX as usize), 0xFFFF_FFFF_8000_0000);
+        assert_eq!(emu.get_register64(Register::EAX as usize), 0xFFFF_FFFF_8000_0000);
+        assert_eq!(emu.get_register64(Register::EDX as usize), u64::MAX);
+        assert_eq!(emu.get_register64(Register::ECX as usize), 0xFFFF_FFFF_FFFF_FFFE);
+    }
+
+    #[test]
+    fn non_canonical_addresses_raise_general_protection_or_stack_fault() {
+        let mut emu = long_mode_64bit_emulator(&[]);
+        emu.linear_address(SegmentRegister::DS as usize, 0x0000_7FFF_FFFF_FFFC, 8);
+        assert_eq!(emu.memory_fault.take().map(|e| e.vector), Some(GENERAL_PROTECTION));
+        emu.linear_address(SegmentRegister::SS as usize, 0xFFFF_0000_0000_0000, 8);
+        assert_eq!(emu.memory_fault.take().map(|e| e.vector), Some(STACK_FAULT));
+        // 64ビットモードではFSとGSのベースだけを足し、リミットは確かめない
+        emu.segment_caches[SegmentRegister::GS as usize].base = 0x1000;
+        assert_eq!(emu.linear_address(SegmentRegister::GS as usize, 0x1_0000_0000, 8), 0x1_0000_1000);
+        assert_eq!(emu.linear_address(SegmentRegister::ES as usize, 0x1_0000_0000, 8), 0x1_0000_0000);
+        assert!(emu.memory_fault.is_none());
+    }
+
+    #[test]
+    fn removed_opcodes_are_invalid_only_in_64bit_mode() {
+        let mut emu = long_mode_64bit_emulator(&[0x06]); // push es
+        assert_eq!(emu.decode_instruction().map_err(|e| e.vector), Err(INVALID_OPCODE));
+        // 互換モードでは32ビットの命令として実行する
+        let mut emu = long_mode_emulator(&[0x06]);
+        run(&mut emu, 1);
+        assert_eq!(emu.get_register32(Register::ESP as usize), STACK - 4);
+    }
+
+    #[test]
+    fn rex_prefixes_lengthen_only_the_move_immediate() {
+        let mut emu = long_mode_64bit_emulator(&[0x48, 0xB8, 0, 0, 0, 0, 0, 0, 0, 0]); // mov rax, imm64
+        assert_eq!(emu.decode_instruction().map_err(|e| e.vector), Ok(10));
+        let mut emu = long_mode_64bit_emulator(&[0x48, 0x05, 0, 0, 0, 0]); // add rax, imm32
+        assert_eq!(emu.decode_instruction().map_err(|e| e.vector), Ok(6));
+        // REXの後に別のプレフィックスが続けばREXは無視される
+        let mut emu = long_mode_64bit_emulator(&[0x48, 0x66, 0xB8, 0, 0]); // mov ax, imm16
+        assert_eq!(emu.decode_instruction().map_err(|e| e.vector), Ok(5));
+        assert_eq!(emu.rex, 0);
+    }
+
+    #[test]
+    fn instructions_without_64bit_support_are_unimplemented() {
+        let mut emu = long_mode_64bit_emulator(&[0x9C]); // pushfq
+        assert_eq!(
+            emu.run_instructions(true, Some(1)),
+            Err(EmuError::UnimplementedOpcode {
+                eip: 0x7c00,
+                bytes: vec![0x9C]
+            })
+        );
+    }
+
+    #[test]
+    fn interrupts_push_an_aligned_eight_byte_frame_and_iretq_returns() {
+        let mut emu = long_mode_64bit_emulator(&[
+            0xCD, 0x20, // int 0x20
+        ]);
+        emu.bus.load(0x7e00, &[0x48, 0xCF]); // iretq
+        install_idt64(&mut emu, 0x30);
+        set_gate64(&mut emu, 0x20, 0x7e00, 0x8E, 0);
+        // スタックは16バイト境界に揃えてから積む
+        emu.set_register64(Register::ESP as usize, STACK as u64 - 8);
+        emu.eflags |= Emulator::INTERRUPT_FLAG;
+        run(&mut emu, 1);
+        assert_eq!(emu.eip, 0x7e00);
+        assert_eq!(emu.get_register64(Register::ESP as usize), STACK as u64 - 56);
+        assert_eq!(read64(&emu, STACK - 56), 0x7c02);
+        assert_eq!(read64(&emu, STACK - 48), 0x08);
+        assert_ne!(read64(&emu, STACK - 40) & Emulator::INTERRUPT_FLAG as u64, 0);
+        assert_eq!(read64(&emu, STACK - 32), STACK as u64 - 8);
+        assert_eq!(read64(&emu, STACK - 24), 0x10);
+        assert_eq!(emu.eflags & Emulator::INTERRUPT_FLAG, 0);
+        run(&mut emu, 1);
+        assert_eq!((emu.eip, emu.get_register64(Register::ESP as usize)), (0x7c02, STACK as u64 - 8));
+        assert_ne!(emu.eflags & Emulator::INTERRUPT_FLAG, 0);
+    }
+
+    #[test]
+    fn page_faults_reach_the_handler_with_cr2_and_error_code() {
+        let mut emu = long_mode_64bit_emulator(&[
+            0x48, 0x89, 0x03, // mov [rbx], rax
+        ]);
+        install_idt64(&mut emu, 0x30);
+        set_gate64(&mut emu, 14, 0x7e00, 0x8E, 0);
+        write64(&mut emu, PAGE_TABLE + 0xA * 8, 0);
+        emu.set_register64(Register::EBX as usize, 0xA010);
+        run(&mut emu, 1);
+        assert_eq!(emu.eip, 0x7e00);
+        assert_eq!(emu.control_registers[2], 0xA010);
+        // エラーコード(書き込み、ページなし)とフォールトした命令のRIP
+        assert_eq!(read64(&emu, STACK - 48), 2);
+        assert_eq!(read64(&emu, STACK - 40), 0x7c00);
+    }
+
+    #[test]
+    fn interrupts_from_ring3_switch_to_rsp0_or_the_interrupt_stack_table() {
+        let mut emu = long_mode_64bit_emulator(&[
+            0xCD, 0x20, // int 0x20
+            0xCD, 0x21, // int 0x21
+        ]);
+        emu.bus.load(0x7e00, &[0x48, 0xCF]); // iretq
+        install_idt64(&mut emu, 0x30);
+        set_gate64(&mut emu, 0x20, 0x7e00, 0xEE, 0);
+        set_gate64(&mut emu, 0x21, 0x7e00, 0xEE, 1);
+        install_tss(&mut emu);
+        write64(&mut emu, TSS + 4, RING0_STACK as u64);
+        write64(&mut emu, TSS + 0x24, 0x6000);
+        emu.set_segment_register(SegmentRegister::CS as usize, 0x1B);
+        emu.set_segment_register(SegmentRegister::SS as usize, 0x23);
+
+        run(&mut emu, 1);
+        assert_eq!(emu.get_register64(Register::ESP as usize), RING0_STACK as u64 - 40);
+        // SSにはヌルセレクタが入り、戻り先のSSとRSPが積まれる
+        assert_eq!(emu.segment_registers[SegmentRegister::SS as usize], 0);
+        assert_eq!(read64(&emu, RING0_STACK - 8), 0x23);
+        assert_eq!(read64(&emu, RING0_STACK - 16), STACK as u64);
+        run(&mut emu, 1);
+        assert_eq!(emu.segment_registers[SegmentRegister::CS as usize], 0x1B);
+        assert_eq!(emu.segment_registers[SegmentRegister::SS as usize], 0x23);
+        assert_eq!(emu.get_register64(Register::ESP as usize), STACK as u64);
+
+        run(&mut emu, 1);
+        assert_eq!(emu.get_register64(Register::ESP as usize), 0x6000 - 40);
+        assert_eq!(read64(&emu, 0x6000 - 40), 0x7c04);
+    }
+
+    #[test]
+    fn compatibility_mode_interrupts_enter_64bit_handlers() {
+        let mut emu = long_mode_64bit_emulator(&[
+            0xCD, 0x20, // int 0x20
+        ]);
+        emu.bus.load(0x7e00, &[0x48, 0xCF]); // iretq
+        install_idt64(&mut emu, 0x30);
+        set_gate64(&mut emu, 0x20, 0x7e00, 0x8E, 0);
+        emu.set_segment_register(SegmentRegister::CS as usize, 0x30);
+        assert!(!emu.is_64bit_mode());
+        run(&mut emu, 1);
+        assert!(emu.is_64bit_mode());
+        assert_eq!(read64(&emu, STACK - 32), 0x30);
+        run(&mut emu, 1);
+        assert!(!emu.is_64bit_mode());
+        assert_eq!(emu.eip, 0x7c02);
+    }
+
+    #[test]
+    fn descriptor_table_registers_take_eight_byte_bases_in_64bit_mode() {
+        let mut emu = long_mode_64bit_emulator(&[
+            0x0F, 0x01, 0x18, // lidt [rax]
+            0x0F, 0x01, 0x13, // lgdt [rbx]
+        ]);
+        emu.set_register64(Register::EAX as usize, 0x8000);
+        emu.set_register64(Register::EBX as usize, 0x8010);
+        write16(&mut emu, 0x8000, 0x0FFF);
+        write64(&mut emu, 0x8002, 0xFFFF_8000_0000_2000);
+        write16(&mut emu, 0x8010, 0x7F);
+        write64(&mut emu, 0x8012, 0x1_0000_1000);
+        run(&mut emu, 2);
+        assert_eq!((emu.idtr.base, emu.idtr.limit), (0xFFFF_8000_0000_2000, 0x0FFF));
+        assert_eq!((emu.gdtr.base, emu.gdtr.limit), (0x1_0000_1000, 0x7F));
+    }
+}
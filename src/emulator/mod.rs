@@ -1,19 +1,78 @@
+pub mod a20;
+pub mod alignment_check;
+pub mod bit_manipulation;
+pub mod bus;
+pub mod compare_exchange;
+pub mod control_register;
+pub mod cpu_model;
+pub mod cpuid;
+pub mod crypto;
+pub mod debug_register;
+pub mod decoder;
+pub mod descriptor_query;
 mod emulator_function;
+pub mod fast_system_call;
 pub mod instruction;
+pub mod interrupt;
 pub mod io;
+pub mod long_mode;
 pub mod modrm;
+pub mod msr;
+pub mod paging;
+pub mod segment;
+pub mod shift;
+pub mod sparse_memory;
+pub mod sse;
+pub mod stop;
+pub mod task;
+#[cfg(test)]
+mod testing;
+pub mod undefined_flags;
+pub mod unimplemented;
+pub mod vex;
+pub mod virtual8086;
 
 use std::fs::File;
 use std::io::{BufReader, Read};
 
+use self::a20::{register_a20_devices, A20Gate};
+use self::alignment_check::AlignmentCheck;
+use self::bit_manipulation::BitManipulation;
+use self::bus::{Bus, MapError};
+use self::compare_exchange::CompareExchange;
+use self::control_register::{ControlRegister, CR0_INITIAL, CR0_PE};
+use self::cpu_model::CpuModel;
+use self::cpuid::{Cpuid, Feature};
+use self::crypto::Crypto;
+use self::debug_register::{DebugRegister, DR6_BS, DR6_INITIAL, DR7_INITIAL};
+use self::decoder::Decoder;
+use self::descriptor_query::DescriptorQuery;
 use self::emulator_function::EmulatorFunction;
+use self::fast_system_call::FastSystemCall;
 use self::instruction::Instruction;
-use self::io::Io;
-use self::modrm::{Disp, Function as ModRMFunction, ModRM};
-
-// メモリは1MB
+use self::interrupt::{general_protection, invalid_opcode, stack_fault, DescriptorTableRegister, Exception, Interrupt};
+use self::io::{InterruptLines, PortBus, SerialConsole};
+use self::long_mode::{is_canonical, LongMode, REX_B, REX_R, REX_W, REX_X};
+use self::modrm::{Function as ModRMFunction, ModRM};
+use self::msr::{ModelSpecificRegisters, Msr, EFER_LMA};
+use self::paging::{PageAccess, Paging};
+use self::segment::{LocalDescriptorTableRegister, Segment, SegmentCache, FLAT_CODE_SEGMENT, FLAT_DATA_SEGMENT};
+use self::shift::Shift;
+use self::sse::Sse;
+use self::stop::{EmuError, Stop, StopReason};
+use self::task::{Task, TaskRegister};
+use self::undefined_flags::FlagsPolicy;
+use self::unimplemented::{Unimplemented, UnimplementedEncounter, UnimplementedPolicy};
+use self::vex::Vex;
+use self::virtual8086::Virtual8086;
+
+// 指定しなければメモリは1MB
 pub const MEMORY_SIZE: usize = 1024 * 1024;
 const REGISTERS_NAME: [&str; 8] = ["EAX", "ECX", "EDX", "EBX", "ESP", "EBP", "ESI", "EDI"];
+const REGISTERS_NAME64: [&str; 16] = [
+    "RAX", "RCX", "RDX", "RBX", "RSP", "RBP", "RSI", "RDI", "R8", "R9", "R10", "R11", "R12", "R13", "R14", "R15",
+];
+const SEGMENT_REGISTERS_NAME: [&str; 6] = ["ES", "CS", "SS", "DS", "FS", "GS"];
 
 #[allow(dead_code)]
 enum Register {
@@ -25,6 +84,15 @@ enum Register {
     EBP,
     ESI,
     EDI,
+    // ここから下はロングモードでREXプレフィックスを付けたときだけ使える
+    R8,
+    R9,
+    R10,
+    R11,
+    R12,
+    R13,
+    R14,
+    R15,
     RegistersCount,
 }
 
@@ -40,31 +108,154 @@ enum Register8 {
     BH,
 }
 
+// セグメントレジスタ(命令のエンコードと同じ順)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SegmentRegister {
+    ES,
+    CS,
+    SS,
+    DS,
+    FS,
+    GS,
+}
+const SEGMENT_REGISTERS_COUNT: usize = 6;
+
+// バスとポートにはSendでないデバイス(Box<dyn MmioDevice>, Box<dyn PortDevice>)を登録できるので、
+// Emulatorもスレッド間で受け渡せない(作ったスレッドで実行する)
 pub struct Emulator {
-    // 汎用レジスタ
-    registers: [u32; Register::RegistersCount as usize],
+    // 汎用レジスタ(64ビットのRAX〜R15。32ビットのCPUでは下位32ビットのEAX〜EDIだけを使う)
+    registers: [u64; Register::RegistersCount as usize],
+    // セグメントレジスタ
+    segment_registers: [u16; SEGMENT_REGISTERS_COUNT],
     // EFLAGSレジスタ
     eflags: u32,
-    // メモリ(バイト列)
-    pub memory: Vec<u8>,
-    // プログラムカウンタ
-    eip: u32,
+    // 物理メモリ(RAM, ROM, MMIOの領域)
+    bus: Bus,
+    // プログラムカウンタ(64ビットモード以外では下位32ビットだけを使う)
+    eip: u64,
+    // 実行中の命令の先頭アドレス(フォールト時の戻り先)
+    instruction_eip: u64,
+    // グローバルディスクリプタテーブルレジスタ
+    gdtr: DescriptorTableRegister,
+    // 割り込みディスクリプタテーブルレジスタ
+    idtr: DescriptorTableRegister,
+    // ローカルディスクリプタテーブルレジスタ
+    ldtr: LocalDescriptorTableRegister,
+    // タスクレジスタ
+    task_register: TaskRegister,
+    // モデル固有レジスタ
+    msr: ModelSpecificRegisters,
+    // デバッグレジスタ(DR0〜DR7)
+    debug_registers: [u32; 8],
+    // 実行中の命令で検出したデバッグ例外の要因(DR6のビット)
+    pending_debug: u32,
+    // 実行中の命令で割り込みハンドラへ制御が移ったか
+    interrupt_delivered: bool,
+    // MOV SS/POP SSの直後で、次の命令が終わるまで割り込みとデバッグ例外を保留するか
+    interrupt_shadow: bool,
+    // コントロールレジスタ(CR0〜CR4)
+    control_registers: [u64; 5],
+    // エミュレートするCPUのモデル
+    cpu_model: CpuModel,
+    // 実行中の命令にオペランドサイズ/アドレスサイズプレフィックスが付いているか
+    operand_size_override: bool,
+    address_size_override: bool,
+    // セグメントオーバーライドプレフィックスで指定されたセグメント
+    segment_override: Option<usize>,
+    // REP/REPNEプレフィックス(F3/F2)
+    repeat_prefix: Option<u8>,
+    // LOCKプレフィックス(F0)
+    lock_prefix: bool,
+    // 64ビットモードでオペコードの直前に付いたREXプレフィックス(なければ0)
+    rex: u8,
+    // セグメントレジスタごとのディスクリプタキャッシュ(ベース, リミット, 属性)
+    segment_caches: [SegmentCache; SEGMENT_REGISTERS_COUNT],
+    // 実行中の命令のメモリアクセスで検出したフォールト(命令を取り消してから通知する)
+    memory_fault: Option<Exception>,
+    // ディスクリプタテーブルなどをCPLに関係なくスーパーバイザとしてアクセスしている途中か(ページの保護で使う)
+    supervisor_access: bool,
+    // 実行中の命令で書き込んだメモリの元の値(フォールト時に書き戻す)
+    memory_journal: Vec<(usize, u8)>,
+    // XMMレジスタ(XMM0〜XMM7)
+    xmm_registers: [u128; 8],
+    // 未定義のフラグの決め方と、Poisonで使う乱数の状態
+    flags_policy: FlagsPolicy,
+    poison_state: u32,
+    // 実行中の命令の長さ(プレフィックスを含む)
+    instruction_length: u32,
+    // 実行中の命令で起きた、エミュレータが続けられなくなったエラー(命令を取り消してからホストに返す)
+    emulation_error: Option<EmuError>,
+    // HLTやトリプルフォールトで実行を止めるとき、その理由
+    stop_request: Option<StopReason>,
+    // ホストが設定したブレークポイント(リニアアドレス)
+    breakpoints: Vec<u64>,
+    // 実装していない命令の扱いと、これまでに出会った実装していない命令
+    unimplemented_policy: UnimplementedPolicy,
+    unimplemented_encounters: Vec<UnimplementedEncounter>,
+    // I/Oポートに登録したデバイスと、デバイスからの割り込みライン
+    ports: PortBus,
+    interrupt_lines: InterruptLines,
+    // IRQ0に割り当てる割り込みベクタ(IRQnはこれにnを足したベクタになる)
+    irq_vector_base: u8,
+    // HLTで停止していて、割り込みを待っているか
+    halted: bool,
+}
+
+// 命令を取り消すために実行前に保存しておくCPUの状態
+#[derive(Clone, Copy)]
+struct CpuState {
+    registers: [u64; Register::RegistersCount as usize],
+    eflags: u32,
+    segment_registers: [u16; SEGMENT_REGISTERS_COUNT],
+    segment_caches: [SegmentCache; SEGMENT_REGISTERS_COUNT],
 }
 
 impl EmulatorFunction for Emulator {
     const CARRY_FLAG: u32 = 1;
+    // ビット1は常に1
+    const FIXED_FLAGS: u32 = (1 << 1);
+    const PARITY_FLAG: u32 = (1 << 2);
+    const ADJUST_FLAG: u32 = (1 << 4);
     const ZERO_FLAG: u32 = (1 << 6);
     const SIGN_FLAG: u32 = (1 << 7);
+    const TRAP_FLAG: u32 = (1 << 8);
+    const INTERRUPT_FLAG: u32 = (1 << 9);
+    const DIRECTION_FLAG: u32 = (1 << 10);
     const OVERFLOW_FLAG: u32 = (1 << 11);
-    fn get_code8(&self, index: i32) -> u8 {
-        self.memory[(self.eip + index as u32) as usize]
+    const IOPL_MASK: u32 = (3 << 12);
+    const NESTED_TASK_FLAG: u32 = (1 << 14);
+    const RESUME_FLAG: u32 = (1 << 16);
+    const VIRTUAL_8086_FLAG: u32 = (1 << 17);
+    const ALIGNMENT_CHECK_FLAG: u32 = (1 << 18);
+    const VIRTUAL_INTERRUPT_FLAG: u32 = (1 << 19);
+    const VIRTUAL_INTERRUPT_PENDING: u32 = (1 << 20);
+    // 予約ビット(3, 5, 15, 22〜31)を除いたEFLAGSのビット
+    const DEFINED_FLAGS: u32 = 0x003F_7FD5;
+    fn get_code8(&mut self, index: i32) -> u8 {
+        let address = self.code_address(index);
+        let physical_address = match self.translate(address, PageAccess::Fetch) {
+            Some(physical_address) => self.mask_a20(physical_address),
+            None => return 0,
+        };
+        // 命令の途中が読めないアドレスにあれば、データのアクセスと同じく命令を取り消してホストに返す
+        match self.bus.fetch8(physical_address) {
+            Some(value) => value,
+            None => {
+                self.set_emulation_error(EmuError::MemoryFault { address: physical_address });
+                0
+            }
+        }
+    }
+
+    fn get_sign_code8(&mut self, index: i32) -> i8 {
+        self.get_code8(index) as i8
     }
 
-    fn get_sign_code8(&self, index: i32) -> i8 {
-        self.memory[(self.eip + index as u32) as usize] as i8
+    fn get_code16(&mut self, index: i32) -> u16 {
+        (self.get_code8(index) as u16) | ((self.get_code8(index + 1) as u16) << 8)
     }
 
-    fn get_code32(&self, index: i32) -> u32 {
+    fn get_code32(&mut self, index: i32) -> u32 {
         let mut ret: u32 = 0;
 
         // リトルエンディアンでメモリの値を取得する
@@ -74,32 +265,86 @@ impl EmulatorFunction for Emulator {
         return ret;
     }
 
-    fn get_sign_code32(&self, index: i32) -> i32 {
+    fn get_sign_code32(&mut self, index: i32) -> i32 {
         self.get_code32(index) as i32
     }
 
     fn get_memory8(&mut self, address: usize) -> u32 {
-        self.memory[address] as u32
+        // 同じ命令でフォールトが起きていれば、以降のアクセスは行わない
+        if self.memory_fault.is_some() {
+            return 0;
+        }
+        self.check_data_breakpoint(address, false);
+        let physical_address = match self.translate(address as u64, PageAccess::Read) {
+            Some(physical_address) => self.mask_a20(physical_address),
+            None => return 0,
+        };
+        match self.bus.read8(physical_address) {
+            Ok(value) => value as u32,
+            Err(_) => {
+                self.set_emulation_error(EmuError::MemoryFault { address: physical_address });
+                0
+            }
+        }
+    }
+
+    // 複数バイトのアクセスは4GBの端で0番地へ折り返す(64ビットモードでは折り返さない)
+    fn get_memory16(&mut self, address: usize) -> u32 {
+        let next = self.byte_address(address, 1);
+        self.get_memory8(address) | (self.get_memory8(next) << 8)
     }
 
     fn get_memory32(&mut self, address: usize) -> u32 {
         let mut ret = 0;
         for i in 0..=3 {
-            ret |= (self.get_memory8(address + i)) << (8 * i);
+            let next = self.byte_address(address, i);
+            ret |= (self.get_memory8(next)) << (8 * i);
         }
         return ret;
     }
 
+    fn get_memory64(&mut self, address: usize) -> u64 {
+        let high = self.byte_address(address, 4);
+        (self.get_memory32(address) as u64) | ((self.get_memory32(high) as u64) << 32)
+    }
+
     fn set_memory8(&mut self, address: usize, value: u32) {
-        self.memory[address] = (value & 0xFF) as u8;
+        if self.memory_fault.is_some() {
+            return;
+        }
+        self.check_data_breakpoint(address, true);
+        let physical_address = match self.translate(address as u64, PageAccess::Write) {
+            Some(physical_address) => self.mask_a20(physical_address),
+            None => return,
+        };
+        // MMIOへの書き込みは取り消せないので、RAMとROMの元の値だけを残しておく
+        if let Some(original) = self.bus.peek8(physical_address) {
+            self.memory_journal.push((physical_address, original));
+        }
+        if self.bus.write8(physical_address, value as u8).is_err() {
+            self.set_emulation_error(EmuError::MemoryFault { address: physical_address });
+        }
+    }
+
+    fn set_memory16(&mut self, address: usize, value: u32) {
+        let next = self.byte_address(address, 1);
+        self.set_memory8(address, value);
+        self.set_memory8(next, value >> 8);
     }
 
     fn set_memory32(&mut self, address: usize, value: u32) {
         for i in 0..=3 {
-            self.set_memory8(address + i, value >> (i * 8));
+            let next = self.byte_address(address, i);
+            self.set_memory8(next, value >> (i * 8));
         }
     }
 
+    fn set_memory64(&mut self, address: usize, value: u64) {
+        let high = self.byte_address(address, 4);
+        self.set_memory32(address, value as u32);
+        self.set_memory32(high, (value >> 32) as u32);
+    }
+
     fn get_register8(&self, index: usize) -> u8 {
         if index < 4 {
             (self.registers[index] & 0xff) as u8
@@ -108,37 +353,176 @@ impl EmulatorFunction for Emulator {
         }
     }
 
+    fn get_register16(&self, index: usize) -> u16 {
+        self.registers[index] as u16
+    }
+
     fn get_register32(&self, index: usize) -> u32 {
+        self.registers[index] as u32
+    }
+
+    fn get_register64(&self, index: usize) -> u64 {
         self.registers[index]
     }
 
     fn set_register8(&mut self, index: usize, value: u8) {
         if index < 4 {
-            let r = self.registers[index] & 0xffffff00;
-            self.registers[index] = r | (value as u32);
+            let r = self.registers[index] & !0xff;
+            self.registers[index] = r | (value as u64);
         } else {
-            let r = self.registers[index - 4] & 0xffff00ff;
-            self.registers[index - 4] = r | ((value as u32) << 8);
+            let r = self.registers[index - 4] & !0xff00;
+            self.registers[index - 4] = r | ((value as u64) << 8);
         }
     }
 
+    // 8ビットと16ビットの書き込みは上位のビットを残す
+    fn set_register16(&mut self, index: usize, value: u16) {
+        self.registers[index] = (self.registers[index] & !0xffff) | value as u64;
+    }
+
+    // 32ビットの書き込みは上位32ビットを0にする(64ビットモード以外では上位32ビットは見えない)
     fn set_register32(&mut self, index: usize, value: u32) {
+        self.registers[index] = value as u64;
+    }
+
+    fn set_register64(&mut self, index: usize, value: u64) {
         self.registers[index] = value;
     }
 
+    fn push16(&mut self, value: u16) {
+        let sp = self.get_stack_pointer().wrapping_sub(2);
+        self.set_stack_pointer(sp);
+        let address = self.stack_address(sp, 2);
+        self.check_stack_alignment(address, 2);
+        self.set_memory16(address, value as u32);
+    }
+
     fn push32(&mut self, value: u32) {
-        let address = self.get_register32(Register::ESP as usize) - 4;
-        self.set_register32(Register::ESP as usize, address);
-        self.set_memory32(address as usize, value);
+        let sp = self.get_stack_pointer().wrapping_sub(4);
+        self.set_stack_pointer(sp);
+        let address = self.stack_address(sp, 4);
+        self.check_stack_alignment(address, 4);
+        self.set_memory32(address, value);
+    }
+
+    // 64ビットモードのスタックはSSのD/Bビットに関係なくRSPをそのまま使う
+    fn push64(&mut self, value: u64) {
+        let rsp = self.get_register64(Register::ESP as usize).wrapping_sub(8);
+        self.set_register64(Register::ESP as usize, rsp);
+        let address = self.linear_address(SegmentRegister::SS as usize, rsp, 8) as usize;
+        self.check_stack_alignment(address, 8);
+        self.set_memory64(address, value);
+    }
+
+    fn pop16(&mut self) -> u16 {
+        let sp = self.get_stack_pointer();
+        let address = self.stack_address(sp, 2);
+        self.check_stack_alignment(address, 2);
+        let ret = self.get_memory16(address) as u16;
+        self.set_stack_pointer(sp.wrapping_add(2));
+        ret
     }
 
     fn pop32(&mut self) -> u32 {
-        let address = self.get_register32(Register::ESP as usize);
-        let ret = self.get_memory32(address as usize);
-        self.set_register32(Register::ESP as usize, address + 4);
+        let sp = self.get_stack_pointer();
+        let address = self.stack_address(sp, 4);
+        self.check_stack_alignment(address, 4);
+        let ret = self.get_memory32(address);
+        self.set_stack_pointer(sp.wrapping_add(4));
+        ret
+    }
+
+    fn pop64(&mut self) -> u64 {
+        let rsp = self.get_register64(Register::ESP as usize);
+        let address = self.linear_address(SegmentRegister::SS as usize, rsp, 8) as usize;
+        self.check_stack_alignment(address, 8);
+        let ret = self.get_memory64(address);
+        self.set_register64(Register::ESP as usize, rsp.wrapping_add(8));
         ret
     }
 
+    fn is_virtual_8086(&self) -> bool {
+        (self.eflags & Self::VIRTUAL_8086_FLAG) != 0
+    }
+
+    fn is_real_mode(&self) -> bool {
+        self.control_registers[0] & CR0_PE == 0
+    }
+
+    // セレクタを16倍した値をベースとして使うモード(リアルモードとV86モード)
+    fn uses_real_mode_segments(&self) -> bool {
+        self.is_real_mode() || self.is_virtual_8086()
+    }
+
+    // ロングモードでLビットの立ったコードセグメントを実行している
+    fn is_64bit_mode(&self) -> bool {
+        self.msr.efer & EFER_LMA != 0 && self.segment_caches[SegmentRegister::CS as usize].is_64bit()
+    }
+
+    fn is_operand_size16(&self) -> bool {
+        // 既定のサイズはCSのD/Bビットで決まり(64ビットモードでは32ビット)、プレフィックスが付くと反転する
+        // REX.Wが付いていれば66プレフィックスは無視される
+        let default32 = self.segment_caches[SegmentRegister::CS as usize].is_32bit() || self.is_64bit_mode();
+        default32 == self.operand_size_override && !self.is_operand_size64()
+    }
+
+    // REX.Wは64ビットモードでだけ付けられる
+    fn is_operand_size64(&self) -> bool {
+        self.rex & REX_W != 0
+    }
+
+    fn is_address_size16(&self) -> bool {
+        !self.is_64bit_mode() && self.segment_caches[SegmentRegister::CS as usize].is_32bit() == self.address_size_override
+    }
+
+    // 64ビットモードのアドレスサイズは64ビットで、67プレフィックスが付くと32ビットになる
+    fn is_address_size64(&self) -> bool {
+        self.is_64bit_mode() && !self.address_size_override
+    }
+
+    fn segment_base(&self, index: usize) -> u32 {
+        self.segment_caches[index].base
+    }
+
+    // セグメントのoffsetからsizeバイトにアクセスするときのリニアアドレス
+    // セグメントが使えないかリミットを超えていれば、SSなら#SS(0)、それ以外は#GP(0)を記録する(8086は確かめない)
+    // 64ビットモードではFSとGSのベースだけを足してリミットは確かめず、正規形でないアドレスをフォールトにする
+    fn linear_address(&mut self, index: usize, offset: u64, size: u32) -> u64 {
+        let cache = self.segment_caches[index];
+        let (address, valid) = if self.is_64bit_mode() {
+            let base = if index == SegmentRegister::FS as usize || index == SegmentRegister::GS as usize {
+                cache.base as u64
+            } else {
+                0
+            };
+            let address = base.wrapping_add(offset);
+            (address, is_canonical(address) && is_canonical(address.wrapping_add(size as u64 - 1)))
+        } else {
+            let valid = self.cpu_model == CpuModel::I8086 || (cache.is_present() && cache.contains(offset as u32, size));
+            (cache.base.wrapping_add(offset as u32) as u64, valid)
+        };
+        if !valid && self.memory_fault.is_none() {
+            self.memory_fault = Some(if index == SegmentRegister::SS as usize {
+                stack_fault(0)
+            } else {
+                general_protection(0)
+            });
+        }
+        address
+    }
+
+    fn set_flag(&mut self, flag: u32, value: bool) {
+        if value {
+            self.eflags |= flag;
+        } else {
+            self.eflags &= !flag;
+        }
+    }
+
+    fn set_eflags(&mut self, value: u32) {
+        self.eflags = (value & Self::DEFINED_FLAGS) | Self::FIXED_FLAGS;
+    }
+
     fn is_carry(&self) -> bool {
         (self.eflags & Self::CARRY_FLAG) != 0
     }
@@ -154,6 +538,10 @@ impl EmulatorFunction for Emulator {
         (self.eflags & Self::OVERFLOW_FLAG) != 0
     }
 
+    fn get_iopl(&self) -> u8 {
+        ((self.eflags & Self::IOPL_MASK) >> 12) as u8
+    }
+
     fn set_carry(&mut self, is_carry: bool) {
         if is_carry {
             self.eflags |= Self::CARRY_FLAG;
@@ -195,28 +583,146 @@ impl EmulatorFunction for Emulator {
         self.set_zero(result == 0);
         self.set_sign(signr);
         self.set_overflow(sign1 != sign2 && sign1 != signr);
+        self.set_parity_adjust(v1, v2, result as u32);
+    }
+
+    fn update_eflags_sub16(&mut self, v1: u16, v2: u16, result: u32) {
+        let sign1 = (v1 >> 15) == 1;
+        let sign2 = (v2 >> 15) == 1;
+        let signr = ((result >> 15) & 1) == 1;
+
+        self.set_carry((result >> 16) != 0);
+        self.set_zero(result & 0xffff == 0);
+        self.set_sign(signr);
+        self.set_overflow(sign1 != sign2 && sign1 != signr);
+        self.set_parity_adjust(v1 as u32, v2 as u32, result);
+    }
+
+    fn update_eflags_sub64(&mut self, v1: u64, v2: u64, result: u128) {
+        let sign1 = (v1 >> 63) == 1;
+        let sign2 = (v2 >> 63) == 1;
+        let signr = ((result >> 63) & 1) == 1;
+
+        self.set_carry((result >> 64) != 0);
+        self.set_zero(result as u64 == 0);
+        self.set_sign(signr);
+        self.set_overflow(sign1 != sign2 && sign1 != signr);
+        self.set_parity_adjust(v1 as u32, v2 as u32, result as u32);
     }
 }
 
 impl Instruction for Emulator {
-    fn run_instructions(&mut self, quiet: bool) {
-        while self.eip < MEMORY_SIZE as u32 {
-            self.exec_instruction(quiet);
+    // budgetを指定すると、その数の命令を実行したところで止まる
+    fn run_instructions(&mut self, quiet: bool, budget: Option<u64>) -> Result<StopReason, EmuError> {
+        let mut executed = 0;
+        loop {
+            if budget == Some(executed) {
+                return Ok(StopReason::BudgetExhausted);
+            }
+            // HLTで停止したままなら、割り込みが来るまで命令を実行しない
+            if self.halted && !self.has_deliverable_interrupt() {
+                return Ok(StopReason::Halted);
+            }
+            // ブレークポイントで止まった後に再開したときは、最初の命令をそのまま実行する
+            if executed > 0 {
+                if let Some(address) = self.reached_breakpoint() {
+                    return Ok(StopReason::Breakpoint(address));
+                }
+            }
+            self.exec_instruction(quiet)?;
+            executed += 1;
+            if let Some(reason) = self.stop_request.take() {
+                return Ok(reason);
+            }
             if self.eip == 0x00 {
-                println!("end of program.");
-                break;
+                return Ok(StopReason::GuestExit);
             }
         }
     }
 
-    fn exec_instruction(&mut self, quiet: bool) {
+    fn exec_instruction(&mut self, quiet: bool) -> Result<(), EmuError> {
+        // MOV SS/POP SSの直後の命令では命令ブレークポイントを検出しない
+        let inhibited = self.interrupt_shadow;
+        self.interrupt_shadow = false;
+        // IFが立っていれば、命令の前にデバイスからの割り込みを受け付ける
+        if !inhibited && self.eflags & Self::INTERRUPT_FLAG != 0 {
+            if let Some(irq) = self.interrupt_lines.highest_priority() {
+                self.interrupt_lines.acknowledge(irq);
+                self.halted = false;
+                self.instruction_eip = self.eip;
+                self.raise_interrupt(self.irq_vector_base.wrapping_add(irq));
+                return Ok(());
+            }
+        }
+        // RFが立っていれば命令ブレークポイントを1命令分だけ無視する
+        if self.eflags & Self::RESUME_FLAG != 0 {
+            self.eflags &= !Self::RESUME_FLAG;
+        } else if !inhibited && self.check_instruction_breakpoint() {
+            self.deliver_debug_exception();
+            return Ok(());
+        }
+        let single_step = self.eflags & Self::TRAP_FLAG != 0;
+        self.interrupt_delivered = false;
+        self.instruction_eip = self.eip;
+        // メモリアクセスでフォールトが起きたら、命令の実行前の状態に戻せるようにしておく
+        let state = self.save_cpu_state();
+        self.memory_fault = None;
+        self.memory_journal.clear();
+        self.emulation_error = None;
+
+        // 命令の先頭が読めないアドレスにあれば実行できない(ページがなければ#PFにする)
+        if let Some(address) = self.translate(self.code_address(0), PageAccess::Fetch) {
+            let address = self.mask_a20(address);
+            if self.bus.fetch8(address).is_none() {
+                return Err(EmuError::MemoryFault { address });
+            }
+        }
+
+        // プレフィックスを読み取り、命令の長さと符号化を確かめる(不正な命令は実行せずに#GP/#UDにする)
+        let decoded = if self.memory_fault.is_none() { self.decode_instruction() } else { Ok(0) };
+        if let Some(error) = self.emulation_error.take() {
+            return Err(error);
+        }
+        // 命令のバイトが読めないページにまたがっていれば#PF
+        if let Some(fault) = self.memory_fault.take() {
+            self.raise_exception(fault);
+            return Ok(());
+        }
+        self.instruction_length = match decoded {
+            Ok(length) => length,
+            Err(e) => {
+                self.raise_exception(e);
+                return Ok(());
+            }
+        };
+        // 命令の最後のバイトまでがCSのリミットに収まっていなければ#GP(0)(64ビットモードにはリミットがない)
+        let code_segment = self.segment_caches[SegmentRegister::CS as usize];
+        if self.cpu_model != CpuModel::I8086 && !self.is_64bit_mode() && !code_segment.contains(self.eip as u32, self.instruction_length) {
+            self.raise_exception(general_protection(0));
+            return Ok(());
+        }
+
         let code = self.get_code8(0);
         // 現在のプログラムカウンタと実行されるバイナリを出力する
         if !quiet {
             println!("EIP = {:0X}, Code = {:02X}", self.eip, code);
         }
         match code {
+            // 8086では0FがPOP CSになる
+            0x0F if self.cpu_model == CpuModel::I8086 => self.pop_sreg(SegmentRegister::CS as usize, 1),
+            // 選択したCPUの世代にない命令は#UDにする
+            _ if !self.cpu_model.supports_opcode(code) => self.raise_exception(invalid_opcode()),
+            _ if self.is_64bit_mode() && !self.is_implemented_in_64bit_mode() => self.unimplemented_opcode(),
+            0x63 if self.is_64bit_mode() => self.movsxd(),
             0x01 => self.add_rm32_r32(),
+            0x06 => self.push_sreg(SegmentRegister::ES as usize, 1),
+            0x07 => self.pop_sreg(SegmentRegister::ES as usize, 1),
+            0x0E => self.push_sreg(SegmentRegister::CS as usize, 1),
+            0x0F => self.code_0f(),
+            0x16 => self.push_sreg(SegmentRegister::SS as usize, 1),
+            0x17 => self.pop_sreg(SegmentRegister::SS as usize, 1),
+            0x1E => self.push_sreg(SegmentRegister::DS as usize, 1),
+            0x1F => self.pop_sreg(SegmentRegister::DS as usize, 1),
             0x3B => self.cmp_r32_rm32(),
             0x3C => self.cmp_al_imm8(),
             0x3D => self.cmp_eax_imm32(),
@@ -224,7 +730,12 @@ impl Instruction for Emulator {
             0x50..=0x57 => self.push_r32(),
             0x58..=0x5f => self.pop_r32(),
             0x68 => self.push_imm32(),
+            0x63 => self.arpl(),
             0x6A => self.push_imm8(),
+            0x6C => self.ins_m8_dx(),
+            0x6D => self.ins_m32_dx(),
+            0x6E => self.outs_dx_m8(),
+            0x6F => self.outs_dx_m32(),
             0x70 => self.jo(),
             0x71 => self.jno(),
             0x72 => self.jc(),
@@ -240,49 +751,281 @@ impl Instruction for Emulator {
             0x89 => self.mov_rm32_r32(),
             0x8A => self.mov_r8_rm8(),
             0x8B => self.mov_r32_rm32(),
+            0x8D => self.lea(),
+            0x8C => self.mov_rm16_sreg(),
+            0x8E => self.mov_sreg_rm16(),
+            0x98 => self.cbw(),
+            0x99 => self.cwd(),
+            0x9A => self.call_ptr16_32(),
+            0x9C => self.pushfd(),
+            0x9D => self.popfd(),
+            0x9E => self.sahf(),
+            0x9F => self.lahf(),
+            0xA0 => self.mov_al_moffs8(),
+            0xA1 => self.mov_eax_moffs32(),
+            0xA2 => self.mov_moffs8_al(),
+            0xA3 => self.mov_moffs32_eax(),
             0xB0..=0xB7 => self.mov_r8_imm8(),
             0xB8..=0xBF => self.mov_r32_imm32(),
+            0xC0 | 0xC1 => self.shift_group(),
             0xC3 => self.ret(),
+            0xC4 => self.code_c4(),
+            0xC5 => self.code_c5(),
             0xC7 => self.move_rm32_imm32(),
             0xC9 => self.leave(),
+            0xCA => self.retf_imm16(),
+            0xCB => self.retf(),
+            0xCC => self.int3(),
+            0xCD => self.int_imm8(),
+            0xCF => self.iret(),
             0xE8 => self.call_rel32(),
+            0xD0..=0xD3 => self.shift_group(),
+            0xD6 => self.salc(),
+            0xD7 => self.xlat(),
+            0xE4 => self.in_al_imm8(),
+            0xE5 => self.in_eax_imm8(),
+            0xE6 => self.out_imm8_al(),
+            0xE7 => self.out_imm8_eax(),
             0xE9 => self.near_jump(),
+            0xEA => self.jmp_ptr16_32(),
             0xEB => self.short_jump(),
             0xEC => self.in_al_dx(),
+            0xED => self.in_eax_dx(),
             0xEE => self.out_dx_al(),
+            0xEF => self.out_dx_eax(),
+            0xF1 => self.int1(),
+            0xF4 => self.hlt(),
+            0xF5 => self.cmc(),
+            0xF8 => self.clc(),
+            0xF9 => self.stc(),
+            0xFA => self.cli(),
+            0xFB => self.sti(),
+            0xFC => self.cld(),
+            0xFD => self.std(),
             0xFF => self.code_ff(),
-            _ => {
-                eprintln!("Not Implemented: {:0x}", code);
-                ::std::process::exit(1);
+            _ => self.unimplemented_opcode(),
+        }
+
+        // 実装していない命令は、実行前の状態に戻してからポリシーに従って止めるか#UDにするか読み飛ばす
+        if let Some(error) = self.emulation_error.take() {
+            self.restore_cpu_state(&state);
+            self.eip = self.instruction_eip;
+            if !self.handle_emulation_error(error)? {
+                return Ok(());
             }
         }
+
+        // フォールトした命令がそれまでに書き込んだメモリとレジスタは元に戻し、部分的な書き込みを残さない
+        if let Some(fault) = self.memory_fault.take() {
+            self.restore_cpu_state(&state);
+            self.raise_exception(fault);
+            return Ok(());
+        }
+
+        // タイムスタンプカウンタは実行した命令数で進める
+        self.msr.time_stamp_counter = self.msr.time_stamp_counter.wrapping_add(1);
+
+        // ソフトウェア割り込みなどでハンドラに入った場合はシングルステップのトラップを発生させない
+        if single_step && !self.interrupt_delivered {
+            self.pending_debug |= DR6_BS;
+        }
+        // MOV SS/POP SSで発生したデバッグ例外は次の命令の後にまとめて通知する
+        if self.pending_debug != 0 && !self.interrupt_shadow {
+            self.deliver_debug_exception();
+        }
+        Ok(())
+    }
+
+    fn code_0f(&mut self) {
+        let code = self.get_code8(1);
+        let prefix = self.mandatory_prefix();
+        match code {
+            _ if !self.cpu_model.supports_opcode_0f(code) => self.raise_exception(invalid_opcode()),
+            0x00 => self.code_0f_00(),
+            0x01 => self.code_0f_01(),
+            0x02 => self.lar(),
+            0x03 => self.lsl(),
+            0x05 => self.syscall(),
+            0x06 => self.clts(),
+            0x07 => self.sysret(),
+            0x20 => self.mov_r32_cr(),
+            0x21 => self.mov_r32_dr(),
+            0x22 => self.mov_cr_r32(),
+            0x23 => self.mov_dr_r32(),
+            0x30 => self.wrmsr(),
+            0x31 => self.rdtsc(),
+            0x32 => self.rdmsr(),
+            0x34 => self.sysenter(),
+            0x35 => self.sysexit(),
+            0x10 | 0x28 if prefix == 0 => self.movdq_load(Feature::Sse, code == 0x28),
+            0x11 | 0x29 if prefix == 0 => self.movdq_store(Feature::Sse, code == 0x29),
+            0x10 | 0x28 if prefix == 0x66 => self.movdq_load(Feature::Sse2, code == 0x28),
+            0x11 | 0x29 if prefix == 0x66 => self.movdq_store(Feature::Sse2, code == 0x29),
+            0x38 => self.code_0f_38(),
+            0x3A => self.code_0f_3a(),
+            0x6E if prefix == 0x66 => self.movd_xmm_rm32(),
+            0x6F if prefix == 0x66 => self.movdq_load(Feature::Sse2, true),
+            0x6F if prefix == 0xF3 => self.movdq_load(Feature::Sse2, false),
+            0x70 if prefix == 0x66 => self.pshufd(),
+            0x73 if prefix == 0x66 => self.shift_double_quadword(),
+            0x7E if prefix == 0x66 => self.movd_rm32_xmm(),
+            0x7F if prefix == 0x66 => self.movdq_store(Feature::Sse2, true),
+            0x7F if prefix == 0xF3 => self.movdq_store(Feature::Sse2, false),
+            0xA0 => self.push_sreg(SegmentRegister::FS as usize, 2),
+            0xA1 => self.pop_sreg(SegmentRegister::FS as usize, 2),
+            0xA2 => self.cpuid(),
+            0xA8 => self.push_sreg(SegmentRegister::GS as usize, 2),
+            0xA9 => self.pop_sreg(SegmentRegister::GS as usize, 2),
+            0xB0 | 0xB1 => self.cmpxchg(),
+            0xB2 => self.load_far_pointer(SegmentRegister::SS as usize, 2),
+            0xB4 => self.load_far_pointer(SegmentRegister::FS as usize, 2),
+            0xB5 => self.load_far_pointer(SegmentRegister::GS as usize, 2),
+            0xB8 => self.popcnt(),
+            0xBC => self.bsf(),
+            0xBD => self.bsr(),
+            0xC7 => self.code_0f_c7(),
+            0xC8..=0xCF => self.bswap(),
+            0xEF if prefix == 0x66 => self.pxor(),
+            _ => self.unimplemented_opcode(),
+        }
+    }
+
+    fn code_0f_38(&mut self) {
+        let code = self.get_code8(2);
+        let prefix = self.mandatory_prefix();
+        match code {
+            0xDB if prefix == 0x66 => self.aesimc(),
+            0xDC if prefix == 0x66 => self.aesenc(),
+            0xDD if prefix == 0x66 => self.aesenclast(),
+            0xDE if prefix == 0x66 => self.aesdec(),
+            0xDF if prefix == 0x66 => self.aesdeclast(),
+            0xF0 if self.repeat_prefix == Some(0xF2) => self.crc32(true),
+            0xF1 if self.repeat_prefix == Some(0xF2) => self.crc32(false),
+            0xF0 => self.movbe(false),
+            0xF1 => self.movbe(true),
+            0xF6 => self.adcx_adox(),
+            _ => self.unimplemented_opcode(),
+        }
+    }
+
+    fn code_0f_3a(&mut self) {
+        let code = self.get_code8(2);
+        let prefix = self.mandatory_prefix();
+        match code {
+            0x44 if prefix == 0x66 => self.pclmulqdq(),
+            0xDF if prefix == 0x66 => self.aeskeygenassist(),
+            _ => self.unimplemented_opcode(),
+        }
+    }
+
+    fn code_0f_00(&mut self) {
+        // リアルモードとV86モードではシステムセグメントを扱う命令は使えない
+        if self.uses_real_mode_segments() {
+            self.raise_exception(invalid_opcode());
+            return;
+        }
+        self.eip += 2;
+        let modrm = self.parse_modrm();
+        match modrm.get_opecode() {
+            0 => self.sldt(&modrm),
+            1 => self.str(&modrm),
+            2 => self.lldt(&modrm),
+            3 => self.ltr(&modrm),
+            4 => self.verr(&modrm),
+            5 => self.verw(&modrm),
+            _ => self.unimplemented_opcode(),
+        }
+    }
+
+    fn code_0f_01(&mut self) {
+        self.eip += 2;
+        let modrm = self.parse_modrm();
+        match modrm.get_opecode() {
+            // mod=11の0F 01はXGETBVなど別の命令になる
+            _ if modrm.mode == 3 && modrm.get_opecode() != 4 && modrm.get_opecode() != 6 => self.unimplemented_opcode(),
+            2 => self.lgdt(&modrm),
+            3 => self.lidt(&modrm),
+            4 => self.smsw(&modrm),
+            6 => self.lmsw(&modrm),
+            7 => self.invlpg(),
+            _ => self.unimplemented_opcode(),
+        }
+    }
+
+    fn code_0f_c7(&mut self) {
+        self.eip += 2;
+        let modrm = self.parse_modrm();
+        match modrm.get_opecode() {
+            1 => self.cmpxchg8b(&modrm),
+            _ => self.unimplemented_opcode(),
+        }
     }
 
     fn mov_r32_imm32(&mut self) {
-        let reg = self.get_code8(0) - 0xB8;
+        let reg = self.opcode_register_index(0xB8);
+        if self.is_operand_size16() {
+            let value = self.get_code16(1);
+            self.set_register16(reg, value);
+            self.eip += 3;
+            return;
+        }
+        // REX.Wが付けば即値も8バイトになる
+        if self.is_operand_size64() {
+            let value = self.get_code32(1) as u64 | (self.get_code32(5) as u64) << 32;
+            self.set_register64(reg, value);
+            self.eip += 9;
+            return;
+        }
         let value = self.get_code32(1);
-        self.registers[reg as usize] = value;
+        self.set_register32(reg, value);
         self.eip += 5;
     }
 
     fn move_rm32_imm32(&mut self) {
         self.eip += 1;
         let modrm = self.parse_modrm();
+        if self.is_operand_size16() {
+            let value = self.get_code16(0);
+            self.eip += 2;
+            self.set_rm16(&modrm, value);
+            return;
+        }
         let value = self.get_code32(0);
         self.eip += 4;
+        // REX.Wでは32ビットの即値を符号拡張する
+        if self.is_operand_size64() {
+            self.set_rm64(&modrm, value as i32 as i64 as u64);
+            return;
+        }
         self.set_rm32(&modrm, value);
     }
 
     fn mov_rm32_r32(&mut self) {
         self.eip += 1;
         let modrm = self.parse_modrm();
+        if self.is_operand_size16() {
+            let r16 = self.get_r16(&modrm);
+            self.set_rm16(&modrm, r16);
+            return;
+        }
+        if self.is_operand_size64() {
+            let r64 = self.get_r64(&modrm);
+            self.set_rm64(&modrm, r64);
+            return;
+        }
         let r32 = self.get_r32(&modrm);
         self.set_rm32(&modrm, r32);
     }
 
     fn inc_r32(&mut self) {
         let reg = self.get_code8(0) - 0x40;
-        let value = self.get_register32(reg as usize) + 1;
+        if self.is_operand_size16() {
+            let value = self.get_register16(reg as usize).wrapping_add(1);
+            self.set_register16(reg as usize, value);
+            self.eip += 1;
+            return;
+        }
+        let value = self.get_register32(reg as usize).wrapping_add(1);
         self.set_register32(reg as usize, value);
         self.eip += 1;
     }
@@ -297,86 +1040,212 @@ impl Instruction for Emulator {
     fn mov_r32_rm32(&mut self) {
         self.eip += 1;
         let modrm = self.parse_modrm();
+        if self.is_operand_size16() {
+            let rm16 = self.get_rm16(&modrm);
+            self.set_r16(&modrm, rm16);
+            return;
+        }
+        if self.is_operand_size64() {
+            let rm64 = self.get_rm64(&modrm);
+            self.set_r64(&modrm, rm64);
+            return;
+        }
         let rm32 = self.get_rm32(&modrm);
         self.set_r32(&modrm, rm32);
     }
 
+    // セグメントのベースを足さない実効アドレスを格納する(レジスタ形式はデコード時に#UDにしている)
+    fn lea(&mut self) {
+        self.eip += 1;
+        let modrm = self.parse_modrm();
+        let address = self.calc_memory_address(&modrm);
+        if self.is_operand_size16() {
+            self.set_r16(&modrm, address as u16);
+        } else if self.is_operand_size64() {
+            self.set_r64(&modrm, address);
+        } else {
+            self.set_r32(&modrm, address as u32);
+        }
+    }
+
+    fn mov_al_moffs8(&mut self) {
+        let address = self.get_moffs_address(1);
+        let value = self.get_memory8(address) as u8;
+        self.set_register8(Register8::AL as usize, value);
+    }
+
+    fn mov_eax_moffs32(&mut self) {
+        let size = self.operand_size();
+        let address = self.get_moffs_address(size);
+        let value = self.get_memory_sized(address, size);
+        self.set_accumulator(size, value);
+    }
+
+    fn mov_moffs8_al(&mut self) {
+        let address = self.get_moffs_address(1);
+        let value = self.get_register8(Register8::AL as usize);
+        self.set_memory8(address, value as u32);
+    }
+
+    fn mov_moffs32_eax(&mut self) {
+        let size = self.operand_size();
+        let address = self.get_moffs_address(size);
+        let value = self.get_accumulator(size);
+        self.set_memory_sized(address, size, value);
+    }
+
     fn mov_r8_imm8(&mut self) {
-        let reg = self.get_code8(0) - 0xB0;
+        let reg = self.opcode_register_index(0xB0);
         let value = self.get_code8(1);
-        self.set_register8(reg as usize, value);
+        self.set_byte_register(reg, value);
         self.eip += 2;
     }
 
     fn add_rm32_r32(&mut self) {
         self.eip += 1;
         let modrm = self.parse_modrm();
+        if self.is_operand_size16() {
+            let r16 = self.get_r16(&modrm);
+            let rm16 = self.get_rm16(&modrm);
+            self.set_rm16(&modrm, rm16.wrapping_add(r16));
+            return;
+        }
+        if self.is_operand_size64() {
+            let r64 = self.get_r64(&modrm);
+            let rm64 = self.get_rm64(&modrm);
+            self.set_rm64(&modrm, rm64.wrapping_add(r64));
+            return;
+        }
         let r32 = self.get_r32(&modrm);
         let rm32 = self.get_rm32(&modrm);
-        self.set_rm32(&modrm, rm32 + r32);
+        self.set_rm32(&modrm, rm32.wrapping_add(r32));
     }
 
     fn add_rm32_imm8(&mut self, modrm: &ModRM) {
+        if self.is_operand_size16() {
+            let rm16 = self.get_rm16(modrm);
+            let imm8 = self.get_sign_code8(0) as u16;
+            self.eip += 1;
+            self.set_rm16(modrm, rm16.wrapping_add(imm8));
+            return;
+        }
+        if self.is_operand_size64() {
+            let rm64 = self.get_rm64(modrm);
+            let imm8 = self.get_sign_code8(0) as i64 as u64;
+            self.eip += 1;
+            self.set_rm64(modrm, rm64.wrapping_add(imm8));
+            return;
+        }
         let rm32 = self.get_rm32(modrm);
-        let imm8 = self.get_sign_code8(0) as i32;
+        let imm8 = self.get_sign_code8(0) as i32 as u32;
         self.eip += 1;
-
-        let sum: u32;
-        if imm8 > 0 {
-            sum = rm32 + imm8 as u32;
-        } else {
-            sum = rm32 - (imm8.abs() as u32);
-        }
-        self.set_rm32(modrm, sum);
+        self.set_rm32(modrm, rm32.wrapping_add(imm8));
     }
 
     fn sub_rm32_imm8(&mut self, modrm: &ModRM) {
+        if self.is_operand_size16() {
+            let rm16 = self.get_rm16(modrm);
+            let imm8 = self.get_sign_code8(0) as u16;
+            self.eip += 1;
+            let result = (rm16 as u32).wrapping_sub(imm8 as u32);
+            self.set_rm16(modrm, result as u16);
+            self.update_eflags_sub16(rm16, imm8, result);
+            return;
+        }
+        if self.is_operand_size64() {
+            let rm64 = self.get_rm64(modrm);
+            let imm8 = self.get_sign_code8(0) as i64 as u64;
+            self.eip += 1;
+            let result = (rm64 as u128).wrapping_sub(imm8 as u128);
+            self.set_rm64(modrm, result as u64);
+            self.update_eflags_sub64(rm64, imm8, result);
+            return;
+        }
         let rm32 = self.get_rm32(&modrm);
-        let imm8 = self.get_sign_code8(0) as i32;
+        let imm8 = self.get_sign_code8(0) as i32 as u32;
         self.eip += 1;
-        // imm8の正負で条件分岐
-        let result = if imm8 >= 0 { rm32 - (imm8 as u32) } else { rm32 + (imm8.abs() as u32) };
-        self.set_rm32(&modrm, result);
-        self.update_eflags_sub(rm32, imm8 as u32, result as u64);
+        // 上位32ビットに残る借りがCFになる
+        let result = (rm32 as u64).wrapping_sub(imm8 as u64);
+        self.set_rm32(&modrm, result as u32);
+        self.update_eflags_sub(rm32, imm8, result);
     }
 
     fn cmp_rm32_imm8(&mut self, modrm: &ModRM) {
+        if self.is_operand_size16() {
+            let rm16 = self.get_rm16(modrm);
+            let imm8 = self.get_sign_code8(0) as u16;
+            self.eip += 1;
+            let result = (rm16 as u32).wrapping_sub(imm8 as u32);
+            self.update_eflags_sub16(rm16, imm8, result);
+            return;
+        }
+        if self.is_operand_size64() {
+            let rm64 = self.get_rm64(modrm);
+            let imm8 = self.get_sign_code8(0) as i64 as u64;
+            self.eip += 1;
+            let result = (rm64 as u128).wrapping_sub(imm8 as u128);
+            self.update_eflags_sub64(rm64, imm8, result);
+            return;
+        }
         let rm32 = self.get_rm32(modrm);
-        let imm8 = self.get_sign_code8(0) as i32;
+        let imm8 = self.get_sign_code8(0) as i32 as u32;
         self.eip += 1;
-        let result = if imm8 >= 0 { rm32 - (imm8 as u32) } else { rm32 + (imm8.abs() as u32) };
-        self.update_eflags_sub(rm32, imm8 as u32, result as u64);
+        let result = (rm32 as u64).wrapping_sub(imm8 as u64);
+        self.update_eflags_sub(rm32, imm8, result);
     }
 
     fn cmp_r32_rm32(&mut self) {
         self.eip += 1;
         let modrm = self.parse_modrm();
+        if self.is_operand_size16() {
+            let r16 = self.get_r16(&modrm);
+            let rm16 = self.get_rm16(&modrm);
+            let result = (r16 as u32).wrapping_sub(rm16 as u32);
+            self.update_eflags_sub16(r16, rm16, result);
+            return;
+        }
+        if self.is_operand_size64() {
+            let r64 = self.get_r64(&modrm);
+            let rm64 = self.get_rm64(&modrm);
+            let result = (r64 as u128).wrapping_sub(rm64 as u128);
+            self.update_eflags_sub64(r64, rm64, result);
+            return;
+        }
         let r32 = self.get_r32(&modrm);
         let rm32 = self.get_rm32(&modrm);
-        let result = if r32 > rm32 {
-            r32 as u64 - rm32 as u64
-        } else {
-            (r32 as i64 - rm32 as i64).abs() as u64
-        };
+        let result = (r32 as u64).wrapping_sub(rm32 as u64);
         self.update_eflags_sub(r32, rm32, result);
     }
 
     fn cmp_al_imm8(&mut self) {
         let value = self.get_code8(1);
         let al = self.get_register8(Register8::AL as usize);
-        let result = if al > value {
-            al as u64 - value as u64
-        } else {
-            (al as i32 - value as i32).abs() as u64
-        };
+        let result = (al as u64).wrapping_sub(value as u64);
         self.update_eflags_sub(al as u32, value as u32, result);
         self.eip += 2;
     }
 
     fn cmp_eax_imm32(&mut self) {
+        if self.is_operand_size16() {
+            let value = self.get_code16(1);
+            let ax = self.get_register16(Register::EAX as usize);
+            let result = (ax as u32).wrapping_sub(value as u32);
+            self.update_eflags_sub16(ax, value, result);
+            self.eip += 3;
+            return;
+        }
+        // REX.Wでは32ビットの即値を符号拡張してRAXと比べる
+        if self.is_operand_size64() {
+            let value = self.get_code32(1) as i32 as i64 as u64;
+            let rax = self.get_register64(Register::EAX as usize);
+            let result = (rax as u128).wrapping_sub(value as u128);
+            self.update_eflags_sub64(rax, value, result);
+            self.eip += 5;
+            return;
+        }
         let value = self.get_code32(1);
         let eax = self.get_register32(Register::EAX as usize);
-        let result = eax as u64 - value as u64;
+        let result = (eax as u64).wrapping_sub(value as u64);
         self.update_eflags_sub(eax, value, result);
         self.eip += 5
     }
@@ -388,7 +1257,7 @@ impl Instruction for Emulator {
             0 => self.add_rm32_imm8(&modrm),
             5 => self.sub_rm32_imm8(&modrm),
             7 => self.cmp_rm32_imm8(&modrm),
-            opecode @ _ => panic!("not implemented: 83 /{}", opecode),
+            _ => self.unimplemented_opcode(),
         }
     }
 
@@ -400,8 +1269,18 @@ impl Instruction for Emulator {
     }
 
     fn inc_rm32(&mut self, modrm: &ModRM) {
+        if self.is_operand_size16() {
+            let value = self.get_rm16(modrm);
+            self.set_rm16(modrm, value.wrapping_add(1));
+            return;
+        }
+        if self.is_operand_size64() {
+            let value = self.get_rm64(modrm);
+            self.set_rm64(modrm, value.wrapping_add(1));
+            return;
+        }
         let value = self.get_rm32(&modrm);
-        self.set_rm32(&modrm, value + 1);
+        self.set_rm32(&modrm, value.wrapping_add(1));
     }
 
     fn code_ff(&mut self) {
@@ -409,25 +1288,68 @@ impl Instruction for Emulator {
         let modrm = self.parse_modrm();
         match modrm.get_opecode() {
             0 => self.inc_rm32(&modrm),
-            opecode @ _ => panic!("not implemented: FF /{}", opecode),
+            3 => self.call_m16_32(&modrm),
+            5 => self.jmp_m16_32(&modrm),
+            _ => self.unimplemented_opcode(),
         }
     }
 
     fn push_r32(&mut self) {
         let push_r32_code = 0x50;
-        let reg = self.get_code8(0) - push_r32_code;
-        let value = self.get_register32(reg as usize);
+        let reg = self.opcode_register_index(push_r32_code);
+        if self.is_operand_size16() {
+            let mut value = self.get_register16(reg);
+            // 8086のPUSH SPはデクリメントした後のSPを積む
+            if reg == Register::ESP as usize && !self.cpu_model.pushes_original_stack_pointer() {
+                value = value.wrapping_sub(2);
+            }
+            self.push16(value);
+            self.eip += 1;
+            return;
+        }
+        // 64ビットモードのスタック操作は常に8バイト
+        if self.is_64bit_mode() {
+            let value = self.get_register64(reg);
+            self.push64(value);
+            self.eip += 1;
+            return;
+        }
+        let value = self.get_register32(reg);
         self.push32(value);
         self.eip += 1;
     }
 
     fn push_imm8(&mut self) {
+        if self.is_operand_size16() {
+            let value = self.get_sign_code8(1) as u16;
+            self.push16(value);
+            self.eip += 2;
+            return;
+        }
+        if self.is_64bit_mode() {
+            let value = self.get_sign_code8(1) as i64 as u64;
+            self.push64(value);
+            self.eip += 2;
+            return;
+        }
         let value = self.get_code8(1);
         self.push32(value as u32);
         self.eip += 2;
     }
 
     fn push_imm32(&mut self) {
+        if self.is_operand_size16() {
+            let value = self.get_code16(1);
+            self.push16(value);
+            self.eip += 3;
+            return;
+        }
+        if self.is_64bit_mode() {
+            let value = self.get_code32(1) as i32 as i64 as u64;
+            self.push64(value);
+            self.eip += 5;
+            return;
+        }
         let value = self.get_code32(1);
         self.push32(value);
         self.eip += 5;
@@ -435,28 +1357,104 @@ impl Instruction for Emulator {
 
     fn pop_r32(&mut self) {
         let pop_r32_code = 0x58;
-        let reg = self.get_code8(0) - pop_r32_code;
+        let reg = self.opcode_register_index(pop_r32_code);
+        if self.is_operand_size16() {
+            let value = self.pop16();
+            self.set_register16(reg, value);
+            self.eip += 1;
+            return;
+        }
+        if self.is_64bit_mode() {
+            let value = self.pop64();
+            self.set_register64(reg, value);
+            self.eip += 1;
+            return;
+        }
         let value = self.pop32();
-        self.set_register32(reg as usize, value);
+        self.set_register32(reg, value);
         self.eip += 1;
     }
 
     fn call_rel32(&mut self) {
+        if self.is_operand_size16() {
+            let diff = self.get_code16(1) as i16;
+            let ip = (self.eip as u16).wrapping_add(3);
+            self.push16(ip);
+            self.jump_relative(3, diff as i32);
+            return;
+        }
         let diff = self.get_sign_code32(1);
-        let eip = self.eip;
-        self.push32(eip + 5);
-        if diff + 5 > 0 {
-            self.eip += (diff + 5) as u32;
-        } else {
-            self.eip -= (diff + 5).abs() as u32;
+        if self.is_64bit_mode() {
+            let rip = self.eip.wrapping_add(5);
+            self.push64(rip);
+            self.jump_relative(5, diff);
+            return;
         }
+        let eip = (self.eip as u32).wrapping_add(5);
+        self.push32(eip);
+        self.jump_relative(5, diff);
     }
 
     fn ret(&mut self) {
-        self.eip = self.pop32();
+        if self.is_operand_size16() {
+            self.eip = self.pop16() as u64;
+            return;
+        }
+        if self.is_64bit_mode() {
+            self.eip = self.pop64();
+            return;
+        }
+        self.eip = self.pop32() as u64;
+    }
+
+    fn pushfd(&mut self) {
+        if self.is_virtual_8086() {
+            self.virtual_8086_pushf();
+            return;
+        }
+        // RFはスタックに積まない(16ビットのPUSHFは下位16ビットだけを積む)
+        let eflags = self.eflags & !Self::RESUME_FLAG;
+        if self.is_operand_size16() {
+            self.push16(eflags as u16);
+        } else {
+            self.push32(eflags);
+        }
+        self.eip += 1;
+    }
+
+    fn popfd(&mut self) {
+        if self.is_virtual_8086() {
+            self.virtual_8086_popf();
+            return;
+        }
+        // VM, VIF, VIPは変更できず、IOPLはリング0でのみ、IFはCPL<=IOPLのときのみ変更できる
+        let mut mask = !(Self::RESUME_FLAG | Self::VIRTUAL_8086_FLAG | Self::VIRTUAL_INTERRUPT_FLAG | Self::VIRTUAL_INTERRUPT_PENDING);
+        if self.cpl() != 0 {
+            mask &= !Self::IOPL_MASK;
+        }
+        if self.cpl() > self.get_iopl() {
+            mask &= !Self::INTERRUPT_FLAG;
+        }
+        // 16ビットのPOPFは下位16ビットだけを変更する
+        let value = if self.is_operand_size16() {
+            mask &= 0xffff;
+            self.pop16() as u32
+        } else {
+            self.pop32()
+        };
+        self.set_eflags((self.eflags & !mask) | (value & mask));
+        self.eip += 1;
     }
 
     fn leave(&mut self) {
+        if self.is_operand_size16() {
+            let bp = self.get_register16(Register::EBP as usize);
+            self.set_stack_pointer(bp as u32);
+            let value = self.pop16();
+            self.set_register16(Register::EBP as usize, value);
+            self.eip += 1;
+            return;
+        }
         let ebp = self.get_register32(Register::EBP as usize);
         self.set_register32(Register::ESP as usize, ebp);
         let value = self.pop32();
@@ -465,164 +1463,891 @@ impl Instruction for Emulator {
     }
 
     fn short_jump(&mut self) {
-        let diff: i8 = self.get_sign_code8(1) + 2;
-        match diff > 0 {
-            true => {
-                self.eip += diff as u32;
-            }
-            false => {
-                self.eip -= diff.abs() as u32;
-            }
-        }
+        let diff = self.get_sign_code8(1);
+        self.jump_relative(2, diff as i32);
     }
 
     fn near_jump(&mut self) {
-        let diff = self.get_sign_code32(1) + 5;
-        match diff > 0 {
-            true => {
-                self.eip += diff as u32;
-            }
-            false => {
-                self.eip -= diff.abs() as u32;
-            }
+        if self.is_operand_size16() {
+            let diff = self.get_code16(1) as i16;
+            self.jump_relative(3, diff as i32);
+            return;
         }
+        let diff = self.get_sign_code32(1);
+        self.jump_relative(5, diff);
     }
 
     fn in_al_dx(&mut self) {
-        let address = self.get_register32(Register::EDX as usize) & 0xffff;
-        let value = Self::io_in8(address as u16);
-        self.set_register8(Register8::AL as usize, value);
-        self.eip += 1;
+        let port = self.get_register16(Register::EDX as usize);
+        self.input_accumulator(port, 1, 1);
     }
 
-    fn out_dx_al(&mut self) {
-        let address = self.get_register32(Register::EDX as usize) & 0xffff;
-        let value = self.get_register8(Register8::AL as usize);
-        Self::io_out8(address as u16, value);
-        self.eip += 1;
+    fn in_eax_dx(&mut self) {
+        let port = self.get_register16(Register::EDX as usize);
+        let size = self.operand_size();
+        self.input_accumulator(port, size, 1);
     }
 
-    fn jo(&mut self) {
-        let diff = if self.is_overflow() { self.get_sign_code8(1) } else { 0 };
-        if diff + 2 > 0 {
-            self.eip += (diff + 2) as u32;
-        } else {
-            self.eip -= (diff + 2).abs() as u32;
-        }
+    fn in_al_imm8(&mut self) {
+        let port = self.get_code8(1) as u16;
+        self.input_accumulator(port, 1, 2);
     }
 
-    fn jno(&mut self) {
-        let diff = if self.is_overflow() { 0 } else { self.get_sign_code8(1) };
-        if diff + 2 > 0 {
-            self.eip += (diff + 2) as u32;
-        } else {
-            self.eip -= (diff + 2).abs() as u32;
-        }
+    fn in_eax_imm8(&mut self) {
+        let port = self.get_code8(1) as u16;
+        let size = self.operand_size();
+        self.input_accumulator(port, size, 2);
     }
 
-    fn jc(&mut self) {
-        let diff = if self.is_carry() { self.get_sign_code8(1) } else { 0 };
-        if diff + 2 > 0 {
-            self.eip += (diff + 2) as u32;
-        } else {
-            self.eip -= (diff + 2).abs() as u32;
-        }
+    fn out_dx_al(&mut self) {
+        let port = self.get_register16(Register::EDX as usize);
+        self.output_accumulator(port, 1, 1);
     }
 
-    fn jnc(&mut self) {
-        let diff = if self.is_carry() { 0 } else { self.get_sign_code8(1) };
-        if diff + 2 > 0 {
-            self.eip += (diff + 2) as u32;
-        } else {
-            self.eip -= (diff + 2).abs() as u32;
-        }
+    fn out_dx_eax(&mut self) {
+        let port = self.get_register16(Register::EDX as usize);
+        let size = self.operand_size();
+        self.output_accumulator(port, size, 1);
     }
 
-    fn jz(&mut self) {
-        let diff = if self.is_zero() { self.get_sign_code8(1) } else { 0 };
-        if diff + 2 > 0 {
-            self.eip += (diff + 2) as u32;
-        } else {
-            self.eip -= (diff + 2).abs() as u32;
-        }
+    fn out_imm8_al(&mut self) {
+        let port = self.get_code8(1) as u16;
+        self.output_accumulator(port, 1, 2);
     }
 
-    fn jnz(&mut self) {
-        let diff = if self.is_zero() { 0 } else { self.get_sign_code8(1) };
-        if diff + 2 > 0 {
-            self.eip += (diff + 2) as u32;
-        } else {
-            self.eip -= (diff + 2).abs() as u32;
-        }
+    fn out_imm8_eax(&mut self) {
+        let port = self.get_code8(1) as u16;
+        let size = self.operand_size();
+        self.output_accumulator(port, size, 2);
     }
 
-    fn js(&mut self) {
-        let diff = if self.is_sign() { self.get_sign_code8(1) } else { 0 };
-        if diff + 2 > 0 {
-            self.eip += (diff + 2) as u32;
-        } else {
-            self.eip -= (diff + 2).abs() as u32;
+    fn ins_m8_dx(&mut self) {
+        self.input_string(1);
+    }
+
+    fn ins_m32_dx(&mut self) {
+        let size = self.operand_size();
+        self.input_string(size);
+    }
+
+    fn outs_dx_m8(&mut self) {
+        self.output_string(1);
+    }
+
+    fn outs_dx_m32(&mut self) {
+        let size = self.operand_size();
+        self.output_string(size);
+    }
+
+    fn cli(&mut self) {
+        if self.is_virtual_8086() {
+            self.virtual_8086_cli();
+            return;
+        }
+        if self.cpl() > self.get_iopl() {
+            self.raise_exception(general_protection(0));
+            return;
         }
+        self.eflags &= !Self::INTERRUPT_FLAG;
+        self.eip += 1;
     }
 
-    fn jns(&mut self) {
-        let diff = if self.is_sign() { 0 } else { self.get_sign_code8(1) };
-        if diff + 2 > 0 {
-            self.eip += (diff + 2) as u32;
-        } else {
-            self.eip -= (diff + 2).abs() as u32;
+    fn sti(&mut self) {
+        if self.is_virtual_8086() {
+            self.virtual_8086_sti();
+            return;
         }
+        if self.cpl() > self.get_iopl() {
+            self.raise_exception(general_protection(0));
+            return;
+        }
+        self.eflags |= Self::INTERRUPT_FLAG;
+        self.eip += 1;
     }
 
-    fn jl(&mut self) {
-        let diff = if self.is_sign() != self.is_overflow() {
-            self.get_sign_code8(1)
+    fn clc(&mut self) {
+        self.set_carry(false);
+        self.eip += 1;
+    }
+
+    fn stc(&mut self) {
+        self.set_carry(true);
+        self.eip += 1;
+    }
+
+    // CPL=0でなければ#GP(0)。受け付けられる割り込みが来るまで停止し、その間はホストに制御を返す
+    fn hlt(&mut self) {
+        if self.cpl() != 0 {
+            self.raise_exception(general_protection(0));
+            return;
+        }
+        self.eip += 1;
+        self.halted = true;
+        if !self.has_deliverable_interrupt() {
+            self.stop_request = Some(StopReason::Halted);
+        }
+    }
+
+    fn cmc(&mut self) {
+        let carry = self.is_carry();
+        self.set_carry(!carry);
+        self.eip += 1;
+    }
+
+    fn cld(&mut self) {
+        self.set_flag(Self::DIRECTION_FLAG, false);
+        self.eip += 1;
+    }
+
+    fn std(&mut self) {
+        self.set_flag(Self::DIRECTION_FLAG, true);
+        self.eip += 1;
+    }
+
+    fn lahf(&mut self) {
+        // SF, ZF, AF, PF, CFと固定ビットをAHへ読み出す
+        let flags = self.eflags & (Self::SIGN_FLAG | Self::ZERO_FLAG | Self::ADJUST_FLAG | Self::PARITY_FLAG | Self::CARRY_FLAG);
+        self.set_register8(Register8::AH as usize, (flags | Self::FIXED_FLAGS) as u8);
+        self.eip += 1;
+    }
+
+    fn sahf(&mut self) {
+        let mask = Self::SIGN_FLAG | Self::ZERO_FLAG | Self::ADJUST_FLAG | Self::PARITY_FLAG | Self::CARRY_FLAG;
+        let ah = self.get_register8(Register8::AH as usize) as u32;
+        self.eflags = (self.eflags & !mask) | (ah & mask);
+        self.eip += 1;
+    }
+
+    fn xlat(&mut self) {
+        // DS:[EBX + AL]のテーブルを引く(セグメントオーバーライドできる)
+        let al = self.get_register8(Register8::AL as usize) as u32;
+        let offset = if self.is_address_size16() {
+            self.get_register16(Register::EBX as usize).wrapping_add(al as u16) as u32
         } else {
-            0
+            self.get_register32(Register::EBX as usize).wrapping_add(al)
         };
-        if diff + 2 > 0 {
-            self.eip += (diff + 2) as u32;
+        let segment = self.segment_override.unwrap_or(SegmentRegister::DS as usize);
+        let address = self.linear_address(segment, offset as u64, 1);
+        let value = self.get_memory8(address as usize) as u8;
+        self.set_register8(Register8::AL as usize, value);
+        self.eip += 1;
+    }
+
+    fn salc(&mut self) {
+        let value = if self.is_carry() { 0xff } else { 0 };
+        self.set_register8(Register8::AL as usize, value);
+        self.eip += 1;
+    }
+
+    fn cbw(&mut self) {
+        // オペランドサイズが16ビットならCBW、32ビットならCWDE、64ビットならCDQE
+        if self.is_operand_size16() {
+            let al = self.get_register8(Register8::AL as usize) as i8;
+            self.set_register16(Register::EAX as usize, al as u16);
+        } else if self.is_operand_size64() {
+            let eax = self.get_register32(Register::EAX as usize) as i32;
+            self.set_register64(Register::EAX as usize, eax as i64 as u64);
         } else {
-            self.eip -= (diff + 2).abs() as u32;
+            let ax = self.get_register16(Register::EAX as usize) as i16;
+            self.set_register32(Register::EAX as usize, ax as u32);
         }
+        self.eip += 1;
     }
 
-    fn jle(&mut self) {
-        let diff = if self.is_zero() || (self.is_sign() != self.is_overflow()) {
-            self.get_sign_code8(1)
-        } else {
-            0
-        };
-        if diff + 2 > 0 {
-            self.eip += (diff + 2) as u32;
+    fn cwd(&mut self) {
+        // オペランドサイズが16ビットならCWD、32ビットならCDQ、64ビットならCQO
+        if self.is_operand_size16() {
+            let ax = self.get_register16(Register::EAX as usize) as i16;
+            self.set_register16(Register::EDX as usize, (ax >> 15) as u16);
+        } else if self.is_operand_size64() {
+            let rax = self.get_register64(Register::EAX as usize) as i64;
+            self.set_register64(Register::EDX as usize, (rax >> 63) as u64);
         } else {
-            self.eip -= (diff + 2).abs() as u32;
+            let eax = self.get_register32(Register::EAX as usize) as i32;
+            self.set_register32(Register::EDX as usize, (eax >> 31) as u32);
         }
+        self.eip += 1;
+    }
+
+    fn jo(&mut self) {
+        let condition = self.is_overflow();
+        self.jump_short_if(condition);
+    }
+
+    fn jno(&mut self) {
+        let condition = !self.is_overflow();
+        self.jump_short_if(condition);
+    }
+
+    fn jc(&mut self) {
+        let condition = self.is_carry();
+        self.jump_short_if(condition);
+    }
+
+    fn jnc(&mut self) {
+        let condition = !self.is_carry();
+        self.jump_short_if(condition);
+    }
+
+    fn jz(&mut self) {
+        let condition = self.is_zero();
+        self.jump_short_if(condition);
+    }
+
+    fn jnz(&mut self) {
+        let condition = !self.is_zero();
+        self.jump_short_if(condition);
+    }
+
+    fn js(&mut self) {
+        let condition = self.is_sign();
+        self.jump_short_if(condition);
+    }
+
+    fn jns(&mut self) {
+        let condition = !self.is_sign();
+        self.jump_short_if(condition);
+    }
+
+    fn jl(&mut self) {
+        let condition = self.is_sign() != self.is_overflow();
+        self.jump_short_if(condition);
+    }
+
+    fn jle(&mut self) {
+        let condition = self.is_zero() || (self.is_sign() != self.is_overflow());
+        self.jump_short_if(condition);
     }
 }
 
 impl Emulator {
-    pub fn new(size: usize, eip: u32, esp: u32, file: File) -> Emulator {
-        let memory: Vec<u8> = vec![0; size];
+    // 0番地からsizeバイト(最大4GB)のRAMを用意して、ファイルの先頭を0x7c00に読み込む
+    // RAMは書き込んだページだけを確保するので、大きくしてもホストのメモリはすぐには使わない
+    pub fn new(size: usize, eip: u32, esp: u32, file: File) -> Result<Emulator, MapError> {
+        let mut bus = Bus::new();
+        bus.add_ram(0, size)?;
+        let mut emu = Emulator::with_bus(bus, eip, esp);
+        let mut program = [0; 0x201];
+        let mut br = BufReader::new(file);
+        let _ = br.read_exact(&mut program);
+        emu.bus.load(0x7c00, &program);
+        Ok(emu)
+    }
+
+    // 領域を登録したバスを使う(プログラムはbus_mut()で読み込む)
+    // ポートには既定でCOM1(0x3F8)、キーボードコントローラ(0x60, 0x64)、System Control Port A(0x92)を登録する
+    // これらを置き換えるときは、ports_mut().unregister()で外してから自分のデバイスを登録する
+    pub fn with_bus(bus: Bus, eip: u32, esp: u32) -> Emulator {
+        // COM1の送受信レジスタは標準入出力につないでおく
+        let mut ports = PortBus::new();
+        let _ = ports.register(0x3F8, 1, Box::new(SerialConsole));
+        // A20ゲートはポート0x92とキーボードコントローラから操作する
+        register_a20_devices(&mut ports);
         let mut registers = [0; Register::RegistersCount as usize];
-        registers[Register::ESP as usize] = esp;
+        registers[Register::ESP as usize] = esp as u64;
+        let mut debug_registers = [0; 8];
+        debug_registers[6] = DR6_INITIAL;
+        debug_registers[7] = DR7_INITIAL;
         let mut emu = Emulator {
             registers: registers,
-            eflags: 0,
-            memory: memory,
-            eip: eip,
+            segment_registers: [0; SEGMENT_REGISTERS_COUNT],
+            eflags: Self::FIXED_FLAGS,
+            bus,
+            eip: eip as u64,
+            instruction_eip: eip as u64,
+            gdtr: DescriptorTableRegister::default(),
+            idtr: DescriptorTableRegister::default(),
+            ldtr: LocalDescriptorTableRegister::default(),
+            task_register: TaskRegister::default(),
+            msr: ModelSpecificRegisters::new(),
+            debug_registers,
+            pending_debug: 0,
+            interrupt_delivered: false,
+            interrupt_shadow: false,
+            control_registers: [CR0_INITIAL, 0, 0, 0, 0],
+            cpu_model: CpuModel::default(),
+            operand_size_override: false,
+            address_size_override: false,
+            segment_override: None,
+            repeat_prefix: None,
+            lock_prefix: false,
+            rex: 0,
+            segment_caches: [
+                FLAT_DATA_SEGMENT,
+                FLAT_CODE_SEGMENT,
+                FLAT_DATA_SEGMENT,
+                FLAT_DATA_SEGMENT,
+                FLAT_DATA_SEGMENT,
+                FLAT_DATA_SEGMENT,
+            ],
+            memory_fault: None,
+            supervisor_access: false,
+            memory_journal: Vec::new(),
+            xmm_registers: [0; 8],
+            flags_policy: FlagsPolicy::default(),
+            poison_state: 0x2545_F491,
+            instruction_length: 0,
+            emulation_error: None,
+            stop_request: None,
+            breakpoints: Vec::new(),
+            unimplemented_policy: UnimplementedPolicy::default(),
+            unimplemented_encounters: Vec::new(),
+            ports,
+            interrupt_lines: InterruptLines::default(),
+            irq_vector_base: 0x20,
+            halted: false,
         };
-        let mut br = BufReader::new(file);
-        let _ = br.read_exact(&mut emu.memory[0x7c00..(0x7c00 + 0x201)]);
+        // 保護モードで起動するので、A20ゲートもブートローダが有効にした後の状態にしておく
+        emu.set_a20_enabled(true);
         emu
     }
 
+    // リセット直後と同じリアルモードにする(セレクタとEIPはそのままで、ベースをセレクタの16倍に戻す)
+    pub fn reset_to_real_mode(&mut self) {
+        self.control_registers[0] = 0;
+        self.msr.efer &= !EFER_LMA;
+        // 割り込みベクタテーブルは0番地からの1KB
+        self.idtr = DescriptorTableRegister { base: 0, limit: 0x3FF };
+        for (cache, &selector) in self.segment_caches.iter_mut().zip(self.segment_registers.iter()) {
+            *cache = SegmentCache::real_mode(selector);
+        }
+        // リセット直後はA20ゲートも無効になっている
+        self.set_a20_enabled(false);
+    }
+
+    pub fn bus(&self) -> &Bus {
+        &self.bus
+    }
+
+    pub fn bus_mut(&mut self) -> &mut Bus {
+        &mut self.bus
+    }
+
+    pub fn ports_mut(&mut self) -> &mut PortBus {
+        &mut self.ports
+    }
+
+    pub fn interrupt_lines(&self) -> &InterruptLines {
+        &self.interrupt_lines
+    }
+
+    // ホストからも割り込みラインを上げ下げできる
+    pub fn interrupt_lines_mut(&mut self) -> &mut InterruptLines {
+        &mut self.interrupt_lines
+    }
+
+    pub fn set_irq_vector_base(&mut self, vector: u8) {
+        self.irq_vector_base = vector;
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    // IFが立っていて、通知できる割り込みラインが上がっている
+    fn has_deliverable_interrupt(&self) -> bool {
+        self.eflags & Self::INTERRUPT_FLAG != 0 && self.interrupt_lines.highest_priority().is_some()
+    }
+
+    // ロングモードでは64ビットのRAX〜R15とRIPを表示する
     pub fn dump_registers(&self) {
-        for i in 0..Register::RegistersCount as usize {
-            println!("{} = {:08x}", REGISTERS_NAME[i], self.registers[i]);
+        if self.msr.efer & EFER_LMA != 0 {
+            for (i, name) in REGISTERS_NAME64.iter().enumerate() {
+                println!("{} = {:016x}", name, self.get_register64(i));
+            }
+            println!("RIP = {:016x}", self.eip);
+            return;
+        }
+        for (i, name) in REGISTERS_NAME.iter().enumerate() {
+            println!("{} = {:08x}", name, self.get_register32(i));
         }
         println!("EIP = {:08x}", self.eip);
     }
+
+    // セレクタとディスクリプタキャッシュの内容を表示する
+    pub fn dump_segment_registers(&self) {
+        for (i, name) in SEGMENT_REGISTERS_NAME.iter().enumerate() {
+            let cache = &self.segment_caches[i];
+            println!(
+                "{} = {:04x} (base = {:08x}, limit = {:08x}, attributes = {:08x})",
+                name, self.segment_registers[i], cache.base, cache.limit, cache.attributes
+            );
+        }
+    }
+
+    pub fn segment_cache(&self, register: SegmentRegister) -> SegmentCache {
+        self.segment_caches[register as usize]
+    }
+
+    // デバッガや状態の復元のために、セレクタを読み込まずにディスクリプタキャッシュを書き換える
+    pub fn set_segment_cache(&mut self, register: SegmentRegister, cache: SegmentCache) {
+        self.segment_caches[register as usize] = cache;
+    }
+
+    // CS:EIPからindexバイト先の命令のリニアアドレス(EIPにベースを足して4GBで折り返す)
+    // 64ビットモードではCSのベースを使わず、RIPがそのままリニアアドレスになる
+    fn code_address(&self, index: i32) -> u64 {
+        if self.is_64bit_mode() {
+            return self.eip.wrapping_add(index as i64 as u64);
+        }
+        let offset = (self.eip as u32).wrapping_add(index as u32);
+        self.segment_base(SegmentRegister::CS as usize).wrapping_add(offset) as u64
+    }
+
+    // 複数バイトのアクセスでaddressからindexバイト先のアドレス(64ビットモード以外では4GBの端で0番地へ折り返す)
+    fn byte_address(&self, address: usize, index: u32) -> usize {
+        if self.is_64bit_mode() {
+            address.wrapping_add(index as usize)
+        } else {
+            (address as u32).wrapping_add(index) as usize
+        }
+    }
+
+    // REX.RとREX.Bで8〜15に広げた、ModRMのregとr/mのレジスタ番号
+    fn modrm_reg_index(&self, modrm: &ModRM) -> usize {
+        (modrm.get_reg_index() | (self.rex & REX_R) << 1) as usize
+    }
+
+    fn modrm_rm_index(&self, modrm: &ModRM) -> usize {
+        (modrm.rm | (self.rex & REX_B) << 3) as usize
+    }
+
+    // オペコードの下位3ビットで指定するレジスタの番号(REX.Bで8〜15に広げる)
+    fn opcode_register_index(&mut self, base: u8) -> usize {
+        ((self.get_code8(0) - base) | (self.rex & REX_B) << 3) as usize
+    }
+
+    // REXプレフィックスがあれば4〜7はAH〜BHではなくSPL〜DILになり、8〜15でR8B〜R15Bも使える
+    fn get_byte_register(&self, index: usize) -> u8 {
+        if self.rex == 0 {
+            self.get_register8(index)
+        } else {
+            self.registers[index] as u8
+        }
+    }
+
+    fn set_byte_register(&mut self, index: usize, value: u8) {
+        if self.rex == 0 {
+            self.set_register8(index, value);
+        } else {
+            self.registers[index] = (self.registers[index] & !0xff) | value as u64;
+        }
+    }
+
+    // SSE命令の必須プレフィックス(F3/F2があればそれが優先され、66だけなら66)。なければ0
+    fn mandatory_prefix(&self) -> u8 {
+        match (self.repeat_prefix, self.operand_size_override) {
+            (Some(prefix), _) => prefix,
+            (None, true) => 0x66,
+            (None, false) => 0,
+        }
+    }
+
+    // ディスクリプタテーブルのoffsetバイト目のリニアアドレス(ロングモード以外では4GBで折り返す)
+    fn descriptor_table_address(&self, base: u64, offset: u32) -> usize {
+        if self.msr.efer & EFER_LMA != 0 {
+            base.wrapping_add(offset as u64) as usize
+        } else {
+            (base as u32).wrapping_add(offset) as usize
+        }
+    }
+
+    // LGDT/LIDTのメモリオペランド(リミットとベース)を読む
+    // 16ビットオペランドではベースの下位24ビットだけを使い、64ビットモードではベースが8バイトになる
+    fn read_descriptor_table_register(&mut self, modrm: &ModRM) -> DescriptorTableRegister {
+        let size = if self.is_64bit_mode() { 10 } else { 6 };
+        let address = self.calc_linear_address(modrm, size) as usize;
+        let limit = self.get_memory16(address) as u16;
+        let base = if self.is_64bit_mode() {
+            self.get_memory64(address + 2)
+        } else if self.is_operand_size16() {
+            (self.get_memory32(address + 2) & 0x00FF_FFFF) as u64
+        } else {
+            self.get_memory32(address + 2) as u64
+        };
+        DescriptorTableRegister { base, limit }
+    }
+
+    // ディスクリプタテーブルやTSSはCPLに関係なくスーパーバイザとしてアクセスする
+    fn supervisor_access<T, F: FnOnce(&mut Emulator) -> T>(&mut self, access: F) -> T {
+        let previous = self.supervisor_access;
+        self.supervisor_access = true;
+        let result = access(self);
+        self.supervisor_access = previous;
+        result
+    }
+
+    fn save_cpu_state(&self) -> CpuState {
+        CpuState {
+            registers: self.registers,
+            eflags: self.eflags,
+            segment_registers: self.segment_registers,
+            segment_caches: self.segment_caches,
+        }
+    }
+
+    fn restore_cpu_state(&mut self, state: &CpuState) {
+        while let Some((address, value)) = self.memory_journal.pop() {
+            self.bus.poke8(address, value);
+        }
+        self.registers = state.registers;
+        self.eflags = state.eflags;
+        self.segment_registers = state.segment_registers;
+        self.segment_caches = state.segment_caches;
+    }
+
+    // SSのD/Bビットが立っていなければSPの16ビットだけを使う
+    fn get_stack_pointer(&self) -> u32 {
+        let esp = self.get_register32(Register::ESP as usize);
+        if self.segment_caches[SegmentRegister::SS as usize].is_32bit() {
+            esp
+        } else {
+            esp & 0xffff
+        }
+    }
+
+    fn set_stack_pointer(&mut self, value: u32) {
+        if !self.segment_caches[SegmentRegister::SS as usize].is_32bit() {
+            self.set_register16(Register::ESP as usize, value as u16);
+        } else {
+            self.set_register32(Register::ESP as usize, value);
+        }
+    }
+
+    // 次の命令の位置(eip + length)にrelを足した先へ分岐する。オペランドサイズが16ビットならIPは64KBで折り返す
+    // 64ビットモードでは符号拡張したrelをRIPに足す
+    fn jump_relative(&mut self, length: u32, rel: i32) {
+        if self.is_64bit_mode() {
+            self.eip = self.eip.wrapping_add(length as u64).wrapping_add(rel as i64 as u64);
+            return;
+        }
+        let target = (self.eip as u32).wrapping_add(length).wrapping_add(rel as u32);
+        self.eip = if self.is_operand_size16() { target & 0xFFFF } else { target } as u64;
+    }
+
+    // 条件が成り立てばrel8の分岐先へ、成り立たなければ次の命令へ進む
+    fn jump_short_if(&mut self, condition: bool) {
+        let diff = if condition { self.get_sign_code8(1) } else { 0 };
+        self.jump_relative(2, diff as i32);
+    }
+
+    // PFは結果の下位8ビットに1が偶数個あるとき、AFはビット3からの桁上がり(借り)があるときに立つ
+    fn set_parity_adjust(&mut self, v1: u32, v2: u32, result: u32) {
+        self.set_flag(Self::PARITY_FLAG, (result as u8).count_ones() & 1 == 0);
+        self.set_flag(Self::ADJUST_FLAG, (v1 ^ v2 ^ result) & 0x10 != 0);
+    }
+
+    // オペランドサイズ(バイト数)
+    fn operand_size(&self) -> u32 {
+        if self.is_operand_size16() {
+            2
+        } else {
+            4
+        }
+    }
+
+    // POPせずにスタックのspの位置から16ビットか32ビットの値を読む
+    fn read_stack(&mut self, sp: u32, size: u32) -> u32 {
+        let address = self.stack_address(sp, size);
+        if size == 2 {
+            self.get_memory16(address)
+        } else {
+            self.get_memory32(address)
+        }
+    }
+
+    fn read_port(&mut self, port: u16, size: u32) -> u32 {
+        self.ports.read(port, size, &mut self.interrupt_lines)
+    }
+
+    fn write_port(&mut self, port: u16, size: u32, value: u32) {
+        self.ports.write(port, size, value, &mut self.interrupt_lines);
+    }
+
+    fn get_memory_sized(&mut self, address: usize, size: u32) -> u32 {
+        self.check_alignment(address, size as usize);
+        match size {
+            1 => self.get_memory8(address),
+            2 => self.get_memory16(address),
+            _ => self.get_memory32(address),
+        }
+    }
+
+    fn set_memory_sized(&mut self, address: usize, size: u32, value: u32) {
+        self.check_alignment(address, size as usize);
+        match size {
+            1 => self.set_memory8(address, value),
+            2 => self.set_memory16(address, value),
+            _ => self.set_memory32(address, value),
+        }
+    }
+
+    // r/mとregをサイズ(バイト数)に合わせて読み書きする
+    fn get_rm_sized(&mut self, modrm: &ModRM, size: u32) -> u32 {
+        match size {
+            1 => self.get_rm8(modrm) as u32,
+            2 => self.get_rm16(modrm) as u32,
+            _ => self.get_rm32(modrm),
+        }
+    }
+
+    fn set_rm_sized(&mut self, modrm: &ModRM, size: u32, value: u32) {
+        match size {
+            1 => self.set_rm8(modrm, value as u8),
+            2 => self.set_rm16(modrm, value as u16),
+            _ => self.set_rm32(modrm, value),
+        }
+    }
+
+    fn get_r_sized(&mut self, modrm: &ModRM, size: u32) -> u32 {
+        match size {
+            1 => self.get_r8(modrm) as u32,
+            2 => self.get_r16(modrm) as u32,
+            _ => self.get_r32(modrm),
+        }
+    }
+
+    // AL/AX/EAXをサイズに合わせて読み書きする
+    fn get_accumulator(&self, size: u32) -> u32 {
+        match size {
+            1 => self.get_register8(Register8::AL as usize) as u32,
+            2 => self.get_register16(Register::EAX as usize) as u32,
+            _ => self.get_register32(Register::EAX as usize),
+        }
+    }
+
+    fn set_accumulator(&mut self, size: u32, value: u32) {
+        match size {
+            1 => self.set_register8(Register8::AL as usize, value as u8),
+            2 => self.set_register16(Register::EAX as usize, value as u16),
+            _ => self.set_register32(Register::EAX as usize, value),
+        }
+    }
+
+    fn input_accumulator(&mut self, port: u16, size: u32, length: u32) {
+        if let Err(e) = self.check_io_permission(port, size) {
+            self.raise_exception(e);
+            return;
+        }
+        let value = self.read_port(port, size);
+        self.set_accumulator(size, value);
+        self.eip += length as u64;
+    }
+
+    fn output_accumulator(&mut self, port: u16, size: u32, length: u32) {
+        if let Err(e) = self.check_io_permission(port, size) {
+            self.raise_exception(e);
+            return;
+        }
+        let value = self.get_accumulator(size);
+        self.write_port(port, size, value);
+        self.eip += length as u64;
+    }
+
+    // アドレスサイズに合わせてESI/EDI/ECX(SI/DI/CX)を読み書きする
+    fn get_string_register(&self, index: usize) -> u32 {
+        if self.is_address_size16() {
+            self.get_register16(index) as u32
+        } else {
+            self.get_register32(index)
+        }
+    }
+
+    fn set_string_register(&mut self, index: usize, value: u32) {
+        if self.is_address_size16() {
+            self.set_register16(index, value as u16);
+        } else {
+            self.set_register32(index, value);
+        }
+    }
+
+    // DFに従ってESI/EDIを進める
+    fn advance_string_register(&mut self, index: usize, size: u32) {
+        let value = self.get_string_register(index);
+        let value = if self.eflags & Self::DIRECTION_FLAG != 0 {
+            value.wrapping_sub(size)
+        } else {
+            value.wrapping_add(size)
+        };
+        self.set_string_register(index, value);
+    }
+
+    // REPプレフィックスが付いていればECXが0になるまで繰り返す
+    fn repeat_string<F: FnMut(&mut Emulator)>(&mut self, mut operation: F) {
+        if self.repeat_prefix.is_none() {
+            operation(self);
+        } else {
+            while self.get_string_register(Register::ECX as usize) != 0 {
+                operation(self);
+                let count = self.get_string_register(Register::ECX as usize) - 1;
+                self.set_string_register(Register::ECX as usize, count);
+            }
+        }
+        self.eip += 1;
+    }
+
+    // INSはES:EDIへ書き込む(セグメントオーバーライドできない)
+    fn input_string(&mut self, size: u32) {
+        let port = self.get_register16(Register::EDX as usize);
+        if let Err(e) = self.check_io_permission(port, size) {
+            self.raise_exception(e);
+            return;
+        }
+        self.repeat_string(|emu| {
+            let value = emu.read_port(port, size);
+            let offset = emu.get_string_register(Register::EDI as usize);
+            let address = emu.linear_address(SegmentRegister::ES as usize, offset as u64, size);
+            emu.set_memory_sized(address as usize, size, value);
+            emu.advance_string_register(Register::EDI as usize, size);
+        });
+    }
+
+    // OUTSはDS:ESIから読み込む
+    fn output_string(&mut self, size: u32) {
+        let port = self.get_register16(Register::EDX as usize);
+        if let Err(e) = self.check_io_permission(port, size) {
+            self.raise_exception(e);
+            return;
+        }
+        let segment = self.segment_override.unwrap_or(SegmentRegister::DS as usize);
+        self.repeat_string(|emu| {
+            let offset = emu.get_string_register(Register::ESI as usize);
+            let address = emu.linear_address(segment, offset as u64, size);
+            let value = emu.get_memory_sized(address as usize, size);
+            emu.write_port(port, size, value);
+            emu.advance_string_register(Register::ESI as usize, size);
+        });
+    }
+
+    // FS/GSのベースアドレスを設定する(スレッドローカルストレージ用)
+    pub fn set_fs_base(&mut self, base: u32) {
+        self.segment_caches[SegmentRegister::FS as usize].base = base;
+    }
+
+    pub fn set_gs_base(&mut self, base: u32) {
+        self.segment_caches[SegmentRegister::GS as usize].base = base;
+    }
+
+    // MOV AL/EAX, moffsのオフセットを読み取り、リニアアドレスを求める
+    fn get_moffs_address(&mut self, size: u32) -> usize {
+        let offset = if self.is_address_size16() {
+            let offset = self.get_code16(1) as u32;
+            self.eip += 3;
+            offset
+        } else {
+            let offset = self.get_code32(1);
+            self.eip += 5;
+            offset
+        };
+        let segment = self.segment_override.unwrap_or(SegmentRegister::DS as usize);
+        self.linear_address(segment, offset as u64, size) as usize
+    }
+
+    // 16ビットのスタックではSPの下位16ビットだけを使う
+    fn stack_address(&mut self, sp: u32, size: u32) -> usize {
+        let sp = if self.segment_caches[SegmentRegister::SS as usize].is_32bit() {
+            sp
+        } else {
+            sp & 0xffff
+        };
+        self.linear_address(SegmentRegister::SS as usize, sp as u64, size) as usize
+    }
+
+    // 16ビットアドレッシングの実効アドレスを求める
+    fn calc_memory_address16(&self, modrm: &ModRM) -> u32 {
+        let bx = self.get_register16(Register::EBX as usize);
+        let bp = self.get_register16(Register::EBP as usize);
+        let si = self.get_register16(Register::ESI as usize);
+        let di = self.get_register16(Register::EDI as usize);
+        let base = match modrm.rm {
+            0 => bx.wrapping_add(si),
+            1 => bx.wrapping_add(di),
+            2 => bp.wrapping_add(si),
+            3 => bp.wrapping_add(di),
+            4 => si,
+            5 => di,
+            6 if modrm.mode == 0 => 0,
+            6 => bp,
+            _ => bx,
+        };
+        // mod=3はcalc_memory_addressで除いている
+        let disp = match modrm.mode {
+            0 if modrm.rm == 6 => modrm.get_disp32() as u16,
+            0 => 0,
+            1 => modrm.get_disp8() as u16,
+            _ => modrm.get_disp32() as u16,
+        };
+        base.wrapping_add(disp) as u32
+    }
+
+    // 64ビットアドレッシングの実効アドレスを求める
+    // REXでR8〜R15をベースとインデックスに使え、mod=00でr/mが101なら次の命令のRIPからの相対になる
+    fn calc_memory_address64(&self, modrm: &ModRM) -> u64 {
+        let disp32 = modrm.get_disp32() as i32 as u64;
+        let base = if modrm.rm == 4 {
+            let scale = modrm.sib >> 6;
+            let index = ((modrm.sib >> 3) & 7 | (self.rex & REX_X) << 2) as usize;
+            let base = ((modrm.sib & 7) | (self.rex & REX_B) << 3) as usize;
+            // REX.Xのないインデックス100はインデックスなし、mod=00でベースの下位3ビットが101ならベースの代わりにdisp32
+            let index_value = if index == Register::ESP as usize {
+                0
+            } else {
+                self.get_register64(index) << scale
+            };
+            let base_value = if modrm.sib & 7 == 5 && modrm.mode == 0 {
+                disp32
+            } else {
+                self.get_register64(base)
+            };
+            base_value.wrapping_add(index_value)
+        } else if modrm.mode == 0 && modrm.rm == 5 {
+            self.instruction_eip.wrapping_add(self.instruction_length as u64).wrapping_add(disp32)
+        } else {
+            self.get_register64(self.modrm_rm_index(modrm))
+        };
+        match modrm.mode {
+            1 => base.wrapping_add(modrm.get_disp8() as i64 as u64),
+            2 => base.wrapping_add(disp32),
+            _ => base,
+        }
+    }
+
+    // SIBバイトのベースとスケール付きインデックスからアドレスを求める
+    fn calc_sib_address(&self, modrm: &ModRM) -> u32 {
+        let scale = modrm.sib >> 6;
+        let index = ((modrm.sib >> 3) & 7) as usize;
+        let base = (modrm.sib & 7) as usize;
+        // インデックスがESPのときはインデックスなし
+        let index_value = if index == Register::ESP as usize {
+            0
+        } else {
+            self.get_register32(index) << scale
+        };
+        // mod=00でベースがEBPのときはベースの代わりにdisp32を使う
+        let base_value = if base == Register::EBP as usize && modrm.mode == 0 {
+            modrm.get_disp32()
+        } else {
+            self.get_register32(base)
+        };
+        base_value.wrapping_add(index_value)
+    }
+
+    // BP/EBP/ESPをベースにしたアドレスはSS、それ以外はDSを使う
+    fn default_segment(&self, modrm: &ModRM) -> usize {
+        let stack = if self.is_address_size16() {
+            modrm.rm == 2 || modrm.rm == 3 || (modrm.rm == 6 && modrm.mode != 0)
+        } else if modrm.rm == 4 {
+            let base = modrm.sib & 7;
+            base == 4 || (base == 5 && modrm.mode != 0)
+        } else {
+            modrm.rm == 5 && modrm.mode != 0
+        };
+        if stack {
+            SegmentRegister::SS as usize
+        } else {
+            SegmentRegister::DS as usize
+        }
+    }
 }
 impl ModRMFunction for Emulator {
     fn parse_modrm(&mut self) -> ModRM {
@@ -635,12 +2360,26 @@ impl ModRMFunction for Emulator {
 
         self.eip += 1;
 
+        // 16ビットアドレッシングにはSIBがなく、ディスプレースメントは16ビット
+        if self.is_address_size16() {
+            if (modrm.mode == 0 && modrm.rm == 6) || modrm.mode == 2 {
+                modrm.disp.disp32 = self.get_code16(0) as u32;
+                self.eip += 2;
+            } else if modrm.mode == 1 {
+                modrm.disp.disp8 = self.get_sign_code8(0);
+                self.eip += 1;
+            }
+            return modrm;
+        }
+
         if modrm.mode != 3 && modrm.rm == 4 {
-            modrm.sib = code;
+            modrm.sib = self.get_code8(0);
             self.eip += 1;
         }
 
-        if (modrm.mode == 0 && modrm.rm == 5) || modrm.mode == 2 {
+        // mod=00でSIBのベースがEBPのときもdisp32が続く
+        let sib_disp32 = modrm.mode == 0 && modrm.rm == 4 && modrm.sib & 7 == 5;
+        if (modrm.mode == 0 && modrm.rm == 5) || modrm.mode == 2 || sib_disp32 {
             modrm.disp.disp32 = self.get_sign_code32(0) as u32;
             self.eip += 4;
         } else if modrm.mode == 1 {
@@ -650,85 +2389,464 @@ impl ModRMFunction for Emulator {
         modrm
     }
 
-    fn calc_memory_address(&self, modrm: &ModRM) -> u32 {
-        match modrm.mode {
+    fn calc_memory_address(&mut self, modrm: &ModRM) -> u64 {
+        // レジスタ形式にはアドレスがないので#UDにする(ここに来る前にデコーダや各命令で除いておく)
+        if modrm.mode == 3 {
+            if self.memory_fault.is_none() {
+                self.memory_fault = Some(invalid_opcode());
+            }
+            return 0;
+        }
+        if self.is_address_size16() {
+            return self.calc_memory_address16(modrm) as u64;
+        }
+        // 64ビットモードで67プレフィックスが付いていれば、下位32ビットだけを使う
+        if self.is_64bit_mode() {
+            let address = self.calc_memory_address64(modrm);
+            return if self.is_address_size64() { address } else { address as u32 as u64 };
+        }
+        let address = match modrm.mode {
             0 => match modrm.rm {
-                4 => panic!("not implemented ModRM mode = 0, rm = 4"),
+                4 => self.calc_sib_address(modrm),
                 5 => modrm.get_disp32(),
                 _ => self.get_register32(modrm.rm as usize),
             },
+            // ディスプレースメントは符号拡張して足し、4GBで折り返す
             1 => match modrm.rm {
-                4 => panic!("not implemented ModRM mode = 1, rm = 4"),
-                _ => {
-                    let disp = modrm.get_disp8();
-                    if disp > 0 {
-                        self.get_register32(modrm.rm as usize) + modrm.get_disp8() as u32
-                    } else {
-                        self.get_register32(modrm.rm as usize) - modrm.get_disp8().abs() as u32
-                    }
-                }
+                4 => self.calc_sib_address(modrm).wrapping_add(modrm.get_disp8() as i32 as u32),
+                _ => self.get_register32(modrm.rm as usize).wrapping_add(modrm.get_disp8() as i32 as u32),
             },
-            2 => match modrm.rm {
-                4 => panic!("not implemented ModRM mode = 2, rm = 4"),
-                _ => self.get_register32(modrm.rm as usize) + modrm.get_disp32() as u32,
+            _ => match modrm.rm {
+                4 => self.calc_sib_address(modrm).wrapping_add(modrm.get_disp32()),
+                _ => self.get_register32(modrm.rm as usize).wrapping_add(modrm.get_disp32()),
             },
-            _ => {
-                panic!("not implemented ModRM mod = 3");
-            }
-        }
+        };
+        address as u64
+    }
+
+    fn calc_linear_address(&mut self, modrm: &ModRM, size: u32) -> u64 {
+        let segment = self.segment_override.unwrap_or_else(|| self.default_segment(modrm));
+        let offset = self.calc_memory_address(modrm);
+        self.linear_address(segment, offset, size)
     }
 
     fn get_r8(&mut self, modrm: &ModRM) -> u8 {
-        return self.get_register8(modrm.get_reg_index() as usize);
+        let index = self.modrm_reg_index(modrm);
+        self.get_byte_register(index)
+    }
+
+    fn get_r16(&mut self, modrm: &ModRM) -> u16 {
+        self.get_register16(self.modrm_reg_index(modrm))
     }
 
     fn get_r32(&mut self, modrm: &ModRM) -> u32 {
-        self.get_register32(modrm.get_reg_index() as usize)
+        self.get_register32(self.modrm_reg_index(modrm))
+    }
+
+    fn get_r64(&mut self, modrm: &ModRM) -> u64 {
+        self.get_register64(self.modrm_reg_index(modrm))
     }
 
     fn get_rm8(&mut self, modrm: &ModRM) -> u8 {
         if modrm.mode == 3 {
-            self.get_register8(modrm.rm as usize)
+            let index = self.modrm_rm_index(modrm);
+            self.get_byte_register(index)
         } else {
-            let address = self.calc_memory_address(modrm);
+            let address = self.calc_linear_address(modrm, 1);
             self.get_memory8(address as usize) as u8
         }
     }
 
+    fn get_rm16(&mut self, modrm: &ModRM) -> u16 {
+        if modrm.mode == 3 {
+            self.get_register16(self.modrm_rm_index(modrm))
+        } else {
+            let address = self.calc_linear_address(modrm, 2);
+            self.check_alignment(address as usize, 2);
+            self.get_memory16(address as usize) as u16
+        }
+    }
+
     fn get_rm32(&mut self, modrm: &ModRM) -> u32 {
         if modrm.mode == 3 {
-            self.get_register32(modrm.rm as usize)
+            self.get_register32(self.modrm_rm_index(modrm))
         } else {
-            let address = self.calc_memory_address(modrm);
+            let address = self.calc_linear_address(modrm, 4);
+            self.check_alignment(address as usize, 4);
             self.get_memory32(address as usize)
         }
     }
 
+    fn get_rm64(&mut self, modrm: &ModRM) -> u64 {
+        if modrm.mode == 3 {
+            self.get_register64(self.modrm_rm_index(modrm))
+        } else {
+            let address = self.calc_linear_address(modrm, 8);
+            self.check_alignment(address as usize, 8);
+            self.get_memory64(address as usize)
+        }
+    }
+
     fn set_r8(&mut self, modrm: &ModRM, value: u8) {
-        self.set_register8(modrm.get_reg_index() as usize, value);
+        let index = self.modrm_reg_index(modrm);
+        self.set_byte_register(index, value);
+    }
+
+    fn set_r16(&mut self, modrm: &ModRM, value: u16) {
+        let index = self.modrm_reg_index(modrm);
+        self.set_register16(index, value);
     }
 
     fn set_r32(&mut self, modrm: &ModRM, value: u32) {
-        self.set_register32(modrm.get_reg_index() as usize, value);
+        let index = self.modrm_reg_index(modrm);
+        self.set_register32(index, value);
+    }
+
+    fn set_r64(&mut self, modrm: &ModRM, value: u64) {
+        let index = self.modrm_reg_index(modrm);
+        self.set_register64(index, value);
     }
 
     fn set_rm8(&mut self, modrm: &ModRM, value: u8) {
         if modrm.mode == 3 {
-            self.set_register8(modrm.rm as usize, value);
+            let index = self.modrm_rm_index(modrm);
+            self.set_byte_register(index, value);
         } else {
-            let address = self.calc_memory_address(modrm);
+            let address = self.calc_linear_address(modrm, 1);
             self.set_memory8(address as usize, value as u32);
         }
     }
 
+    fn set_rm16(&mut self, modrm: &ModRM, value: u16) {
+        if modrm.mode == 3 {
+            let index = self.modrm_rm_index(modrm);
+            self.set_register16(index, value);
+        } else {
+            let address = self.calc_linear_address(modrm, 2);
+            self.check_alignment(address as usize, 2);
+            self.set_memory16(address as usize, value as u32);
+        }
+    }
+
     fn set_rm32(&mut self, modrm: &ModRM, value: u32) {
         if modrm.mode == 3 {
-            self.set_register32(modrm.rm as usize, value);
+            let index = self.modrm_rm_index(modrm);
+            self.set_register32(index, value);
         } else {
-            let address = self.calc_memory_address(modrm);
+            let address = self.calc_linear_address(modrm, 4);
+            self.check_alignment(address as usize, 4);
             self.set_memory32(address as usize, value);
         }
     }
+
+    fn set_rm64(&mut self, modrm: &ModRM, value: u64) {
+        if modrm.mode == 3 {
+            let index = self.modrm_rm_index(modrm);
+            self.set_register64(index, value);
+        } else {
+            let address = self.calc_linear_address(modrm, 8);
+            self.check_alignment(address as usize, 8);
+            self.set_memory64(address as usize, value);
+        }
+    }
 }
 
-impl Io for Emulator {}
+#[cfg(test)]
+mod tests {
+    use emulator::bus::{Bus, MapError};
+    use emulator::emulator_function::EmulatorFunction;
+    use emulator::instruction::Instruction;
+    use emulator::stop::EmuError;
+    use emulator::testing::*;
+    use emulator::{Emulator, Register, Register8, SegmentRegister};
+
+    // 0x8000までしかRAMがないバスで、RAMの終わりの直前にcodeを置く
+    fn emulator_at_ram_end(code: &[u8]) -> Emulator {
+        let mut bus = Bus::new();
+        bus.add_ram(0, 0x8000).unwrap();
+        let eip = 0x8000 - code.len() as u32;
+        bus.load(eip as usize, code);
+        Emulator::with_bus(bus, eip, 0x7000)
+    }
+
+    #[test]
+    fn immediate_beyond_ram_is_a_memory_fault() {
+        // mov eax, imm32の即値の途中でRAMが終わる
+        let mut emu = emulator_at_ram_end(&[0xB8, 0x01, 0x02]);
+        let result = emu.run_instructions(true, Some(1));
+        assert_eq!(result, Err(EmuError::MemoryFault { address: 0x8000 }));
+        assert_eq!(emu.eip, 0x7FFD);
+        assert_eq!(emu.registers[0], 0);
+    }
+
+    #[test]
+    fn modrm_beyond_ram_is_a_memory_fault() {
+        // mov ecx, [eax]のModR/MがRAMの外にある
+        let mut emu = emulator_at_ram_end(&[0x8B]);
+        let result = emu.run_instructions(true, Some(1));
+        assert_eq!(result, Err(EmuError::MemoryFault { address: 0x8000 }));
+        assert_eq!(emu.eip, 0x7FFF);
+    }
+
+    #[test]
+    fn new_reports_an_invalid_ram_size() {
+        let file = ::std::fs::File::open("/dev/null").unwrap();
+        let result = Emulator::new(0, 0x7c00, 0x7c00, file);
+        assert_eq!(result.err(), Some(MapError::InvalidRange { base: 0, size: 0 }));
+    }
+
+    #[test]
+    fn gs_override_reads_through_host_set_base() {
+        let mut emu = emulator(&[
+            0x65, 0xA1, 0x14, 0x00, 0x00, 0x00, // mov eax, gs:[0x14]
+            0x65, 0x8B, 0x0D, 0x18, 0x00, 0x00, 0x00, // mov ecx, gs:[0x18]
+            0x64, 0x8B, 0x15, 0x14, 0x00, 0x00, 0x00, // mov edx, fs:[0x14]
+        ]);
+        emu.set_gs_base(0x4000);
+        emu.set_fs_base(0x5000);
+        write32(&mut emu, 0x4014, 0x1234_5678);
+        write32(&mut emu, 0x4018, 0x9ABC_DEF0);
+        write32(&mut emu, 0x5014, 0x0BAD_F00D);
+        run(&mut emu, 3);
+        assert_eq!(emu.registers[0], 0x1234_5678);
+        assert_eq!(emu.registers[1], 0x9ABC_DEF0);
+        assert_eq!(emu.registers[2], 0x0BAD_F00D);
+    }
+
+    #[test]
+    fn ebp_and_esp_bases_default_to_stack_segment() {
+        let mut emu = emulator(&[
+            0x8B, 0x45, 0x04, // mov eax, [ebp+4]
+            0x8B, 0x4C, 0x24, 0x04, // mov ecx, [esp+4]
+            0x8B, 0x53, 0x04, // mov edx, [ebx+4]
+            0x3E, 0x8B, 0x75, 0x04, // mov esi, ds:[ebp+4]
+        ]);
+        // SSだけベースを0x10000にずらす
+        emu.segment_caches[SegmentRegister::SS as usize].base = 0x10000;
+        emu.registers[Register::EBP as usize] = 0x100;
+        emu.registers[Register::EBX as usize] = 0x100;
+        emu.registers[Register::ESP as usize] = 0x200;
+        write32(&mut emu, 0x10104, 1);
+        write32(&mut emu, 0x10204, 2);
+        write32(&mut emu, 0x104, 3);
+        run(&mut emu, 4);
+        assert_eq!(emu.registers[Register::EAX as usize], 1);
+        assert_eq!(emu.registers[Register::ECX as usize], 2);
+        assert_eq!(emu.registers[Register::EDX as usize], 3);
+        assert_eq!(emu.registers[Register::ESI as usize], 3);
+    }
+
+    #[test]
+    fn bp_based_16bit_addresses_default_to_stack_segment() {
+        let mut emu = emulator(&[
+            0x67, 0x8B, 0x46, 0x04, // mov eax, [bp+4]
+            0x67, 0x8B, 0x4F, 0x04, // mov ecx, [bx+4]
+            0x26, 0x67, 0x8B, 0x56, 0x04, // mov edx, es:[bp+4]
+        ]);
+        emu.segment_caches[SegmentRegister::SS as usize].base = 0x10000;
+        emu.segment_caches[SegmentRegister::ES as usize].base = 0x20000;
+        emu.registers[Register::EBP as usize] = 0x100;
+        emu.registers[Register::EBX as usize] = 0x100;
+        write32(&mut emu, 0x10104, 1);
+        write32(&mut emu, 0x104, 2);
+        write32(&mut emu, 0x20104, 3);
+        run(&mut emu, 3);
+        assert_eq!(emu.registers[Register::EAX as usize], 1);
+        assert_eq!(emu.registers[Register::ECX as usize], 2);
+        assert_eq!(emu.registers[Register::EDX as usize], 3);
+    }
+
+    #[test]
+    fn negative_displacements_wrap_below_the_base() {
+        let mut emu = emulator(&[
+            0x8B, 0x45, 0xF0, // mov eax, [ebp-0x10]
+            0x8B, 0x8D, 0x00, 0xFE, 0xFF, 0xFF, // mov ecx, [ebp-0x200]
+            0x8B, 0x9E, 0x00, 0x00, 0x00, 0x80, // mov ebx, [esi-0x80000000]
+        ]);
+        emu.registers[Register::EBP as usize] = 0x9000;
+        emu.registers[Register::ESI as usize] = 0x8000_4004;
+        write32(&mut emu, 0x8FF0, 1);
+        write32(&mut emu, 0x8E00, 2);
+        write32(&mut emu, 0x4004, 3);
+        run(&mut emu, 2);
+        assert_eq!(emu.registers[Register::EAX as usize], 1);
+        assert_eq!(emu.registers[Register::ECX as usize], 2);
+        run(&mut emu, 1);
+        // 0x80004004 + 0x80000000は4GBで折り返す
+        assert_eq!(emu.registers[Register::EBX as usize], 3);
+    }
+
+    #[test]
+    fn arithmetic_wraps_and_reports_borrow() {
+        let mut emu = emulator(&[
+            0x01, 0xC8, // add eax, ecx
+            0x40, // inc eax
+            0x83, 0xEA, 0x02, // sub edx, 2
+            0x3B, 0xD9, // cmp ebx, ecx
+            0x3D, 0x02, 0x00, 0x00, 0x00, // cmp eax, 2
+            0x3C, 0x05, // cmp al, 5
+        ]);
+        emu.registers[Register::EAX as usize] = 0xFFFF_FFFF;
+        emu.registers[Register::ECX as usize] = 0xFFFF_FFFF;
+        emu.registers[Register::EDX as usize] = 1;
+        run(&mut emu, 2);
+        assert_eq!(emu.registers[Register::EAX as usize], 0xFFFF_FFFF);
+        run(&mut emu, 1);
+        assert_eq!(emu.registers[Register::EDX as usize], 0xFFFF_FFFF);
+        assert!(emu.is_carry() && emu.is_sign());
+        // 0 - 0xFFFFFFFF, 0xFFFFFFFF - 2, 0xFF - 5
+        for &carry in [true, false, false].iter() {
+            run(&mut emu, 1);
+            assert_eq!(emu.is_carry(), carry);
+        }
+    }
+
+    #[test]
+    fn real_mode_relative_branches_wrap_within_the_segment() {
+        let mut emu = emulator(&[
+            0xEB, 0xFC, // jmp short -4
+        ]);
+        emu.segment_registers[SegmentRegister::CS as usize] = 0x07c0;
+        emu.eip = 0;
+        emu.reset_to_real_mode();
+        // 07c0:fffe jmp short +3
+        emu.bus.load(0x7c00 + 0xFFFE, &[0xEB, 0x03]);
+        // 07c0:0003 jmp near -0x16
+        emu.bus.load(0x7c00 + 0x0003, &[0xE9, 0xEA, 0xFF]);
+        // 07c0:fff0 jnc +0x7f
+        emu.bus.load(0x7c00 + 0xFFF0, &[0x73, 0x7F]);
+        let expected = [0xFFFE, 0x0003, 0xFFF0, 0x0071];
+        for &ip in expected.iter() {
+            run(&mut emu, 1);
+            assert_eq!(emu.eip, ip);
+        }
+    }
+
+    #[test]
+    fn carry_and_direction_flag_instructions() {
+        let mut emu = emulator(&[
+            0xF9, // stc
+            0xF5, // cmc
+            0xF5, // cmc
+            0xFD, // std
+            0xFC, // cld
+            0xF8, // clc
+        ]);
+        let carry = [true, false, true, true, true, false];
+        let direction = [false, false, false, true, false, false];
+        for i in 0..6 {
+            run(&mut emu, 1);
+            assert_eq!(emu.is_carry(), carry[i]);
+            assert_eq!(emu.eflags & Emulator::DIRECTION_FLAG != 0, direction[i]);
+        }
+    }
+
+    #[test]
+    fn lahf_and_sahf_transfer_the_low_flags() {
+        let mut emu = emulator(&[
+            0xB4, 0xFF, // mov ah, 0xFF
+            0x9E, // sahf
+            0xB4, 0x00, // mov ah, 0
+            0x9F, // lahf
+        ]);
+        emu.eflags |= Emulator::OVERFLOW_FLAG;
+        run(&mut emu, 2);
+        // 予約ビット(3, 5)は立たず、OFは変わらない
+        assert_eq!(emu.eflags, 0x0802 | 0xD5);
+        run(&mut emu, 2);
+        assert_eq!(emu.get_register8(Register8::AH as usize), 0xD7);
+    }
+
+    #[test]
+    fn xlat_reads_the_table_through_the_segment() {
+        let mut emu = emulator(&[
+            0xB0, 0x03, // mov al, 3
+            0xD7, // xlat
+            0x64, 0xD7, // xlat fs:
+        ]);
+        emu.registers[Register::EBX as usize] = 0x4000;
+        emu.set_fs_base(0x1000);
+        emu.bus.load(0x4000, &[0x10, 0x11, 0x12, 0x13, 0x14]);
+        emu.bus.load(0x5013, &[0x04]);
+        run(&mut emu, 2);
+        assert_eq!(emu.get_register8(Register8::AL as usize), 0x13);
+        run(&mut emu, 1);
+        assert_eq!(emu.get_register8(Register8::AL as usize), 0x04);
+    }
+
+    #[test]
+    fn narrow_register_writes_keep_the_upper_bits_and_32bit_writes_clear_them() {
+        let mut emu = emulator(&[]);
+        emu.registers[Register::EBX as usize] = 0x1122_3344_5566_7788;
+        emu.set_register8(Register8::BH as usize, 0xAA);
+        assert_eq!(emu.registers[Register::EBX as usize], 0x1122_3344_5566_AA88);
+        emu.set_register16(Register::EBX as usize, 0xBEEF);
+        assert_eq!(emu.registers[Register::EBX as usize], 0x1122_3344_5566_BEEF);
+        assert_eq!(emu.get_register32(Register::EBX as usize), 0x5566_BEEF);
+        emu.set_register32(Register::EBX as usize, 0xCCDD_EEFF);
+        assert_eq!(emu.registers[Register::EBX as usize], 0xCCDD_EEFF);
+    }
+
+    #[test]
+    fn salc_copies_carry_into_al() {
+        let mut emu = emulator(&[
+            0xF9, // stc
+            0xD6, // salc
+            0xB4, 0x12, // mov ah, 0x12
+            0xF8, // clc
+            0xD6, // salc
+        ]);
+        run(&mut emu, 2);
+        assert_eq!(emu.registers[0], 0xFF);
+        run(&mut emu, 3);
+        assert_eq!(emu.registers[0], 0x1200);
+    }
+
+    #[test]
+    fn sign_extensions_for_each_operand_size() {
+        let mut emu = emulator(&[
+            0x66, 0x98, // cbw
+            0x98, // cwde
+            0x66, 0x99, // cwd
+            0x99, // cdq
+        ]);
+        emu.registers[Register::EAX as usize] = 0x1234_5680;
+        emu.registers[Register::EDX as usize] = 0x5555_5555;
+        run(&mut emu, 1);
+        assert_eq!(emu.registers[Register::EAX as usize], 0x1234_FF80);
+        run(&mut emu, 1);
+        assert_eq!(emu.registers[Register::EAX as usize], 0xFFFF_FF80);
+        run(&mut emu, 1);
+        assert_eq!(emu.registers[Register::EDX as usize], 0x5555_FFFF);
+        run(&mut emu, 1);
+        assert_eq!(emu.registers[Register::EDX as usize], 0xFFFF_FFFF);
+    }
+
+    #[test]
+    fn pushf_and_popf_with_16bit_operand_size_touch_only_the_low_word() {
+        let mut emu = emulator(&[
+            0x66, 0x9C, // pushf
+            0x66, 0x68, 0xC1, 0x08, // push 0x08C1(OF, SF, CF)
+            0x66, 0x9D, // popf
+        ]);
+        emu.eflags |= Emulator::ALIGNMENT_CHECK_FLAG | Emulator::ZERO_FLAG;
+        run(&mut emu, 1);
+        assert_eq!(emu.get_register32(Register::ESP as usize), STACK - 2);
+        assert_eq!(read16(&emu, STACK - 2), 0x0042);
+        run(&mut emu, 2);
+        assert_eq!(emu.get_register32(Register::ESP as usize), STACK - 2);
+        // 上位16ビットのACはそのまま残る
+        assert_eq!(emu.eflags, Emulator::ALIGNMENT_CHECK_FLAG | 0x08C3);
+    }
+
+    #[test]
+    fn popf_keeps_reserved_and_virtual_8086_flags() {
+        let mut emu = emulator(&[
+            0x68, 0xFF, 0xFE, 0xFF, 0xFF, // push 0xFFFFFEFF (TF以外のすべてのビット)
+            0x9D, // popfd
+        ]);
+        run(&mut emu, 2);
+        // 予約ビット、RF, VM, VIF, VIPは立たない
+        assert_eq!(emu.eflags, 0x0024_7ED7);
+    }
+}
@@ -61,13 +61,53 @@ impl ModRM {
 
 pub trait Function {
     fn parse_modrm(&mut self) -> ModRM;
-    fn calc_memory_address(&self, &ModRM) -> u32;
+    fn calc_memory_address(&mut self, modrm: &ModRM) -> u64;
+    fn calc_linear_address(&mut self, modrm: &ModRM, size: u32) -> u64;
     fn get_r8(&mut self, modrm: &ModRM) -> u8;
+    fn get_r16(&mut self, modrm: &ModRM) -> u16;
     fn get_r32(&mut self, modrm: &ModRM) -> u32;
+    fn get_r64(&mut self, modrm: &ModRM) -> u64;
     fn get_rm8(&mut self, modrm: &ModRM) -> u8;
+    fn get_rm16(&mut self, modrm: &ModRM) -> u16;
     fn get_rm32(&mut self, modrm: &ModRM) -> u32;
+    fn get_rm64(&mut self, modrm: &ModRM) -> u64;
     fn set_r8(&mut self, modrm: &ModRM, value: u8);
+    fn set_r16(&mut self, modrm: &ModRM, value: u16);
     fn set_r32(&mut self, modrm: &ModRM, value: u32);
+    fn set_r64(&mut self, modrm: &ModRM, value: u64);
     fn set_rm8(&mut self, modrm: &ModRM, value: u8);
+    fn set_rm16(&mut self, modrm: &ModRM, value: u16);
     fn set_rm32(&mut self, modrm: &ModRM, value: u32);
+    fn set_rm64(&mut self, modrm: &ModRM, value: u64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Function, ModRM};
+    use emulator::testing::*;
+
+    #[test]
+    fn register_form_has_no_memory_address() {
+        let mut emu = emulator(&[]);
+        let mut modrm = ModRM::new();
+        modrm.mode = 3;
+        modrm.rm = 1;
+        emu.calc_memory_address(&modrm);
+        assert_eq!(emu.memory_fault.unwrap().vector, 6);
+    }
+
+    #[test]
+    fn memory_forms_add_base_index_and_displacement() {
+        let mut emu = emulator(&[]);
+        emu.registers[3] = 0x1000;
+        emu.registers[6] = 0x20;
+        // [ebx + esi*4 + 8]
+        let mut modrm = ModRM::new();
+        modrm.mode = 1;
+        modrm.rm = 4;
+        modrm.sib = 0b10_110_011;
+        modrm.disp.disp8 = 8;
+        assert_eq!(emu.calc_memory_address(&modrm), 0x1088);
+        assert!(emu.memory_fault.is_none());
+    }
 }
@@ -0,0 +1,338 @@
+use std::collections::HashMap;
+
+use emulator::control_register::CR0_PG;
+use emulator::emulator_function::EmulatorFunction;
+use emulator::interrupt::{general_protection, Exception, Interrupt};
+use emulator::segment::Segment;
+use emulator::{Emulator, Register};
+
+// MSRのアドレス
+pub const IA32_TIME_STAMP_COUNTER: u32 = 0x10;
+pub const IA32_APIC_BASE: u32 = 0x1B;
+pub const IA32_MTRRCAP: u32 = 0xFE;
+pub const IA32_SYSENTER_CS: u32 = 0x174;
+pub const IA32_SYSENTER_ESP: u32 = 0x175;
+pub const IA32_SYSENTER_EIP: u32 = 0x176;
+pub const IA32_MTRR_PHYSBASE0: u32 = 0x200;
+pub const IA32_MTRR_PHYSMASK7: u32 = 0x20F;
+pub const IA32_MTRR_FIX64K_00000: u32 = 0x250;
+pub const IA32_MTRR_FIX16K_80000: u32 = 0x258;
+pub const IA32_MTRR_FIX16K_A0000: u32 = 0x259;
+pub const IA32_MTRR_FIX4K_C0000: u32 = 0x268;
+pub const IA32_MTRR_FIX4K_F8000: u32 = 0x26F;
+pub const IA32_MTRR_DEF_TYPE: u32 = 0x2FF;
+pub const IA32_EFER: u32 = 0xC000_0080;
+pub const STAR: u32 = 0xC000_0081;
+
+// EFERのビット
+pub const EFER_SCE: u64 = 1;
+pub const EFER_LME: u64 = 1 << 8;
+pub const EFER_LMA: u64 = 1 << 10;
+
+// APICベース(0xFEE00000)でBSPかつグローバルに有効
+const APIC_BASE_INITIAL: u64 = 0xFEE0_0900;
+// 可変長MTRRが8個、固定長MTRRとライトコンバインに対応
+const MTRRCAP_VALUE: u64 = 0x508;
+
+// ホスト側から独自のMSRを追加するためのハンドラ
+pub trait MsrHandler {
+    // Noneを返すと#GPになる
+    fn read(&mut self, index: u32) -> Option<u64>;
+    // falseを返すと#GPになる
+    fn write(&mut self, index: u32, value: u64) -> bool;
+}
+
+// モデル固有レジスタ
+pub struct ModelSpecificRegisters {
+    pub time_stamp_counter: u64,
+    pub apic_base: u64,
+    pub sysenter_cs: u64,
+    pub sysenter_esp: u64,
+    pub sysenter_eip: u64,
+    pub efer: u64,
+    pub star: u64,
+    // 値を保持するだけのMSR(MTRRや、許容モードでの未知のMSR)
+    stored: HashMap<u32, u64>,
+    // 未知のMSRへのアクセスを#GPにせずに許容するか
+    permissive: bool,
+    handlers: HashMap<u32, Box<dyn MsrHandler>>,
+}
+
+impl ModelSpecificRegisters {
+    pub fn new() -> ModelSpecificRegisters {
+        ModelSpecificRegisters {
+            time_stamp_counter: 0,
+            apic_base: APIC_BASE_INITIAL,
+            sysenter_cs: 0,
+            sysenter_esp: 0,
+            sysenter_eip: 0,
+            efer: 0,
+            star: 0,
+            stored: HashMap::new(),
+            permissive: false,
+            handlers: HashMap::new(),
+        }
+    }
+}
+
+impl Default for ModelSpecificRegisters {
+    fn default() -> ModelSpecificRegisters {
+        ModelSpecificRegisters::new()
+    }
+}
+
+// 値を保持するだけのMTRR
+fn is_mtrr(index: u32) -> bool {
+    matches!(
+        index,
+        IA32_MTRR_PHYSBASE0..=IA32_MTRR_PHYSMASK7
+            | IA32_MTRR_FIX64K_00000
+            | IA32_MTRR_FIX16K_80000
+            | IA32_MTRR_FIX16K_A0000
+            | IA32_MTRR_FIX4K_C0000..=IA32_MTRR_FIX4K_F8000
+            | IA32_MTRR_DEF_TYPE
+    )
+}
+
+pub trait Msr {
+    fn read_msr(&mut self, index: u32) -> Result<u64, Exception>;
+    fn write_msr(&mut self, index: u32, value: u64) -> Result<(), Exception>;
+    fn rdmsr(&mut self);
+    fn wrmsr(&mut self);
+    fn rdtsc(&mut self);
+}
+
+impl Emulator {
+    // 指定したMSRへのアクセスをホスト側のハンドラで処理する
+    pub fn register_msr_handler(&mut self, index: u32, handler: Box<dyn MsrHandler>) {
+        self.msr.handlers.insert(index, handler);
+    }
+
+    // 未知のMSRへのアクセスを許容する(書き込んだ値を読み出せる)
+    pub fn set_msr_permissive(&mut self, permissive: bool) {
+        self.msr.permissive = permissive;
+    }
+
+    // EFERで書き込めるビット(LMEはロングモードに対応したCPUだけ)
+    fn writable_efer_bits(&self) -> u64 {
+        if self.cpu_model.supports_long_mode() {
+            EFER_SCE | EFER_LME
+        } else {
+            EFER_SCE
+        }
+    }
+}
+
+impl Msr for Emulator {
+    fn read_msr(&mut self, index: u32) -> Result<u64, Exception> {
+        if let Some(handler) = self.msr.handlers.get_mut(&index) {
+            return handler.read(index).ok_or_else(|| general_protection(0));
+        }
+        match index {
+            IA32_TIME_STAMP_COUNTER => Ok(self.msr.time_stamp_counter),
+            IA32_APIC_BASE => Ok(self.msr.apic_base),
+            IA32_MTRRCAP => Ok(MTRRCAP_VALUE),
+            IA32_SYSENTER_CS => Ok(self.msr.sysenter_cs),
+            IA32_SYSENTER_ESP => Ok(self.msr.sysenter_esp),
+            IA32_SYSENTER_EIP => Ok(self.msr.sysenter_eip),
+            IA32_EFER => Ok(self.msr.efer),
+            STAR => Ok(self.msr.star),
+            _ if is_mtrr(index) || self.msr.permissive => Ok(*self.msr.stored.get(&index).unwrap_or(&0)),
+            _ => Err(general_protection(0)),
+        }
+    }
+
+    fn write_msr(&mut self, index: u32, value: u64) -> Result<(), Exception> {
+        if let Some(handler) = self.msr.handlers.get_mut(&index) {
+            return if handler.write(index, value) {
+                Ok(())
+            } else {
+                Err(general_protection(0))
+            };
+        }
+        match index {
+            IA32_TIME_STAMP_COUNTER => self.msr.time_stamp_counter = value,
+            IA32_APIC_BASE => self.msr.apic_base = value,
+            IA32_SYSENTER_CS => self.msr.sysenter_cs = value & 0xFFFF,
+            IA32_SYSENTER_ESP => self.msr.sysenter_esp = value,
+            IA32_SYSENTER_EIP => self.msr.sysenter_eip = value,
+            // LMEはロングモードに対応したCPUだけが持ち、ページングが有効な間は切り替えられない(LMAは読み出し専用)
+            IA32_EFER if value & !(self.writable_efer_bits() | EFER_LMA) == 0 => {
+                if (value ^ self.msr.efer) & EFER_LME != 0 && self.control_registers[0] & CR0_PG != 0 {
+                    return Err(general_protection(0));
+                }
+                self.msr.efer = (value & !EFER_LMA) | (self.msr.efer & EFER_LMA);
+            }
+            STAR => self.msr.star = value,
+            _ if is_mtrr(index) || (self.msr.permissive && index != IA32_MTRRCAP && index != IA32_EFER) => {
+                self.msr.stored.insert(index, value);
+            }
+            _ => return Err(general_protection(0)),
+        }
+        Ok(())
+    }
+
+    fn rdmsr(&mut self) {
+        if self.cpl() != 0 {
+            self.raise_exception(general_protection(0));
+            return;
+        }
+        let index = self.get_register32(Register::ECX as usize);
+        match self.read_msr(index) {
+            Ok(value) => {
+                self.set_register32(Register::EAX as usize, value as u32);
+                self.set_register32(Register::EDX as usize, (value >> 32) as u32);
+                self.eip += 2;
+            }
+            Err(e) => self.raise_exception(e),
+        }
+    }
+
+    fn wrmsr(&mut self) {
+        if self.cpl() != 0 {
+            self.raise_exception(general_protection(0));
+            return;
+        }
+        let index = self.get_register32(Register::ECX as usize);
+        let eax = self.get_register32(Register::EAX as usize) as u64;
+        let edx = self.get_register32(Register::EDX as usize) as u64;
+        match self.write_msr(index, (edx << 32) | eax) {
+            Ok(()) => self.eip += 2,
+            Err(e) => self.raise_exception(e),
+        }
+    }
+
+    fn rdtsc(&mut self) {
+        let tsc = self.msr.time_stamp_counter;
+        self.set_register32(Register::EAX as usize, tsc as u32);
+        self.set_register32(Register::EDX as usize, (tsc >> 32) as u32);
+        self.eip += 2;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use super::*;
+    use emulator::cpu_model::CpuModel;
+    use emulator::testing::*;
+
+    #[test]
+    fn efer_accepts_long_mode_enable_only_on_long_mode_models() {
+        let mut emu = emulator(&[]);
+        assert!(emu.write_msr(IA32_EFER, EFER_SCE).is_ok());
+        assert_eq!(emu.read_msr(IA32_EFER).unwrap(), EFER_SCE);
+        // Pentium IIはロングモードに対応していないのでLMEは設定できない
+        assert_eq!(emu.write_msr(IA32_EFER, EFER_SCE | EFER_LME).unwrap_err().vector, 13);
+        assert_eq!(emu.read_msr(IA32_EFER).unwrap(), EFER_SCE);
+
+        emu.set_cpu_model(CpuModel::X86_64);
+        assert!(emu.write_msr(IA32_EFER, EFER_SCE | EFER_LME).is_ok());
+        // LMAは読み出し専用
+        assert!(emu.write_msr(IA32_EFER, EFER_SCE | EFER_LME | EFER_LMA).is_ok());
+        assert_eq!(emu.read_msr(IA32_EFER).unwrap(), EFER_SCE | EFER_LME);
+    }
+
+    // 書き込まれた値を記録し、上位32ビットを反転した値を返すハンドラ
+    struct Echo {
+        written: Rc<Cell<u64>>,
+    }
+
+    impl MsrHandler for Echo {
+        fn read(&mut self, _index: u32) -> Option<u64> {
+            Some(self.written.get() ^ 0xFFFF_FFFF_0000_0000)
+        }
+
+        fn write(&mut self, _index: u32, value: u64) -> bool {
+            self.written.set(value);
+            value != 0
+        }
+    }
+
+    #[test]
+    fn wrmsr_and_rdmsr_transfer_edx_eax() {
+        let mut emu = emulator(&[
+            0x0F, 0x30, // wrmsr
+            0xB8, 0x00, 0x00, 0x00, 0x00, // mov eax, 0
+            0xBA, 0x00, 0x00, 0x00, 0x00, // mov edx, 0
+            0x0F, 0x32, // rdmsr
+        ]);
+        emu.registers[0] = 0x7d00;
+        emu.set_register32(1, IA32_SYSENTER_EIP);
+        emu.registers[2] = 0x1234;
+        run(&mut emu, 1);
+        assert_eq!(emu.msr.sysenter_eip, 0x1234_0000_7d00);
+        run(&mut emu, 3);
+        assert_eq!(emu.eip, 0x7c0e);
+        assert_eq!((emu.registers[2], emu.registers[0]), (0x1234, 0x7d00));
+    }
+
+    #[test]
+    fn unknown_msr_raises_general_protection() {
+        let mut emu = protected_mode_emulator(
+            &[
+                0x0F, 0x32, // rdmsr
+            ],
+            13,
+        );
+        emu.registers[1] = 0x1234;
+        run(&mut emu, 1);
+        assert_eq!(emu.eip, 0x7e00);
+        assert_eq!(read32(&emu, emu.get_register32(4) + 4), 0x7c00);
+    }
+
+    #[test]
+    fn rdmsr_outside_ring0_raises_general_protection() {
+        let mut emu = ring3_emulator(
+            &[
+                0x0F, 0x32, // rdmsr
+            ],
+            13,
+        );
+        emu.set_register32(1, IA32_APIC_BASE);
+        run(&mut emu, 1);
+        assert_eq!(emu.eip, 0x7e00);
+        assert_eq!(read32(&emu, RING0_STACK - 20), 0x7c00);
+        assert_eq!(emu.registers[0], 0);
+    }
+
+    #[test]
+    fn msr_file_has_initial_values_and_mtrr_stubs() {
+        let mut emu = emulator(&[]);
+        assert_eq!(emu.read_msr(IA32_APIC_BASE).unwrap(), APIC_BASE_INITIAL);
+        assert_eq!(emu.read_msr(IA32_MTRRCAP).unwrap(), MTRRCAP_VALUE);
+        assert_eq!(emu.write_msr(IA32_MTRRCAP, 0).unwrap_err().vector, 13);
+        // MTRRは書き込んだ値を保持するだけ
+        assert_eq!(emu.read_msr(IA32_MTRR_DEF_TYPE).unwrap(), 0);
+        emu.write_msr(IA32_MTRR_DEF_TYPE, 0xC06).unwrap();
+        assert_eq!(emu.read_msr(IA32_MTRR_DEF_TYPE).unwrap(), 0xC06);
+        emu.write_msr(IA32_TIME_STAMP_COUNTER, 100).unwrap();
+        assert_eq!(emu.read_msr(IA32_TIME_STAMP_COUNTER).unwrap(), 100);
+    }
+
+    #[test]
+    fn permissive_mode_stores_unknown_msrs() {
+        let mut emu = emulator(&[]);
+        assert!(emu.write_msr(0x1234, 5).is_err());
+        emu.set_msr_permissive(true);
+        assert_eq!(emu.read_msr(0x1234).unwrap(), 0);
+        emu.write_msr(0x1234, 5).unwrap();
+        assert_eq!(emu.read_msr(0x1234).unwrap(), 5);
+        // 読み出し専用のMSRは許容モードでも書き込めない
+        assert!(emu.write_msr(IA32_MTRRCAP, 0).is_err());
+    }
+
+    #[test]
+    fn registered_handler_overrides_msr() {
+        let mut emu = emulator(&[]);
+        let written = Rc::new(Cell::new(0));
+        emu.register_msr_handler(IA32_APIC_BASE, Box::new(Echo { written: written.clone() }));
+        emu.write_msr(IA32_APIC_BASE, 0x1).unwrap();
+        assert_eq!(written.get(), 0x1);
+        assert_eq!(emu.read_msr(IA32_APIC_BASE).unwrap(), 0xFFFF_FFFF_0000_0001);
+        // ハンドラが拒否した書き込みは#GPになる
+        assert_eq!(emu.write_msr(IA32_APIC_BASE, 0).unwrap_err().vector, 13);
+    }
+}
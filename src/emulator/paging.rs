@@ -0,0 +1,207 @@
+use emulator::a20::A20Gate;
+use emulator::control_register::{CR0_PG, CR0_WP};
+use emulator::interrupt::page_fault;
+use emulator::segment::Segment;
+use emulator::stop::{EmuError, Stop};
+use emulator::Emulator;
+
+// ページテーブルのエントリのビット
+const PAGE_PRESENT: u64 = 1;
+const PAGE_WRITABLE: u64 = 1 << 1;
+const PAGE_USER: u64 = 1 << 2;
+const PAGE_ACCESSED: u64 = 1 << 5;
+const PAGE_DIRTY: u64 = 1 << 6;
+// PDPTとPDのエントリで、1GBと2MBのページを直接指す
+const PAGE_SIZE: u64 = 1 << 7;
+// エントリが指す物理アドレス(ビット12〜51)
+const PAGE_ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+// #PFのエラーコードのビット
+const PF_PROTECTION: u32 = 1;
+const PF_WRITE: u32 = 1 << 1;
+const PF_USER: u32 = 1 << 2;
+
+// PML4, PDPT, PD, PTの4段
+const PAGING_LEVELS: usize = 4;
+
+// メモリアクセスの種類(書き込みだけがページの書き込み禁止を確かめる)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PageAccess {
+    Read,
+    Write,
+    Fetch,
+}
+
+pub trait Paging {
+    fn translate(&mut self, linear: u64, access: PageAccess) -> Option<usize>;
+}
+
+impl Emulator {
+    // ページテーブルのエントリは物理アドレスで直接読み書きする
+    fn read_page_entry(&mut self, address: u64) -> Option<u64> {
+        let mut entry = 0;
+        for i in 0..8 {
+            let physical = self.mask_a20(address as usize + i);
+            match self.bus.read8(physical) {
+                Ok(value) => entry |= (value as u64) << (i * 8),
+                Err(_) => {
+                    self.set_emulation_error(EmuError::MemoryFault { address: physical });
+                    return None;
+                }
+            }
+        }
+        Some(entry)
+    }
+
+    fn write_page_entry(&mut self, address: u64, entry: u64) {
+        for i in 0..8 {
+            let physical = self.mask_a20(address as usize + i);
+            if self.bus.write8(physical, (entry >> (i * 8)) as u8).is_err() {
+                self.set_emulation_error(EmuError::MemoryFault { address: physical });
+                return;
+            }
+        }
+    }
+
+    // 命令を取り消してから#PFを通知する(CR2にはフォールトしたリニアアドレスが入る)
+    fn fault_page(&mut self, linear: u64, error_code: u32) -> Option<usize> {
+        if self.memory_fault.is_none() {
+            self.memory_fault = Some(page_fault(error_code));
+            self.control_registers[2] = linear;
+        }
+        None
+    }
+}
+
+impl Paging for Emulator {
+    // リニアアドレスを物理アドレスに変換する。ページングが無効ならそのまま返す
+    // フォールトしたときやページテーブルが読めないときはNoneを返す
+    fn translate(&mut self, linear: u64, access: PageAccess) -> Option<usize> {
+        if self.control_registers[0] & CR0_PG == 0 {
+            return Some(linear as usize);
+        }
+        // ディスクリプタテーブルなどへのアクセスはCPLに関係なくスーパーバイザとして扱う
+        let user = self.cpl() == 3 && !self.supervisor_access;
+        let write = access == PageAccess::Write;
+        let mut error_code = 0;
+        if write {
+            error_code |= PF_WRITE;
+        }
+        if user {
+            error_code |= PF_USER;
+        }
+
+        let mut walked = [(0, 0); PAGING_LEVELS];
+        let mut depth = 0;
+        let mut table = self.control_registers[3] & PAGE_ADDRESS_MASK;
+        let mut writable = true;
+        let mut user_page = true;
+        let mut physical = 0;
+        while depth < PAGING_LEVELS {
+            let shift = 39 - 9 * depth;
+            let entry_address = table + ((linear >> shift) & 0x1FF) * 8;
+            let entry = self.read_page_entry(entry_address)?;
+            if entry & PAGE_PRESENT == 0 {
+                return self.fault_page(linear, error_code);
+            }
+            writable &= entry & PAGE_WRITABLE != 0;
+            user_page &= entry & PAGE_USER != 0;
+            walked[depth] = (entry_address, entry);
+            depth += 1;
+            // PTのエントリか、PSビットの立ったPDPTかPDのエントリがページを指す
+            if depth == PAGING_LEVELS || (depth > 1 && entry & PAGE_SIZE != 0) {
+                let offset_mask = (1 << shift) - 1;
+                physical = (entry & PAGE_ADDRESS_MASK & !offset_mask) | (linear & offset_mask);
+                break;
+            }
+            table = entry & PAGE_ADDRESS_MASK;
+        }
+
+        // ユーザーモードからはスーパーバイザのページにアクセスできない
+        // 書き込み禁止のページには、ユーザーモードかCR0.WPが立っていれば書き込めない
+        let write_protected = user || self.control_registers[0] & CR0_WP != 0;
+        if (user && !user_page) || (write && !writable && write_protected) {
+            error_code |= PF_PROTECTION;
+            return self.fault_page(linear, error_code);
+        }
+
+        // たどったエントリにはアクセス済み、書き込んだページにはダーティのビットを立てる
+        for (level, &(address, entry)) in walked[..depth].iter().enumerate() {
+            let mut updated = entry | PAGE_ACCESSED;
+            if write && level == depth - 1 {
+                updated |= PAGE_DIRTY;
+            }
+            if updated != entry {
+                self.write_page_entry(address, updated);
+            }
+        }
+        Some(physical as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use emulator::emulator_function::EmulatorFunction;
+    use emulator::testing::*;
+
+    // long_mode_emulatorの割り当てで、addressのページのPTのエントリ
+    fn page_entry(address: u32) -> u32 {
+        PAGE_TABLE + (address >> 12) * 8
+    }
+
+    #[test]
+    fn pages_translate_linear_addresses_and_record_accesses() {
+        let mut emu = long_mode_emulator(&[]);
+        // リニアアドレス0x2000のページを物理アドレス0x30000に割り当てる
+        write64(&mut emu, page_entry(0x2000), 0x30000 | PAGE_WRITABLE | PAGE_PRESENT);
+        emu.set_memory8(0x2345, 0xAB);
+        assert_eq!(read8(&emu, 0x30345), 0xAB);
+        assert_eq!(emu.get_memory8(0x2345), 0xAB);
+        let entry = read64(&emu, page_entry(0x2000));
+        assert_eq!(entry & (PAGE_ACCESSED | PAGE_DIRTY), PAGE_ACCESSED | PAGE_DIRTY);
+        assert_ne!(read64(&emu, PML4) & PAGE_ACCESSED, 0);
+        // 読み出しだけのページはダーティにならない
+        emu.get_memory8(0x3000);
+        assert_eq!(read64(&emu, page_entry(0x3000)) & (PAGE_ACCESSED | PAGE_DIRTY), PAGE_ACCESSED);
+    }
+
+    #[test]
+    fn large_pages_map_two_megabytes() {
+        let mut emu = long_mode_emulator(&[]);
+        // リニアアドレス0x4000_0000からの1GBを、PDの先頭の2MBページで物理アドレス0に割り当てる
+        write64(&mut emu, PDPT + 8, 0x14000 | PAGE_WRITABLE | PAGE_PRESENT);
+        write64(&mut emu, 0x14000, PAGE_SIZE | PAGE_WRITABLE | PAGE_PRESENT);
+        assert_eq!(emu.translate(0x4000_7C00, PageAccess::Read), Some(0x7C00));
+        assert_eq!(emu.translate(0x4010_0000, PageAccess::Read), Some(0x10_0000));
+    }
+
+    #[test]
+    fn missing_pages_raise_page_fault_with_cr2() {
+        let mut emu = long_mode_emulator(&[]);
+        write64(&mut emu, page_entry(0x8000), 0);
+        emu.set_memory8(0x8010, 1);
+        let fault = emu.memory_fault.take().unwrap();
+        assert_eq!((fault.vector, fault.error_code), (14, Some(PF_WRITE)));
+        assert_eq!(emu.control_registers[2], 0x8010);
+        assert_eq!(emu.translate(0x8000, PageAccess::Fetch), None);
+    }
+
+    #[test]
+    fn protection_depends_on_cpl_and_write_protect() {
+        let mut emu = long_mode_emulator(&[]);
+        // スーパーバイザの読み出し専用ページ
+        write64(&mut emu, page_entry(0x8000), 0x8000 | PAGE_PRESENT);
+        // CR0.WPが立っていなければスーパーバイザは書き込める
+        assert_eq!(emu.translate(0x8000, PageAccess::Write), Some(0x8000));
+        emu.control_registers[0] |= CR0_WP;
+        assert_eq!(emu.translate(0x8000, PageAccess::Write), None);
+        assert_eq!(emu.memory_fault.take().unwrap().error_code, Some(PF_WRITE | PF_PROTECTION));
+
+        enter_ring3(&mut emu);
+        assert_eq!(emu.translate(0x8000, PageAccess::Read), None);
+        assert_eq!(emu.memory_fault.take().unwrap().error_code, Some(PF_USER | PF_PROTECTION));
+        // ディスクリプタテーブルなどへのアクセスはスーパーバイザとして扱う
+        assert_eq!(emu.supervisor_access(|emu| emu.translate(0x8000, PageAccess::Read)), Some(0x8000));
+    }
+}
@@ -0,0 +1,1220 @@
+use emulator::alignment_check::AlignmentCheck;
+use emulator::emulator_function::EmulatorFunction;
+use emulator::interrupt::{general_protection, invalid_opcode, invalid_tss, segment_not_present, stack_fault, Exception, Interrupt};
+use emulator::modrm::{Function as ModRMFunction, ModRM};
+use emulator::task::{Task, TaskSwitchReason};
+use emulator::{Emulator, Register, SegmentRegister, SEGMENT_REGISTERS_COUNT};
+
+// システムディスクリプタ(TSS/ゲート)のタイプ
+pub const LDT: u32 = 0x2;
+pub const TASK_GATE: u32 = 0x5;
+pub const TSS_AVAILABLE_32: u32 = 0x9;
+pub const TSS_BUSY_32: u32 = 0xB;
+pub const CALL_GATE_32: u32 = 0xC;
+pub const INTERRUPT_GATE_32: u32 = 0xE;
+pub const TRAP_GATE_32: u32 = 0xF;
+
+// GDT/IDTに格納されている8バイトのディスクリプタ
+#[derive(Clone, Copy)]
+pub struct SegmentDescriptor {
+    pub low: u32,
+    pub high: u32,
+}
+
+impl SegmentDescriptor {
+    pub fn base(&self) -> u32 {
+        (self.low >> 16) | ((self.high & 0xFF) << 16) | (self.high & 0xFF00_0000)
+    }
+
+    pub fn limit(&self) -> u32 {
+        let limit = (self.low & 0xFFFF) | (self.high & 0x000F_0000);
+        // Gビットが立っていれば4KB単位
+        if self.high & (1 << 23) != 0 {
+            (limit << 12) | 0xFFF
+        } else {
+            limit
+        }
+    }
+
+    pub fn descriptor_type(&self) -> u32 {
+        (self.high >> 8) & 0x0F
+    }
+
+    pub fn is_system(&self) -> bool {
+        self.high & (1 << 12) == 0
+    }
+
+    pub fn dpl(&self) -> u8 {
+        ((self.high >> 13) & 3) as u8
+    }
+
+    pub fn is_present(&self) -> bool {
+        self.high & (1 << 15) != 0
+    }
+
+    pub fn is_code(&self) -> bool {
+        !self.is_system() && self.high & (1 << 11) != 0
+    }
+
+    // Lビットが立ち、D/Bビットの消えた64ビットコードセグメント
+    pub fn is_64bit_code(&self) -> bool {
+        self.is_code() && self.high & (1 << 21) != 0 && self.high & (1 << 22) == 0
+    }
+
+    pub fn is_conforming(&self) -> bool {
+        self.is_code() && self.high & (1 << 10) != 0
+    }
+
+    pub fn is_writable_data(&self) -> bool {
+        !self.is_system() && !self.is_code() && self.high & (1 << 9) != 0
+    }
+
+    // データセグメントと読み出し可能なコードセグメント
+    pub fn is_readable(&self) -> bool {
+        !self.is_system() && (!self.is_code() || self.high & (1 << 9) != 0)
+    }
+
+    pub fn gate_selector(&self) -> u16 {
+        (self.low >> 16) as u16
+    }
+
+    pub fn gate_offset(&self) -> u32 {
+        (self.low & 0xFFFF) | (self.high & 0xFFFF_0000)
+    }
+
+    pub fn gate_parameter_count(&self) -> u32 {
+        self.high & 0x1F
+    }
+}
+
+// セグメントレジスタの不可視部分(ディスクリプタキャッシュ)
+// セレクタを読み込んだときにディスクリプタから写され、リアルモードに戻っても保持される
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SegmentCache {
+    pub base: u32,
+    pub limit: u32,
+    // ディスクリプタ上位の属性(LARで読める形式: タイプ, S, DPL, P, AVL, L, D/B, G)
+    pub attributes: u32,
+}
+
+// 起動時のフラットな32ビットコード/データセグメント
+pub const FLAT_CODE_SEGMENT: SegmentCache = SegmentCache {
+    base: 0,
+    limit: 0xFFFF_FFFF,
+    attributes: 0x00C0_9B00,
+};
+pub const FLAT_DATA_SEGMENT: SegmentCache = SegmentCache {
+    base: 0,
+    limit: 0xFFFF_FFFF,
+    attributes: 0x00C0_9300,
+};
+// 特権レベルが変わるときに確かめるデータセグメントレジスタ
+const DATA_SEGMENTS: [usize; 4] = [
+    SegmentRegister::ES as usize,
+    SegmentRegister::DS as usize,
+    SegmentRegister::FS as usize,
+    SegmentRegister::GS as usize,
+];
+// リセット直後のリアルモードのセグメントは64KBの読み書き可能なデータセグメントとして扱われる(CSも同じ)
+const REAL_MODE_ATTRIBUTES: u32 = 0x9300;
+// V86モードのセグメントは64KBで、リング3の読み書き可能なデータセグメントとして扱われる
+const VIRTUAL_8086_ATTRIBUTES: u32 = 0xF300;
+
+impl SegmentCache {
+    pub fn from_descriptor(descriptor: &SegmentDescriptor) -> SegmentCache {
+        SegmentCache {
+            base: descriptor.base(),
+            limit: descriptor.limit(),
+            attributes: descriptor.high & 0x00F0_FF00,
+        }
+    }
+
+    pub fn real_mode(selector: u16) -> SegmentCache {
+        SegmentCache {
+            base: (selector as u32) << 4,
+            limit: 0xFFFF,
+            attributes: REAL_MODE_ATTRIBUTES,
+        }
+    }
+
+    // D/Bビットが立っていれば32ビットセグメント
+    pub fn is_32bit(&self) -> bool {
+        self.attributes & (1 << 22) != 0
+    }
+
+    // Lビットが立っていれば64ビットコードセグメント(ロングモードでなければ無視される)
+    pub fn is_64bit(&self) -> bool {
+        self.attributes & (1 << 21) != 0
+    }
+
+    pub fn dpl(&self) -> u8 {
+        ((self.attributes >> 13) & 3) as u8
+    }
+
+    // S, コード, コンフォーミングの各ビットが立っている
+    pub fn is_conforming_code(&self) -> bool {
+        self.attributes & 0x1C00 == 0x1C00
+    }
+
+    pub fn is_present(&self) -> bool {
+        self.attributes & (1 << 15) != 0
+    }
+
+    // Sが立っていて、コードではなくエクスパンドダウンのデータセグメント
+    pub fn is_expand_down(&self) -> bool {
+        self.attributes & 0x1C00 == 0x1400
+    }
+
+    // offsetからsizeバイトがセグメントのリミットに収まるか
+    // エクスパンドダウンではリミットより上から、D/Bに応じて0xFFFFか0xFFFFFFFFまでが有効
+    pub fn contains(&self, offset: u32, size: u32) -> bool {
+        let last = offset as u64 + size as u64 - 1;
+        if self.is_expand_down() {
+            let upper = if self.is_32bit() { 0xFFFF_FFFF } else { 0xFFFF };
+            offset > self.limit && last <= upper
+        } else {
+            last <= self.limit as u64
+        }
+    }
+}
+
+// ローカルディスクリプタテーブルレジスタ(LDTのセレクタとベース・リミット)
+#[derive(Clone, Copy, Default)]
+pub struct LocalDescriptorTableRegister {
+    pub selector: u16,
+    pub base: u32,
+    pub limit: u32,
+}
+
+// セレクタからエラーコードを作る(RPLを落とす)
+pub fn selector_error(selector: u16) -> u32 {
+    selector as u32 & 0xFFFC
+}
+
+pub trait Segment {
+    fn cpl(&self) -> u8;
+    fn read_descriptor(&mut self, selector: u16) -> Result<SegmentDescriptor, Exception>;
+    fn set_segment_register(&mut self, index: usize, selector: u16);
+    fn set_descriptor_type(&mut self, selector: u16, descriptor_type: u32);
+    fn check_stack_segment(&mut self, selector: u16, cpl: u8) -> Result<(), Exception>;
+    fn check_return_code_segment(&mut self, selector: u16) -> Result<(), Exception>;
+    fn far_jump(&mut self, selector: u16, offset: u32) -> Result<(), Exception>;
+    fn far_call(&mut self, selector: u16, offset: u32) -> Result<(), Exception>;
+    fn far_return(&mut self, release: u32) -> Result<(), Exception>;
+    fn invalidate_data_segments(&mut self);
+    fn jmp_ptr16_32(&mut self);
+    fn call_ptr16_32(&mut self);
+    fn jmp_m16_32(&mut self, modrm: &ModRM);
+    fn call_m16_32(&mut self, modrm: &ModRM);
+    fn retf(&mut self);
+    fn retf_imm16(&mut self);
+    fn lgdt(&mut self, modrm: &ModRM);
+    fn read_ldt_descriptor(&mut self, selector: u16) -> Result<LocalDescriptorTableRegister, Exception>;
+    fn lldt(&mut self, modrm: &ModRM);
+    fn sldt(&mut self, modrm: &ModRM);
+    fn load_segment_register(&mut self, index: usize, selector: u16) -> Result<(), Exception>;
+    fn mov_rm16_sreg(&mut self);
+    fn mov_sreg_rm16(&mut self);
+    fn push_sreg(&mut self, index: usize, length: u32);
+    fn pop_sreg(&mut self, index: usize, length: u32);
+    fn load_far_pointer(&mut self, index: usize, length: u32);
+}
+
+impl Emulator {
+    // ゲートやTSSを通した転送で、ディスクリプタのDPLとセレクタのRPLを確認する
+    fn check_gate_privilege(&self, selector: u16, descriptor: &SegmentDescriptor) -> Result<(), Exception> {
+        let rpl = (selector & 3) as u8;
+        if descriptor.dpl() < self.cpl() || descriptor.dpl() < rpl {
+            return Err(general_protection(selector_error(selector)));
+        }
+        if !descriptor.is_present() {
+            return Err(segment_not_present(selector_error(selector)));
+        }
+        Ok(())
+    }
+
+    // ゲートの転送先のコードセグメントを確認する
+    fn read_gate_target(&mut self, selector: u16) -> Result<SegmentDescriptor, Exception> {
+        if selector & 0xFFFC == 0 {
+            return Err(general_protection(0));
+        }
+        let code = self.read_descriptor(selector)?;
+        if !code.is_code() || code.dpl() > self.cpl() {
+            return Err(general_protection(selector_error(selector)));
+        }
+        if !code.is_present() {
+            return Err(segment_not_present(selector_error(selector)));
+        }
+        Ok(code)
+    }
+
+    // 同じ特権レベルのコードセグメントへの直接の転送を確認する
+    fn check_direct_code_segment(&self, selector: u16, code: &SegmentDescriptor) -> Result<(), Exception> {
+        let cpl = self.cpl();
+        let allowed = if code.is_conforming() {
+            code.dpl() <= cpl
+        } else {
+            (selector & 3) as u8 <= cpl && code.dpl() == cpl
+        };
+        if !allowed {
+            return Err(general_protection(selector_error(selector)));
+        }
+        if !code.is_present() {
+            return Err(segment_not_present(selector_error(selector)));
+        }
+        Ok(())
+    }
+
+    fn set_code_segment(&mut self, selector: u16, cpl: u8, offset: u32) {
+        self.set_segment_register(SegmentRegister::CS as usize, (selector & 0xFFFC) | cpl as u16);
+        self.eip = offset as u64;
+    }
+
+    // オペランドサイズに合わせてptr16:16またはptr16:32を読み取る
+    fn read_far_pointer(&mut self, address: usize) -> (u16, u32) {
+        self.check_alignment(address, 4);
+        if self.is_operand_size16() {
+            (self.get_memory16(address + 2) as u16, self.get_memory16(address))
+        } else {
+            (self.get_memory16(address + 4) as u16, self.get_memory32(address))
+        }
+    }
+
+    // 命令に埋め込まれたptr16:16/ptr16:32と命令長を読み取る
+    fn get_far_pointer_code(&mut self) -> (u16, u32, u32) {
+        if self.is_operand_size16() {
+            (self.get_code16(3), self.get_code16(1) as u32, 5)
+        } else {
+            (self.get_code16(5), self.get_code32(1), 7)
+        }
+    }
+
+    fn push_return_address(&mut self, cs: u16, eip: u32) {
+        if self.is_operand_size16() {
+            self.push16(cs);
+            self.push16(eip as u16);
+        } else {
+            self.push32(cs as u32);
+            self.push32(eip);
+        }
+    }
+}
+
+impl Segment for Emulator {
+    fn cpl(&self) -> u8 {
+        // V86モードは常にリング3、リアルモードは常にリング0で動作する
+        if self.is_virtual_8086() {
+            return 3;
+        }
+        if self.is_real_mode() {
+            return 0;
+        }
+        (self.segment_registers[SegmentRegister::CS as usize] & 3) as u8
+    }
+
+    fn read_descriptor(&mut self, selector: u16) -> Result<SegmentDescriptor, Exception> {
+        let index = selector as u32 & 0xFFF8;
+        // TIビットが立っていればLDTを参照する
+        let (base, limit) = if selector & 4 != 0 {
+            if self.ldtr.selector & 0xFFFC == 0 {
+                return Err(general_protection(selector_error(selector)));
+            }
+            (self.ldtr.base as u64, self.ldtr.limit)
+        } else {
+            (self.gdtr.base, self.gdtr.limit as u32)
+        };
+        if index + 7 > limit {
+            return Err(general_protection(selector_error(selector)));
+        }
+        let address = self.descriptor_table_address(base, index);
+        Ok(self.supervisor_access(|emu| SegmentDescriptor {
+            low: emu.get_memory32(address),
+            high: emu.get_memory32(address + 4),
+        }))
+    }
+
+    // 確認済みのセレクタを読み込み、ディスクリプタキャッシュを更新する
+    fn set_segment_register(&mut self, index: usize, selector: u16) {
+        self.segment_registers[index] = selector;
+        if self.is_virtual_8086() {
+            self.segment_caches[index] = SegmentCache {
+                base: (selector as u32) << 4,
+                limit: 0xFFFF,
+                attributes: VIRTUAL_8086_ATTRIBUTES,
+            };
+        } else if self.is_real_mode() {
+            // リアルモードではベースだけが変わり、リミットと属性は以前のものが残る(アンリアルモード)
+            self.segment_caches[index].base = (selector as u32) << 4;
+        } else if selector & 0xFFFC == 0 {
+            // ヌルセレクタを読み込んだセグメントは使用できない
+            self.segment_caches[index] = SegmentCache::default();
+        } else {
+            let cache = self
+                .read_descriptor(selector)
+                .map(|descriptor| SegmentCache::from_descriptor(&descriptor))
+                .unwrap_or_default();
+            self.segment_caches[index] = cache;
+        }
+    }
+
+    fn set_descriptor_type(&mut self, selector: u16, descriptor_type: u32) {
+        let address = self.descriptor_table_address(self.gdtr.base, (selector as u32 & 0xFFF8) + 5);
+        self.supervisor_access(|emu| {
+            let access = emu.get_memory8(address);
+            emu.set_memory8(address, (access & 0xF0) | descriptor_type);
+        });
+    }
+
+    fn check_stack_segment(&mut self, selector: u16, cpl: u8) -> Result<(), Exception> {
+        if selector & 0xFFFC == 0 {
+            return Err(general_protection(0));
+        }
+        let stack = self.read_descriptor(selector)?;
+        if (selector & 3) as u8 != cpl || stack.dpl() != cpl || !stack.is_writable_data() {
+            return Err(general_protection(selector_error(selector)));
+        }
+        if !stack.is_present() {
+            return Err(stack_fault(selector_error(selector)));
+        }
+        Ok(())
+    }
+
+    fn check_return_code_segment(&mut self, selector: u16) -> Result<(), Exception> {
+        // 内側の特権レベルへは戻れない
+        let rpl = (selector & 3) as u8;
+        if selector & 0xFFFC == 0 || rpl < self.cpl() {
+            return Err(general_protection(selector_error(selector)));
+        }
+        let code = self.read_descriptor(selector)?;
+        let allowed = code.is_code() && if code.is_conforming() { code.dpl() <= rpl } else { code.dpl() == rpl };
+        if !allowed {
+            return Err(general_protection(selector_error(selector)));
+        }
+        if !code.is_present() {
+            return Err(segment_not_present(selector_error(selector)));
+        }
+        Ok(())
+    }
+
+    fn far_jump(&mut self, selector: u16, offset: u32) -> Result<(), Exception> {
+        // リアルモードとV86モードではセレクタをそのままCSに読み込む
+        if self.uses_real_mode_segments() {
+            self.set_segment_register(SegmentRegister::CS as usize, selector);
+            self.eip = offset as u64;
+            return Ok(());
+        }
+        if selector & 0xFFFC == 0 {
+            return Err(general_protection(0));
+        }
+        let descriptor = self.read_descriptor(selector)?;
+        let cpl = self.cpl();
+        if descriptor.is_code() {
+            self.check_direct_code_segment(selector, &descriptor)?;
+            self.set_code_segment(selector, cpl, offset);
+            return Ok(());
+        }
+        if !descriptor.is_system() {
+            return Err(general_protection(selector_error(selector)));
+        }
+
+        match descriptor.descriptor_type() {
+            CALL_GATE_32 => {
+                self.check_gate_privilege(selector, &descriptor)?;
+                let target = descriptor.gate_selector();
+                let code = self.read_gate_target(target)?;
+                // JMPでは特権レベルは変わらない
+                if !code.is_conforming() && code.dpl() != cpl {
+                    return Err(general_protection(selector_error(target)));
+                }
+                self.set_code_segment(target, cpl, descriptor.gate_offset());
+                Ok(())
+            }
+            TASK_GATE => {
+                self.check_gate_privilege(selector, &descriptor)?;
+                self.switch_task(descriptor.gate_selector(), TaskSwitchReason::Jump, 0)
+            }
+            TSS_AVAILABLE_32 => {
+                self.check_gate_privilege(selector, &descriptor)?;
+                self.switch_task(selector, TaskSwitchReason::Jump, 0)
+            }
+            _ => Err(general_protection(selector_error(selector))),
+        }
+    }
+
+    fn far_call(&mut self, selector: u16, offset: u32) -> Result<(), Exception> {
+        let cs = self.segment_registers[SegmentRegister::CS as usize];
+        let eip = self.eip as u32;
+        if self.uses_real_mode_segments() {
+            self.push_return_address(cs, eip);
+            self.set_segment_register(SegmentRegister::CS as usize, selector);
+            self.eip = offset as u64;
+            return Ok(());
+        }
+        if selector & 0xFFFC == 0 {
+            return Err(general_protection(0));
+        }
+        let descriptor = self.read_descriptor(selector)?;
+        let cpl = self.cpl();
+        if descriptor.is_code() {
+            self.check_direct_code_segment(selector, &descriptor)?;
+            self.push_return_address(cs, eip);
+            self.set_code_segment(selector, cpl, offset);
+            return Ok(());
+        }
+        if !descriptor.is_system() {
+            return Err(general_protection(selector_error(selector)));
+        }
+
+        match descriptor.descriptor_type() {
+            CALL_GATE_32 => {
+                self.check_gate_privilege(selector, &descriptor)?;
+                let target = descriptor.gate_selector();
+                let code = self.read_gate_target(target)?;
+                let new_cpl = if !code.is_conforming() && code.dpl() < cpl {
+                    // 特権レベルが上がる場合はTSSのスタックに切り替えて引数をコピーする
+                    let (ss, esp) = self.tss_stack(code.dpl())?;
+                    self.check_stack_segment(ss, code.dpl()).map_err(|_| invalid_tss(selector_error(ss)))?;
+                    let old_ss = self.segment_registers[SegmentRegister::SS as usize];
+                    let old_esp = self.get_register32(Register::ESP as usize);
+                    // 引数は切り替える前のスタック(SS:ESP)から読む
+                    let old_sp = self.get_stack_pointer();
+                    let count = descriptor.gate_parameter_count();
+                    let parameters: Vec<u32> = (0..count).map(|i| self.read_stack(old_sp.wrapping_add(i * 4), 4)).collect();
+                    self.set_segment_register(SegmentRegister::SS as usize, ss);
+                    self.set_register32(Register::ESP as usize, esp);
+                    self.push32(old_ss as u32);
+                    self.push32(old_esp);
+                    for parameter in parameters.into_iter().rev() {
+                        self.push32(parameter);
+                    }
+                    code.dpl()
+                } else {
+                    cpl
+                };
+                self.push_return_address(cs, eip);
+                self.set_code_segment(target, new_cpl, descriptor.gate_offset());
+                Ok(())
+            }
+            TASK_GATE => {
+                self.check_gate_privilege(selector, &descriptor)?;
+                self.switch_task(descriptor.gate_selector(), TaskSwitchReason::Call, 0)
+            }
+            TSS_AVAILABLE_32 => {
+                self.check_gate_privilege(selector, &descriptor)?;
+                self.switch_task(selector, TaskSwitchReason::Call, 0)
+            }
+            _ => Err(general_protection(selector_error(selector))),
+        }
+    }
+
+    fn far_return(&mut self, release: u32) -> Result<(), Exception> {
+        if self.uses_real_mode_segments() {
+            let (eip, cs) = if self.is_operand_size16() {
+                (self.pop16() as u32, self.pop16())
+            } else {
+                (self.pop32(), self.pop32() as u16)
+            };
+            let sp = self.get_stack_pointer().wrapping_add(release);
+            self.set_stack_pointer(sp);
+            self.set_segment_register(SegmentRegister::CS as usize, cs);
+            self.eip = eip as u64;
+            return Ok(());
+        }
+        // オペランドサイズに合わせて16ビットか32ビットのEIP, CS(, ESP, SS)を読む
+        let size = self.operand_size();
+        let sp = self.get_stack_pointer();
+        let eip = self.read_stack(sp, size);
+        let cs = self.read_stack(sp.wrapping_add(size), size) as u16;
+        self.check_return_code_segment(cs)?;
+
+        let rpl = (cs & 3) as u8;
+        let outer = rpl > self.cpl();
+        if outer {
+            // 外側の特権レベルへ戻る場合は呼び出し元のスタックに戻す
+            let new_sp = self.read_stack(sp.wrapping_add(size * 2 + release), size);
+            let new_ss = self.read_stack(sp.wrapping_add(size * 3 + release), size) as u16;
+            self.check_stack_segment(new_ss, rpl)?;
+            self.set_segment_register(SegmentRegister::SS as usize, new_ss);
+            self.set_stack_pointer(new_sp.wrapping_add(release));
+        } else {
+            self.set_stack_pointer(sp.wrapping_add(size * 2 + release));
+        }
+        self.set_code_segment(cs, rpl, eip);
+        if outer {
+            self.invalidate_data_segments();
+        }
+        Ok(())
+    }
+
+    // 外側の特権レベルへ戻ったとき、新しいCPLから使えないセグメントを指すES/DS/FS/GSをヌルにする
+    // (DPLがCPLより小さいデータセグメントと非コンフォーミングコードセグメント)
+    fn invalidate_data_segments(&mut self) {
+        let cpl = self.cpl();
+        for &index in DATA_SEGMENTS.iter() {
+            let cache = self.segment_caches[index];
+            if !cache.is_conforming_code() && cache.dpl() < cpl {
+                self.segment_registers[index] = 0;
+                self.segment_caches[index] = SegmentCache::default();
+            }
+        }
+    }
+
+    fn jmp_ptr16_32(&mut self) {
+        let (selector, offset, length) = self.get_far_pointer_code();
+        self.eip += length as u64;
+        if let Err(e) = self.far_jump(selector, offset) {
+            self.raise_exception(e);
+        }
+    }
+
+    fn call_ptr16_32(&mut self) {
+        let (selector, offset, length) = self.get_far_pointer_code();
+        self.eip += length as u64;
+        if let Err(e) = self.far_call(selector, offset) {
+            self.raise_exception(e);
+        }
+    }
+
+    fn jmp_m16_32(&mut self, modrm: &ModRM) {
+        let size = self.operand_size() + 2;
+        let address = self.calc_linear_address(modrm, size) as usize;
+        let (selector, offset) = self.read_far_pointer(address);
+        if let Err(e) = self.far_jump(selector, offset) {
+            self.raise_exception(e);
+        }
+    }
+
+    fn call_m16_32(&mut self, modrm: &ModRM) {
+        let size = self.operand_size() + 2;
+        let address = self.calc_linear_address(modrm, size) as usize;
+        let (selector, offset) = self.read_far_pointer(address);
+        if let Err(e) = self.far_call(selector, offset) {
+            self.raise_exception(e);
+        }
+    }
+
+    fn retf(&mut self) {
+        if let Err(e) = self.far_return(0) {
+            self.raise_exception(e);
+        }
+    }
+
+    fn retf_imm16(&mut self) {
+        let release = self.get_code16(1) as u32;
+        if let Err(e) = self.far_return(release) {
+            self.raise_exception(e);
+        }
+    }
+
+    fn lgdt(&mut self, modrm: &ModRM) {
+        if self.cpl() != 0 {
+            self.raise_exception(general_protection(0));
+            return;
+        }
+        self.gdtr = self.read_descriptor_table_register(modrm);
+    }
+
+    fn load_segment_register(&mut self, index: usize, selector: u16) -> Result<(), Exception> {
+        // リアルモードとV86モードではセレクタを16倍した値がそのままベースになる
+        if self.uses_real_mode_segments() {
+            self.set_segment_register(index, selector);
+            return Ok(());
+        }
+        let cache = if index == SegmentRegister::SS as usize {
+            let cpl = self.cpl();
+            self.check_stack_segment(selector, cpl)?;
+            SegmentCache::from_descriptor(&self.read_descriptor(selector)?)
+        } else if selector & 0xFFFC == 0 {
+            // データセグメントにはヌルセレクタを読み込める
+            SegmentCache::default()
+        } else {
+            let descriptor = self.read_descriptor(selector)?;
+            let rpl = (selector & 3) as u8;
+            if !descriptor.is_readable() {
+                return Err(general_protection(selector_error(selector)));
+            }
+            // データセグメントと非コンフォーミングコードセグメントはDPL >= max(CPL, RPL)でなければならない
+            if !descriptor.is_conforming() && descriptor.dpl() < self.cpl().max(rpl) {
+                return Err(general_protection(selector_error(selector)));
+            }
+            if !descriptor.is_present() {
+                return Err(segment_not_present(selector_error(selector)));
+            }
+            SegmentCache::from_descriptor(&descriptor)
+        };
+        self.segment_registers[index] = selector;
+        self.segment_caches[index] = cache;
+        Ok(())
+    }
+
+    fn mov_rm16_sreg(&mut self) {
+        self.eip += 1;
+        let modrm = self.parse_modrm();
+        let index = modrm.get_reg_index() as usize;
+        if index >= SEGMENT_REGISTERS_COUNT {
+            self.raise_exception(invalid_opcode());
+            return;
+        }
+        let selector = self.segment_registers[index];
+        // レジスタへ格納する場合は上位をゼロ拡張する
+        if modrm.mode == 3 && !self.is_operand_size16() {
+            self.set_rm32(&modrm, selector as u32);
+        } else {
+            self.set_rm16(&modrm, selector);
+        }
+    }
+
+    fn mov_sreg_rm16(&mut self) {
+        self.eip += 1;
+        let modrm = self.parse_modrm();
+        let index = modrm.get_reg_index() as usize;
+        // CSはMOVで変更できない
+        if index == SegmentRegister::CS as usize || index >= SEGMENT_REGISTERS_COUNT {
+            self.raise_exception(invalid_opcode());
+            return;
+        }
+        let selector = self.get_rm16(&modrm);
+        if let Err(e) = self.load_segment_register(index, selector) {
+            self.raise_exception(e);
+            return;
+        }
+        if index == SegmentRegister::SS as usize {
+            self.interrupt_shadow = true;
+        }
+    }
+
+    fn push_sreg(&mut self, index: usize, length: u32) {
+        let selector = self.segment_registers[index];
+        if self.is_operand_size16() {
+            self.push16(selector);
+        } else {
+            self.push32(selector as u32);
+        }
+        self.eip += length as u64;
+    }
+
+    fn pop_sreg(&mut self, index: usize, length: u32) {
+        // 読み込みに失敗した場合はスタックポインタを戻す
+        let sp = self.get_stack_pointer();
+        let selector = if self.is_operand_size16() { self.pop16() } else { self.pop32() as u16 };
+        if let Err(e) = self.load_segment_register(index, selector) {
+            self.set_stack_pointer(sp);
+            self.raise_exception(e);
+            return;
+        }
+        if index == SegmentRegister::SS as usize {
+            self.interrupt_shadow = true;
+        }
+        self.eip += length as u64;
+    }
+
+    fn load_far_pointer(&mut self, index: usize, length: u32) {
+        self.eip += length as u64;
+        let modrm = self.parse_modrm();
+        // ファーポインタはメモリからしか読み込めない
+        if modrm.mode == 3 {
+            self.raise_exception(invalid_opcode());
+            return;
+        }
+        let size = self.operand_size() + 2;
+        let address = self.calc_linear_address(&modrm, size) as usize;
+        let (selector, offset) = self.read_far_pointer(address);
+        if let Err(e) = self.load_segment_register(index, selector) {
+            self.raise_exception(e);
+            return;
+        }
+        if self.is_operand_size16() {
+            self.set_r16(&modrm, offset as u16);
+        } else {
+            self.set_r32(&modrm, offset);
+        }
+    }
+
+    fn read_ldt_descriptor(&mut self, selector: u16) -> Result<LocalDescriptorTableRegister, Exception> {
+        // ヌルセレクタを読み込むとLDTは使えなくなる
+        if selector & 0xFFFC == 0 {
+            return Ok(LocalDescriptorTableRegister {
+                selector,
+                ..Default::default()
+            });
+        }
+        // LDTのディスクリプタはGDTに置かなければならない
+        if selector & 4 != 0 {
+            return Err(general_protection(selector_error(selector)));
+        }
+        let descriptor = self.read_descriptor(selector)?;
+        if !descriptor.is_system() || descriptor.descriptor_type() != LDT {
+            return Err(general_protection(selector_error(selector)));
+        }
+        if !descriptor.is_present() {
+            return Err(segment_not_present(selector_error(selector)));
+        }
+        Ok(LocalDescriptorTableRegister {
+            selector,
+            base: descriptor.base(),
+            limit: descriptor.limit(),
+        })
+    }
+
+    fn lldt(&mut self, modrm: &ModRM) {
+        if self.cpl() != 0 {
+            self.raise_exception(general_protection(0));
+            return;
+        }
+        let selector = self.get_rm16(modrm);
+        match self.read_ldt_descriptor(selector) {
+            Ok(ldtr) => self.ldtr = ldtr,
+            Err(e) => self.raise_exception(e),
+        }
+    }
+
+    fn sldt(&mut self, modrm: &ModRM) {
+        let selector = self.ldtr.selector;
+        // レジスタへ格納する場合は上位をゼロ拡張する
+        if modrm.mode == 3 {
+            self.set_rm32(modrm, selector as u32);
+        } else {
+            self.set_rm16(modrm, selector);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SegmentCache;
+    use emulator::control_register::CR0_PE;
+    use emulator::emulator_function::EmulatorFunction;
+    use emulator::testing::*;
+    use emulator::{Emulator, SegmentRegister};
+
+    // 保護モードの例外ハンドラ(0x7e00)へ飛んだことと、積まれたエラーコードと戻り先を確かめる
+    fn assert_fault(emu: &Emulator, vector: u32, eip: u32) {
+        assert_eq!(emu.eip, 0x7e00, "例外{}が起きていない", vector);
+        assert_eq!(read32(emu, emu.get_register32(4)), 0);
+        assert_eq!(read32(emu, emu.get_register32(4) + 4), eip);
+    }
+
+    #[test]
+    fn retf_with_operand_size_prefix_pops_16bit_ip_and_cs() {
+        let mut emu = emulator(&[
+            0x66, 0x6A, 0x08, // push word 0x08
+            0x66, 0x68, 0x20, 0x7C, // push word 0x7c20
+            0x66, 0xCB, // retf(16ビット)
+        ]);
+        install_flat_segments(&mut emu);
+        run(&mut emu, 3);
+        assert_eq!(emu.eip, 0x7c20);
+        assert_eq!(emu.segment_registers[SegmentRegister::CS as usize], 0x08);
+        assert_eq!(emu.get_register32(4), STACK);
+    }
+
+    #[test]
+    fn retf_to_outer_ring_nulls_inaccessible_data_segments() {
+        let mut emu = emulator(&[
+            0x66, 0xB8, 0x10, 0x00, // mov ax, 0x10
+            0x8E, 0xD8, // mov ds, ax
+            0x66, 0xB8, 0x23, 0x00, // mov ax, 0x23
+            0x8E, 0xE0, // mov fs, ax
+            0x66, 0x6A, 0x23, // push word 0x23(SS)
+            0x66, 0x68, 0x00, 0x80, // push word 0x8000(SP)
+            0x66, 0x6A, 0x1B, // push word 0x1B(CS)
+            0x66, 0x68, 0x00, 0x7D, // push word 0x7d00(IP)
+            0x66, 0xCB, // retf(16ビット)
+        ]);
+        install_flat_segments(&mut emu);
+        run(&mut emu, 9);
+        assert_eq!(emu.eip, 0x7d00);
+        assert_eq!(emu.segment_registers[SegmentRegister::CS as usize], 0x1B);
+        assert_eq!(emu.segment_registers[SegmentRegister::SS as usize], 0x23);
+        assert_eq!(emu.registers[4] & 0xFFFF, 0x8000);
+        // DPL0のDSはヌルになり、DPL3のFSはそのまま残る
+        assert_eq!(emu.segment_registers[SegmentRegister::DS as usize], 0);
+        assert_eq!(emu.segment_caches[SegmentRegister::DS as usize].attributes, 0);
+        assert_eq!(emu.segment_registers[SegmentRegister::FS as usize], 0x23);
+    }
+
+    #[test]
+    fn segment_cache_limits_expand_up_and_down() {
+        let normal = SegmentCache {
+            base: 0,
+            limit: 0xFFF,
+            attributes: 0x9300,
+        };
+        assert!(normal.contains(0xFFC, 4));
+        assert!(!normal.contains(0xFFD, 4));
+        // 16ビットのエクスパンドダウンはリミットの次から0xFFFFまで
+        let down16 = SegmentCache {
+            base: 0,
+            limit: 0xFFF,
+            attributes: 0x9700,
+        };
+        assert!(!down16.contains(0xFFF, 1));
+        assert!(down16.contains(0x1000, 2));
+        assert!(!down16.contains(0xFFFF, 2));
+        let down32 = SegmentCache {
+            attributes: 0x0040_9700,
+            ..down16
+        };
+        assert!(down32.contains(0xFFFF, 2));
+        assert!(!down32.contains(0xFFFF_FFFF, 2));
+    }
+
+    #[test]
+    fn data_access_beyond_limit_raises_general_protection() {
+        let mut emu = protected_mode_emulator(
+            &[
+                0x66, 0xB8, 0x28, 0x00, // mov ax, 0x28
+                0x8E, 0xD8, // mov ds, ax
+                0x8B, 0x0D, 0xFC, 0x0F, 0x00, 0x00, // mov ecx, [0xffc]
+                0x8B, 0x0D, 0xFD, 0x0F, 0x00, 0x00, // mov ecx, [0xffd]
+            ],
+            13,
+        );
+        // ベース0, リミット0xFFFのデータセグメント
+        set_descriptor(&mut emu, 5, 0, 0xFFF, 0x92, 0x4);
+        write32(&mut emu, 0xFFC, 0x1234_5678);
+        run(&mut emu, 3);
+        assert_eq!(emu.registers[1], 0x1234_5678);
+        run(&mut emu, 1);
+        assert_fault(&emu, 13, 0x7c0c);
+    }
+
+    #[test]
+    fn stack_access_beyond_limit_records_stack_fault() {
+        let mut emu = emulator(&[]);
+        // リミット0x7FFFのエクスパンドダウンの32ビットスタック(0x8000〜0xFFFFFFFFが有効)
+        emu.segment_caches[SegmentRegister::SS as usize] = SegmentCache {
+            base: 0,
+            limit: 0x7FFF,
+            attributes: 0x0040_9700,
+        };
+        emu.stack_address(0x8000, 4);
+        assert!(emu.memory_fault.is_none());
+        emu.stack_address(0x7FFE, 2);
+        assert_eq!(emu.memory_fault.unwrap().vector, 12);
+    }
+
+    #[test]
+    fn instruction_crossing_code_limit_raises_general_protection() {
+        let mut emu = protected_mode_emulator(
+            &[
+                0xF8, // clc
+                0xB8, 0x01, 0x00, 0x00, 0x00, // mov eax, 1
+            ],
+            13,
+        );
+        emu.segment_caches[SegmentRegister::CS as usize].limit = 0x7c04;
+        run(&mut emu, 2);
+        assert_fault(&emu, 13, 0x7c01);
+        assert_eq!(emu.registers[0], 0);
+    }
+
+    #[test]
+    fn reset_to_real_mode_runs_16bit_code() {
+        let mut emu = emulator(&[
+            0xB8, 0x34, 0x12, // mov ax, 0x1234
+            0x8E, 0xD8, // mov ds, ax
+        ]);
+        emu.reset_to_real_mode();
+        run(&mut emu, 2);
+        assert_eq!(emu.eip, 0x7c05);
+        assert_eq!(emu.control_registers[0], 0);
+        let ds = emu.segment_cache(SegmentRegister::DS);
+        assert_eq!((ds.base, ds.limit), (0x12340, 0xFFFF));
+    }
+
+    #[test]
+    fn descriptor_cache_survives_return_to_real_mode() {
+        let mut emu = emulator(&[
+            0x0F, 0x20, 0xC0, // mov eax, cr0
+            0x66, 0x40, // inc eax
+            0x0F, 0x22, 0xC0, // mov cr0, eax
+            0xBB, 0x10, 0x00, // mov bx, 0x10
+            0x8E, 0xDB, // mov ds, bx
+            0x66, 0xB8, 0x00, 0x00, 0x00, 0x00, // mov eax, 0
+            0x0F, 0x22, 0xC0, // mov cr0, eax
+            0xBB, 0x00, 0x10, // mov bx, 0x1000
+            0x8E, 0xDB, // mov ds, bx
+            0x67, 0x66, 0x8B, 0x0D, 0x00, 0x00, 0x02, 0x00, // mov ecx, [0x20000]
+        ]);
+        emu.reset_to_real_mode();
+        install_flat_segments(&mut emu);
+        emu.segment_registers = [0; 6];
+        write32(&mut emu, 0x30000, 0x1234_5678);
+        run(&mut emu, 9);
+        assert_eq!(emu.control_registers[0] & CR0_PE, 0);
+        // リアルモードでセレクタを読み込んでもベースだけが変わり、4GBのリミットが残る
+        let ds = emu.segment_cache(SegmentRegister::DS);
+        assert_eq!((ds.base, ds.limit), (0x10000, 0xFFFF_FFFF));
+        run(&mut emu, 1);
+        assert_eq!(emu.registers[1], 0x1234_5678);
+    }
+
+    #[test]
+    fn real_mode_access_beyond_64k_raises_general_protection() {
+        let mut emu = real_mode_emulator(
+            &[
+                0x67, 0x66, 0x8B, 0x0D, 0x00, 0x00, 0x02, 0x00, // mov ecx, [0x20000]
+            ],
+            13,
+        );
+        write32(&mut emu, 0x20000, 0x1234_5678);
+        run(&mut emu, 1);
+        assert_eq!(emu.eip, 0x7e00);
+        assert_eq!(emu.registers[1], 0);
+    }
+
+    #[test]
+    fn descriptor_table_address_wraps_at_4gb() {
+        let mut emu = emulator(&[
+            0x66, 0xB8, 0x10, 0x00, // mov ax, 0x10
+            0x8E, 0xD8, // mov ds, ax
+        ]);
+        // GDTのベースが4GBの手前にあり、セレクタ0x10のディスクリプタは0番地に折り返す
+        emu.gdtr.base = 0xFFFF_FFF0;
+        emu.gdtr.limit = 0x17;
+        write32(&mut emu, 0, 0x0000_FFFF);
+        write32(&mut emu, 4, 0x00CF_9200);
+        run(&mut emu, 2);
+        assert_eq!(emu.segment_registers[SegmentRegister::DS as usize], 0x10);
+        assert_eq!(emu.segment_cache(SegmentRegister::DS).limit, 0xFFFF_FFFF);
+    }
+
+    #[test]
+    fn call_gate_to_inner_ring_switches_stack() {
+        let mut emu = emulator(&[
+            0x9A, 0x00, 0x00, 0x00, 0x00, 0x33, 0x00, // call 0x33:0
+        ]);
+        emu.bus.load(0x7d00, &[0xCB]); // retf
+        install_flat_segments(&mut emu);
+        // リング3から呼べる、リング0の0x7d00への32ビットコールゲート
+        set_gate(&mut emu, GDT, 6, 0x08, 0x7d00, 0xEC);
+        install_tss(&mut emu);
+        enter_ring3(&mut emu);
+
+        run(&mut emu, 1);
+        assert_eq!(emu.eip, 0x7d00);
+        assert_eq!(emu.segment_registers[SegmentRegister::CS as usize], 0x08);
+        assert_eq!(emu.segment_registers[SegmentRegister::SS as usize], 0x10);
+        // 新しいスタックに呼び出し元のSS:ESPとCS:EIPが積まれる
+        assert_eq!(emu.get_register32(4), RING0_STACK - 16);
+        assert_eq!(read32(&emu, RING0_STACK - 16), 0x7c07);
+        assert_eq!(read32(&emu, RING0_STACK - 12), 0x1B);
+        assert_eq!(read32(&emu, RING0_STACK - 8), STACK);
+        assert_eq!(read32(&emu, RING0_STACK - 4), 0x23);
+
+        // RETFで元のリングとスタックに戻る
+        run(&mut emu, 1);
+        assert_eq!(emu.eip, 0x7c07);
+        assert_eq!(emu.segment_registers[SegmentRegister::CS as usize], 0x1B);
+        assert_eq!(emu.segment_registers[SegmentRegister::SS as usize], 0x23);
+        assert_eq!(emu.get_register32(4), STACK);
+    }
+
+    #[test]
+    fn call_gate_copies_parameters_through_the_stack_segment() {
+        let mut emu = emulator(&[
+            0x9A, 0x00, 0x00, 0x00, 0x00, 0x33, 0x00, // call 0x33:0
+        ]);
+        install_flat_segments(&mut emu);
+        // 引数を2つ(ダブルワード)コピーするコールゲート
+        set_gate(&mut emu, GDT, 6, 0x08, 0x7d00, 0xEC);
+        emu.bus.load((GDT + 6 * 8 + 4) as usize, &[2]);
+        install_tss(&mut emu);
+        enter_ring3(&mut emu);
+        // 呼び出し元のスタックセグメントのベースは0x10000
+        emu.segment_caches[SegmentRegister::SS as usize].base = 0x10000;
+        write32(&mut emu, 0x10000 + STACK, 0x1111);
+        write32(&mut emu, 0x10000 + STACK + 4, 0x2222);
+
+        run(&mut emu, 1);
+        assert_eq!(emu.eip, 0x7d00);
+        assert_eq!(emu.get_register32(4), RING0_STACK - 24);
+        assert_eq!(read32(&emu, RING0_STACK - 16), 0x1111);
+        assert_eq!(read32(&emu, RING0_STACK - 12), 0x2222);
+        assert_eq!(read32(&emu, RING0_STACK - 8), STACK);
+    }
+
+    #[test]
+    fn far_call16_pushes_a_16bit_return_address() {
+        let mut emu = emulator(&[
+            0x66, 0x9A, 0x00, 0x7D, 0x08, 0x00, // call 0x08:0x7d00(オペランドサイズ16ビット)
+        ]);
+        emu.bus.load(0x7d00, &[0x66, 0xCB]); // retf(オペランドサイズ16ビット)
+        install_flat_segments(&mut emu);
+
+        run(&mut emu, 1);
+        assert_eq!(emu.eip, 0x7d00);
+        assert_eq!(emu.get_register32(4), STACK - 4);
+        assert_eq!(read16(&emu, STACK - 4), 0x7c06);
+        assert_eq!(read16(&emu, STACK - 2), 0x08);
+
+        run(&mut emu, 1);
+        assert_eq!(emu.eip, 0x7c06);
+        assert_eq!(emu.segment_registers[SegmentRegister::CS as usize], 0x08);
+        assert_eq!(emu.get_register32(4), STACK);
+    }
+
+    #[test]
+    fn call_gate_with_insufficient_privilege_raises_general_protection() {
+        let mut emu = protected_mode_emulator(
+            &[
+                0x9A, 0x00, 0x00, 0x00, 0x00, 0x33, 0x00, // call 0x33:0
+            ],
+            13,
+        );
+        // DPL0のコールゲートはリング3から呼べない
+        set_gate(&mut emu, GDT, 6, 0x08, 0x7d00, 0x8C);
+        install_tss(&mut emu);
+        enter_ring3(&mut emu);
+
+        run(&mut emu, 1);
+        assert_eq!(emu.eip, 0x7e00);
+        // エラーコードはゲートのセレクタ
+        assert_eq!(read32(&emu, RING0_STACK - 24), 0x30);
+        assert_eq!(read32(&emu, RING0_STACK - 20), 0x7c00);
+    }
+
+    #[test]
+    fn far_pointer_loads_set_selector_and_offset() {
+        let mut emu = emulator(&[
+            0xC5, 0x05, 0x00, 0x40, 0x00, 0x00, // lds eax, [0x4000]
+            0x0F, 0xB2, 0x1D, 0x00, 0x40, 0x00, 0x00, // lss ebx, [0x4000]
+            0x0F, 0xB4, 0x0D, 0x00, 0x40, 0x00, 0x00, // lfs ecx, [0x4000]
+            0x0F, 0xB5, 0x15, 0x00, 0x40, 0x00, 0x00, // lgs edx, [0x4000]
+            0x66, 0xC4, 0x35, 0x10, 0x40, 0x00, 0x00, // les si, [0x4010]
+        ]);
+        install_flat_segments(&mut emu);
+        write32(&mut emu, 0x4000, 0x1234_5678);
+        write16(&mut emu, 0x4004, 0x10);
+        write16(&mut emu, 0x4010, 0xABCD);
+        write16(&mut emu, 0x4012, 0x23);
+        emu.registers[6] = 0xFFFF_FFFF;
+        run(&mut emu, 5);
+        assert_eq!(&emu.registers[0..4], &[0x1234_5678; 4]);
+        assert_eq!(emu.registers[6], 0xFFFF_ABCD);
+        assert_eq!(emu.segment_registers, [0x23, 0x08, 0x10, 0x10, 0x10, 0x10]);
+    }
+
+    #[test]
+    fn lss_with_null_selector_raises_general_protection() {
+        let mut emu = protected_mode_emulator(
+            &[
+                0x0F, 0xB2, 0x1D, 0x00, 0x40, 0x00, 0x00, // lss ebx, [0x4000]
+            ],
+            13,
+        );
+        write32(&mut emu, 0x4000, 0x1234_5678);
+        run(&mut emu, 1);
+        assert_fault(&emu, 13, 0x7c00);
+        assert_eq!(emu.registers[3], 0);
+    }
+
+    #[test]
+    fn mov_to_and_from_segment_registers() {
+        let mut emu = protected_mode_emulator(
+            &[
+                0xB8, 0x23, 0x00, 0x00, 0x00, // mov eax, 0x23
+                0x8E, 0xD8, // mov ds, ax
+                0x8C, 0xD9, // mov ecx, ds
+                0x8C, 0x1D, 0x00, 0x40, 0x00, 0x00, // mov [0x4000], ds
+                0x8E, 0xC8, // mov cs, ax
+            ],
+            6,
+        );
+        emu.registers[1] = 0xFFFF_FFFF;
+        write32(&mut emu, 0x4000, 0xFFFF_FFFF);
+        run(&mut emu, 4);
+        assert_eq!(emu.segment_registers[SegmentRegister::DS as usize], 0x23);
+        // レジスタへはゼロ拡張し、メモリへは16ビットだけ書き込む
+        assert_eq!(emu.registers[1], 0x23);
+        assert_eq!(read32(&emu, 0x4000), 0xFFFF_0023);
+        // CSはMOVで変更できない
+        run(&mut emu, 1);
+        assert_eq!(emu.eip, 0x7e00);
+        assert_eq!(read32(&emu, emu.get_register32(4)), 0x7c0f);
+    }
+
+    #[test]
+    fn push_and_pop_segment_registers() {
+        let mut emu = emulator(&[
+            0x1E, // push ds
+            0x07, // pop es
+            0x66, 0x0F, 0xA0, // push fs (16ビット)
+            0x66, 0x0F, 0xA9, // pop gs (16ビット)
+        ]);
+        install_flat_segments(&mut emu);
+        emu.segment_registers[SegmentRegister::DS as usize] = 0x23;
+        emu.segment_registers[SegmentRegister::FS as usize] = 0x23;
+        run(&mut emu, 1);
+        assert_eq!(emu.get_register32(4), STACK - 4);
+        run(&mut emu, 1);
+        assert_eq!(emu.segment_registers[SegmentRegister::ES as usize], 0x23);
+        run(&mut emu, 1);
+        assert_eq!(emu.get_register32(4), STACK - 2);
+        run(&mut emu, 1);
+        assert_eq!(emu.segment_registers[SegmentRegister::GS as usize], 0x23);
+        assert_eq!(emu.get_register32(4), STACK);
+    }
+
+    #[test]
+    fn mov_ss_inhibits_interrupts_for_one_instruction() {
+        let mut emu = emulator(&[
+            0xB8, 0x10, 0x00, 0x00, 0x00, // mov eax, 0x10
+            0x8E, 0xD0, // mov ss, ax
+            0xBC, 0x00, 0x80, 0x00, 0x00, // mov esp, 0x8000
+            0xF8, // clc
+        ]);
+        install_flat_segments(&mut emu);
+        install_idt(&mut emu, 0x30);
+        set_interrupt_gate(&mut emu, 0x21, 0x7e00);
+        emu.eflags |= Emulator::INTERRUPT_FLAG;
+        run(&mut emu, 2);
+        emu.interrupt_lines_mut().raise(1);
+        // MOV SSの次の命令までは割り込みを受け付けない
+        run(&mut emu, 1);
+        assert_eq!(emu.registers[4], 0x8000);
+        run(&mut emu, 1);
+        assert_eq!(emu.eip, 0x7e00);
+        assert_eq!(read32(&emu, 0x8000 - 12), 0x7c0c);
+    }
+
+    #[test]
+    fn lldt_makes_local_descriptors_loadable() {
+        let mut emu = emulator(&[
+            0xB8, 0x38, 0x00, 0x00, 0x00, // mov eax, 0x38
+            0x0F, 0x00, 0xD0, // lldt ax
+            0xB8, 0x0C, 0x00, 0x00, 0x00, // mov eax, 0x0C
+            0x8E, 0xD8, // mov ds, ax
+            0x8B, 0x0D, 0x00, 0x01, 0x00, 0x00, // mov ecx, [0x100]
+            0x0F, 0x00, 0xC2, // sldt edx
+        ]);
+        install_flat_segments(&mut emu);
+        // GDTの7番目に0x3800のLDTを置き、LDTの1番目(セレクタ0x0C)はベース0x10000のデータセグメント
+        set_descriptor(&mut emu, 7, 0x3800, 0x17, 0x82, 0);
+        let ldt_entry = (0x3800 + 8 - GDT) / 8;
+        set_descriptor(&mut emu, ldt_entry as u16, 0x10000, 0xFFFFF, 0x92, 0xC);
+        write32(&mut emu, 0x10100, 0xCAFE);
+        emu.registers[2] = 0xFFFF_FFFF;
+        run(&mut emu, 6);
+        assert_eq!(emu.ldtr.selector, 0x38);
+        assert_eq!((emu.ldtr.base, emu.ldtr.limit), (0x3800, 0x17));
+        assert_eq!(emu.registers[1], 0xCAFE);
+        assert_eq!(emu.registers[2], 0x38);
+    }
+
+    #[test]
+    fn lldt_rejects_descriptors_that_are_not_ldts() {
+        let mut emu = protected_mode_emulator(
+            &[
+                0xB8, 0x10, 0x00, 0x00, 0x00, // mov eax, 0x10
+                0x0F, 0x00, 0xD0, // lldt ax
+            ],
+            13,
+        );
+        run(&mut emu, 2);
+        assert_eq!(emu.eip, 0x7e00);
+        assert_eq!(read32(&emu, emu.get_register32(4)), 0x10);
+        assert_eq!(read32(&emu, emu.get_register32(4) + 4), 0x7c05);
+    }
+}
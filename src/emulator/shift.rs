@@ -0,0 +1,122 @@
+use emulator::emulator_function::EmulatorFunction;
+use emulator::modrm::Function as ModRMFunction;
+use emulator::undefined_flags::UndefinedFlags;
+use emulator::{Emulator, Register8};
+
+pub trait Shift {
+    fn shift_group(&mut self);
+}
+
+impl Shift for Emulator {
+    // C0/C1: 回数は即値, D0/D1: 1回, D2/D3: CLの回数。偶数のオペコードは8ビット
+    // ModRMのregで /0 ROL, /1 ROR, /2 RCL, /3 RCR, /4 SHL, /5 SHR, /6 SAL(SHLと同じ), /7 SAR
+    fn shift_group(&mut self) {
+        let code = self.get_code8(0);
+        self.eip += 1;
+        let modrm = self.parse_modrm();
+        let count = match code {
+            0xC0 | 0xC1 => {
+                let count = self.get_code8(0);
+                self.eip += 1;
+                count
+            }
+            0xD0 | 0xD1 => 1,
+            _ => self.get_register8(Register8::CL as usize),
+        };
+        // 80186以降は回数の下位5ビットだけを使う(8086は指定された回数だけ繰り返す)
+        let count = if self.cpu_model.masks_shift_count() { count & 0x1F } else { count };
+        let size = if code & 1 == 0 { 1 } else { self.operand_size() };
+        let value = self.get_rm_sized(&modrm, size);
+        // 回数が0ならオペランドもフラグも変化しない
+        if count == 0 {
+            return;
+        }
+        let bits = size * 8;
+        let msb = 1 << (bits - 1);
+        let mask = 0xFFFF_FFFF >> (32 - bits);
+        let operation = modrm.get_opecode();
+        let mut result = value;
+        let mut carry = self.is_carry();
+        for _ in 0..count {
+            let (next, out) = match operation {
+                0 => ((result << 1) | (result >> (bits - 1)), result & msb != 0),
+                1 => ((result >> 1) | ((result & 1) << (bits - 1)), result & 1 != 0),
+                2 => ((result << 1) | carry as u32, result & msb != 0),
+                3 => ((result >> 1) | ((carry as u32) << (bits - 1)), result & 1 != 0),
+                4 | 6 => (result << 1, result & msb != 0),
+                5 => (result >> 1, result & 1 != 0),
+                _ => ((result >> 1) | (result & msb), result & 1 != 0),
+            };
+            result = next & mask;
+            carry = out;
+        }
+        self.set_rm_sized(&modrm, size, result);
+        self.set_carry(carry);
+        // OFは1回のときの定義に合わせて最後の結果から求める
+        let sign = result & msb != 0;
+        let overflow = match operation {
+            1 | 3 => sign != (result & (msb >> 1) != 0),
+            5 => value & msb != 0,
+            7 => false,
+            _ => sign != carry,
+        };
+        self.set_overflow(overflow);
+        // 回数が2以上のときOFは未定義(IntelもAMDも上の式の値になる)
+        if count > 1 {
+            let flags = self.eflags;
+            self.set_undefined_flags(Self::OVERFLOW_FLAG, flags, flags);
+        }
+        // ローテートはCFとOFだけを変更する
+        if operation >= 4 {
+            self.set_sign(sign);
+            self.set_zero(result == 0);
+            self.set_flag(Self::PARITY_FLAG, (result as u8).count_ones() & 1 == 0);
+            // シフトのAFは未定義(Intelは0, AMDは1にする)
+            self.set_undefined_flags(Self::ADJUST_FLAG, 0, Self::ADJUST_FLAG);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use emulator::emulator_function::EmulatorFunction;
+    use emulator::testing::*;
+
+    #[test]
+    fn shifts_and_rotates_set_carry_from_last_bit_out() {
+        let mut emu = emulator(&[
+            0xC1, 0xE0, 0x04, // shl eax, 4
+            0xD1, 0xF9, // sar ecx, 1
+            0xD0, 0xCA, // ror dl, 1
+            0xD1, 0xD3, // rcl ebx, 1
+        ]);
+        emu.registers[0] = 0x1234_5678;
+        emu.registers[1] = 0x8000_0003;
+        emu.registers[2] = 0x01;
+        emu.registers[3] = 0x8000_0000;
+        run(&mut emu, 1);
+        assert_eq!((emu.registers[0], emu.is_carry()), (0x2345_6780, true));
+        run(&mut emu, 1);
+        assert_eq!((emu.registers[1], emu.is_carry()), (0xC000_0001, true));
+        assert!(emu.is_sign());
+        run(&mut emu, 1);
+        assert_eq!((emu.registers[2] & 0xFF, emu.is_carry()), (0x80, true));
+        // RCLはCFを最下位ビットに入れ、最上位ビットをCFに出す
+        run(&mut emu, 1);
+        assert_eq!((emu.registers[3], emu.is_carry()), (1, true));
+    }
+
+    #[test]
+    fn zero_count_leaves_operand_and_flags_unchanged() {
+        let mut emu = emulator(&[0xD3, 0xE0]); // shl eax, cl
+        emu.registers[0] = 0x8000_0000;
+        emu.registers[1] = 0x20;
+        emu.set_carry(true);
+        emu.set_zero(false);
+        run(&mut emu, 1);
+        // 32ビットでは回数0x20が0にマスクされる
+        assert_eq!(emu.registers[0], 0x8000_0000);
+        assert!(emu.is_carry());
+        assert!(!emu.is_zero());
+    }
+}
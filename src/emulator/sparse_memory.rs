@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+
+// ページ単位で確保する大きさ
+pub const PAGE_SIZE: usize = 4096;
+
+// 書き込まれたページだけを確保するRAM(確保していないページは0として読める)
+pub struct SparseMemory {
+    size: usize,
+    pages: HashMap<usize, Box<[u8; PAGE_SIZE]>>,
+}
+
+impl SparseMemory {
+    pub fn new(size: usize) -> SparseMemory {
+        SparseMemory { size, pages: HashMap::new() }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    // 実際に確保したページ数
+    pub fn allocated_pages(&self) -> usize {
+        self.pages.len()
+    }
+
+    pub fn read8(&self, offset: usize) -> u8 {
+        match self.pages.get(&(offset / PAGE_SIZE)) {
+            Some(page) => page[offset % PAGE_SIZE],
+            None => 0,
+        }
+    }
+
+    pub fn write8(&mut self, offset: usize, value: u8) {
+        // 確保していないページに0を書いても読める値は変わらない
+        if value == 0 && !self.pages.contains_key(&(offset / PAGE_SIZE)) {
+            return;
+        }
+        let page = self.pages.entry(offset / PAGE_SIZE).or_insert_with(|| Box::new([0; PAGE_SIZE]));
+        page[offset % PAGE_SIZE] = value;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use emulator::bus::Bus;
+    use emulator::testing::*;
+    use emulator::{Emulator, SegmentRegister};
+
+    #[test]
+    fn pages_are_allocated_only_for_nonzero_writes() {
+        let mut memory = SparseMemory::new(1 << 32);
+        assert_eq!(memory.size(), 1 << 32);
+        assert_eq!(memory.read8(0xFFFF_FFFF), 0);
+        memory.write8(0x1234, 0);
+        assert_eq!(memory.allocated_pages(), 0);
+        memory.write8(0xFFFF_FFFF, 0xAB);
+        memory.write8(0xFFFF_F000, 0xCD);
+        assert_eq!(memory.allocated_pages(), 1);
+        assert_eq!((memory.read8(0xFFFF_FFFF), memory.read8(0xFFFF_F000)), (0xAB, 0xCD));
+        // 確保したページには0も書き込める
+        memory.write8(0xFFFF_FFFF, 0);
+        assert_eq!(memory.read8(0xFFFF_FFFF), 0);
+        assert_eq!(memory.allocated_pages(), 1);
+    }
+
+    #[test]
+    fn guest_runs_anywhere_in_four_gigabytes() {
+        let mut bus = Bus::new();
+        bus.add_ram(0, 1 << 32).unwrap();
+        bus.load(
+            CODE as usize,
+            &[
+                0xE9, 0xFB, 0x83, 0xFE, 0xFF, // jmp 0xFFFF0000
+            ],
+        );
+        bus.load(
+            0xFFFF_0000,
+            &[
+                0xA3, 0xFC, 0xFF, 0xFF, 0xFF, // mov [0xFFFFFFFC], eax
+                0xF4, // hlt
+            ],
+        );
+        let mut emu = Emulator::with_bus(bus, CODE, STACK);
+        emu.registers[0] = 0x1234_5678;
+        run(&mut emu, 10);
+        assert_eq!(emu.eip, 0xFFFF_0006);
+        assert_eq!(read32(&emu, 0xFFFF_FFFC), 0x1234_5678);
+        // 2か所のコードと書き込んだページだけを確保している
+        assert_eq!(emu.bus().allocated_ram(), 3 * PAGE_SIZE);
+    }
+
+    #[test]
+    fn multibyte_accesses_wrap_at_four_gigabytes() {
+        let mut bus = Bus::new();
+        bus.add_ram(0, 1 << 32).unwrap();
+        bus.load(
+            CODE as usize,
+            &[
+                0x66, 0xA1, 0x0F, 0x00, 0x00, 0x00, // mov ax, [0xF]
+                0xA3, 0x0E, 0x00, 0x00, 0x00, // mov [0xE], eax
+            ],
+        );
+        bus.load(0, &[0x12]);
+        bus.load(0xFFFF_FFFF, &[0x34]);
+        let mut emu = Emulator::with_bus(bus, CODE, STACK);
+        // DSのベースを4GBの手前にずらして、オフセット0xFからのワードが0番地にまたがるようにする
+        emu.segment_caches[SegmentRegister::DS as usize].base = 0xFFFF_FFF0;
+        run(&mut emu, 1);
+        assert_eq!(emu.registers[0], 0x1234);
+        emu.registers[0] = 0xAABB_CCDD;
+        run(&mut emu, 1);
+        assert_eq!(read16(&emu, 0xFFFF_FFFE), 0xCCDD);
+        assert_eq!(read16(&emu, 0), 0xAABB);
+    }
+}
@@ -0,0 +1,218 @@
+use emulator::control_register::{CR0_EM, CR0_TS, CR4_OSFXSR};
+use emulator::cpuid::{Cpuid, Feature};
+use emulator::emulator_function::EmulatorFunction;
+use emulator::interrupt::{device_not_available, general_protection, invalid_opcode, Exception, Interrupt};
+use emulator::modrm::{Function as ModRMFunction, ModRM};
+use emulator::stop::Stop;
+use emulator::Emulator;
+
+// XMMレジスタを読み書きする命令(結果はModRMのregで指定したXMMレジスタに書き込む)
+pub type XmmOperation = fn(destination: u128, source: u128, immediate: u8) -> u128;
+
+pub trait Sse {
+    fn check_sse(&self, feature: Feature) -> Result<(), Exception>;
+    fn get_xmm_r(&self, modrm: &ModRM) -> u128;
+    fn set_xmm_r(&mut self, modrm: &ModRM, value: u128);
+    fn get_xmm_rm(&mut self, modrm: &ModRM, aligned: bool) -> Result<u128, Exception>;
+    fn set_xmm_rm(&mut self, modrm: &ModRM, value: u128, aligned: bool) -> Result<(), Exception>;
+    fn exec_xmm_instruction(&mut self, feature: Feature, opcode_length: u32, has_immediate: bool, operation: XmmOperation);
+    fn movdq_load(&mut self, feature: Feature, aligned: bool);
+    fn movdq_store(&mut self, feature: Feature, aligned: bool);
+    fn movd_xmm_rm32(&mut self);
+    fn movd_rm32_xmm(&mut self);
+    fn pxor(&mut self);
+    fn pshufd(&mut self);
+    fn shift_double_quadword(&mut self);
+}
+
+impl Emulator {
+    pub fn xmm_register(&self, index: usize) -> u128 {
+        self.xmm_registers[index]
+    }
+
+    pub fn set_xmm_register(&mut self, index: usize, value: u128) {
+        self.xmm_registers[index] = value;
+    }
+
+    // 16バイト境界に揃っていなければならないオペランドが揃っていなければ#GP(0)
+    fn xmm_memory_address(&mut self, modrm: &ModRM, aligned: bool) -> Result<usize, Exception> {
+        let address = self.calc_linear_address(modrm, 16);
+        if aligned && address & 0xF != 0 {
+            return Err(general_protection(0));
+        }
+        Ok(address as usize)
+    }
+
+    fn movdq_load_operand(&mut self, feature: Feature, aligned: bool) -> Result<(), Exception> {
+        self.check_sse(feature)?;
+        self.eip += 2;
+        let modrm = self.parse_modrm();
+        let value = self.get_xmm_rm(&modrm, aligned)?;
+        self.set_xmm_r(&modrm, value);
+        Ok(())
+    }
+
+    fn movdq_store_operand(&mut self, feature: Feature, aligned: bool) -> Result<(), Exception> {
+        self.check_sse(feature)?;
+        self.eip += 2;
+        let modrm = self.parse_modrm();
+        let value = self.get_xmm_r(&modrm);
+        self.set_xmm_rm(&modrm, value, aligned)
+    }
+}
+
+// 32ビット単位で並べ替える
+fn shuffle_doublewords(_: u128, source: u128, order: u8) -> u128 {
+    let mut result = 0;
+    for i in 0..4 {
+        let index = (order >> (i * 2)) & 3;
+        result |= ((source >> (index * 32)) & 0xFFFF_FFFF) << (i * 32);
+    }
+    result
+}
+
+fn xor(destination: u128, source: u128, _: u8) -> u128 {
+    destination ^ source
+}
+
+impl Sse for Emulator {
+    // CR0.EMが立っているかCR4.OSFXSRが立っていなければ#UD, CR0.TSが立っていれば#NM
+    fn check_sse(&self, feature: Feature) -> Result<(), Exception> {
+        let cr0 = self.control_registers[0];
+        if !self.has_feature(feature) || cr0 & CR0_EM != 0 || self.control_registers[4] & CR4_OSFXSR == 0 {
+            return Err(invalid_opcode());
+        }
+        if cr0 & CR0_TS != 0 {
+            return Err(device_not_available());
+        }
+        Ok(())
+    }
+
+    fn get_xmm_r(&self, modrm: &ModRM) -> u128 {
+        self.xmm_registers[modrm.get_reg_index() as usize]
+    }
+
+    fn set_xmm_r(&mut self, modrm: &ModRM, value: u128) {
+        self.xmm_registers[modrm.get_reg_index() as usize] = value;
+    }
+
+    fn get_xmm_rm(&mut self, modrm: &ModRM, aligned: bool) -> Result<u128, Exception> {
+        if modrm.mode == 3 {
+            return Ok(self.xmm_registers[modrm.rm as usize]);
+        }
+        let address = self.xmm_memory_address(modrm, aligned)?;
+        let mut value = 0;
+        for i in 0..4 {
+            value |= (self.get_memory32(address + i * 4) as u128) << (i * 32);
+        }
+        Ok(value)
+    }
+
+    fn set_xmm_rm(&mut self, modrm: &ModRM, value: u128, aligned: bool) -> Result<(), Exception> {
+        if modrm.mode == 3 {
+            self.xmm_registers[modrm.rm as usize] = value;
+            return Ok(());
+        }
+        let address = self.xmm_memory_address(modrm, aligned)?;
+        for i in 0..4 {
+            self.set_memory32(address + i * 4, (value >> (i * 32)) as u32);
+        }
+        Ok(())
+    }
+
+    // MOVDQU/MOVUPS以外のレガシーSSE命令のメモリオペランドは16バイト境界に揃っていなければならない
+    fn exec_xmm_instruction(&mut self, feature: Feature, opcode_length: u32, has_immediate: bool, operation: XmmOperation) {
+        if let Err(e) = self.check_sse(feature) {
+            self.raise_exception(e);
+            return;
+        }
+        self.eip += opcode_length as u64;
+        let modrm = self.parse_modrm();
+        let source = match self.get_xmm_rm(&modrm, true) {
+            Ok(source) => source,
+            Err(e) => {
+                self.raise_exception(e);
+                return;
+            }
+        };
+        let immediate = if has_immediate {
+            let immediate = self.get_code8(0);
+            self.eip += 1;
+            immediate
+        } else {
+            0
+        };
+        let destination = self.get_xmm_r(&modrm);
+        self.set_xmm_r(&modrm, operation(destination, source, immediate));
+    }
+
+    fn movdq_load(&mut self, feature: Feature, aligned: bool) {
+        if let Err(e) = self.movdq_load_operand(feature, aligned) {
+            self.raise_exception(e);
+        }
+    }
+
+    fn movdq_store(&mut self, feature: Feature, aligned: bool) {
+        if let Err(e) = self.movdq_store_operand(feature, aligned) {
+            self.raise_exception(e);
+        }
+    }
+
+    fn movd_xmm_rm32(&mut self) {
+        if let Err(e) = self.check_sse(Feature::Sse2) {
+            self.raise_exception(e);
+            return;
+        }
+        self.eip += 2;
+        let modrm = self.parse_modrm();
+        // 上位の96ビットは0になる
+        let value = self.get_rm32(&modrm);
+        self.set_xmm_r(&modrm, value as u128);
+    }
+
+    fn movd_rm32_xmm(&mut self) {
+        if let Err(e) = self.check_sse(Feature::Sse2) {
+            self.raise_exception(e);
+            return;
+        }
+        self.eip += 2;
+        let modrm = self.parse_modrm();
+        let value = self.get_xmm_r(&modrm);
+        self.set_rm32(&modrm, value as u32);
+    }
+
+    fn pxor(&mut self) {
+        self.exec_xmm_instruction(Feature::Sse2, 2, false, xor);
+    }
+
+    fn pshufd(&mut self) {
+        self.exec_xmm_instruction(Feature::Sse2, 2, true, shuffle_doublewords);
+    }
+
+    // 66 0F 73 /3 ib: PSRLDQ, /7 ib: PSLLDQ (バイト単位のシフト)
+    fn shift_double_quadword(&mut self) {
+        if let Err(e) = self.check_sse(Feature::Sse2) {
+            self.raise_exception(e);
+            return;
+        }
+        self.eip += 2;
+        let modrm = self.parse_modrm();
+        if modrm.mode != 3 {
+            self.raise_exception(invalid_opcode());
+            return;
+        }
+        let count = self.get_code8(0) as u32;
+        self.eip += 1;
+        let value = self.xmm_registers[modrm.rm as usize];
+        // 16バイト以上シフトすると0になる
+        let result = match modrm.get_opecode() {
+            3 => value.checked_shr(count * 8).unwrap_or(0),
+            7 => value.checked_shl(count * 8).unwrap_or(0),
+            _ => {
+                self.unimplemented_opcode();
+                return;
+            }
+        };
+        self.xmm_registers[modrm.rm as usize] = result;
+    }
+}
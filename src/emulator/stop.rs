@@ -0,0 +1,168 @@
+use std::fmt;
+
+use emulator::emulator_function::EmulatorFunction;
+use emulator::Emulator;
+
+// run_instructionsが実行をやめた理由(どれもゲストやホストの指示どおりの停止)
+#[derive(Clone, Debug, PartialEq)]
+pub enum StopReason {
+    // EIPが0に戻った(ゲストのプログラムが終了した)
+    GuestExit,
+    // CPL=0でHLTを実行した(EIPは次の命令を指している)
+    Halted,
+    // ホストが設定したブレークポイントのリニアアドレスに達した(その命令はまだ実行していない)
+    Breakpoint(u64),
+    // 指定した数の命令を実行し終えた
+    BudgetExhausted,
+    // 例外の配送中にダブルフォールトも配送できずトリプルフォールトになった
+    Shutdown,
+}
+
+// エミュレータが実行を続けられなくなったエラー(その命令は実行前の状態に戻してある)
+#[derive(Clone, Debug, PartialEq)]
+pub enum EmuError {
+    // 実装していない命令(命令の先頭のEIPと、プレフィックスを含む命令のバイト列)
+    UnimplementedOpcode { eip: u64, bytes: Vec<u8> },
+    // 実装していない機能(ページングなど)
+    UnsupportedFeature(&'static str),
+    // どの領域にも割り当てられていない物理アドレスを読み書きしようとした(オープンバスでないとき)
+    MemoryFault { address: usize },
+}
+
+impl fmt::Display for EmuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EmuError::UnimplementedOpcode { eip, ref bytes } => {
+                write!(f, "Not Implemented: EIP = {:0X}, Code =", eip)?;
+                for byte in bytes {
+                    write!(f, " {:02x}", byte)?;
+                }
+                Ok(())
+            }
+            EmuError::UnsupportedFeature(feature) => write!(f, "Not Implemented: {}", feature),
+            EmuError::MemoryFault { address } => write!(f, "割り当てのないアドレスをアクセスしました: {:08x}", address),
+        }
+    }
+}
+
+pub trait Stop {
+    fn reached_breakpoint(&self) -> Option<u64>;
+    fn unimplemented_opcode(&mut self);
+    fn set_emulation_error(&mut self, error: EmuError);
+}
+
+impl Emulator {
+    // 命令を実行する前に止まるリニアアドレスを登録する
+    pub fn add_breakpoint(&mut self, address: u64) {
+        if !self.breakpoints.contains(&address) {
+            self.breakpoints.push(address);
+        }
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u64) {
+        self.breakpoints.retain(|&breakpoint| breakpoint != address);
+    }
+}
+
+impl Stop for Emulator {
+    // 次に実行する命令のリニアアドレスにブレークポイントがあるか
+    fn reached_breakpoint(&self) -> Option<u64> {
+        let address = self.code_address(0);
+        if self.breakpoints.contains(&address) {
+            Some(address)
+        } else {
+            None
+        }
+    }
+
+    // 実装していない命令を見つけたら、命令の実行後に実行前の状態へ戻してポリシーに従って扱う
+    fn unimplemented_opcode(&mut self) {
+        let start = self.instruction_eip.wrapping_sub(self.eip) as i32;
+        let bytes = (0..self.instruction_length as i32).map(|i| self.get_code8(start + i)).collect();
+        self.set_emulation_error(EmuError::UnimplementedOpcode {
+            eip: self.instruction_eip,
+            bytes,
+        });
+    }
+
+    // 同じ命令で最初に起きたエラーだけを残す
+    fn set_emulation_error(&mut self, error: EmuError) {
+        if self.emulation_error.is_none() {
+            self.emulation_error = Some(error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use emulator::instruction::Instruction;
+    use emulator::testing::*;
+
+    #[test]
+    fn run_stops_for_guest_exit_halt_and_budget() {
+        // スタックの0をRETで取り出してEIP=0に戻る
+        let mut emu = emulator(&[0xC3]);
+        assert_eq!(run(&mut emu, 10), StopReason::GuestExit);
+        let mut emu = emulator(&[0xF4]); // hlt
+        assert_eq!(run(&mut emu, 10), StopReason::Halted);
+        assert_eq!(emu.eip, 0x7c01);
+        let mut emu = emulator(&[0xB8, 0x01, 0x00, 0x00, 0x00, 0xF4]);
+        assert_eq!(run(&mut emu, 1), StopReason::BudgetExhausted);
+        assert_eq!(emu.eip, 0x7c05);
+    }
+
+    #[test]
+    fn breakpoint_stops_before_the_instruction_and_resumes() {
+        let mut emu = emulator(&[
+            0xB8, 0x01, 0x00, 0x00, 0x00, // mov eax, 1
+            0xB9, 0x02, 0x00, 0x00, 0x00, // mov ecx, 2
+            0xF4, // hlt
+        ]);
+        emu.add_breakpoint(0x7c05);
+        assert_eq!(run(&mut emu, 10), StopReason::Breakpoint(0x7c05));
+        assert_eq!((emu.eip, emu.registers[1]), (0x7c05, 0));
+        // 再開すると止まった命令から実行する
+        assert_eq!(run(&mut emu, 10), StopReason::Halted);
+        assert_eq!(emu.registers[1], 2);
+        emu.remove_breakpoint(0x7c05);
+        emu.eip = 0x7c00;
+        assert_eq!(run(&mut emu, 10), StopReason::Halted);
+    }
+
+    #[test]
+    fn triple_fault_shuts_down() {
+        let mut emu = emulator(&[0x0F, 0x0B]); // ud2
+        install_flat_segments(&mut emu);
+        // どのベクタもIDTに入らない
+        install_idt(&mut emu, 1);
+        assert_eq!(run(&mut emu, 10), StopReason::Shutdown);
+    }
+
+    #[test]
+    fn unimplemented_opcode_is_returned_with_its_bytes() {
+        let mut emu = emulator(&[0x66, 0x90]);
+        let error = emu.run_instructions(true, Some(10)).unwrap_err();
+        assert_eq!(
+            error,
+            EmuError::UnimplementedOpcode {
+                eip: 0x7c00,
+                bytes: vec![0x66, 0x90],
+            }
+        );
+        assert_eq!(emu.eip, 0x7c00);
+        assert_eq!(error.to_string(), "Not Implemented: EIP = 7C00, Code = 66 90");
+    }
+
+    #[test]
+    fn access_to_unmapped_memory_is_a_fault() {
+        let mut emu = emulator(&[0xA1, 0x00, 0x00, 0x20, 0x00]); // mov eax, [0x200000]
+        let error = emu.run_instructions(true, Some(10)).unwrap_err();
+        assert_eq!(error, EmuError::MemoryFault { address: 0x20_0000 });
+        assert_eq!(emu.eip, 0x7c00);
+        // 命令の取り出しでも同じ
+        let mut emu = emulator(&[]);
+        emu.eip = 0x20_0000;
+        assert_eq!(emu.run_instructions(true, Some(10)), Err(EmuError::MemoryFault { address: 0x20_0000 }));
+    }
+}
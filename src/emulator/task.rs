@@ -0,0 +1,422 @@
+use emulator::emulator_function::EmulatorFunction;
+use emulator::interrupt::{general_protection, invalid_tss, segment_not_present, Exception, Interrupt};
+use emulator::modrm::{Function as ModRMFunction, ModRM};
+use emulator::segment::{selector_error, Segment, TSS_AVAILABLE_32, TSS_BUSY_32};
+use emulator::{Emulator, Register, SegmentRegister, SEGMENT_REGISTERS_COUNT};
+
+// 32ビットTSSの各フィールドのオフセット
+const TSS_BACK_LINK: usize = 0x00;
+const TSS_ESP0: usize = 0x04;
+const TSS_EIP: usize = 0x20;
+const TSS_EFLAGS: usize = 0x24;
+const TSS_REGISTERS: usize = 0x28;
+const TSS_SEGMENT_REGISTERS: usize = 0x48;
+const TSS_LDT: usize = 0x60;
+pub const TSS_IO_MAP_BASE: usize = 0x66;
+// 32ビットTSSとして必要なリミットの最小値
+pub const TSS_MINIMUM_LIMIT: u32 = 0x67;
+
+// タスクレジスタ(TSSのセレクタとベース・リミット)
+#[derive(Clone, Copy, Default)]
+pub struct TaskRegister {
+    pub selector: u16,
+    pub base: u32,
+    pub limit: u32,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum TaskSwitchReason {
+    Jump,
+    // CALLと割り込みは呼び出し元へのバックリンクを残す
+    Call,
+    Iret,
+}
+
+pub trait Task {
+    fn tss_stack(&mut self, dpl: u8) -> Result<(u16, u32), Exception>;
+    fn switch_task(&mut self, selector: u16, reason: TaskSwitchReason, ext: u32) -> Result<(), Exception>;
+    fn check_io_permission(&mut self, port: u16, size: u32) -> Result<(), Exception>;
+    fn ltr(&mut self, modrm: &ModRM);
+    fn str(&mut self, modrm: &ModRM);
+}
+
+impl Emulator {
+    // 新しいタスクのCS/SSを確認する
+    fn check_task_segments(&mut self, cs: u16, ss: u16, ext: u32) -> Result<(), Exception> {
+        let code = self.read_descriptor(cs).map_err(|_| invalid_tss(selector_error(cs) + ext))?;
+        if cs & 0xFFFC == 0 || !code.is_code() || !code.is_present() {
+            return Err(invalid_tss(selector_error(cs) + ext));
+        }
+        self.check_stack_segment(ss, (cs & 3) as u8)
+            .map_err(|_| invalid_tss(selector_error(ss) + ext))
+    }
+}
+
+impl Task for Emulator {
+    fn tss_stack(&mut self, dpl: u8) -> Result<(u16, u32), Exception> {
+        let offset = TSS_ESP0 + dpl as usize * 8;
+        if offset as u32 + 5 > self.task_register.limit {
+            return Err(invalid_tss(selector_error(self.task_register.selector)));
+        }
+        let address = self.task_register.base as usize + offset;
+        Ok(self.supervisor_access(|emu| (emu.get_memory16(address + 4) as u16, emu.get_memory32(address))))
+    }
+
+    fn switch_task(&mut self, selector: u16, reason: TaskSwitchReason, ext: u32) -> Result<(), Exception> {
+        let error_code = selector_error(selector) + ext;
+        // IRETで戻る先のTSSはビジー、それ以外は利用可能でなければならない
+        let (expected, violation) = if reason == TaskSwitchReason::Iret {
+            (TSS_BUSY_32, invalid_tss(error_code))
+        } else {
+            (TSS_AVAILABLE_32, general_protection(error_code))
+        };
+        let descriptor = self.read_descriptor(selector).map_err(|_| violation)?;
+        if !descriptor.is_system() || descriptor.descriptor_type() != expected {
+            return Err(violation);
+        }
+        if !descriptor.is_present() {
+            return Err(segment_not_present(error_code));
+        }
+        if descriptor.limit() < TSS_MINIMUM_LIMIT {
+            return Err(invalid_tss(error_code));
+        }
+
+        // 新しいタスクの状態を読み込み、CS/SSを確認しておく
+        let new_base = descriptor.base() as usize;
+        let eip = self.get_memory32(new_base + TSS_EIP);
+        let mut eflags = self.get_memory32(new_base + TSS_EFLAGS);
+        // 32ビットのTSSにはEAX〜EDIだけが入っている
+        let registers: Vec<u32> = (0..Register::R8 as usize)
+            .map(|i| self.get_memory32(new_base + TSS_REGISTERS + i * 4))
+            .collect();
+        let segment_registers: Vec<u16> = (0..SEGMENT_REGISTERS_COUNT)
+            .map(|i| self.get_memory16(new_base + TSS_SEGMENT_REGISTERS + i * 4) as u16)
+            .collect();
+        // CS/SSは新しいタスクのLDTにあるかもしれないので、先にLDTを確認して切り替えておく
+        let ldt_selector = self.get_memory16(new_base + TSS_LDT) as u16;
+        let ldtr = self
+            .read_ldt_descriptor(ldt_selector)
+            .map_err(|_| invalid_tss(selector_error(ldt_selector) + ext))?;
+        let old_ldtr = self.ldtr;
+        self.ldtr = ldtr;
+        // V86モードのタスクではセレクタをそのままベースとして使うので確認しない
+        if eflags & Self::VIRTUAL_8086_FLAG == 0 {
+            let cs = segment_registers[SegmentRegister::CS as usize];
+            let ss = segment_registers[SegmentRegister::SS as usize];
+            if let Err(e) = self.check_task_segments(cs, ss, ext) {
+                self.ldtr = old_ldtr;
+                return Err(e);
+            }
+        }
+
+        // 現在のタスクの状態をTSSに保存する(LTR前はタスクがないので保存しない)
+        let old = self.task_register;
+        if old.selector & 0xFFFC != 0 {
+            let old_base = old.base as usize;
+            let mut old_eflags = self.eflags;
+            if reason == TaskSwitchReason::Iret {
+                old_eflags &= !Self::NESTED_TASK_FLAG;
+            }
+            let eip = self.eip as u32;
+            self.set_memory32(old_base + TSS_EIP, eip);
+            self.set_memory32(old_base + TSS_EFLAGS, old_eflags);
+            for i in 0..Register::R8 as usize {
+                let value = self.get_register32(i);
+                self.set_memory32(old_base + TSS_REGISTERS + i * 4, value);
+            }
+            for i in 0..SEGMENT_REGISTERS_COUNT {
+                let value = self.segment_registers[i];
+                self.set_memory16(old_base + TSS_SEGMENT_REGISTERS + i * 4, value as u32);
+            }
+            if reason != TaskSwitchReason::Call {
+                self.set_descriptor_type(old.selector, TSS_AVAILABLE_32);
+            }
+        }
+
+        if reason == TaskSwitchReason::Call {
+            self.set_memory16(new_base + TSS_BACK_LINK, old.selector as u32);
+            eflags |= Self::NESTED_TASK_FLAG;
+        }
+        if reason != TaskSwitchReason::Iret {
+            self.set_descriptor_type(selector, TSS_BUSY_32);
+        }
+
+        self.task_register = TaskRegister {
+            selector,
+            base: new_base as u32,
+            limit: descriptor.limit(),
+        };
+        self.eip = eip as u64;
+        self.set_eflags(eflags);
+        for (i, &value) in registers.iter().enumerate() {
+            self.set_register32(i, value);
+        }
+        // EFLAGSを読み込んでからセレクタを読み込み、V86モードかどうかに合わせてキャッシュを更新する
+        for (i, &selector) in segment_registers.iter().enumerate() {
+            self.set_segment_register(i, selector);
+        }
+        self.interrupt_delivered = true;
+        Ok(())
+    }
+
+    fn check_io_permission(&mut self, port: u16, size: u32) -> Result<(), Exception> {
+        // V86モードではIOPLに関係なく常にI/O許可ビットマップを参照する
+        if !self.is_virtual_8086() && self.cpl() <= self.get_iopl() {
+            return Ok(());
+        }
+
+        // CPL > IOPLのときはTSSのI/O許可ビットマップで許可されたポートのみアクセスできる
+        let tss = self.task_register;
+        if tss.limit < TSS_MINIMUM_LIMIT {
+            return Err(general_protection(0));
+        }
+        // TSSが4GBの終わりにあってもアドレスは32ビットで折り返す
+        let io_map_base = self.get_memory16(tss.base.wrapping_add(TSS_IO_MAP_BASE as u32) as usize);
+        let offset = io_map_base + port as u32 / 8;
+        if offset + 1 > tss.limit {
+            return Err(general_protection(0));
+        }
+        let bitmap = self.get_memory16(tss.base.wrapping_add(offset) as usize) >> (port & 7);
+        if bitmap & ((1 << size) - 1) != 0 {
+            return Err(general_protection(0));
+        }
+        Ok(())
+    }
+
+    fn ltr(&mut self, modrm: &ModRM) {
+        if self.cpl() != 0 {
+            self.raise_exception(general_protection(0));
+            return;
+        }
+        let selector = self.get_rm16(modrm);
+        if selector & 0xFFFC == 0 {
+            self.raise_exception(general_protection(0));
+            return;
+        }
+        let descriptor = match self.read_descriptor(selector) {
+            Ok(descriptor) => descriptor,
+            Err(e) => {
+                self.raise_exception(e);
+                return;
+            }
+        };
+        if !descriptor.is_system() || descriptor.descriptor_type() != TSS_AVAILABLE_32 {
+            self.raise_exception(general_protection(selector_error(selector)));
+            return;
+        }
+        if !descriptor.is_present() {
+            self.raise_exception(segment_not_present(selector_error(selector)));
+            return;
+        }
+        self.set_descriptor_type(selector, TSS_BUSY_32);
+        self.task_register = TaskRegister {
+            selector,
+            base: descriptor.base(),
+            limit: descriptor.limit(),
+        };
+    }
+
+    fn str(&mut self, modrm: &ModRM) {
+        let selector = self.task_register.selector;
+        // レジスタへ格納する場合は上位をゼロ拡張する
+        if modrm.mode == 3 {
+            self.set_rm32(modrm, selector as u32);
+        } else {
+            self.set_rm16(modrm, selector);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Task, TSS_BACK_LINK, TSS_EFLAGS, TSS_EIP, TSS_IO_MAP_BASE, TSS_REGISTERS, TSS_SEGMENT_REGISTERS};
+    use emulator::emulator_function::EmulatorFunction;
+    use emulator::segment::{TSS_AVAILABLE_32, TSS_BUSY_32};
+    use emulator::task::TaskRegister;
+    use emulator::testing::*;
+    use emulator::{Emulator, SegmentRegister};
+
+    #[test]
+    fn io_permission_bitmap_wraps_around_4gb() {
+        let mut emu = emulator(&[]);
+        // リング3(IOPL=0)から、4GBの終わりにあるTSSのビットマップを参照する
+        emu.segment_registers[SegmentRegister::CS as usize] = 0x1B;
+        emu.task_register = TaskRegister {
+            selector: 0x28,
+            base: 0xFFFF_FFF0,
+            limit: 0x2000,
+        };
+        // I/Oマップベース(0xFFFFFFF0 + 0x66 = 0x56)とポート0x80のビット(0xFFFFFFF0 + 0x68 + 0x10 = 0x68)
+        write16(&mut emu, 0x56, 0x68);
+        write16(&mut emu, 0x68, 0x0001);
+        let denied = emu.check_io_permission(0x80, 1).unwrap_err();
+        assert_eq!(denied.vector, 13);
+        assert!(emu.check_io_permission(0x81, 1).is_ok());
+    }
+
+    // 実行中のタスクのTSS(0x3000, セレクタ0x28)と、0x7d00から実行するタスクのTSS(0x3100, セレクタ0x30)を用意する
+    fn two_tasks(code: &[u8], task_code: &[u8]) -> Emulator {
+        let mut emu = emulator(code);
+        emu.bus.load(0x7d00, task_code);
+        install_flat_segments(&mut emu);
+        set_tss_descriptor(&mut emu, 5, 0x3000, true);
+        set_tss_descriptor(&mut emu, 6, 0x3100, false);
+        emu.task_register = TaskRegister {
+            selector: 0x28,
+            base: 0x3000,
+            limit: 0x67,
+        };
+        write32(&mut emu, 0x3100 + TSS_EIP as u32, 0x7d00);
+        write32(&mut emu, 0x3100 + TSS_EFLAGS as u32, 0x2);
+        write32(&mut emu, 0x3100 + TSS_REGISTERS as u32 + 4 * 4, 0x6000);
+        for index in 0..6 {
+            let selector = if index == SegmentRegister::CS as u32 { 0x08 } else { 0x10 };
+            write32(&mut emu, 0x3100 + TSS_SEGMENT_REGISTERS as u32 + index * 4, selector);
+        }
+        emu
+    }
+
+    fn descriptor_type(emu: &Emulator, selector: u32) -> u8 {
+        read8(emu, GDT + selector + 5) & 0xF
+    }
+
+    #[test]
+    fn jump_to_tss_switches_task() {
+        let mut emu = two_tasks(
+            &[
+                0xB8, 0x11, 0x00, 0x00, 0x00, // mov eax, 0x11
+                0xEA, 0x00, 0x00, 0x00, 0x00, 0x30, 0x00, // jmp 0x30:0
+            ],
+            &[],
+        );
+        run(&mut emu, 2);
+        assert_eq!(emu.eip, 0x7d00);
+        assert_eq!(emu.registers[4], 0x6000);
+        assert_eq!(emu.task_register.selector, 0x30);
+        assert_eq!(emu.task_register.base, 0x3100);
+        // 元のタスクの状態がTSSに保存され、JMPなので元のTSSはビジーでなくなる
+        assert_eq!(read32(&emu, 0x3000 + TSS_EIP as u32), 0x7c0c);
+        assert_eq!(read32(&emu, 0x3000 + TSS_REGISTERS as u32), 0x11);
+        assert_eq!(descriptor_type(&emu, 0x28) as u32, TSS_AVAILABLE_32);
+        assert_eq!(descriptor_type(&emu, 0x30) as u32, TSS_BUSY_32);
+        assert_eq!(emu.eflags & Emulator::NESTED_TASK_FLAG, 0);
+        assert_eq!(read16(&emu, 0x3100 + TSS_BACK_LINK as u32), 0);
+    }
+
+    #[test]
+    fn call_to_tss_nests_task_and_iret_returns() {
+        let mut emu = two_tasks(
+            &[
+                0x9A, 0x00, 0x00, 0x00, 0x00, 0x30, 0x00, // call 0x30:0
+                0xF8, // clc
+            ],
+            &[
+                0xB9, 0x05, 0x00, 0x00, 0x00, // mov ecx, 5
+                0xCF, // iret
+            ],
+        );
+        run(&mut emu, 1);
+        assert_eq!(emu.eip, 0x7d00);
+        assert_eq!(emu.task_register.selector, 0x30);
+        assert_ne!(emu.eflags & Emulator::NESTED_TASK_FLAG, 0);
+        assert_eq!(read16(&emu, 0x3100 + TSS_BACK_LINK as u32), 0x28);
+        assert_eq!(descriptor_type(&emu, 0x28) as u32, TSS_BUSY_32);
+
+        // NTが立っているのでIRETはバックリンクのタスクへ戻る
+        run(&mut emu, 2);
+        assert_eq!(emu.eip, 0x7c07);
+        assert_eq!(emu.task_register.selector, 0x28);
+        assert_eq!(emu.get_register32(4), STACK);
+        assert_eq!(emu.registers[1], 0);
+        assert_eq!(read32(&emu, 0x3100 + TSS_REGISTERS as u32 + 4), 5);
+        assert_eq!(descriptor_type(&emu, 0x30) as u32, TSS_AVAILABLE_32);
+        assert_eq!(emu.eflags & Emulator::NESTED_TASK_FLAG, 0);
+    }
+    // リング0のスタックにエラーコード0と戻り先eipを積んで#GPのハンドラへ入ったことを確かめる
+    fn assert_general_protection(emu: &Emulator, eip: u32) {
+        assert_eq!(emu.eip, 0x7e00);
+        assert_eq!(emu.get_register32(4), RING0_STACK - 24);
+        assert_eq!(read32(emu, RING0_STACK - 24), 0);
+        assert_eq!(read32(emu, RING0_STACK - 20), eip);
+    }
+
+    #[test]
+    fn port_access_above_iopl_without_bitmap_raises_general_protection() {
+        // I/Oマップベースがリミットを超えているので、どのポートも許可されない
+        let mut emu = ring3_emulator(
+            &[
+                0xE4, 0x80, // in al, 0x80
+            ],
+            13,
+        );
+        write16(&mut emu, 0x3000 + TSS_IO_MAP_BASE as u32, 0x68);
+        run(&mut emu, 1);
+        assert_general_protection(&emu, 0x7c00);
+    }
+
+    #[test]
+    fn port_access_is_allowed_when_cpl_is_within_iopl() {
+        let mut emu = ring3_emulator(
+            &[
+                0xE4, 0x80, // in al, 0x80
+                0xE6, 0x80, // out 0x80, al
+            ],
+            13,
+        );
+        emu.eflags |= 3 << 12;
+        run(&mut emu, 2);
+        assert_eq!(emu.eip, 0x7c04);
+    }
+
+    #[test]
+    fn io_permission_bitmap_grants_individual_ports() {
+        let mut emu = ring3_emulator(
+            &[
+                0x66, 0xBA, 0x80, 0x00, // mov dx, 0x80
+                0xEC, // in al, dx
+                0x66, 0xED, // in ax, dx
+            ],
+            13,
+        );
+        emu.task_register.limit = 0x68 + 0x20;
+        // ポート0x80だけを許可する(0x81は拒否されるので、0x80からの16ビットアクセスは#GP)
+        write16(&mut emu, 0x3000 + TSS_IO_MAP_BASE as u32, 0x68);
+        for offset in 0..0x20 {
+            write16(&mut emu, 0x3068 + offset, 0xFFFF);
+        }
+        write16(&mut emu, 0x3068 + 0x10, 0xFFFE);
+        run(&mut emu, 2);
+        assert_eq!(emu.eip, 0x7c05);
+        run(&mut emu, 1);
+        assert_general_protection(&emu, 0x7c05);
+    }
+
+    #[test]
+    fn cli_above_iopl_raises_general_protection() {
+        let mut emu = ring3_emulator(
+            &[
+                0xFA, // cli
+            ],
+            13,
+        );
+        emu.eflags |= Emulator::INTERRUPT_FLAG;
+        run(&mut emu, 1);
+        assert_general_protection(&emu, 0x7c00);
+    }
+
+    #[test]
+    fn popf_above_iopl_keeps_interrupt_flag_and_iopl() {
+        let mut emu = ring3_emulator(
+            &[
+                0x6A, 0x02, // push 2
+                0x9D, // popfd
+            ],
+            13,
+        );
+        emu.eflags |= Emulator::INTERRUPT_FLAG | (1 << 12);
+        run(&mut emu, 2);
+        assert_eq!(emu.eip, 0x7c03);
+        assert_ne!(emu.eflags & Emulator::INTERRUPT_FLAG, 0);
+        assert_eq!((emu.eflags >> 12) & 3, 1);
+    }
+}
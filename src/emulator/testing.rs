@@ -0,0 +1,303 @@
+// テストで使うエミュレータと、メモリ上のディスクリプタテーブルを組み立てる関数
+#![allow(dead_code)]
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use emulator::bus::Bus;
+use emulator::control_register::{ControlRegister, CR0_ET, CR0_PE, CR0_PG, CR4_PAE};
+use emulator::cpu_model::CpuModel;
+use emulator::emulator_function::EmulatorFunction;
+use emulator::instruction::Instruction;
+use emulator::interrupt::DescriptorTableRegister;
+use emulator::io::{InterruptLines, PortDevice};
+use emulator::msr::{Msr, EFER_LME, IA32_EFER};
+use emulator::segment::{Segment, FLAT_CODE_SEGMENT, FLAT_DATA_SEGMENT};
+use emulator::stop::StopReason;
+use emulator::task::TaskRegister;
+use emulator::{Emulator, SegmentRegister, MEMORY_SIZE};
+
+// テストのコードを置くアドレス(main.rsでプログラムを読み込むアドレスと同じ)
+pub const CODE: u32 = 0x7c00;
+// GDTとIDTを置くアドレス
+pub const GDT: u32 = 0x1000;
+pub const IDT: u32 = 0x2000;
+// 初期のスタックポインタ
+pub const STACK: u32 = 0x9000;
+// install_tssで置くTSSと、そのリング0のスタックポインタ
+pub const TSS: u32 = 0x3000;
+pub const RING0_STACK: u32 = 0x5000;
+// long_mode_emulatorで置くPML4, PDPT, PD, PT
+pub const PML4: u32 = 0x10000;
+pub const PDPT: u32 = 0x11000;
+pub const PAGE_DIRECTORY: u32 = 0x12000;
+pub const PAGE_TABLE: u32 = 0x13000;
+
+// 起動時と同じフラットな32ビット保護モードで、codeを0x7c00から実行するエミュレータ
+pub fn emulator(code: &[u8]) -> Emulator {
+    let mut bus = Bus::new();
+    bus.add_ram(0, MEMORY_SIZE).unwrap();
+    bus.load(CODE as usize, code);
+    Emulator::with_bus(bus, CODE, STACK)
+}
+
+// count個の命令を実行する(ホストへのエラーはテストの失敗にする)
+pub fn run(emu: &mut Emulator, count: u64) -> StopReason {
+    emu.run_instructions(true, Some(count)).unwrap()
+}
+
+pub fn read8(emu: &Emulator, address: u32) -> u8 {
+    emu.bus.peek8(address as usize).unwrap()
+}
+
+pub fn read16(emu: &Emulator, address: u32) -> u16 {
+    (read8(emu, address) as u16) | ((read8(emu, address + 1) as u16) << 8)
+}
+
+pub fn read32(emu: &Emulator, address: u32) -> u32 {
+    (read16(emu, address) as u32) | ((read16(emu, address + 2) as u32) << 16)
+}
+
+pub fn write16(emu: &mut Emulator, address: u32, value: u16) {
+    emu.bus.load(address as usize, &[value as u8, (value >> 8) as u8]);
+}
+
+pub fn write32(emu: &mut Emulator, address: u32, value: u32) {
+    write16(emu, address, value as u16);
+    write16(emu, address + 2, (value >> 16) as u16);
+}
+
+pub fn write64(emu: &mut Emulator, address: u32, value: u64) {
+    write32(emu, address, value as u32);
+    write32(emu, address + 4, (value >> 32) as u32);
+}
+
+pub fn read64(emu: &Emulator, address: u32) -> u64 {
+    (read32(emu, address) as u64) | ((read32(emu, address + 4) as u64) << 32)
+}
+
+// GDTを0x1000に置き、entries個のディスクリプタが入る大きさにする
+pub fn install_gdt(emu: &mut Emulator, entries: u16) {
+    emu.gdtr = DescriptorTableRegister {
+        base: GDT as u64,
+        limit: entries * 8 - 1,
+    };
+}
+
+// IDTを0x2000に置き、vectors個のゲートが入る大きさにする
+pub fn install_idt(emu: &mut Emulator, vectors: u16) {
+    emu.idtr = DescriptorTableRegister {
+        base: IDT as u64,
+        limit: vectors * 8 - 1,
+    };
+}
+
+// GDTのindex番目にセグメントディスクリプタを書き込む(accessはP, DPL, S, タイプ、flagsはG, D/B, L, AVL)
+pub fn set_descriptor(emu: &mut Emulator, index: u16, base: u32, limit: u32, access: u8, flags: u8) {
+    let low = (base << 16) | (limit & 0xFFFF);
+    let high = (base & 0xFF00_0000) | ((flags as u32 & 0xF) << 20) | (limit & 0x000F_0000) | ((access as u32) << 8) | ((base >> 16) & 0xFF);
+    let address = GDT + index as u32 * 8;
+    write32(emu, address, low);
+    write32(emu, address + 4, high);
+}
+
+// tableのindex番目にゲートディスクリプタを書き込む
+pub fn set_gate(emu: &mut Emulator, table: u32, index: u16, selector: u16, offset: u32, access: u8) {
+    let address = table + index as u32 * 8;
+    write32(emu, address, ((selector as u32) << 16) | (offset & 0xFFFF));
+    write32(emu, address + 4, (offset & 0xFFFF_0000) | ((access as u32) << 8));
+}
+
+// リング0と3のフラットなコード/データセグメント(セレクタ0x08, 0x10, 0x1B, 0x23)を用意して、
+// CSに0x08、それ以外に0x10を読み込んだ状態にする(ディスクリプタキャッシュは起動時のまま)
+pub fn install_flat_segments(emu: &mut Emulator) {
+    install_gdt(emu, 16);
+    set_descriptor(emu, 1, 0, 0xFFFFF, 0x9A, 0xC);
+    set_descriptor(emu, 2, 0, 0xFFFFF, 0x92, 0xC);
+    set_descriptor(emu, 3, 0, 0xFFFFF, 0xFA, 0xC);
+    set_descriptor(emu, 4, 0, 0xFFFFF, 0xF2, 0xC);
+    emu.segment_registers = [0x10, 0x08, 0x10, 0x10, 0x10, 0x10];
+}
+
+// リング3のフラットなセグメント(CSが0x1B、それ以外が0x23)で実行している状態にする
+pub fn enter_ring3(emu: &mut Emulator) {
+    emu.segment_registers = [0x23, 0x1B, 0x23, 0x23, 0x23, 0x23];
+    for (index, cache) in emu.segment_caches.iter_mut().enumerate() {
+        *cache = if index == SegmentRegister::CS as usize {
+            FLAT_CODE_SEGMENT
+        } else {
+            FLAT_DATA_SEGMENT
+        };
+        cache.attributes |= 3 << 13;
+    }
+}
+
+// GDTのindex番目にbaseのTSS(リミット0x67)を置く
+pub fn set_tss_descriptor(emu: &mut Emulator, index: u16, base: u32, busy: bool) {
+    let access = if busy { 0x8B } else { 0x89 };
+    set_descriptor(emu, index, base, 0x67, access, 0);
+}
+
+// 0x3000にリング0のスタック(0x10:0x5000)を持つTSSを置き、セレクタ0x28でタスクレジスタに読み込んだ状態にする
+pub fn install_tss(emu: &mut Emulator) {
+    set_tss_descriptor(emu, 5, TSS, true);
+    emu.task_register = TaskRegister {
+        selector: 0x28,
+        base: TSS,
+        limit: 0x67,
+    };
+    write32(emu, TSS + 4, RING0_STACK);
+    write32(emu, TSS + 8, 0x10);
+}
+
+// IDTのvector番目に、リング0のフラットなコードセグメント(0x08)のhandlerへの割り込みゲートを置く
+pub fn set_interrupt_gate(emu: &mut Emulator, vector: u16, handler: u32) {
+    set_gate(emu, IDT, vector, 0x08, handler, 0x8E);
+}
+
+// フラットな保護モードで、vector番の例外を0x7e00のハンドラで受けるエミュレータ
+pub fn protected_mode_emulator(code: &[u8], vector: u16) -> Emulator {
+    let mut emu = emulator(code);
+    install_flat_segments(&mut emu);
+    install_idt(&mut emu, 32);
+    set_interrupt_gate(&mut emu, vector, 0x7e00);
+    emu
+}
+
+// protected_mode_emulatorにTSSを加え、リング3で実行している状態にする
+pub fn ring3_emulator(code: &[u8], vector: u16) -> Emulator {
+    let mut emu = protected_mode_emulator(code, vector);
+    install_tss(&mut emu);
+    enter_ring3(&mut emu);
+    emu
+}
+
+// リセット直後のリアルモードで、割り込みベクタテーブルのvector番目を0000:7e00にしたエミュレータ
+pub fn real_mode_emulator(code: &[u8], vector: u8) -> Emulator {
+    let mut emu = emulator(code);
+    emu.reset_to_real_mode();
+    write16(&mut emu, vector as u32 * 4, 0x7e00);
+    write16(&mut emu, vector as u32 * 4 + 2, 0);
+    emu
+}
+
+// リング0のIRETで、0x0800:0000(リニアアドレス0x8000)のcodeをSS:SP=0x0600:1000のV86モードで実行する
+// (#GPは0x7e00のモニタへ入る)
+pub fn virtual_8086_emulator(code: &[u8], eflags: u32) -> Emulator {
+    let mut emu = emulator(&[
+        0xCF, // iret
+    ]);
+    emu.bus.load(0x8000, code);
+    install_flat_segments(&mut emu);
+    install_idt(&mut emu, 0x30);
+    set_interrupt_gate(&mut emu, 13, 0x7e00);
+    install_tss(&mut emu);
+    let frame = [0, 0x0800, Emulator::VIRTUAL_8086_FLAG | 0x2 | eflags, 0x1000, 0x0600, 0, 0, 0, 0];
+    for (i, &value) in frame.iter().enumerate() {
+        write32(&mut emu, STACK + i as u32 * 4, value);
+    }
+    run(&mut emu, 1);
+    assert!(emu.is_virtual_8086());
+    emu
+}
+
+// STI; HLTで割り込みを待ち、IRQ1(ベクタ0x21)のトラップゲートのハンドラ(0x7e00)へ飛ぶ
+pub fn halted_emulator() -> Emulator {
+    let mut emu = emulator(&[
+        0xFB, // sti
+        0xF4, // hlt
+    ]);
+    install_flat_segments(&mut emu);
+    install_idt(&mut emu, 0x30);
+    set_gate(&mut emu, IDT, 0x21, 0x08, 0x7e00, 0x8F);
+    emu.bus.load(0x7e00, &[0xF8, 0xF8, 0xF8]);
+    emu
+}
+
+// 0x28に空きTSS、0x30に実行専用コード、0x38に読み出し専用データ、0x40に割り込みゲートを置く
+pub fn query_emulator(code: &[u8]) -> Emulator {
+    let mut emu = emulator(code);
+    install_flat_segments(&mut emu);
+    set_tss_descriptor(&mut emu, 5, 0x3000, false);
+    set_descriptor(&mut emu, 6, 0, 0xFFFFF, 0x98, 0xC);
+    set_descriptor(&mut emu, 7, 0x10000, 0xFFF, 0x90, 0x4);
+    set_gate(&mut emu, GDT, 8, 0x08, 0x7e00, 0x8E);
+    emu
+}
+
+// x86_64のCPUで、メモリの先頭1MBを4KBのページで同じアドレスに割り当て(ユーザーも書き込める)、
+// EFER.LMEを立ててページングを有効にしたエミュレータ(CSは32ビットのままなので互換モードで実行する)
+pub fn long_mode_emulator(code: &[u8]) -> Emulator {
+    let mut emu = emulator(code);
+    emu.set_cpu_model(CpuModel::X86_64);
+    write64(&mut emu, PML4, PDPT as u64 | 7);
+    write64(&mut emu, PDPT, PAGE_DIRECTORY as u64 | 7);
+    write64(&mut emu, PAGE_DIRECTORY, PAGE_TABLE as u64 | 7);
+    for page in 0..(MEMORY_SIZE as u32 >> 12) {
+        write64(&mut emu, PAGE_TABLE + page * 8, (page as u64) << 12 | 7);
+    }
+    emu.write_msr(IA32_EFER, EFER_LME).unwrap();
+    emu.write_control_register(4, CR4_PAE).unwrap();
+    emu.write_control_register(3, PML4 as u64).unwrap();
+    emu.write_control_register(0, CR0_PE | CR0_ET | CR0_PG).unwrap();
+    emu
+}
+
+// long_mode_emulatorのGDTに、リング0と3の64ビットコードセグメント(0x08, 0x1B)とフラットなデータセグメント(0x10, 0x23)、
+// 32ビットコードセグメント(0x30)を置き、CSに0x08、それ以外に0x10を読み込んで64ビットモードで実行する
+pub fn long_mode_64bit_emulator(code: &[u8]) -> Emulator {
+    let mut emu = long_mode_emulator(code);
+    install_gdt(&mut emu, 16);
+    set_descriptor(&mut emu, 1, 0, 0xFFFFF, 0x9A, 0xA);
+    set_descriptor(&mut emu, 2, 0, 0xFFFFF, 0x92, 0xC);
+    set_descriptor(&mut emu, 3, 0, 0xFFFFF, 0xFA, 0xA);
+    set_descriptor(&mut emu, 4, 0, 0xFFFFF, 0xF2, 0xC);
+    set_descriptor(&mut emu, 6, 0, 0xFFFFF, 0x9A, 0xC);
+    for (index, &selector) in [0x10, 0x08, 0x10, 0x10, 0x10, 0x10].iter().enumerate() {
+        emu.set_segment_register(index, selector);
+    }
+    emu
+}
+
+// ロングモードのIDTを0x2000に置き、vectors個の16バイトのゲートが入る大きさにする
+pub fn install_idt64(emu: &mut Emulator, vectors: u16) {
+    emu.idtr = DescriptorTableRegister {
+        base: IDT as u64,
+        limit: vectors * 16 - 1,
+    };
+}
+
+// IDTのvector番目に、リング0の64ビットコードセグメント(0x08)のhandlerへの16バイトのゲートを置く(istは0〜7)
+pub fn set_gate64(emu: &mut Emulator, vector: u16, handler: u64, access: u8, ist: u8) {
+    let address = IDT + vector as u32 * 16;
+    write32(emu, address, (0x08 << 16) | (handler as u32 & 0xFFFF));
+    write32(emu, address + 4, (handler as u32 & 0xFFFF_0000) | ((access as u32) << 8) | ist as u32);
+    write64(emu, address + 8, handler >> 32);
+}
+
+// 読むとオフセット+0x10を返し、書き込まれた(オフセット, 値)を記録するI/Oポートのデバイス
+pub struct Recorder {
+    writes: Rc<RefCell<Vec<(u16, u8)>>>,
+}
+
+impl PortDevice for Recorder {
+    fn read8(&mut self, offset: u16, _: &mut InterruptLines) -> u8 {
+        0x10 + offset as u8
+    }
+
+    fn write8(&mut self, offset: u16, value: u8, _: &mut InterruptLines) {
+        self.writes.borrow_mut().push((offset, value));
+    }
+}
+
+pub fn recorder() -> (Box<Recorder>, Rc<RefCell<Vec<(u16, u8)>>>) {
+    let writes = Rc::new(RefCell::new(Vec::new()));
+    (Box::new(Recorder { writes: writes.clone() }), writes)
+}
+
+// ポート0x40〜0x47にRecorderをつないだエミュレータ
+pub fn port_emulator(code: &[u8]) -> (Emulator, Rc<RefCell<Vec<(u16, u8)>>>) {
+    let mut emu = emulator(code);
+    let (device, writes) = recorder();
+    emu.ports_mut().register(0x40, 8, device).unwrap();
+    (emu, writes)
+}
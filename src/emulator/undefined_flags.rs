@@ -0,0 +1,153 @@
+use emulator::emulator_function::EmulatorFunction;
+use emulator::Emulator;
+
+// 命令が「未定義」とするフラグの値の決め方
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum FlagsPolicy {
+    // Intelの実機に合わせる(多くは結果から論理演算と同じように求める)
+    #[default]
+    Intel,
+    // AMDの実機に合わせる(多くは命令の前の値が残る)
+    Amd,
+    // 乱数で埋めて、未定義のフラグに依存したゲストのコードを見つけやすくする
+    Poison,
+}
+
+impl FlagsPolicy {
+    // コマンドラインで指定するポリシー名
+    pub fn from_name(name: &str) -> Option<FlagsPolicy> {
+        match name {
+            "intel" => Some(FlagsPolicy::Intel),
+            "amd" => Some(FlagsPolicy::Amd),
+            "poison" => Some(FlagsPolicy::Poison),
+            _ => None,
+        }
+    }
+}
+
+// 結果から求めたSF, ZF, PF(CF, OF, AFは0)
+pub fn result_flags(result: u32, bits: u32) -> u32 {
+    let mut flags = 0;
+    if (result >> (bits - 1)) & 1 != 0 {
+        flags |= Emulator::SIGN_FLAG;
+    }
+    if result & (0xFFFF_FFFF >> (32 - bits)) == 0 {
+        flags |= Emulator::ZERO_FLAG;
+    }
+    if (result as u8).count_ones() & 1 == 0 {
+        flags |= Emulator::PARITY_FLAG;
+    }
+    flags
+}
+
+pub trait UndefinedFlags {
+    fn set_undefined_flags(&mut self, mask: u32, intel: u32, amd: u32);
+}
+
+impl Emulator {
+    pub fn flags_policy(&self) -> FlagsPolicy {
+        self.flags_policy
+    }
+
+    pub fn set_flags_policy(&mut self, policy: FlagsPolicy) {
+        self.flags_policy = policy;
+    }
+
+    // xorshift32で次の乱数を作る(同じプログラムなら毎回同じ値になる)
+    fn next_poison(&mut self) -> u32 {
+        let mut x = self.poison_state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.poison_state = x;
+        x
+    }
+}
+
+impl UndefinedFlags for Emulator {
+    // maskのフラグを、ポリシーに従ってintelかamdの同じビットの値、または乱数で設定する
+    fn set_undefined_flags(&mut self, mask: u32, intel: u32, amd: u32) {
+        let value = match self.flags_policy {
+            FlagsPolicy::Intel => intel,
+            FlagsPolicy::Amd => amd,
+            FlagsPolicy::Poison => self.next_poison(),
+        };
+        self.eflags = (self.eflags & !mask) | (value & mask);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use emulator::testing::*;
+
+    const SCAN_FLAGS: u32 = Emulator::CARRY_FLAG | Emulator::OVERFLOW_FLAG | Emulator::SIGN_FLAG | Emulator::ADJUST_FLAG | Emulator::PARITY_FLAG;
+
+    // policyに従って、未定義のフラグをすべて立てた状態からcodeを1命令実行する
+    fn execute(policy: FlagsPolicy, code: &[u8], ecx: u32) -> Emulator {
+        let mut emu = emulator(code);
+        emu.set_flags_policy(policy);
+        emu.set_register32(1, ecx);
+        emu.eflags |= SCAN_FLAGS;
+        run(&mut emu, 1);
+        emu
+    }
+
+    #[test]
+    fn policies_are_named() {
+        assert_eq!(FlagsPolicy::from_name("intel"), Some(FlagsPolicy::Intel));
+        assert_eq!(FlagsPolicy::from_name("amd"), Some(FlagsPolicy::Amd));
+        assert_eq!(FlagsPolicy::from_name("poison"), Some(FlagsPolicy::Poison));
+        assert_eq!(FlagsPolicy::from_name("via"), None);
+        assert_eq!(emulator(&[]).flags_policy(), FlagsPolicy::Intel);
+    }
+
+    #[test]
+    fn result_flags_follow_operand_size() {
+        assert_eq!(result_flags(0, 32), Emulator::ZERO_FLAG | Emulator::PARITY_FLAG);
+        assert_eq!(result_flags(0x8000, 16), Emulator::SIGN_FLAG | Emulator::PARITY_FLAG);
+        assert_eq!(result_flags(0x1_0000, 16), Emulator::ZERO_FLAG | Emulator::PARITY_FLAG);
+        assert_eq!(result_flags(0x01, 8), 0);
+    }
+
+    #[test]
+    fn bsf_undefined_flags_depend_on_vendor() {
+        let code = [0x0F, 0xBC, 0xC1]; // bsf eax, ecx
+                                       // Intelはソースから求める
+        let emu = execute(FlagsPolicy::Intel, &code, 0x80);
+        assert_eq!(emu.registers[0], 7);
+        assert_eq!(emu.eflags & SCAN_FLAGS, 0);
+        // AMDは命令の前の値が残る
+        let emu = execute(FlagsPolicy::Amd, &code, 0x80);
+        assert_eq!(emu.registers[0], 7);
+        assert_eq!(emu.eflags & SCAN_FLAGS, SCAN_FLAGS);
+    }
+
+    #[test]
+    fn shift_adjust_flag_depends_on_vendor() {
+        let code = [0xD1, 0xE1]; // shl ecx, 1
+        let emu = execute(FlagsPolicy::Intel, &code, 1);
+        assert_eq!(emu.eflags & Emulator::ADJUST_FLAG, 0);
+        let emu = execute(FlagsPolicy::Amd, &code, 1);
+        assert_eq!(emu.eflags & Emulator::ADJUST_FLAG, Emulator::ADJUST_FLAG);
+    }
+
+    #[test]
+    fn poison_is_deterministic_and_only_touches_the_mask() {
+        let poisoned = || {
+            let mut emu = emulator(&[]);
+            emu.set_flags_policy(FlagsPolicy::Poison);
+            let mut values = Vec::new();
+            for _ in 0..8 {
+                emu.set_undefined_flags(SCAN_FLAGS, 0, 0);
+                values.push(emu.eflags);
+            }
+            values
+        };
+        let values = poisoned();
+        // 同じプログラムなら毎回同じ値になる
+        assert_eq!(values, poisoned());
+        assert!(values.iter().any(|&flags| flags != values[0]));
+        assert!(values.iter().all(|&flags| flags & !SCAN_FLAGS == values[0] & !SCAN_FLAGS));
+    }
+}
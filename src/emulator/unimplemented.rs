@@ -0,0 +1,162 @@
+use emulator::interrupt::{invalid_opcode, Interrupt};
+use emulator::stop::EmuError;
+use emulator::Emulator;
+
+// 実装していない命令に出会ったときの扱い
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum UnimplementedPolicy {
+    // 実行を止めてホストにEmuError::UnimplementedOpcodeを返す
+    #[default]
+    Abort,
+    // ゲストに#UDを発生させて、ゲストのハンドラに任せる
+    InvalidOpcode,
+    // 何もしない命令として読み飛ばす
+    Skip,
+}
+
+impl UnimplementedPolicy {
+    // コマンドラインで指定するポリシー名
+    pub fn from_name(name: &str) -> Option<UnimplementedPolicy> {
+        match name {
+            "abort" => Some(UnimplementedPolicy::Abort),
+            "ud" => Some(UnimplementedPolicy::InvalidOpcode),
+            "skip" => Some(UnimplementedPolicy::Skip),
+            _ => None,
+        }
+    }
+}
+
+// 実装していない命令を実行しようとした場所(同じEIPの同じ命令は回数だけ数える)
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnimplementedEncounter {
+    pub eip: u64,
+    // プレフィックスを含む命令のバイト列
+    pub bytes: Vec<u8>,
+    pub count: u64,
+}
+
+pub trait Unimplemented {
+    fn handle_emulation_error(&mut self, error: EmuError) -> Result<bool, EmuError>;
+}
+
+impl Emulator {
+    pub fn unimplemented_policy(&self) -> UnimplementedPolicy {
+        self.unimplemented_policy
+    }
+
+    pub fn set_unimplemented_policy(&mut self, policy: UnimplementedPolicy) {
+        self.unimplemented_policy = policy;
+    }
+
+    // これまでに出会った実装していない命令(最初に出会った順)
+    pub fn unimplemented_encounters(&self) -> &[UnimplementedEncounter] {
+        &self.unimplemented_encounters
+    }
+
+    fn record_unimplemented(&mut self, eip: u64, bytes: &[u8]) {
+        if let Some(encounter) = self
+            .unimplemented_encounters
+            .iter_mut()
+            .find(|encounter| encounter.eip == eip && encounter.bytes == bytes)
+        {
+            encounter.count += 1;
+            return;
+        }
+        self.unimplemented_encounters.push(UnimplementedEncounter {
+            eip,
+            bytes: bytes.to_vec(),
+            count: 1,
+        });
+    }
+}
+
+impl Unimplemented for Emulator {
+    // 実行前の状態に戻した命令のエラーを扱う。命令を読み飛ばして実行を続けるときはtrueを返す
+    fn handle_emulation_error(&mut self, error: EmuError) -> Result<bool, EmuError> {
+        if let EmuError::UnimplementedOpcode { eip, ref bytes } = error {
+            self.record_unimplemented(eip, bytes);
+            match self.unimplemented_policy {
+                UnimplementedPolicy::Abort => {}
+                UnimplementedPolicy::InvalidOpcode => {
+                    self.raise_exception(invalid_opcode());
+                    return Ok(false);
+                }
+                UnimplementedPolicy::Skip => {
+                    self.eip = self.instruction_eip.wrapping_add(self.instruction_length as u64);
+                    return Ok(true);
+                }
+            }
+        }
+        Err(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use emulator::instruction::Instruction;
+    use emulator::testing::*;
+
+    #[test]
+    fn policies_are_named() {
+        assert_eq!(UnimplementedPolicy::from_name("abort"), Some(UnimplementedPolicy::Abort));
+        assert_eq!(UnimplementedPolicy::from_name("ud"), Some(UnimplementedPolicy::InvalidOpcode));
+        assert_eq!(UnimplementedPolicy::from_name("skip"), Some(UnimplementedPolicy::Skip));
+        assert_eq!(UnimplementedPolicy::from_name("ignore"), None);
+        assert_eq!(emulator(&[]).unimplemented_policy(), UnimplementedPolicy::Abort);
+    }
+
+    #[test]
+    fn abort_returns_the_error_and_records_it() {
+        let mut emu = emulator(&[0x90]);
+        let error = emu.run_instructions(true, Some(10)).unwrap_err();
+        assert_eq!(
+            error,
+            EmuError::UnimplementedOpcode {
+                eip: 0x7c00,
+                bytes: vec![0x90]
+            }
+        );
+        assert_eq!(
+            emu.unimplemented_encounters(),
+            &[UnimplementedEncounter {
+                eip: 0x7c00,
+                bytes: vec![0x90],
+                count: 1,
+            }]
+        );
+    }
+
+    #[test]
+    fn invalid_opcode_policy_runs_the_guest_handler() {
+        let mut emu = protected_mode_emulator(&[0x66, 0x90], 6);
+        emu.set_unimplemented_policy(UnimplementedPolicy::InvalidOpcode);
+        run(&mut emu, 1);
+        assert_eq!(emu.eip, 0x7e00);
+        // フォールトなので戻り先は命令の先頭
+        assert_eq!(read32(&emu, STACK - 12), 0x7c00);
+        assert_eq!(emu.unimplemented_encounters()[0].bytes, vec![0x66, 0x90]);
+    }
+
+    #[test]
+    fn skip_policy_counts_each_encounter() {
+        let mut emu = emulator(&[
+            0x90, // nop
+            0xEB, 0xFD, // jmp 0x7c00
+        ]);
+        emu.set_unimplemented_policy(UnimplementedPolicy::Skip);
+        run(&mut emu, 5);
+        assert_eq!(emu.eip, 0x7c01);
+        let encounters = emu.unimplemented_encounters();
+        assert_eq!(encounters.len(), 1);
+        assert_eq!((encounters[0].eip, encounters[0].count), (0x7c00, 3));
+    }
+
+    #[test]
+    fn other_errors_are_not_skipped() {
+        let mut emu = emulator(&[0xA1, 0x00, 0x00, 0x20, 0x00]); // mov eax, [0x200000]
+        emu.set_unimplemented_policy(UnimplementedPolicy::Skip);
+        assert_eq!(emu.run_instructions(true, Some(10)), Err(EmuError::MemoryFault { address: 0x20_0000 }));
+        assert!(emu.unimplemented_encounters().is_empty());
+    }
+}
@@ -0,0 +1,97 @@
+use emulator::bit_manipulation::BitManipulation;
+use emulator::cpuid::{Cpuid, Feature};
+use emulator::emulator_function::EmulatorFunction;
+use emulator::interrupt::{invalid_opcode, Interrupt};
+use emulator::segment::Segment;
+use emulator::{Emulator, SegmentRegister};
+
+// VEXプレフィックスの各フィールド(vvvvとRなどの反転は戻しておく)
+struct VexPrefix {
+    // 1: 0F, 2: 0F38, 3: 0F3A
+    map: u8,
+    vvvv: usize,
+    l: bool,
+    // 0: なし, 1: 66, 2: F3, 3: F2
+    pp: u8,
+}
+
+pub trait Vex {
+    fn is_vex_prefix(&mut self) -> bool;
+    fn code_c4(&mut self);
+    fn code_c5(&mut self);
+}
+
+impl Emulator {
+    fn decode_vex(&mut self, three_byte: bool) -> VexPrefix {
+        let (map, last) = if three_byte {
+            (self.get_code8(1) & 0x1F, self.get_code8(2))
+        } else {
+            (1, self.get_code8(1))
+        };
+        self.eip += if three_byte { 3 } else { 2 };
+        VexPrefix {
+            map,
+            vvvv: ((!last >> 3) & 0xF) as usize,
+            l: last & 4 != 0,
+            pp: last & 3,
+        }
+    }
+
+    fn exec_vex_instruction(&mut self, three_byte: bool) {
+        // VEXの前に66/F2/F3プレフィックスは置けない
+        if self.operand_size_override || self.repeat_prefix.is_some() {
+            self.raise_exception(invalid_opcode());
+            return;
+        }
+        let vex = self.decode_vex(three_byte);
+        let opcode = self.get_code8(0);
+        self.eip += 1;
+        let (feature, instruction): (Feature, fn(&mut Emulator, usize)) = match (vex.map, opcode, vex.pp) {
+            (2, 0xF2, 0) => (Feature::Bmi1, Emulator::andn),
+            (2, 0xF3, 0) => (Feature::Bmi1, Emulator::bmi1_group),
+            (2, 0xF5, 0) => (Feature::Bmi2, Emulator::bzhi),
+            (2, 0xF5, 2) => (Feature::Bmi2, Emulator::pext),
+            (2, 0xF5, 3) => (Feature::Bmi2, Emulator::pdep),
+            (2, 0xF6, 3) => (Feature::Bmi2, Emulator::mulx),
+            (2, 0xF7, 0) => (Feature::Bmi1, Emulator::bextr),
+            (2, 0xF7, 1) => (Feature::Bmi2, Emulator::shlx),
+            (2, 0xF7, 2) => (Feature::Bmi2, Emulator::sarx),
+            (2, 0xF7, 3) => (Feature::Bmi2, Emulator::shrx),
+            (3, 0xF0, 3) => (Feature::Bmi2, Emulator::rorx),
+            _ => {
+                self.raise_exception(invalid_opcode());
+                return;
+            }
+        };
+        // 汎用レジスタを扱うVEX命令はL=0でなければならない
+        if vex.l || !self.has_feature(feature) {
+            self.raise_exception(invalid_opcode());
+            return;
+        }
+        instruction(self, vex.vvvv);
+    }
+}
+
+impl Vex for Emulator {
+    // 32ビットモードでは次のバイトの上位2ビットが11のときだけVEXプレフィックスで、それ以外はLES/LDS
+    // 64ビットモードにはLES/LDSがないので常にVEXプレフィックス
+    fn is_vex_prefix(&mut self) -> bool {
+        self.is_64bit_mode() || (!self.uses_real_mode_segments() && self.get_code8(1) & 0xC0 == 0xC0)
+    }
+
+    fn code_c4(&mut self) {
+        if self.is_vex_prefix() {
+            self.exec_vex_instruction(true);
+        } else {
+            self.load_far_pointer(SegmentRegister::ES as usize, 1);
+        }
+    }
+
+    fn code_c5(&mut self) {
+        if self.is_vex_prefix() {
+            self.exec_vex_instruction(false);
+        } else {
+            self.load_far_pointer(SegmentRegister::DS as usize, 1);
+        }
+    }
+}
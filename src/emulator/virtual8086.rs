@@ -0,0 +1,410 @@
+use emulator::alignment_check::AlignmentCheck;
+use emulator::control_register::CR4_VME;
+use emulator::emulator_function::EmulatorFunction;
+use emulator::interrupt::{general_protection, Exception, Interrupt};
+use emulator::segment::Segment;
+use emulator::task::{TSS_IO_MAP_BASE, TSS_MINIMUM_LIMIT};
+use emulator::{Emulator, Register, SegmentRegister};
+
+// V86モードから割り込みハンドラに入るときにスタックへ積むデータセグメント(積む順)
+const SAVED_DATA_SEGMENTS: [usize; 4] = [
+    SegmentRegister::GS as usize,
+    SegmentRegister::FS as usize,
+    SegmentRegister::DS as usize,
+    SegmentRegister::ES as usize,
+];
+
+pub trait Virtual8086 {
+    fn enter_virtual_8086(&mut self, sp: u32, eip: u32, cs: u16, eflags: u32);
+    fn leave_virtual_8086(&mut self, ss: u16, esp: u32);
+    fn virtual_8086_cli(&mut self);
+    fn virtual_8086_sti(&mut self);
+    fn virtual_8086_pushf(&mut self);
+    fn virtual_8086_popf(&mut self);
+    fn virtual_8086_interrupt(&mut self, vector: u8);
+    fn virtual_8086_iret(&mut self) -> Result<(), Exception>;
+}
+
+impl Emulator {
+    // VMEが有効でIOPL<3のときは、IFの代わりにVIFを操作する
+    fn uses_virtual_interrupt_flag(&self) -> bool {
+        self.control_registers[4] & CR4_VME != 0 && self.get_iopl() < 3
+    }
+
+    // 16ビットのFLAGSをスタックから読む(フォールト時にSPを動かさないよう先に覗いておく)
+    fn peek_flags16(&mut self, offset: u32) -> u32 {
+        let sp = self.get_stack_pointer().wrapping_add(offset) & 0xffff;
+        let address = self.stack_address(sp, 2);
+        self.check_stack_alignment(address, 2);
+        self.get_memory16(address)
+    }
+
+    // VIPが立っている状態で割り込みを許可しようとしたか、TFを立てようとしたか
+    fn check_virtual_interrupt_flags(&self, flags: u32) -> Result<(), Exception> {
+        let pending = self.eflags & Self::VIRTUAL_INTERRUPT_PENDING != 0;
+        if flags & Self::TRAP_FLAG != 0 || (flags & Self::INTERRUPT_FLAG != 0 && pending) {
+            return Err(general_protection(0));
+        }
+        Ok(())
+    }
+
+    // IFの値をVIFへ写して、それ以外のFLAGSの下位16ビットを更新する
+    fn set_virtual_flags16(&mut self, flags: u32) {
+        let mask = 0xffff & !(Self::IOPL_MASK | Self::INTERRUPT_FLAG);
+        self.set_eflags((self.eflags & !mask) | (flags & mask));
+        if flags & Self::INTERRUPT_FLAG != 0 {
+            self.eflags |= Self::VIRTUAL_INTERRUPT_FLAG;
+        } else {
+            self.eflags &= !Self::VIRTUAL_INTERRUPT_FLAG;
+        }
+    }
+
+    // VMEで、IFの位置にVIFを入れてIOPLを3に見せたFLAGS
+    fn virtual_flags16(&self) -> u16 {
+        let mut flags = (self.eflags & 0xffff & !Self::INTERRUPT_FLAG) | Self::IOPL_MASK;
+        if self.eflags & Self::VIRTUAL_INTERRUPT_FLAG != 0 {
+            flags |= Self::INTERRUPT_FLAG;
+        }
+        flags as u16
+    }
+
+    // TSSの割り込みリダイレクションビットマップでビットが0ならリアルモードのハンドラへ転送する
+    // ビットマップがTSSのリミットの外にあれば、I/O許可ビットマップと同じく#GP(0)になる
+    fn is_interrupt_redirected(&mut self, vector: u8) -> Result<bool, Exception> {
+        let tss = self.task_register;
+        if tss.limit < TSS_MINIMUM_LIMIT {
+            return Err(general_protection(0));
+        }
+        let io_map_base = self.get_memory16(tss.base.wrapping_add(TSS_IO_MAP_BASE as u32) as usize);
+        // リダイレクションビットマップはI/O許可ビットマップの直前の32バイト
+        let offset = io_map_base as i64 - 32 + vector as i64 / 8;
+        if offset < 0 || offset as u32 > tss.limit {
+            return Err(general_protection(0));
+        }
+        let address = tss.base.wrapping_add(offset as u32) as usize;
+        Ok((self.get_memory8(address) >> (vector & 7)) & 1 == 0)
+    }
+
+    // リアルモードと同じく、割り込みベクタテーブルのハンドラへ飛ぶ
+    fn redirect_interrupt(&mut self, vector: u8) {
+        let flags = if self.uses_virtual_interrupt_flag() {
+            self.virtual_flags16()
+        } else {
+            self.eflags as u16
+        };
+        let cs = self.segment_registers[SegmentRegister::CS as usize];
+        let ip = self.eip as u16;
+        self.push16(flags);
+        self.push16(cs);
+        self.push16(ip);
+
+        let address = vector as usize * 4;
+        let new_ip = self.get_memory16(address);
+        let new_cs = self.get_memory16(address + 2) as u16;
+        self.eflags &= !(Self::TRAP_FLAG | Self::RESUME_FLAG);
+        if self.uses_virtual_interrupt_flag() {
+            self.eflags &= !Self::VIRTUAL_INTERRUPT_FLAG;
+        } else {
+            self.eflags &= !Self::INTERRUPT_FLAG;
+        }
+        self.set_segment_register(SegmentRegister::CS as usize, new_cs);
+        self.eip = new_ip as u64;
+        self.interrupt_delivered = true;
+    }
+}
+
+impl Virtual8086 for Emulator {
+    fn enter_virtual_8086(&mut self, sp: u32, eip: u32, cs: u16, eflags: u32) {
+        // IRETのスタックにはEIP, CS, EFLAGSに続いてESP, SS, ES, DS, FS, GSが積まれている
+        let new_esp = self.read_stack(sp.wrapping_add(12), 4);
+        let ss = self.read_stack(sp.wrapping_add(16), 2) as u16;
+        let es = self.read_stack(sp.wrapping_add(20), 2) as u16;
+        let ds = self.read_stack(sp.wrapping_add(24), 2) as u16;
+        let fs = self.read_stack(sp.wrapping_add(28), 2) as u16;
+        let gs = self.read_stack(sp.wrapping_add(32), 2) as u16;
+        self.set_eflags(eflags);
+        self.set_segment_register(SegmentRegister::ES as usize, es);
+        self.set_segment_register(SegmentRegister::CS as usize, cs);
+        self.set_segment_register(SegmentRegister::SS as usize, ss);
+        self.set_segment_register(SegmentRegister::DS as usize, ds);
+        self.set_segment_register(SegmentRegister::FS as usize, fs);
+        self.set_segment_register(SegmentRegister::GS as usize, gs);
+        self.set_register32(Register::ESP as usize, new_esp);
+        self.eip = (eip & 0xffff) as u64;
+    }
+
+    fn leave_virtual_8086(&mut self, ss: u16, esp: u32) {
+        let old_ss = self.segment_registers[SegmentRegister::SS as usize];
+        let old_esp = self.get_register32(Register::ESP as usize);
+        self.eflags &= !Self::VIRTUAL_8086_FLAG;
+        self.set_segment_register(SegmentRegister::SS as usize, ss);
+        self.set_register32(Register::ESP as usize, esp);
+        // データセグメントはスタックに退避してからヌルにする
+        for &index in SAVED_DATA_SEGMENTS.iter() {
+            let selector = self.segment_registers[index];
+            self.push32(selector as u32);
+            self.set_segment_register(index, 0);
+        }
+        self.push32(old_ss as u32);
+        self.push32(old_esp);
+    }
+
+    fn virtual_8086_cli(&mut self) {
+        if self.get_iopl() == 3 {
+            self.eflags &= !Self::INTERRUPT_FLAG;
+        } else if self.uses_virtual_interrupt_flag() {
+            self.eflags &= !Self::VIRTUAL_INTERRUPT_FLAG;
+        } else {
+            self.raise_exception(general_protection(0));
+            return;
+        }
+        self.eip += 1;
+    }
+
+    fn virtual_8086_sti(&mut self) {
+        if self.get_iopl() == 3 {
+            self.eflags |= Self::INTERRUPT_FLAG;
+        } else if self.uses_virtual_interrupt_flag() && self.eflags & Self::VIRTUAL_INTERRUPT_PENDING == 0 {
+            self.eflags |= Self::VIRTUAL_INTERRUPT_FLAG;
+        } else {
+            self.raise_exception(general_protection(0));
+            return;
+        }
+        self.eip += 1;
+    }
+
+    fn virtual_8086_pushf(&mut self) {
+        if self.get_iopl() == 3 {
+            // VMとRFはスタックに積まない
+            let eflags = self.eflags & !(Self::VIRTUAL_8086_FLAG | Self::RESUME_FLAG);
+            if self.is_operand_size16() {
+                self.push16(eflags as u16);
+            } else {
+                self.push32(eflags);
+            }
+        } else if self.uses_virtual_interrupt_flag() && self.is_operand_size16() {
+            let flags = self.virtual_flags16();
+            self.push16(flags);
+        } else {
+            self.raise_exception(general_protection(0));
+            return;
+        }
+        self.eip += 1;
+    }
+
+    fn virtual_8086_popf(&mut self) {
+        if self.get_iopl() == 3 {
+            // V86モードからはIOPLやVMを変更できない
+            let (value, mask) = if self.is_operand_size16() {
+                (self.pop16() as u32, 0xffff & !Self::IOPL_MASK)
+            } else {
+                let mask =
+                    !(Self::IOPL_MASK | Self::RESUME_FLAG | Self::VIRTUAL_8086_FLAG | Self::VIRTUAL_INTERRUPT_FLAG | Self::VIRTUAL_INTERRUPT_PENDING);
+                (self.pop32(), mask)
+            };
+            self.set_eflags((self.eflags & !mask) | (value & mask));
+        } else if self.uses_virtual_interrupt_flag() && self.is_operand_size16() {
+            let flags = self.peek_flags16(0);
+            if let Err(e) = self.check_virtual_interrupt_flags(flags) {
+                self.raise_exception(e);
+                return;
+            }
+            self.pop16();
+            self.set_virtual_flags16(flags);
+        } else {
+            self.raise_exception(general_protection(0));
+            return;
+        }
+        self.eip += 1;
+    }
+
+    fn virtual_8086_interrupt(&mut self, vector: u8) {
+        if self.control_registers[4] & CR4_VME != 0 {
+            match self.is_interrupt_redirected(vector) {
+                Ok(true) => {
+                    self.redirect_interrupt(vector);
+                    return;
+                }
+                Ok(false) => (),
+                Err(e) => {
+                    self.raise_exception(e);
+                    return;
+                }
+            }
+        }
+        if self.get_iopl() < 3 {
+            self.raise_exception(general_protection(0));
+        } else {
+            self.raise_software_interrupt(vector);
+        }
+    }
+
+    fn virtual_8086_iret(&mut self) -> Result<(), Exception> {
+        if self.get_iopl() == 3 {
+            let (eip, cs, flags, mask) = if self.is_operand_size16() {
+                let eip = self.pop16() as u32;
+                let cs = self.pop16();
+                (eip, cs, self.pop16() as u32, 0xffff & !Self::IOPL_MASK)
+            } else {
+                let eip = self.pop32();
+                let cs = self.pop32() as u16;
+                let mask = !(Self::IOPL_MASK | Self::VIRTUAL_8086_FLAG | Self::VIRTUAL_INTERRUPT_FLAG | Self::VIRTUAL_INTERRUPT_PENDING);
+                (eip, cs, self.pop32(), mask)
+            };
+            self.set_eflags((self.eflags & !mask) | (flags & mask));
+            self.set_segment_register(SegmentRegister::CS as usize, cs);
+            self.eip = eip as u64;
+            return Ok(());
+        }
+        if !self.uses_virtual_interrupt_flag() || !self.is_operand_size16() {
+            return Err(general_protection(0));
+        }
+        let flags = self.peek_flags16(4);
+        self.check_virtual_interrupt_flags(flags)?;
+        let ip = self.pop16();
+        let cs = self.pop16();
+        self.pop16();
+        self.set_virtual_flags16(flags);
+        self.set_segment_register(SegmentRegister::CS as usize, cs);
+        self.eip = ip as u64;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use emulator::control_register::CR4_VME;
+    use emulator::emulator_function::EmulatorFunction;
+    use emulator::task::TaskRegister;
+    use emulator::testing::*;
+    use emulator::{Emulator, SegmentRegister};
+
+    fn set_tss(emu: &mut Emulator, limit: u32, io_map_base: u16) {
+        emu.task_register = TaskRegister {
+            selector: 0x28,
+            base: TSS,
+            limit,
+        };
+        write16(emu, TSS + 0x66, io_map_base);
+    }
+
+    #[test]
+    fn redirection_bitmap_selects_real_mode_handler() {
+        let mut emu = emulator(&[]);
+        set_tss(&mut emu, 0x88, 0x88);
+        // ベクタ0x21のビットはビットマップ(0x68〜0x87)の4バイト目のビット1
+        assert!(emu.is_interrupt_redirected(0x21).unwrap());
+        write16(&mut emu, TSS + 0x68 + 4, 0x0002);
+        assert!(!emu.is_interrupt_redirected(0x21).unwrap());
+    }
+
+    #[test]
+    fn redirection_bitmap_before_tss_raises_general_protection() {
+        let mut emu = emulator(&[]);
+        set_tss(&mut emu, 0x67, 0x10);
+        assert_eq!(emu.is_interrupt_redirected(0x21).unwrap_err().vector, 13);
+    }
+
+    #[test]
+    fn redirection_bitmap_beyond_tss_limit_raises_general_protection() {
+        let mut emu = emulator(&[]);
+        set_tss(&mut emu, 0x67, 0x88);
+        assert_eq!(emu.is_interrupt_redirected(0xFF).unwrap_err().vector, 13);
+    }
+    #[test]
+    fn cli_with_iopl_below_3_traps_to_monitor() {
+        let mut emu = virtual_8086_emulator(
+            &[
+                0xFA, // cli
+            ],
+            Emulator::INTERRUPT_FLAG,
+        );
+        run(&mut emu, 1);
+        assert_eq!(emu.eip, 0x7e00);
+        assert!(!emu.is_virtual_8086());
+        // エラーコード、EIP, CS, EFLAGS, ESP, SS, ES, DS, FS, GSの順に積まれる
+        let esp = emu.get_register32(4);
+        assert_eq!(esp, RING0_STACK - 40);
+        assert_eq!(read32(&emu, esp), 0);
+        assert_eq!(read32(&emu, esp + 4), 0);
+        assert_eq!(read32(&emu, esp + 8), 0x0800);
+        assert_ne!(read32(&emu, esp + 12) & Emulator::VIRTUAL_8086_FLAG, 0);
+        assert_ne!(read32(&emu, esp + 12) & Emulator::INTERRUPT_FLAG, 0);
+        assert_eq!(read32(&emu, esp + 16), 0x1000);
+        assert_eq!(read32(&emu, esp + 20), 0x0600);
+        assert_eq!(emu.segment_registers[SegmentRegister::DS as usize], 0);
+    }
+
+    #[test]
+    fn cli_with_iopl_3_clears_interrupt_flag() {
+        let mut emu = virtual_8086_emulator(
+            &[
+                0xFA, // cli
+            ],
+            Emulator::INTERRUPT_FLAG | 3 << 12,
+        );
+        run(&mut emu, 1);
+        assert_eq!(emu.eip, 1);
+        assert!(emu.is_virtual_8086());
+        assert_eq!(emu.eflags & Emulator::INTERRUPT_FLAG, 0);
+    }
+
+    #[test]
+    fn cli_with_vme_clears_virtual_interrupt_flag() {
+        let mut emu = virtual_8086_emulator(
+            &[
+                0xFA, // cli
+                0x9C, // pushf
+            ],
+            Emulator::INTERRUPT_FLAG | Emulator::VIRTUAL_INTERRUPT_FLAG,
+        );
+        emu.control_registers[4] |= CR4_VME;
+        run(&mut emu, 2);
+        assert_eq!(emu.eip, 2);
+        assert_ne!(emu.eflags & Emulator::INTERRUPT_FLAG, 0);
+        assert_eq!(emu.eflags & Emulator::VIRTUAL_INTERRUPT_FLAG, 0);
+        // PUSHFはVIFをIFの位置に入れ、IOPLを3に見せる
+        let flags = read16(&emu, 0x6000 + 0x1000 - 2) as u32;
+        assert_eq!(flags & Emulator::INTERRUPT_FLAG, 0);
+        assert_eq!(flags & (3 << 12), 3 << 12);
+    }
+
+    #[test]
+    fn int_with_iopl_below_3_and_without_vme_traps_to_monitor() {
+        let mut emu = virtual_8086_emulator(
+            &[
+                0xCD, 0x21, // int 0x21
+            ],
+            0,
+        );
+        run(&mut emu, 1);
+        assert_eq!(emu.eip, 0x7e00);
+        assert_eq!(read32(&emu, emu.get_register32(4) + 4), 0);
+    }
+
+    #[test]
+    fn int_with_vme_is_redirected_to_real_mode_handler() {
+        let mut emu = virtual_8086_emulator(
+            &[
+                0xCD, 0x21, // int 0x21
+            ],
+            Emulator::VIRTUAL_INTERRUPT_FLAG,
+        );
+        emu.control_registers[4] |= CR4_VME;
+        // リダイレクションビットマップ(0x68〜0x87)はすべて0
+        set_tss(&mut emu, 0x88, 0x88);
+        // 割り込みベクタテーブルのハンドラは0x0800:0010
+        write16(&mut emu, 0x21 * 4, 0x0010);
+        write16(&mut emu, 0x21 * 4 + 2, 0x0800);
+        run(&mut emu, 1);
+        assert!(emu.is_virtual_8086());
+        assert_eq!(emu.eip, 0x10);
+        assert_eq!(emu.segment_registers[SegmentRegister::CS as usize], 0x0800);
+        assert_eq!(emu.eflags & Emulator::VIRTUAL_INTERRUPT_FLAG, 0);
+        // FLAGS, CS, IPの順に16ビットで積まれ、FLAGSのIFにはVIFが入る
+        assert_eq!(emu.registers[4], 0x1000 - 6);
+        assert_eq!(read16(&emu, 0x6000 + 0x1000 - 6), 2);
+        assert_eq!(read16(&emu, 0x6000 + 0x1000 - 4), 0x0800);
+        assert_ne!(read16(&emu, 0x6000 + 0x1000 - 2) as u32 & Emulator::INTERRUPT_FLAG, 0);
+    }
+}
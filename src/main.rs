@@ -1,7 +1,27 @@
 extern crate rust_emu;
 
 use rust_emu::emulator::Emulator;
+use rust_emu::emulator::bus::PHYSICAL_ADDRESS_SPACE;
+use rust_emu::emulator::cpu_model::CpuModel;
 use rust_emu::emulator::instruction::Instruction;
+use rust_emu::emulator::stop::StopReason;
+use rust_emu::emulator::undefined_flags::FlagsPolicy;
+use rust_emu::emulator::unimplemented::UnimplementedPolicy;
+
+// 1048576や640K, 16M, 4Gのような大きさを読む(プログラムを置く0x7c00を含み、4GB以下)
+fn parse_memory_size(name: &str) -> Option<usize> {
+    let (digits, unit) = match name.chars().last() {
+        Some('K') | Some('k') => (&name[..name.len() - 1], 1 << 10),
+        Some('M') | Some('m') => (&name[..name.len() - 1], 1 << 20),
+        Some('G') | Some('g') => (&name[..name.len() - 1], 1 << 30),
+        _ => (name, 1),
+    };
+    let size = digits.parse::<u64>().ok()?.checked_mul(unit)?;
+    if !(0x8000..=PHYSICAL_ADDRESS_SPACE).contains(&size) {
+        return None;
+    }
+    Some(size as usize)
+}
 
 fn main() {
     let mut args: Vec<String> = std::env::args().collect();
@@ -10,18 +30,115 @@ fn main() {
     let quiet = args.iter().find(|&arg| *arg == "-q".to_string()).is_some();
     args.retain(|ref arg| **arg != "-q".to_string());
 
+    // --cpu=8086のようにエミュレートするCPUの世代を選ぶ
+    let cpu_name = args.iter().find(|arg| arg.starts_with("--cpu=")).map(|arg| arg["--cpu=".len()..].to_string());
+    args.retain(|arg| !arg.starts_with("--cpu="));
+    // --flags=amdのように未定義のフラグの決め方を選ぶ
+    let flags_name = args.iter().find(|arg| arg.starts_with("--flags=")).map(|arg| arg["--flags=".len()..].to_string());
+    args.retain(|arg| !arg.starts_with("--flags="));
+    // --unimplemented=udのように実装していない命令の扱いを選ぶ
+    let unimplemented_name = args.iter().find(|arg| arg.starts_with("--unimplemented=")).map(|arg| arg["--unimplemented=".len()..].to_string());
+    args.retain(|arg| !arg.starts_with("--unimplemented="));
+    // --memory=16Mのようにメモリの大きさを選ぶ(最大4G)
+    let memory_name = args.iter().find(|arg| arg.starts_with("--memory=")).map(|arg| arg["--memory=".len()..].to_string());
+    args.retain(|arg| !arg.starts_with("--memory="));
+    // --realでリセット直後のリアルモードから始める(指定しなければフラットな32ビット保護モード)
+    let real_mode = args.iter().find(|&arg| *arg == "--real".to_string()).is_some();
+    args.retain(|ref arg| **arg != "--real".to_string());
+
     if args.len() != 2 {
-        eprintln!("usage: px86 filename");
+        eprintln!("usage: px86 [-q] [--cpu=8086|286|386|486|pentium|pentium2|x86_64|broadwell] [--flags=intel|amd|poison]");
+        eprintln!("             [--unimplemented=abort|ud|skip] [--memory=SIZE(K|M|G)] [--real] filename");
         ::std::process::exit(1);
     }
 
+    let memory_size = match memory_name {
+        Some(name) => match parse_memory_size(&name) {
+            Some(size) => size,
+            None => {
+                eprintln!("不正なメモリの大きさです: {}", name);
+                ::std::process::exit(1);
+            }
+        },
+        None => rust_emu::emulator::MEMORY_SIZE,
+    };
+
     let mut emu: Emulator;
     if let Ok(f) = ::std::fs::File::open(&args[1]) {
-        emu = Emulator::new(rust_emu::emulator::MEMORY_SIZE, 0x7c00, 0x7c00, f);
+        match Emulator::new(memory_size, 0x7c00, 0x7c00, f) {
+            Ok(e) => emu = e,
+            Err(e) => {
+                eprintln!("メモリを用意できません: {}", e);
+                ::std::process::exit(1);
+            }
+        }
     } else {
         eprintln!("ファイルが開けません: {}", &args[1]);
         ::std::process::exit(1);
     }
-    emu.run_instructions(quiet);
+    if real_mode {
+        emu.reset_to_real_mode();
+    }
+    if let Some(name) = cpu_name {
+        match CpuModel::from_name(&name) {
+            Some(model) => emu.set_cpu_model(model),
+            None => {
+                eprintln!("不明なCPUです: {}", name);
+                ::std::process::exit(1);
+            }
+        }
+    }
+    if let Some(name) = flags_name {
+        match FlagsPolicy::from_name(&name) {
+            Some(policy) => emu.set_flags_policy(policy),
+            None => {
+                eprintln!("不明なフラグのポリシーです: {}", name);
+                ::std::process::exit(1);
+            }
+        }
+    }
+    if let Some(name) = unimplemented_name {
+        match UnimplementedPolicy::from_name(&name) {
+            Some(policy) => emu.set_unimplemented_policy(policy),
+            None => {
+                eprintln!("不明な未実装命令のポリシーです: {}", name);
+                ::std::process::exit(1);
+            }
+        }
+    }
+    let result = emu.run_instructions(quiet, None);
+    // 実装していない命令に出会った場所を報告する
+    for encounter in emu.unimplemented_encounters() {
+        let bytes: Vec<String> = encounter.bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+        eprintln!("未実装の命令: EIP = {:0X}, Code = {} ({}回)", encounter.eip, bytes.join(" "), encounter.count);
+    }
+    match result {
+        Ok(StopReason::GuestExit) => println!("end of program."),
+        Ok(StopReason::Halted) => println!("HLTで停止しました."),
+        Ok(StopReason::Shutdown) => {
+            eprintln!("トリプルフォールトが発生しました");
+            ::std::process::exit(1);
+        }
+        Ok(reason) => println!("停止しました: {:?}", reason),
+        Err(e) => {
+            eprintln!("{}", e);
+            ::std::process::exit(1);
+        }
+    }
     emu.dump_registers();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::parse_memory_size;
+
+    #[test]
+    fn memory_size_must_hold_the_program_and_fit_in_4gb() {
+        assert_eq!(parse_memory_size("32K"), Some(0x8000));
+        assert_eq!(parse_memory_size("16M"), Some(16 << 20));
+        assert_eq!(parse_memory_size("4G"), Some(1 << 32));
+        assert_eq!(parse_memory_size("31K"), None);
+        assert_eq!(parse_memory_size("5G"), None);
+        assert_eq!(parse_memory_size("16X"), None);
+    }
+}